@@ -0,0 +1,115 @@
+// Decides which of the connected clients controls the car. Only the controller's commands reach
+// the failsafe and the channels, all other clients are observers.
+use messages::drive_core::{ Controller, Role };
+
+use std::collections::HashMap;
+
+/// Identifies a client connection for as long as drive-core runs
+pub type ClientId = u64;
+
+pub struct Arbiter {
+  claims: HashMap<ClientId, Controller>,
+  controller: Option<ClientId>,
+}
+
+impl Arbiter {
+  pub fn new() -> Arbiter {
+    Arbiter { claims: HashMap::new(), controller: None }
+  }
+
+  /// Client `id` asks for control as `name` in `role`. It gets control if nobody has it, if it
+  /// has a higher priority than the current controller or if it is a manual driver.
+  /// Returns whether the client is in control now.
+  pub fn claim(&mut self, id: ClientId, name: &str, role: Role) -> bool {
+    self.claims.insert(id, Controller { name: name.to_string(), role });
+
+    let takes_over = match self.controller {
+      None => true,
+      Some(current) if current == id => true,
+      Some(current) => {
+        let current_priority = self.claims[&current].role.priority();
+        role == Role::Manual || role.priority() > current_priority
+      },
+    };
+    if takes_over {
+      self.controller = Some(id);
+    }
+    takes_over
+  }
+
+  /// Client `id` gives up its claim or disconnected. Nobody is in control afterwards if it was the
+  /// controller: the others have to claim again, so no client ends up driving by surprise.
+  /// Returns whether the client was in control.
+  pub fn release(&mut self, id: ClientId) -> bool {
+    self.claims.remove(&id);
+    if self.controller == Some(id) {
+      self.controller = None;
+      return true;
+    }
+    false
+  }
+
+  pub fn is_controller(&self, id: ClientId) -> bool {
+    self.controller == Some(id)
+  }
+
+  pub fn controller(&self) -> Option<Controller> {
+    self.controller.map(|id| self.claims[&id].clone())
+  }
+}
+
+impl Default for Arbiter {
+  fn default() -> Self {
+    Arbiter::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_first_claim_gets_control() {
+    let mut arbiter = Arbiter::new();
+    assert_eq!(None, arbiter.controller());
+
+    assert!(arbiter.claim(1, "planner", Role::Autopilot));
+    assert!(arbiter.is_controller(1));
+    assert_eq!(Some(Controller { name: "planner".to_string(), role: Role::Autopilot }),
+      arbiter.controller());
+  }
+
+  #[test]
+  fn higher_priorities_take_over() {
+    let mut arbiter = Arbiter::new();
+    arbiter.claim(1, "planner", Role::Autopilot);
+    assert!(arbiter.claim(2, "driving-aids", Role::Safety));
+    assert!(!arbiter.is_controller(1));
+
+    assert!(!arbiter.claim(1, "planner", Role::Autopilot));
+    assert!(arbiter.is_controller(2));
+  }
+
+  #[test]
+  fn manual_override_always_wins() {
+    let mut arbiter = Arbiter::new();
+    arbiter.claim(1, "drive-remote", Role::Manual);
+    assert!(arbiter.claim(2, "drive-remote", Role::Manual));
+    assert!(!arbiter.claim(3, "driving-aids", Role::Safety));
+    assert!(arbiter.is_controller(2));
+  }
+
+  #[test]
+  fn release_leaves_nobody_in_control() {
+    let mut arbiter = Arbiter::new();
+    arbiter.claim(1, "planner", Role::Autopilot);
+    arbiter.claim(2, "drive-remote", Role::Manual);
+
+    assert!(!arbiter.release(1));
+    assert!(arbiter.is_controller(2));
+
+    assert!(arbiter.release(2));
+    assert_eq!(None, arbiter.controller());
+    assert!(arbiter.claim(3, "planner", Role::Autopilot));
+  }
+}
@@ -0,0 +1,275 @@
+use config::Config;
+use pwm_driver::{ PwmChannel, PwmChannelArgs };
+
+use std::io;
+use std::io::{ BufRead, Write };
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// Duty cycle steps of the "+" / "-" and "++" / "--" commands
+const FINE_STEP: f32 = 0.0005f32;
+const COARSE_STEP: f32 = 0.005f32;
+
+// Most ESCs need to see the neutral signal for a while after power-up before they accept commands
+const ESC_ARMING_TIME: Duration = Duration::from_secs(3);
+
+/// The three positions that are marked during a calibration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mark {
+  Low,      // Value -1: full left (servo) or full reverse / brake (ESC)
+  Center,   // Value 0: straight ahead (servo) or neutral (ESC)
+  High,     // Value 1: full right (servo) or full forward (ESC)
+}
+
+/// State of the calibration of a single channel. Works on raw duty cycles in the range [0:1].
+pub struct Calibration {
+  esc: bool,
+  duty_cycle: f32,
+  low: Option<f32>,
+  center: Option<f32>,
+  high: Option<f32>,
+}
+
+impl Calibration {
+  pub fn new(esc: bool, duty_cycle: f32) -> Calibration {
+    Calibration { esc, duty_cycle, low: None, center: None, high: None }
+  }
+
+  pub fn duty_cycle(&self) -> f32 {
+    self.duty_cycle
+  }
+
+  pub fn step(&mut self, delta: f32) {
+    self.set_duty_cycle(self.duty_cycle + delta);
+  }
+
+  pub fn set_duty_cycle(&mut self, duty_cycle: f32) {
+    self.duty_cycle = duty_cycle.clamp(0f32, 1f32);
+  }
+
+  /// Remembers the current duty cycle as the given position
+  pub fn mark(&mut self, mark: Mark) {
+    let duty_cycle = Some(self.duty_cycle);
+    match mark {
+      Mark::Low => self.low = duty_cycle,
+      Mark::Center => self.center = duty_cycle,
+      Mark::High => self.high = duty_cycle,
+    }
+  }
+
+  /// Name of the position as the operator knows it
+  pub fn mark_name(&self, mark: Mark) -> &'static str {
+    match (self.esc, mark) {
+      (false, Mark::Low) => "left",
+      (false, Mark::Center) => "center",
+      (false, Mark::High) => "right",
+      (true, Mark::Low) => "reverse",
+      (true, Mark::Center) => "neutral",
+      (true, Mark::High) => "forward",
+    }
+  }
+
+  pub fn parse_mark(&self, name: &str) -> Option<Mark> {
+    [Mark::Low, Mark::Center, Mark::High].iter().cloned().find(|m| self.mark_name(*m) == name)
+  }
+
+  /// Turns the marked positions into a channel calibration. The range covers the side that
+  /// deviates more from the center, the other side is limited to its mark by the min/max clamps.
+  pub fn result(&self, channel: u8) -> Result<PwmChannelArgs, String> {
+    let (low, center, high) = match (self.low, self.center, self.high) {
+      (Some(low), Some(center), Some(high)) => (low, center, high),
+      _ => return Err(format!("Please mark the {}, {} and {} positions first",
+        self.mark_name(Mark::Low), self.mark_name(Mark::Center), self.mark_name(Mark::High))),
+    };
+
+    let inverted = low > center;
+    let (low_range, high_range) = if inverted {
+      (low - center, center - high)
+    } else {
+      (center - low, high - center)
+    };
+    if low_range <= 0f32 || high_range <= 0f32 {
+      return Err(format!("The {} position has to be between the {} and {} positions",
+        self.mark_name(Mark::Center), self.mark_name(Mark::Low), self.mark_name(Mark::High)));
+    }
+
+    // Don't let rounding errors turn a symmetric channel into a clamped one
+    let range = low_range.max(high_range);
+    let limit = |side_range: f32| if side_range == range { 1f32 } else { side_range / range };
+    Ok(PwmChannelArgs {
+      channel,
+      neutral: center,
+      range,
+      min: -limit(low_range),
+      max: limit(high_range),
+      inverted,
+    })
+  }
+}
+
+/// Sends the neutral signal for the time ESCs need to arm themselves
+fn arm_esc(channel: &mut PwmChannel, neutral: f32) -> io::Result<()> {
+  println!("Arming the ESC, keep the car on its stand ...");
+  if let Err(e) = channel.set_duty_cycle(neutral) {
+    return Err(io::Error::other(e.to_string()));
+  }
+  thread::sleep(ESC_ARMING_TIME);
+  println!("ESC armed.");
+  Ok(())
+}
+
+fn print_help(calibration: &Calibration) {
+  let marks = format!("{} / {} / {}", calibration.mark_name(Mark::Low),
+    calibration.mark_name(Mark::Center), calibration.mark_name(Mark::High));
+  let commands = [
+    ("+ / -".to_string(), format!("Increase / decrease the duty cycle by {}", FINE_STEP)),
+    ("++ / --".to_string(), format!("Increase / decrease the duty cycle by {}", COARSE_STEP)),
+    ("= <duty>".to_string(), "Set the duty cycle (in [0:1])".to_string()),
+    (marks, "Mark the current duty cycle as this position".to_string()),
+    ("save".to_string(), "Write the calibration to the configuration file and quit".to_string()),
+    ("quit".to_string(), "Quit without saving".to_string()),
+  ];
+
+  println!("Commands:");
+  for (command, description) in commands.iter() {
+    println!("  {:<28} {}", command, description);
+  }
+}
+
+/// Interactively calibrates the channel `name`, reading the operator's commands from stdin.
+/// On "save", the resulting calibration is written into the configuration file at `path`.
+pub fn run(channel: &mut PwmChannel,
+           name: &str,
+           esc: bool,
+           mut config: Config,
+           path: &Path) -> io::Result<()> {
+  let args = channel.config().clone();
+  let mut calibration = Calibration::new(esc, args.neutral);
+
+  if esc {
+    arm_esc(channel, args.neutral)?;
+  }
+
+  println!("Calibrating channel {} (output {}).", name, args.channel);
+  print_help(&calibration);
+
+  let stdin = io::stdin();
+  let mut lines = stdin.lock().lines();
+  loop {
+    print!("duty cycle {:.4}> ", calibration.duty_cycle());
+    io::stdout().flush()?;
+
+    let line = match lines.next() {
+      Some(line) => line?,
+      None => return Ok(()),
+    };
+
+    let command: Vec<&str> = line.split_whitespace().collect();
+    match command.as_slice() {
+      ["+"] => calibration.step(FINE_STEP),
+      ["-"] => calibration.step(-FINE_STEP),
+      ["++"] => calibration.step(COARSE_STEP),
+      ["--"] => calibration.step(-COARSE_STEP),
+      ["=", value] => match value.parse() {
+        Ok(duty_cycle) => calibration.set_duty_cycle(duty_cycle),
+        Err(_) => println!("Not a number: {}", value),
+      },
+      ["save"] => match calibration.result(args.channel) {
+        Ok(result) => {
+          println!("New calibration: {:?}", result);
+          config.channels.insert(name.to_string(), result);
+          match config.save_channel(path, name) {
+            Ok(()) => {
+              println!("Saved to {}.", path.display());
+              return Ok(());
+            },
+            Err(e) => println!("{}", e),
+          }
+        },
+        Err(msg) => println!("{}", msg),
+      },
+      ["quit"] => return Ok(()),
+      [name] if calibration.parse_mark(name).is_some() => {
+        calibration.mark(calibration.parse_mark(name).unwrap());
+        println!("Marked {} at {:.4}", name, calibration.duty_cycle());
+      },
+      [] => {},
+      _ => print_help(&calibration),
+    };
+
+    if let Err(e) = channel.set_duty_cycle(calibration.duty_cycle()) {
+      println!("Failed to set the duty cycle: {}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn calibrate(esc: bool, low: f32, center: f32, high: f32) -> Calibration {
+    let mut calibration = Calibration::new(esc, center);
+    calibration.set_duty_cycle(low);
+    calibration.mark(Mark::Low);
+    calibration.set_duty_cycle(center);
+    calibration.mark(Mark::Center);
+    calibration.set_duty_cycle(high);
+    calibration.mark(Mark::High);
+    calibration
+  }
+
+  #[test]
+  fn steps_stay_within_the_duty_cycle_range() {
+    let mut calibration = Calibration::new(false, 0.0002f32);
+    calibration.step(-FINE_STEP);
+    assert_eq!(0f32, calibration.duty_cycle());
+
+    calibration.set_duty_cycle(1.5f32);
+    assert_eq!(1f32, calibration.duty_cycle());
+  }
+
+  #[test]
+  fn marks_are_named_after_the_channel_kind() {
+    let servo = Calibration::new(false, 0f32);
+    assert_eq!(Some(Mark::Low), servo.parse_mark("left"));
+    assert_eq!(None, servo.parse_mark("reverse"));
+
+    let esc = Calibration::new(true, 0f32);
+    assert_eq!(Some(Mark::High), esc.parse_mark("forward"));
+    assert_eq!(None, esc.parse_mark("right"));
+  }
+
+  #[test]
+  fn result_requires_all_marks() {
+    let mut calibration = Calibration::new(false, 0.085f32);
+    calibration.mark(Mark::Center);
+    assert!(calibration.result(0).is_err());
+  }
+
+  #[test]
+  fn result_of_a_symmetric_channel() {
+    let args = calibrate(true, 0.05f32, 0.075f32, 0.1f32).result(1).unwrap();
+    assert!(!args.inverted);
+    assert_eq!(1, args.channel);
+    assert_eq!(0.075f32, args.neutral);
+    assert!((args.range - 0.025f32).abs() < 1e-6);
+    assert_eq!(-1f32, args.min);
+    assert!((args.max - 1f32).abs() < 1e-6);
+  }
+
+  #[test]
+  fn result_of_an_inverted_asymmetric_channel() {
+    let args = calibrate(false, 0.1f32, 0.085f32, 0.075f32).result(0).unwrap();
+    assert!(args.inverted);
+    assert_eq!(0.085f32, args.neutral);
+    assert!((args.range - 0.015f32).abs() < 1e-6);
+    assert_eq!(-1f32, args.min);
+    assert!((args.max - 0.6667f32).abs() < 1e-3);
+  }
+
+  #[test]
+  fn result_rejects_a_center_outside_of_the_range() {
+    assert!(calibrate(false, 0.07f32, 0.1f32, 0.09f32).result(0).is_err());
+  }
+}
@@ -0,0 +1,150 @@
+// A connection to one of drive-core's clients. The sockets are non-blocking, so a single silent
+// or slow client can't stall the others or the failsafe.
+use arbiter::ClientId;
+use command::{ CommandFilter, CommandStats, Verdict };
+
+use messages::drive_core::{ DriveCommand, MessageType, Status };
+use messages::frame::{ write_message, FrameError, FrameReader };
+use messages::handshake::{ Handshake, Peer };
+use messages::health::{ Health, HealthReport };
+use util::logging::LogConnection;
+use util::mesh::Service;
+
+use std::io;
+use std::net::{ SocketAddr, TcpStream };
+use std::time::Duration;
+
+// Clients that don't introduce themselves within this time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Client {
+  pub id: ClientId,
+  pub addr: SocketAddr,
+  socket: TcpStream,
+  reader: FrameReader,
+  peer: Option<Peer>,         // Set once the handshake is complete
+  connected_at: Duration,
+  pub heartbeat: u32,         // Sequence number of the client's last heartbeat
+  pub status_requested: bool, // The client sent a heartbeat that we didn't answer yet
+  pub ping: Option<u32>,      // Sequence number of a health check we didn't answer yet
+  filter: CommandFilter,
+  stats: Option<CommandStats>, // Logged once the client sends its first drive command
+}
+
+impl Client {
+  pub fn new(id: ClientId,
+             socket: TcpStream,
+             addr: SocketAddr,
+             now: Duration) -> io::Result<Client> {
+    socket.set_nonblocking(true)?;
+    socket.set_nodelay(true)?;
+    Ok(Client {
+      id,
+      addr,
+      socket,
+      reader: FrameReader::new(),
+      peer: None,
+      connected_at: now,
+      heartbeat: 0,
+      status_requested: false,
+      ping: None,
+      filter: CommandFilter::new(),
+      stats: None,
+    })
+  }
+
+  pub fn name(&self) -> &str {
+    self.peer.as_ref().map_or("unknown", |peer| &peer.name)
+  }
+
+  /// Returns the messages received since the last call. The handshake is handled here, so only
+  /// messages of introduced clients are returned. An error means the client has to be dropped.
+  pub fn poll(&mut self, now: Duration) -> Result<Vec<MessageType>, String> {
+    let mut messages = Vec::new();
+    loop {
+      let frame = match self.reader.read_frame(&mut self.socket) {
+        Ok(frame) => frame,
+        Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof =>
+          return Err("Connection closed".to_string()),
+        Err(e @ FrameError::BadChecksum) | Err(e @ FrameError::TooLarge(_)) => {
+          // The reader skips the broken frame, the next one may be fine again
+          println!("Dropping invalid frame from {}. {}", self.name(), e);
+          continue;
+        },
+        Err(e) => return Err(e.to_string()),
+      };
+
+      if self.peer.is_none() {
+        let hello: Handshake = frame.decode().map_err(|e| e.to_string())?;
+        let (reply, peer) = hello.answer(Service::DriveCore.name(), env!("CARGO_PKG_VERSION"));
+        write_message(&mut self.socket, &reply).map_err(|e| e.to_string())?;
+        match peer {
+          Some(peer) => {
+            println!("Client {} is {} {}", self.addr, peer.name, peer.software_version);
+            self.peer = Some(peer);
+          },
+          None => return Err(format!("Refused: {:?}", reply)),
+        }
+        continue;
+      }
+
+      // Health checks may come from any client, e.g. the supervisor
+      if frame.is::<Health>() {
+        match frame.decode() {
+          Ok(Health::Ping(seq)) => self.ping = Some(seq),
+          Ok(msg) => println!("Ignoring unexpected health message {:?}", msg),
+          Err(e) => println!("Ignoring invalid health message from {}. {}", self.name(), e),
+        }
+        continue;
+      }
+
+      match frame.decode() {
+        Ok(msg) => messages.push(msg),
+        Err(e) => println!("Ignoring invalid message from {}. {}", self.name(), e),
+      }
+    }
+
+    if self.peer.is_none() && now >= self.connected_at + HANDSHAKE_TIMEOUT {
+      return Err("No handshake".to_string());
+    }
+    Ok(messages)
+  }
+
+  /// Checks the order and age of a drive command `received` at that time (microseconds since the
+  /// UNIX epoch) and records the result in the statistics
+  pub fn check_command(&mut self,
+                       command: &DriveCommand,
+                       received: u64,
+                       max_age: Duration,
+                       log_connection: &LogConnection) -> Verdict {
+    let verdict = self.filter.check(command, received, max_age);
+    let name = self.name().to_string();
+    self.stats.get_or_insert_with(|| CommandStats::new(log_connection, &name)).record(verdict);
+    verdict
+  }
+
+  /// Answers the last health check of the client
+  pub fn send_health(&mut self, uptime: Duration, detail: &str) {
+    if let Some(seq) = self.ping.take() {
+      let uptime_ms = uptime.as_millis() as u64;
+      let report = HealthReport { seq, uptime_ms, detail: detail.to_string() };
+      if let Err(e) = write_message(&mut self.socket, &Health::Pong(report)) {
+        println!("Failed to answer the health check of {}: {}", self.name(), e);
+      }
+    }
+  }
+
+  /// Sends a status to introduced clients, answering their last heartbeat. Failures show up on
+  /// the next poll.
+  pub fn send(&mut self, mut status: Status) {
+    if self.peer.is_none() {
+      return;
+    }
+    status.heartbeat = self.heartbeat;
+    if let Err(e) = write_message(&mut self.socket, &status) {
+      println!("Failed to send the status to {}: {}", self.name(), e);
+    }
+    self.status_requested = false;
+  }
+}
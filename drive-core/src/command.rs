@@ -0,0 +1,196 @@
+// Checks incoming drive commands for their values, order and age and keeps per-client statistics.
+//
+// Client clocks aren't synchronized with ours, so the age of a command can't be read off its
+// timestamp directly. Instead, the smallest difference between arrival time and timestamp seen so
+// far serves as the baseline: it's the clock offset plus the fastest transmission. The latency of
+// a command is how much slower it was than that.
+use failsafe::Outputs;
+use messages::drive_core::DriveCommand;
+use util::logging::LogConnection;
+use util::variable::Variable;
+
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+  Apply(Duration),            // Latency of the command
+  Stale,                      // Not newer than the last applied command
+  TooOld(Duration),           // Latency exceeds the maximum age
+  Invalid,                    // A value is out of its range or not a number
+}
+
+pub struct CommandFilter {
+  last_seq: Option<u32>,
+  min_delay: Option<i64>,     // Smallest arrival time minus timestamp in microseconds
+}
+
+impl CommandFilter {
+  pub fn new() -> CommandFilter {
+    CommandFilter { last_seq: None, min_delay: None }
+  }
+
+  /// Decides whether `command`, arriving at `now` (microseconds since the UNIX epoch), may be
+  /// applied. Accepted commands become the new reference for the sequence numbers.
+  pub fn check(&mut self, command: &DriveCommand, now: u64, max_age: Duration) -> Verdict {
+    // NaN would slip through the clamps of the channels as their extreme position
+    if !(-1f32..=1f32).contains(&command.steering) || !(-1f32..=1f32).contains(&command.throttle)
+      || !(0f32..=1f32).contains(&command.brake) {
+      return Verdict::Invalid;
+    }
+
+    if self.last_seq.is_some_and(|seq| command.seq <= seq) {
+      return Verdict::Stale;
+    }
+
+    let delay = now as i64 - command.timestamp as i64;
+    let min_delay = self.min_delay.map_or(delay, |min_delay| min_delay.min(delay));
+    self.min_delay = Some(min_delay);
+
+    let latency = Duration::from_micros((delay - min_delay) as u64);
+    if latency > max_age {
+      return Verdict::TooOld(latency);
+    }
+
+    self.last_seq = Some(command.seq);
+    Verdict::Apply(latency)
+  }
+}
+
+impl Default for CommandFilter {
+  fn default() -> Self {
+    CommandFilter::new()
+  }
+}
+
+/// The channel values a command asks for. Braking is a negative throttle, which ESCs turn into
+/// braking while the car rolls forward.
+pub fn outputs(command: &DriveCommand) -> Outputs {
+  let throttle = if command.brake > 0f32 { -command.brake.min(1f32) } else { command.throttle };
+  Outputs { steering: command.steering, throttle }
+}
+
+/// Latency and drop statistics of a client's drive commands, exported to the logger
+pub struct CommandStats {
+  latency: Variable<'static, f32>,    // Latency of the last applied command in [ms]
+  stale: Variable<'static, i32>,      // Number of commands rejected for their sequence number
+  too_old: Variable<'static, i32>,    // Number of commands rejected for their age
+  invalid: Variable<'static, i32>,    // Number of commands rejected for their values
+}
+
+impl CommandStats {
+  pub fn new(log_connection: &LogConnection, client: &str) -> CommandStats {
+    let mut stats = CommandStats {
+      latency: Variable::new(0f32),
+      stale: Variable::new(0),
+      too_old: Variable::new(0),
+      invalid: Variable::new(0),
+    };
+
+    let prefix = format!("drive-core_{}", client);
+    log_connection.log_variable(&mut stats.latency, &format!("{}_latency", prefix));
+    log_connection.log_variable(&mut stats.stale, &format!("{}_stale", prefix));
+    log_connection.log_variable(&mut stats.too_old, &format!("{}_too_old", prefix));
+    log_connection.log_variable(&mut stats.invalid, &format!("{}_invalid", prefix));
+    stats
+  }
+
+  pub fn record(&mut self, verdict: Verdict) {
+    match verdict {
+      Verdict::Apply(latency) => self.latency.set_value(latency.as_secs_f32() * 1000f32),
+      Verdict::Stale => {
+        let count = *self.stale.value() + 1;
+        self.stale.set_value(count);
+      },
+      Verdict::TooOld(_) => {
+        let count = *self.too_old.value() + 1;
+        self.too_old.set_value(count);
+      },
+      Verdict::Invalid => {
+        let count = *self.invalid.value() + 1;
+        self.invalid.set_value(count);
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MAX_AGE: Duration = Duration::from_millis(100);
+
+  fn command(seq: u32, timestamp: u64) -> DriveCommand {
+    DriveCommand { seq, timestamp, steering: 0f32, throttle: 0f32, brake: 0f32 }
+  }
+
+  #[test]
+  fn it_applies_commands_in_order() {
+    let mut filter = CommandFilter::new();
+    let none = Duration::from_micros(0);
+    assert_eq!(Verdict::Apply(none), filter.check(&command(1, 1000), 5000, MAX_AGE));
+    assert_eq!(Verdict::Apply(none), filter.check(&command(3, 2000), 6000, MAX_AGE));
+  }
+
+  #[test]
+  fn it_rejects_repeated_and_reordered_commands() {
+    let mut filter = CommandFilter::new();
+    filter.check(&command(5, 1000), 1000, MAX_AGE);
+    assert_eq!(Verdict::Stale, filter.check(&command(5, 1000), 1000, MAX_AGE));
+    assert_eq!(Verdict::Stale, filter.check(&command(4, 900), 1100, MAX_AGE));
+  }
+
+  #[test]
+  fn latency_is_relative_to_the_fastest_command() {
+    let mut filter = CommandFilter::new();
+    // The client's clock is 1 s behind ours
+    filter.check(&command(1, 0), 1_000_000, MAX_AGE);
+    assert_eq!(Verdict::Apply(Duration::from_millis(30)),
+      filter.check(&command(2, 50_000), 1_080_000, MAX_AGE));
+    assert_eq!(Verdict::TooOld(Duration::from_millis(150)),
+      filter.check(&command(3, 100_000), 1_250_000, MAX_AGE));
+
+    // A rejected command doesn't advance the sequence
+    assert_eq!(Verdict::Apply(Duration::from_millis(0)),
+      filter.check(&command(3, 300_000), 1_300_000, MAX_AGE));
+  }
+
+  #[test]
+  fn it_rejects_values_out_of_range() {
+    let mut filter = CommandFilter::new();
+    let invalid = [
+      DriveCommand { steering: f32::NAN, ..command(1, 0) },
+      DriveCommand { throttle: 1.5f32, ..command(1, 0) },
+      DriveCommand { throttle: f32::NEG_INFINITY, ..command(1, 0) },
+      DriveCommand { brake: -0.2f32, ..command(1, 0) },
+    ];
+    for command in &invalid {
+      assert_eq!(Verdict::Invalid, filter.check(command, 1000, MAX_AGE));
+    }
+
+    // Rejected commands don't advance the sequence
+    let full = DriveCommand { steering: -1f32, throttle: 1f32, brake: 1f32, ..command(1, 0) };
+    assert_eq!(Verdict::Apply(Duration::from_millis(0)), filter.check(&full, 1000, MAX_AGE));
+  }
+
+  #[test]
+  fn brake_takes_precedence_over_throttle() {
+    let mut brake = command(1, 0);
+    brake.steering = 0.5f32;
+    brake.throttle = 1f32;
+    brake.brake = 0.4f32;
+    assert_eq!(Outputs { steering: 0.5f32, throttle: -0.4f32 }, outputs(&brake));
+
+    brake.brake = 0f32;
+    assert_eq!(Outputs { steering: 0.5f32, throttle: 1f32 }, outputs(&brake));
+  }
+
+  #[test]
+  fn a_faster_command_moves_the_baseline() {
+    let mut filter = CommandFilter::new();
+    filter.check(&command(1, 0), 50_000, MAX_AGE);
+    assert_eq!(Verdict::Apply(Duration::from_millis(0)),
+      filter.check(&command(2, 100_000), 110_000, MAX_AGE));
+    assert_eq!(Verdict::TooOld(Duration::from_millis(110)),
+      filter.check(&command(3, 200_000), 320_000, MAX_AGE));
+  }
+}
@@ -0,0 +1,493 @@
+use control::ControlConfig;
+use failsafe::FailsafeConfig;
+use output_filter::FilterConfig;
+use pwm_driver::{ PwmArgs, PwmChannelArgs, CHANNEL_COUNT };
+
+use std::collections::{ BTreeMap, HashMap };
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ Read, Write };
+use std::path::Path;
+
+use toml;
+use toml_edit::{ DocumentMut, Item, Table, Value };
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/aicc/drive-core.toml";
+
+// The names of the channels drive-core itself drives. Any other channel is optional.
+pub const CHANNEL_STEERING: &str = "steering";
+pub const CHANNEL_THROTTLE: &str = "throttle";
+
+/// Calibration and wiring of the car, loaded from a TOML file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+  pub pwm: PwmConfig,
+  pub gpio: GpioConfig,
+  #[serde(default)]
+  pub control: ControlConfig,
+  #[serde(default)]
+  pub failsafe: FailsafeConfig,
+  pub channels: BTreeMap<String, PwmChannelArgs>,
+  #[serde(default)]
+  pub filters: BTreeMap<String, FilterConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PwmConfig {
+  pub i2c_device: String,   // Path of the I2C bus the PWM driver board is connected to
+  pub address: u16,         // I2C address of the PWM driver board
+  pub frequency: f32,       // PWM frequency to use in [Hz]
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GpioConfig {
+  pub enable_pin: u64,      // sysfs number of the GPIO wired to the PWM driver's output enable pin
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(io::Error),
+  Parse(toml::de::Error),
+  Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+  fn invalid(field: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid { field: field.to_string(), reason: reason.to_string() }
+  }
+}
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigError::Io(ref e) => write!(f, "Could not read configuration: {}", e),
+      ConfigError::Parse(ref e) => write!(f, "Could not parse configuration: {}", e),
+      ConfigError::Invalid { ref field, ref reason } =>
+        write!(f, "Invalid configuration value for `{}`: {}", field, reason),
+    }
+  }
+}
+
+impl From<io::Error> for ConfigError {
+  fn from(e: io::Error) -> Self {
+    ConfigError::Io(e)
+  }
+}
+
+impl From<toml::de::Error> for ConfigError {
+  fn from(e: toml::de::Error) -> Self {
+    ConfigError::Parse(e)
+  }
+}
+
+impl Default for Config {
+  /// The calibration of the AICC prototype car
+  fn default() -> Config {
+    // The steering servo is mounted upside down
+    let mut steering = PwmChannelArgs::new(0, 0.085f32, 0.01625f32);
+    steering.inverted = true;
+
+    let mut channels = BTreeMap::new();
+    channels.insert(CHANNEL_STEERING.to_string(), steering);
+    channels.insert(CHANNEL_THROTTLE.to_string(), PwmChannelArgs::new(1, 0.075f32, 0.025f32));
+
+    Config {
+      pwm: PwmConfig { i2c_device: "/dev/i2c-1".to_string(), address: 0x40, frequency: 50f32 },
+      gpio: GpioConfig { enable_pin: 255 },
+      control: ControlConfig::default(),
+      failsafe: FailsafeConfig::default(),
+      channels,
+      filters: BTreeMap::new(),
+    }
+  }
+}
+
+impl Config {
+  /// Reads and validates the configuration file at `path`
+  pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Config::parse(&contents)
+  }
+
+  pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+    let config: Config = toml::from_str(contents)?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Validates and writes the configuration to `path`. The file is replaced atomically, so a
+  /// crash can't leave a half-written configuration behind.
+  pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+    self.validate()?;
+    let contents = toml::to_string(self)
+      .map_err(|e| ConfigError::Io(io::Error::other(e)))?;
+    write_atomically(path, &contents)
+  }
+
+  /// Writes the calibration of the channel `name` to the configuration file at `path`. Only the
+  /// keys of that channel are changed, so the comments and the layout of a hand-written file
+  /// survive. Writes the whole configuration if there is no file yet.
+  pub fn save_channel(&self, path: &Path, name: &str) -> Result<(), ConfigError> {
+    self.validate()?;
+    let args = match self.channels.get(name) {
+      Some(args) => args,
+      None => return Err(ConfigError::invalid(&format!("channels.{}", name), "is not configured")),
+    };
+    let contents = match fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return self.save(path),
+      Err(e) => return Err(e.into()),
+    };
+
+    let mut document = contents.parse::<DocumentMut>()
+      .map_err(|e| ConfigError::Io(io::Error::other(e)))?;
+    let field = format!("channels.{}", name);
+    let channel = document.entry("channels").or_insert(toml_edit::table())
+      .as_table_mut()
+      .and_then(|channels| {
+        channels.set_implicit(true);
+        channels.entry(name).or_insert(toml_edit::table()).as_table_mut()
+      })
+      .ok_or_else(|| ConfigError::invalid(&field, "has to be a table to be updated in place"))?;
+
+    set_value(channel, "channel", Value::from(args.channel as i64));
+    set_value(channel, "neutral", float(args.neutral));
+    set_value(channel, "range", float(args.range));
+    // Keys that are left out for their default value stay left out
+    if args.min != -1f32 || channel.contains_key("min") {
+      set_value(channel, "min", float(args.min));
+    }
+    if args.max != 1f32 || channel.contains_key("max") {
+      set_value(channel, "max", float(args.max));
+    }
+    if args.inverted || channel.contains_key("inverted") {
+      set_value(channel, "inverted", Value::from(args.inverted));
+    }
+
+    let contents = document.to_string();
+    Config::parse(&contents)?;
+    write_atomically(path, &contents)
+  }
+
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    if self.pwm.address > 0x7F {
+      return Err(ConfigError::invalid("pwm.address", "I2C addresses only have 7 bits"));
+    }
+
+    // Limits of the PCA9685's prescaler
+    if !(24f32..=1526f32).contains(&self.pwm.frequency) {
+      return Err(ConfigError::invalid("pwm.frequency", "must be within [24:1526] Hz"));
+    }
+
+    if !(1..=1000).contains(&self.control.rate_hz) {
+      return Err(ConfigError::invalid("control.rate_hz", "must be within [1:1000] Hz"));
+    }
+
+    if self.failsafe.degraded_timeout_ms == 0 {
+      return Err(ConfigError::invalid("failsafe.degraded_timeout_ms", "must be positive"));
+    }
+    if self.failsafe.failsafe_timeout_ms < self.failsafe.degraded_timeout_ms {
+      return Err(ConfigError::invalid(
+        "failsafe.failsafe_timeout_ms", "must not be shorter than degraded_timeout_ms"));
+    }
+    if self.failsafe.max_command_age_ms == 0 {
+      return Err(ConfigError::invalid("failsafe.max_command_age_ms", "must be positive"));
+    }
+    if !(-1f32..=0f32).contains(&self.failsafe.brake_throttle) {
+      return Err(ConfigError::invalid("failsafe.brake_throttle", "must be within [-1:0]"));
+    }
+
+    for name in &[CHANNEL_STEERING, CHANNEL_THROTTLE] {
+      if !self.channels.contains_key(*name) {
+        return Err(ConfigError::invalid(&format!("channels.{}", name), "channel is missing"));
+      }
+    }
+
+    let mut used: HashMap<u8, &str> = HashMap::new();
+    for (name, channel) in &self.channels {
+      let field = |key: &str| format!("channels.{}.{}", name, key);
+
+      if channel.channel >= CHANNEL_COUNT {
+        return Err(ConfigError::invalid(&field("channel"), "must be within [0:15]"));
+      }
+      if let Some(other) = used.insert(channel.channel, name) {
+        return Err(ConfigError::invalid(
+          &field("channel"), &format!("output {} is already used by `{}`", channel.channel, other)));
+      }
+      if !(0f32..=1f32).contains(&channel.neutral) {
+        return Err(ConfigError::invalid(&field("neutral"), "must be a duty cycle within [0:1]"));
+      }
+      if channel.range <= 0f32
+        || channel.neutral - channel.range < 0f32 || channel.neutral + channel.range > 1f32 {
+        return Err(ConfigError::invalid(
+          &field("range"), "must be positive and keep neutral +/- range within [0:1]"));
+      }
+      if !(-1f32..=1f32).contains(&channel.min) {
+        return Err(ConfigError::invalid(&field("min"), "must be within [-1:1]"));
+      }
+      if !(-1f32..=1f32).contains(&channel.max) || channel.max < channel.min {
+        return Err(ConfigError::invalid(&field("max"), "must be within [min:1]"));
+      }
+    }
+
+    for (name, filter) in &self.filters {
+      let field = |key: &str| format!("filters.{}.{}", name, key);
+
+      // The control loop only drives these two
+      if name != CHANNEL_STEERING && name != CHANNEL_THROTTLE {
+        return Err(ConfigError::invalid(&format!("filters.{}", name),
+          "only the steering and throttle channels can be filtered"));
+      }
+      let rates = [("max_rate", filter.max_rate), ("accel_rate", filter.accel_rate),
+        ("decel_rate", filter.decel_rate)];
+      for &(key, rate) in &rates {
+        if rate.is_some_and(|rate| rate <= 0f32) {
+          return Err(ConfigError::invalid(&field(key), "must be positive"));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// The filter settings of channel `name`
+  pub fn filter(&self, name: &str) -> FilterConfig {
+    self.filters.get(name).cloned().unwrap_or_default()
+  }
+
+  pub fn pwm_args(&self) -> PwmArgs {
+    PwmArgs {
+      address: self.pwm.address,
+      freq: self.pwm.frequency,
+      channels: self.channels.iter().map(|(name, args)| (name.clone(), args.clone())).collect(),
+    }
+  }
+
+  /// Describes the first setting that differs from `other` and that can't be applied to a
+  /// running drive-core. Channel calibrations, filters, the loop rate and the failsafe can change
+  /// at runtime.
+  pub fn restart_required(&self, other: &Config) -> Option<String> {
+    if self.pwm != other.pwm {
+      return Some("pwm".to_string());
+    }
+    if self.gpio != other.gpio {
+      return Some("gpio".to_string());
+    }
+    if !self.channels.keys().eq(other.channels.keys()) {
+      return Some("channels".to_string());
+    }
+    self.channels.iter()
+      .find(|&(name, args)| args.channel != other.channels[name].channel)
+      .map(|(name, _)| format!("channels.{}.channel", name))
+  }
+}
+
+/// Replaces the file at `path`, so a crash can't leave a half-written configuration behind
+fn write_atomically(path: &Path, contents: &str) -> Result<(), ConfigError> {
+  let tmp_path = path.with_extension("toml.tmp");
+  {
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+  }
+  fs::rename(&tmp_path, path)?;
+  Ok(())
+}
+
+/// Sets `key` to `value`, keeping the comment behind the old value
+fn set_value(table: &mut Table, key: &str, value: Value) {
+  match table.get_mut(key).and_then(|item| item.as_value_mut()) {
+    Some(old) => {
+      let decor = old.decor().clone();
+      *old = value;
+      *old.decor_mut() = decor;
+    },
+    None => {
+      table.insert(key, Item::Value(value));
+    },
+  }
+}
+
+/// Writes 0.085 rather than the 0.08500000089406967 of the f32 widened to f64
+fn float(value: f32) -> Value {
+  Value::from(value.to_string().parse::<f64>().unwrap_or(value as f64))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EXAMPLE: &str = r#"
+    [pwm]
+    i2c_device = "/dev/i2c-1"
+    address = 64
+    frequency = 50.0
+
+    [gpio]
+    enable_pin = 255
+
+    [channels.steering]
+    channel = 0
+    neutral = 0.085
+    range = 0.01625
+    inverted = true
+
+    [channels.throttle]
+    channel = 1
+    neutral = 0.075
+    range = 0.025
+    min = -0.5
+  "#;
+
+  fn assert_invalid(contents: &str, expected_field: &str) {
+    match Config::parse(contents) {
+      Err(ConfigError::Invalid { field, .. }) => assert_eq!(expected_field, field),
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn it_parses_the_example() {
+    let config = Config::parse(EXAMPLE).unwrap();
+    assert_eq!(0x40, config.pwm.address);
+    assert_eq!(255, config.gpio.enable_pin);
+
+    let steering = &config.channels["steering"];
+    assert!(steering.inverted);
+    assert_eq!(-1f32, steering.min);
+    assert_eq!(1f32, steering.max);
+    assert_eq!(-0.5f32, config.channels["throttle"].min);
+  }
+
+  #[test]
+  fn the_default_matches_the_example() {
+    assert_eq!(Config::default(), Config::parse(EXAMPLE).unwrap().with_throttle_min(-1f32));
+  }
+
+  #[test]
+  fn it_round_trips_through_toml() {
+    let config = Config::default();
+    let contents = toml::to_string(&config).unwrap();
+    assert_eq!(config, Config::parse(&contents).unwrap());
+  }
+
+  #[test]
+  fn save_writes_a_loadable_file() {
+    let path = ::std::env::temp_dir().join(format!("drive-core-test-{}.toml", ::std::process::id()));
+    let mut config = Config::default();
+    config.channels.get_mut("throttle").unwrap().max = 0.5f32;
+
+    config.save(&path).unwrap();
+    let loaded = Config::load(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(config, loaded.unwrap());
+  }
+
+  #[test]
+  fn save_channel_keeps_the_rest_of_the_file() {
+    let path = ::std::env::temp_dir()
+      .join(format!("drive-core-test-channel-{}.toml", ::std::process::id()));
+    let contents = EXAMPLE
+      .replace("[channels.throttle]", "# The ESC\n    [channels.throttle]")
+      .replace("channel = 1", "channel = 1      # Output 1 of the PWM driver");
+    fs::write(&path, &contents).unwrap();
+
+    let mut config = Config::parse(&contents).unwrap();
+    {
+      let throttle = config.channels.get_mut("throttle").unwrap();
+      throttle.neutral = 0.076f32;
+      throttle.max = 0.8f32;
+    }
+    config.save_channel(&path, "throttle").unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    let loaded = Config::load(&path);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(config, loaded.unwrap());
+    assert!(saved.contains("# The ESC\n    [channels.throttle]"));
+    assert!(saved.contains("channel = 1      # Output 1 of the PWM driver"));
+    assert!(saved.contains("neutral = 0.076\n"));
+    assert!(saved.contains("max = 0.8\n"));
+    // The steering channel is untouched and the defaults stay left out
+    assert!(saved.starts_with(&contents[..contents.find("[channels.throttle]").unwrap()]));
+    assert!(!saved.contains("inverted = false"));
+  }
+
+  #[test]
+  fn it_names_the_invalid_field() {
+    assert_invalid(&EXAMPLE.replace("address = 64", "address = 200"), "pwm.address");
+    assert_invalid(&EXAMPLE.replace("frequency = 50.0", "frequency = 5.0"), "pwm.frequency");
+    assert_invalid(&EXAMPLE.replace("range = 0.025", "range = 0.95"), "channels.throttle.range");
+    assert_invalid(&EXAMPLE.replace("channel = 1", "channel = 0"), "channels.throttle.channel");
+    assert_invalid(&EXAMPLE.replace("min = -0.5", "min = 0.5\nmax = 0.2"), "channels.throttle.max");
+    assert_invalid(&EXAMPLE.replace("[channels.throttle]", "[channels.lights]"), "channels.throttle");
+    assert_invalid(&format!("{}\n[failsafe]\nfailsafe_timeout_ms = 50", EXAMPLE),
+      "failsafe.failsafe_timeout_ms");
+    assert_invalid(&format!("{}\n[control]\nrate_hz = 0", EXAMPLE), "control.rate_hz");
+  }
+
+  #[test]
+  fn it_parses_a_failsafe_section() {
+    let config = Config::parse(&format!("{}\n[failsafe]\nbrake_duration_ms = 0", EXAMPLE)).unwrap();
+    assert_eq!(0, config.failsafe.brake_duration_ms);
+    assert_eq!(100, config.failsafe.degraded_timeout_ms);
+  }
+
+  #[test]
+  fn it_parses_filter_sections() {
+    let config = Config::parse(&format!(
+      "{}\n[filters.throttle]\naccel_rate = 2.0\ntime_constant_ms = 50", EXAMPLE)).unwrap();
+    let throttle = config.filter("throttle");
+    assert_eq!(Some(2f32), throttle.accel_rate);
+    assert_eq!(None, throttle.decel_rate);
+    assert_eq!(50, throttle.time_constant_ms);
+    assert_eq!(FilterConfig::default(), config.filter("steering"));
+
+    assert_invalid(&format!("{}\n[filters.throttle]\nmax_rate = 0.0", EXAMPLE),
+      "filters.throttle.max_rate");
+    assert_invalid(&format!("{}\n[filters.lights]\nmax_rate = 1.0", EXAMPLE), "filters.lights");
+    assert_invalid(&format!("{}\n[channels.lights]\nchannel = 15\nneutral = 0.5\nrange = 0.5\n\
+      [filters.lights]\nmax_rate = 1.0", EXAMPLE), "filters.lights");
+  }
+
+  #[test]
+  fn it_rejects_unknown_keys() {
+    match Config::parse(&EXAMPLE.replace("inverted = true", "inverted = true\nprescaler = -1")) {
+      Err(ConfigError::Parse(_)) => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn only_calibration_changes_apply_at_runtime() {
+    let config = Config::default();
+
+    let mut recalibrated = config.clone();
+    recalibrated.channels.get_mut("steering").unwrap().neutral = 0.08f32;
+    assert_eq!(None, config.restart_required(&recalibrated));
+
+    let mut rewired = config.clone();
+    rewired.channels.get_mut("steering").unwrap().channel = 3;
+    assert_eq!(Some("channels.steering.channel".to_string()), config.restart_required(&rewired));
+
+    let mut moved = config.clone();
+    moved.pwm.address = 0x41;
+    assert_eq!(Some("pwm".to_string()), config.restart_required(&moved));
+  }
+
+  impl Config {
+    fn with_throttle_min(mut self, min: f32) -> Config {
+      self.channels.get_mut("throttle").unwrap().min = min;
+      self
+    }
+  }
+}
@@ -0,0 +1,196 @@
+// The fixed-rate control loop of drive-core. Every tick reads the latest inputs, runs them through
+// the chain of control stages and writes the result to the channels. Stages see the time of the
+// tick instead of a clock, so they can be tested without waiting.
+use config::Config;
+use failsafe::Outputs;
+use messages::drive_core::DriveState;
+use util::logging::LogConnection;
+use util::variable::Variable;
+
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// Loop statistics are aggregated and published this often
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+fn default_rate_hz() -> u32 { 200 }
+
+/// Timing of the control loop, the [control] section of the configuration
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+  /// Ticks of the control loop per second. Must be within [1:1000].
+  #[serde(default = "default_rate_hz")]
+  pub rate_hz: u32,
+}
+
+impl Default for ControlConfig {
+  fn default() -> ControlConfig {
+    ControlConfig { rate_hz: default_rate_hz() }
+  }
+}
+
+/// What a control stage knows about the current tick
+pub struct Tick {
+  pub now: Duration,          // Time since drive-core started
+  pub state: DriveState,      // State of the failsafe after this tick's inputs
+}
+
+impl Tick {
+  /// Whether the car is allowed to move. Otherwise the outputs come from the failsafe, and stages
+  /// must not delay or alter them.
+  pub fn driving(&self) -> bool {
+    self.state == DriveState::Armed || self.state == DriveState::Degraded
+  }
+}
+
+/// A step between the failsafe and the channels, like the output filters or a traction control.
+/// The stages run in order, each one gets the outputs of the previous one.
+pub trait ControlStage {
+  fn update(&mut self, outputs: Outputs, tick: &Tick) -> Outputs;
+
+  /// Takes over the new settings after the configuration was reloaded
+  fn set_config(&mut self, _config: &Config) {}
+}
+
+/// Wakes the control loop at a fixed rate. Ticks that can't be kept are skipped instead of being
+/// made up for in a burst.
+pub struct Scheduler {
+  period: Duration,
+  next: Instant,
+}
+
+impl Scheduler {
+  pub fn new(rate_hz: u32) -> Scheduler {
+    Scheduler { period: period(rate_hz), next: Instant::now() }
+  }
+
+  pub fn set_rate(&mut self, rate_hz: u32) {
+    self.period = period(rate_hz);
+  }
+
+  /// Sleeps until the next tick is due. Returns how late we woke up and whether the previous tick
+  /// overran its period.
+  pub fn wait(&mut self) -> (Duration, bool) {
+    let now = Instant::now();
+    let overrun = now > self.next;
+    if overrun {
+      self.next = now;
+    } else {
+      thread::sleep(self.next - now);
+    }
+    let jitter = Instant::now().saturating_duration_since(self.next);
+    self.next += self.period;
+    (jitter, overrun)
+  }
+}
+
+fn period(rate_hz: u32) -> Duration {
+  Duration::from_secs(1) / rate_hz
+}
+
+/// Jitter and overruns of the control loop, published to the logger once per STATS_INTERVAL
+pub struct LoopStats {
+  window_start: Duration,
+  ticks: u32,
+  jitter_sum: Duration,
+  jitter_max: Duration,
+  mean_jitter: Variable<'static, f32>,  // Mean lateness of the ticks in the last window in [ms]
+  max_jitter: Variable<'static, f32>,   // Worst lateness of a tick in the last window in [ms]
+  overruns: Variable<'static, i32>,     // Number of ticks that took longer than the period
+}
+
+impl LoopStats {
+  pub fn new(now: Duration) -> LoopStats {
+    LoopStats {
+      window_start: now,
+      ticks: 0,
+      jitter_sum: Duration::from_secs(0),
+      jitter_max: Duration::from_secs(0),
+      mean_jitter: Variable::new(0f32),
+      max_jitter: Variable::new(0f32),
+      overruns: Variable::new(0),
+    }
+  }
+
+  pub fn register(&mut self, log_connection: &LogConnection) {
+    log_connection.log_variable(&mut self.mean_jitter, "drive-core_loop_jitter_mean");
+    log_connection.log_variable(&mut self.max_jitter, "drive-core_loop_jitter_max");
+    log_connection.log_variable(&mut self.overruns, "drive-core_loop_overruns");
+  }
+
+  pub fn record(&mut self, jitter: Duration, overrun: bool, now: Duration) {
+    self.ticks += 1;
+    self.jitter_sum += jitter;
+    self.jitter_max = self.jitter_max.max(jitter);
+    if overrun {
+      let count = *self.overruns.value() + 1;
+      self.overruns.set_value(count);
+    }
+
+    if now >= self.window_start + STATS_INTERVAL {
+      self.mean_jitter.set_value(millis(self.jitter_sum / self.ticks));
+      self.max_jitter.set_value(millis(self.jitter_max));
+      self.window_start = now;
+      self.ticks = 0;
+      self.jitter_sum = Duration::from_secs(0);
+      self.jitter_max = Duration::from_secs(0);
+    }
+  }
+}
+
+fn millis(duration: Duration) -> f32 {
+  duration.as_secs_f32() * 1000f32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+  }
+
+  struct Double;
+
+  impl ControlStage for Double {
+    fn update(&mut self, outputs: Outputs, _tick: &Tick) -> Outputs {
+      Outputs { steering: outputs.steering * 2f32, throttle: outputs.throttle * 2f32 }
+    }
+  }
+
+  #[test]
+  fn stages_run_in_order() {
+    let mut stages: Vec<Box<dyn ControlStage>> = vec![Box::new(Double), Box::new(Double)];
+    let tick = Tick { now: ms(5), state: DriveState::Armed };
+    let outputs = stages.iter_mut()
+      .fold(Outputs { steering: 0.1f32, throttle: 0.2f32 }, |o, stage| stage.update(o, &tick));
+    assert_eq!(Outputs { steering: 0.4f32, throttle: 0.8f32 }, outputs);
+  }
+
+  #[test]
+  fn the_period_follows_the_rate() {
+    let mut scheduler = Scheduler::new(200);
+    assert_eq!(ms(5), scheduler.period);
+    scheduler.set_rate(50);
+    assert_eq!(ms(20), scheduler.period);
+  }
+
+  #[test]
+  fn stats_are_published_per_window() {
+    let mut stats = LoopStats::new(ms(0));
+    stats.record(ms(1), false, ms(5));
+    stats.record(ms(3), true, ms(10));
+    assert_eq!(0f32, *stats.max_jitter.value());
+    assert_eq!(1, *stats.overruns.value());
+
+    stats.record(ms(2), false, ms(1000));
+    assert_eq!(2f32, *stats.mean_jitter.value());
+    assert_eq!(3f32, *stats.max_jitter.value());
+
+    // The next window starts from scratch, but overruns keep counting
+    stats.record(ms(1), true, ms(2000));
+    assert_eq!(1f32, *stats.max_jitter.value());
+    assert_eq!(2, *stats.overruns.value());
+  }
+}
@@ -30,9 +30,23 @@ impl fmt::Display for SetupError {
 pub enum I2CError {
   I2CDevice(LinuxI2CError),
   Setup(SetupError),
+  Emulator(String),
   ReferenceInvalid,
 }
 
+impl Error for I2CError {}
+
+impl fmt::Display for I2CError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      I2CError::I2CDevice(ref e) => write!(f, "I2C error: {}", e),
+      I2CError::Setup(ref e) => write!(f, "{}", e),
+      I2CError::Emulator(ref msg) => write!(f, "PCA9685 emulator: {}", msg),
+      I2CError::ReferenceInvalid => write!(f, "The PWM driver is gone"),
+    }
+  }
+}
+
 impl From<LinuxI2CError> for I2CError {
   fn from(e: LinuxI2CError) -> Self {
     I2CError::I2CDevice(e)
@@ -43,4 +57,4 @@ impl From<SetupError> for I2CError {
   fn from(e: SetupError) -> Self {
     I2CError::Setup(e)
   }
-}
\ No newline at end of file
+}
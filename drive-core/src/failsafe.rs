@@ -0,0 +1,330 @@
+// Decides what drive-core does when the link to its controlling client degrades. All methods get
+// the current time passed in, so the state machine can be tested against a simulated clock.
+use messages::drive_core::DriveState;
+
+use std::time::Duration;
+
+fn default_degraded_timeout_ms() -> u64 { 100 }
+fn default_failsafe_timeout_ms() -> u64 { 500 }
+fn default_brake_duration_ms() -> u64 { 1000 }
+fn default_brake_throttle() -> f32 { -0.5f32 }
+fn default_max_command_age_ms() -> u64 { 100 }
+
+/// Timeouts and braking behaviour of the failsafe, the [failsafe] section of the configuration
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FailsafeConfig {
+  /// Without a command for this long, the throttle goes to neutral (state Degraded)
+  #[serde(default = "default_degraded_timeout_ms")]
+  pub degraded_timeout_ms: u64,
+
+  /// Without a command for this long, the link is considered lost (state Failsafe)
+  #[serde(default = "default_failsafe_timeout_ms")]
+  pub failsafe_timeout_ms: u64,
+
+  /// How long to actively brake after entering Failsafe. 0 disables braking.
+  #[serde(default = "default_brake_duration_ms")]
+  pub brake_duration_ms: u64,
+
+  /// Throttle value that makes the ESC brake while the car rolls forward. Must be within [-1:0].
+  #[serde(default = "default_brake_throttle")]
+  pub brake_throttle: f32,
+
+  /// Drive commands that arrive later than this (compared to the fastest one) are rejected
+  #[serde(default = "default_max_command_age_ms")]
+  pub max_command_age_ms: u64,
+}
+
+impl Default for FailsafeConfig {
+  fn default() -> FailsafeConfig {
+    FailsafeConfig {
+      degraded_timeout_ms: default_degraded_timeout_ms(),
+      failsafe_timeout_ms: default_failsafe_timeout_ms(),
+      brake_duration_ms: default_brake_duration_ms(),
+      brake_throttle: default_brake_throttle(),
+      max_command_age_ms: default_max_command_age_ms(),
+    }
+  }
+}
+
+/// Steering and throttle values to apply to the channels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Outputs {
+  pub steering: f32,
+  pub throttle: f32,
+}
+
+const NEUTRAL: Outputs = Outputs { steering: 0f32, throttle: 0f32 };
+
+pub struct Failsafe {
+  config: FailsafeConfig,
+  state: DriveState,
+  commanded: Outputs,       // The last values the client asked for
+  last_command: Duration,
+  brake_until: Duration,
+}
+
+impl Failsafe {
+  pub fn new(config: FailsafeConfig) -> Failsafe {
+    Failsafe {
+      config,
+      state: DriveState::Disarmed,
+      commanded: NEUTRAL,
+      last_command: Duration::from_secs(0),
+      brake_until: Duration::from_secs(0),
+    }
+  }
+
+  pub fn state(&self) -> DriveState {
+    self.state
+  }
+
+  /// Drive commands older than this are rejected
+  pub fn max_command_age(&self) -> Duration {
+    Duration::from_millis(self.config.max_command_age_ms)
+  }
+
+  pub fn set_config(&mut self, config: FailsafeConfig) {
+    self.config = config;
+  }
+
+  /// The client explicitly allows us to drive. This is the only way out of Disarmed, Failsafe and
+  /// EmergencyStop.
+  pub fn arm(&mut self, now: Duration) {
+    self.state = DriveState::Armed;
+    self.commanded = NEUTRAL;
+    self.last_command = now;
+  }
+
+  /// The client logged out cleanly or doesn't want to drive for now
+  pub fn disarm(&mut self) {
+    self.state = DriveState::Disarmed;
+    self.commanded = NEUTRAL;
+  }
+
+  /// Brakes right away, regardless of the state we're in
+  pub fn emergency_stop(&mut self, now: Duration) {
+    self.enter_stop(DriveState::EmergencyStop, now);
+  }
+
+  /// The client is alive, even if it has nothing new to command
+  pub fn heartbeat(&mut self, now: Duration) {
+    self.accept_command(now);
+  }
+
+  /// Steering is applied while disarmed, so the client can check it without moving the car
+  pub fn set_steering(&mut self, value: f32, now: Duration) {
+    if self.state == DriveState::Disarmed || self.accept_command(now) {
+      self.commanded.steering = value;
+    }
+  }
+
+  /// Applies steering and throttle at once. While disarmed, only the steering is taken.
+  pub fn command(&mut self, outputs: Outputs, now: Duration) {
+    if self.state == DriveState::Disarmed {
+      self.commanded.steering = outputs.steering;
+    } else if self.accept_command(now) {
+      self.commanded = outputs;
+    }
+  }
+
+  pub fn set_throttle(&mut self, value: f32, now: Duration) {
+    if self.accept_command(now) {
+      self.commanded.throttle = value;
+    }
+  }
+
+  /// The connection to the client broke without a goodbye
+  pub fn link_lost(&mut self, now: Duration) {
+    match self.state {
+      DriveState::Armed | DriveState::Degraded => self.enter_stop(DriveState::Failsafe, now),
+      // Without a client, nobody could steer
+      DriveState::Disarmed => self.commanded = NEUTRAL,
+      DriveState::Failsafe | DriveState::EmergencyStop => {},
+    }
+  }
+
+  /// Evaluates the timeouts and returns the values to drive the channels with
+  pub fn update(&mut self, now: Duration) -> Outputs {
+    let silence = now.checked_sub(self.last_command).unwrap_or_default();
+    let degraded_timeout = Duration::from_millis(self.config.degraded_timeout_ms);
+    let failsafe_timeout = Duration::from_millis(self.config.failsafe_timeout_ms);
+    if self.state == DriveState::Armed && silence >= degraded_timeout {
+      self.state = DriveState::Degraded;
+    }
+    if self.state == DriveState::Degraded && silence >= failsafe_timeout {
+      self.enter_stop(DriveState::Failsafe, now);
+    }
+
+    match self.state {
+      DriveState::Armed => self.commanded,
+      DriveState::Disarmed | DriveState::Degraded =>
+        Outputs { steering: self.commanded.steering, throttle: 0f32 },
+      DriveState::Failsafe | DriveState::EmergencyStop if now < self.brake_until =>
+        Outputs { steering: 0f32, throttle: self.config.brake_throttle },
+      DriveState::Failsafe | DriveState::EmergencyStop => NEUTRAL,
+    }
+  }
+
+  fn accept_command(&mut self, now: Duration) -> bool {
+    match self.state {
+      DriveState::Armed | DriveState::Degraded => {
+        self.state = DriveState::Armed;
+        self.last_command = now;
+        true
+      },
+      DriveState::Disarmed | DriveState::Failsafe | DriveState::EmergencyStop => false,
+    }
+  }
+
+  fn enter_stop(&mut self, state: DriveState, now: Duration) {
+    // Braking only makes sense while rolling forward. Most ESCs would go backwards otherwise.
+    self.brake_until = if self.commanded.throttle > 0f32 {
+      now + Duration::from_millis(self.config.brake_duration_ms)
+    } else {
+      now
+    };
+    self.state = state;
+    self.commanded = NEUTRAL;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+  }
+
+  fn armed() -> Failsafe {
+    let mut failsafe = Failsafe::new(FailsafeConfig::default());
+    failsafe.arm(ms(0));
+    failsafe.set_steering(0.5f32, ms(0));
+    failsafe.set_throttle(0.8f32, ms(0));
+    failsafe
+  }
+
+  #[test]
+  fn it_starts_disarmed_and_refuses_throttle() {
+    let mut failsafe = Failsafe::new(FailsafeConfig::default());
+    failsafe.set_throttle(1f32, ms(10));
+    assert_eq!(DriveState::Disarmed, failsafe.state());
+    assert_eq!(NEUTRAL, failsafe.update(ms(20)));
+
+    failsafe.set_steering(-0.5f32, ms(30));
+    assert_eq!(Outputs { steering: -0.5f32, throttle: 0f32 }, failsafe.update(ms(40)));
+    assert_eq!(DriveState::Disarmed, failsafe.state());
+  }
+
+  #[test]
+  fn commands_apply_steering_and_throttle_together() {
+    let mut failsafe = Failsafe::new(FailsafeConfig::default());
+    failsafe.command(Outputs { steering: 0.2f32, throttle: 0.6f32 }, ms(0));
+    assert_eq!(Outputs { steering: 0.2f32, throttle: 0f32 }, failsafe.update(ms(10)));
+
+    failsafe.arm(ms(20));
+    failsafe.command(Outputs { steering: -0.2f32, throttle: 0.6f32 }, ms(30));
+    assert_eq!(Outputs { steering: -0.2f32, throttle: 0.6f32 }, failsafe.update(ms(40)));
+  }
+
+  #[test]
+  fn heartbeats_keep_a_quiet_client_armed() {
+    let mut failsafe = armed();
+    for t in 1..10 {
+      failsafe.heartbeat(ms(t * 90));
+      assert_eq!(Outputs { steering: 0.5f32, throttle: 0.8f32 }, failsafe.update(ms(t * 90 + 5)));
+    }
+    assert_eq!(DriveState::Armed, failsafe.state());
+  }
+
+  #[test]
+  fn emergency_stop_brakes_and_latches() {
+    let mut failsafe = armed();
+    failsafe.emergency_stop(ms(10));
+    assert_eq!(Outputs { steering: 0f32, throttle: -0.5f32 }, failsafe.update(ms(20)));
+    assert_eq!(DriveState::EmergencyStop, failsafe.state());
+
+    failsafe.heartbeat(ms(30));
+    failsafe.set_throttle(1f32, ms(30));
+    assert_eq!(NEUTRAL, failsafe.update(ms(2000)));
+    assert_eq!(DriveState::EmergencyStop, failsafe.state());
+
+    failsafe.arm(ms(2010));
+    assert_eq!(DriveState::Armed, failsafe.state());
+  }
+
+  #[test]
+  fn armed_applies_commands() {
+    let mut failsafe = armed();
+    assert_eq!(DriveState::Armed, failsafe.state());
+    assert_eq!(Outputs { steering: 0.5f32, throttle: 0.8f32 }, failsafe.update(ms(50)));
+  }
+
+  #[test]
+  fn a_short_glitch_only_releases_the_throttle() {
+    let mut failsafe = armed();
+    assert_eq!(Outputs { steering: 0.5f32, throttle: 0f32 }, failsafe.update(ms(100)));
+    assert_eq!(DriveState::Degraded, failsafe.state());
+
+    failsafe.set_throttle(0.3f32, ms(150));
+    assert_eq!(DriveState::Armed, failsafe.state());
+    assert_eq!(Outputs { steering: 0.5f32, throttle: 0.3f32 }, failsafe.update(ms(160)));
+  }
+
+  #[test]
+  fn a_lost_link_brakes_then_goes_neutral() {
+    let mut failsafe = armed();
+    failsafe.update(ms(100));
+    assert_eq!(Outputs { steering: 0f32, throttle: -0.5f32 }, failsafe.update(ms(500)));
+    assert_eq!(DriveState::Failsafe, failsafe.state());
+    assert_eq!(Outputs { steering: 0f32, throttle: -0.5f32 }, failsafe.update(ms(1499)));
+    assert_eq!(NEUTRAL, failsafe.update(ms(1500)));
+  }
+
+  #[test]
+  fn it_does_not_brake_when_not_rolling_forward() {
+    let mut failsafe = armed();
+    failsafe.set_throttle(-0.2f32, ms(0));
+    failsafe.link_lost(ms(10));
+    assert_eq!(DriveState::Failsafe, failsafe.state());
+    assert_eq!(NEUTRAL, failsafe.update(ms(20)));
+  }
+
+  #[test]
+  fn failsafe_requires_an_explicit_arm() {
+    let mut failsafe = armed();
+    failsafe.link_lost(ms(10));
+    failsafe.set_throttle(0.4f32, ms(20));
+    assert_eq!(DriveState::Failsafe, failsafe.state());
+
+    failsafe.arm(ms(30));
+    failsafe.set_throttle(0.4f32, ms(40));
+    assert_eq!(Outputs { steering: 0f32, throttle: 0.4f32 }, failsafe.update(ms(50)));
+  }
+
+  #[test]
+  fn disarm_goes_neutral() {
+    let mut failsafe = armed();
+    failsafe.disarm();
+    assert_eq!(NEUTRAL, failsafe.update(ms(10)));
+    failsafe.set_steering(0.3f32, ms(20));
+    failsafe.link_lost(ms(30));
+    assert_eq!(DriveState::Disarmed, failsafe.state());
+    assert_eq!(NEUTRAL, failsafe.update(ms(40)));
+  }
+
+  #[test]
+  fn timeouts_are_configurable() {
+    let config = FailsafeConfig { degraded_timeout_ms: 20, failsafe_timeout_ms: 40,
+      brake_duration_ms: 0, ..FailsafeConfig::default() };
+    let mut failsafe = Failsafe::new(config);
+    failsafe.arm(ms(0));
+    failsafe.set_throttle(1f32, ms(0));
+
+    failsafe.update(ms(20));
+    assert_eq!(DriveState::Degraded, failsafe.state());
+    assert_eq!(NEUTRAL, failsafe.update(ms(40)));
+    assert_eq!(DriveState::Failsafe, failsafe.state());
+  }
+}
@@ -0,0 +1,28 @@
+use error::*;
+
+use i2cdev::core::*;
+use i2cdev::linux::LinuxI2CDevice;
+
+/// Register-level access to a device on the I2C bus. This is all the PWM driver needs from the
+/// bus, so it can run against the real hardware or against an emulated chip.
+pub trait I2CBus {
+  fn read_byte(&mut self, register: u8) -> Result<u8, I2CError>;
+  fn write_byte(&mut self, register: u8, value: u8) -> Result<(), I2CError>;
+
+  /// Writes a 16 bit value to `register` (low byte) and `register + 1` (high byte)
+  fn write_word(&mut self, register: u8, value: u16) -> Result<(), I2CError>;
+}
+
+impl I2CBus for LinuxI2CDevice {
+  fn read_byte(&mut self, register: u8) -> Result<u8, I2CError> {
+    Ok(self.smbus_read_byte_data(register)?)
+  }
+
+  fn write_byte(&mut self, register: u8, value: u8) -> Result<(), I2CError> {
+    Ok(self.smbus_write_byte_data(register, value)?)
+  }
+
+  fn write_word(&mut self, register: u8, value: u16) -> Result<(), I2CError> {
+    Ok(self.smbus_write_word_data(register, value)?)
+  }
+}
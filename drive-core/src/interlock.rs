@@ -0,0 +1,249 @@
+// Owns the output enable (OE) pin of the PWM driver board. While the pin is high, the board sends
+// no pulses at all: the servos go limp and the ESC falls back to its own failsafe. The pin is only
+// low while the failsafe lets the car move, and it goes high on output errors, on panics of any
+// thread and when drive-core shuts down.
+use failsafe::Outputs;
+use messages::drive_core::DriveState;
+
+use std::io;
+use std::panic;
+use std::sync::{ Arc, Mutex, TryLockError };
+
+use sysfs_gpio::{ Direction, Pin };
+
+// OE is active low
+const DISABLED: bool = true;
+const ENABLED: bool = false;
+
+/// A GPIO output
+pub trait Gpio: Send {
+  /// Claims the pin and makes it an output at the level `high`
+  fn setup(&mut self, high: bool) -> io::Result<()>;
+
+  fn set(&mut self, high: bool) -> io::Result<()>;
+
+  /// Hands the pin back to the system
+  fn release(&mut self) -> io::Result<()>;
+}
+
+/// A pin exported through /sys/class/gpio
+pub struct SysfsGpio {
+  pin: Pin,
+}
+
+impl SysfsGpio {
+  pub fn new(number: u64) -> SysfsGpio {
+    SysfsGpio { pin: Pin::new(number) }
+  }
+}
+
+fn to_io(e: ::sysfs_gpio::Error) -> io::Error {
+  io::Error::other(e)
+}
+
+impl Gpio for SysfsGpio {
+  fn setup(&mut self, high: bool) -> io::Result<()> {
+    self.pin.export().map_err(to_io)?;
+    self.pin.set_direction(if high { Direction::High } else { Direction::Low }).map_err(to_io)
+  }
+
+  fn set(&mut self, high: bool) -> io::Result<()> {
+    self.pin.set_value(high as u8).map_err(to_io)
+  }
+
+  fn release(&mut self) -> io::Result<()> {
+    self.pin.unexport().map_err(to_io)
+  }
+}
+
+/// A pin that only remembers its level, for the emulated PWM driver and the tests. Clones share
+/// the level.
+#[derive(Clone, Default)]
+pub struct MockGpio {
+  level: Arc<Mutex<Option<bool>>>,      // None while the pin isn't set up
+}
+
+impl MockGpio {
+  pub fn new() -> MockGpio {
+    MockGpio::default()
+  }
+
+  #[cfg(test)]
+  pub fn level(&self) -> Option<bool> {
+    *self.level.lock().unwrap()
+  }
+}
+
+impl Gpio for MockGpio {
+  fn setup(&mut self, high: bool) -> io::Result<()> {
+    *self.level.lock().unwrap() = Some(high);
+    Ok(())
+  }
+
+  fn set(&mut self, high: bool) -> io::Result<()> {
+    match *self.level.lock().unwrap() {
+      Some(ref mut level) => { *level = high; Ok(()) },
+      None => Err(io::Error::new(io::ErrorKind::NotFound, "The pin isn't set up")),
+    }
+  }
+
+  fn release(&mut self) -> io::Result<()> {
+    *self.level.lock().unwrap() = None;
+    Ok(())
+  }
+}
+
+pub struct SafetyInterlock {
+  gpio: Arc<Mutex<Box<dyn Gpio>>>,  // Shared with the panic hook
+  enabled: bool,
+  fault: Option<String>,            // Keeps the outputs disabled until drive-core restarts
+}
+
+impl SafetyInterlock {
+  /// Takes over `gpio` with the outputs disabled
+  pub fn new(mut gpio: Box<dyn Gpio>) -> io::Result<SafetyInterlock> {
+    gpio.setup(DISABLED)?;
+    Ok(SafetyInterlock { gpio: Arc::new(Mutex::new(gpio)), enabled: false, fault: None })
+  }
+
+  /// Disables the outputs when any thread panics, before the previous hook prints the message
+  pub fn install_panic_hook(&self) {
+    let gpio = Arc::downgrade(&self.gpio);
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      if let Some(gpio) = gpio.upgrade() {
+        // A thread that panicked while holding the lock left the pin usable anyway
+        let guard = match gpio.try_lock() {
+          Ok(guard) => Some(guard),
+          Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+          Err(TryLockError::WouldBlock) => None,
+        };
+        if let Some(mut gpio) = guard {
+          let _ = gpio.set(DISABLED);
+        }
+      }
+      previous(info);
+    }));
+  }
+
+  #[cfg(test)]
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  /// Enables the outputs while the failsafe lets the car move: while it's armed or disarmed (for
+  /// checking the steering) and while it brakes after a stop. Afterwards, the outputs stay disabled
+  /// until the next Arm.
+  pub fn update(&mut self, state: DriveState, outputs: Outputs) -> io::Result<()> {
+    let allowed = match state {
+      DriveState::Armed | DriveState::Degraded | DriveState::Disarmed => true,
+      DriveState::Failsafe | DriveState::EmergencyStop =>
+        outputs != Outputs { steering: 0f32, throttle: 0f32 },
+    };
+    self.set_enabled(allowed && self.fault.is_none())
+  }
+
+  /// Enables the outputs regardless of the failsafe, for the calibration
+  pub fn enable(&mut self) -> io::Result<()> {
+    self.set_enabled(self.fault.is_none())
+  }
+
+  /// The outputs can't be trusted anymore, e.g. because the PWM driver stopped responding.
+  /// Disables them for good.
+  pub fn fault(&mut self, reason: &str) {
+    if self.fault.is_none() {
+      println!("Disabling the outputs until restart. {}", reason);
+      self.fault = Some(reason.to_string());
+    }
+    if let Err(e) = self.set_enabled(false) {
+      println!("Failed to disable the outputs: {:?}", e);
+    }
+  }
+
+  fn set_enabled(&mut self, enabled: bool) -> io::Result<()> {
+    if enabled != self.enabled {
+      self.gpio.lock().unwrap().set(if enabled { ENABLED } else { DISABLED })?;
+      self.enabled = enabled;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for SafetyInterlock {
+  fn drop(&mut self) {
+    let mut gpio = match self.gpio.lock() {
+      Ok(gpio) => gpio,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Err(e) = gpio.set(DISABLED).and_then(|_| gpio.release()) {
+      println!("Failed to release the output enable pin: {:?}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+
+  const NEUTRAL: Outputs = Outputs { steering: 0f32, throttle: 0f32 };
+  const BRAKING: Outputs = Outputs { steering: 0f32, throttle: -0.5f32 };
+
+  fn interlock() -> (SafetyInterlock, MockGpio) {
+    let gpio = MockGpio::new();
+    (SafetyInterlock::new(Box::new(gpio.clone())).unwrap(), gpio)
+  }
+
+  #[test]
+  fn outputs_start_disabled() {
+    let (interlock, gpio) = interlock();
+    assert!(!interlock.is_enabled());
+    assert_eq!(Some(DISABLED), gpio.level());
+  }
+
+  #[test]
+  fn outputs_follow_the_failsafe() {
+    let (mut interlock, gpio) = interlock();
+    interlock.update(DriveState::Armed, NEUTRAL).unwrap();
+    assert_eq!(Some(ENABLED), gpio.level());
+
+    // Braking needs the outputs, afterwards they go dark
+    interlock.update(DriveState::EmergencyStop, BRAKING).unwrap();
+    assert_eq!(Some(ENABLED), gpio.level());
+    interlock.update(DriveState::EmergencyStop, NEUTRAL).unwrap();
+    assert_eq!(Some(DISABLED), gpio.level());
+
+    interlock.update(DriveState::Disarmed, NEUTRAL).unwrap();
+    assert_eq!(Some(ENABLED), gpio.level());
+  }
+
+  #[test]
+  fn faults_latch() {
+    let (mut interlock, gpio) = interlock();
+    interlock.update(DriveState::Armed, NEUTRAL).unwrap();
+    interlock.fault("PWM driver gone");
+    assert_eq!(Some(DISABLED), gpio.level());
+
+    interlock.update(DriveState::Armed, NEUTRAL).unwrap();
+    interlock.enable().unwrap();
+    assert!(!interlock.is_enabled());
+  }
+
+  #[test]
+  fn drop_releases_the_pin() {
+    let (mut interlock, gpio) = interlock();
+    interlock.enable().unwrap();
+    drop(interlock);
+    assert_eq!(None, gpio.level());
+  }
+
+  #[test]
+  fn a_panic_disables_the_outputs() {
+    let (mut interlock, gpio) = interlock();
+    interlock.install_panic_hook();
+    interlock.enable().unwrap();
+
+    assert!(thread::spawn(|| panic!("Expected panic")).join().is_err());
+    assert_eq!(Some(DISABLED), gpio.level());
+  }
+}
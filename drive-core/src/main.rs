@@ -2,88 +2,291 @@ extern crate i2cdev;
 extern crate ctrlc;
 
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate bincode;
-extern crate bufstream;
 extern crate sysfs_gpio;
+extern crate clap;
+extern crate toml;
+extern crate toml_edit;
+extern crate signal_hook;
 
 extern crate messages;
 extern crate util;
 
+mod arbiter;
+mod calibration;
+mod client;
+mod command;
+mod config;
+mod control;
 mod error;
+mod failsafe;
+mod i2c_bus;
+mod interlock;
+mod network;
+mod output_filter;
+mod pca9685_emulator;
 mod pwm_driver;
 
 use pwm_driver::*;
-use messages::drive_core::MessageType;
+use pca9685_emulator::Pca9685Emulator;
+use config::*;
+use error::I2CError;
+use control::{ ControlStage, LoopStats, Scheduler, Tick };
+use failsafe::{ Failsafe, Outputs };
+use interlock::{ Gpio, MockGpio, SafetyInterlock, SysfsGpio };
+use network::{ Event, Snapshot };
+use output_filter::OutputFilters;
 use util::variable::Variable;
 use util::logging::LogConnection;
-use util::mesh::Service;
+use util::mesh::{ load_registry, Service };
+use util::timing::{ Clock, SystemClock };
 
 use std::net::*;
 use std::io;
 use std::rc::*;
 use std::cell::RefCell;
+use std::path::Path;
 use std::time::Duration;
+use std::sync::{ mpsc, Arc, Mutex };
+use std::thread;
 use std::sync::atomic::{ AtomicBool, Ordering };
 
-use bufstream::BufStream;
-use bincode::deserialize_from;
-use sysfs_gpio::{Direction, Pin};
-
-const PWM_DRIVER_ADDRESS: u16 = 0x40;
-const PWM_FREQUENCY: f32 = 50f32;
-const I2C_DEVICE_PATH: &str = "/dev/i2c-1";
+use clap::{ Arg, App, ArgMatches, SubCommand };
 
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
-fn accept_connection(listener: &TcpListener) -> io::Result<BufStream<TcpStream>> {
-  let (socket, addr) = listener.accept()?;
-  println!("Client connected from {}", addr);
-
-  // We don't want to block longer than 100ms. If we go above this threshold, then we consider
-  // the client unresponsive and cut the power to the motor.
-  socket.set_read_timeout(Option::Some(Duration::from_millis(100)))?;
-
-  return Ok(BufStream::new(socket));
+/// Applies an event of the networking thread to the failsafe
+fn apply_event(event: Event, failsafe: &mut Failsafe, now: Duration) {
+  match event {
+    Event::Arm => failsafe.arm(now),
+    Event::Disarm => failsafe.disarm(),
+    Event::EmergencyStop => failsafe.emergency_stop(now),
+    Event::Heartbeat => failsafe.heartbeat(now),
+    Event::Steering(value) => failsafe.set_steering(value, now),
+    Event::Throttle(value) => failsafe.set_throttle(value, now),
+    Event::Command(outputs) => failsafe.command(outputs, now),
+    Event::LinkLost => failsafe.link_lost(now),
+  }
 }
 
+/// Writes every value of `var` to `channel`. Errors end up in `fault` for the control loop.
 fn connect_variable_with_channel(var: & mut Variable<f32>,
-                                 channel: &mut Rc<RefCell<PwmChannel>>,
-                                 prescaler: f32) {
+                                 channel: &Rc<RefCell<PwmChannel>>,
+                                 fault: &Rc<RefCell<Option<String>>>) {
   let weak_ptr = Rc::downgrade(channel);
+  let fault = fault.clone();
   var.add_listener(move |val| {
-    match weak_ptr.upgrade() {
-      Some(rc) => rc.borrow_mut().set_value(*val * prescaler).unwrap(),
-      None => println!("Failed to de-reference the weak reference to the PWM channel :|")
+    let result = match weak_ptr.upgrade() {
+      Some(rc) => rc.borrow_mut().set_value(*val),
+      None => Err(I2CError::ReferenceInvalid),
     };
+    if let Err(e) = result {
+      *fault.borrow_mut() = Some(format!("Failed to write to the PWM driver: {}", e));
+    }
     Ok(())
   });
 }
 
+/// Runs the failsafe's values through the control stages, drives the channels with the result and
+/// records the failsafe's state
+fn update_outputs(failsafe: &mut Failsafe,
+                  stages: &mut [Box<dyn ControlStage>],
+                  now: Duration,
+                  steering: &mut Variable<f32>,
+                  throttle: &mut Variable<f32>,
+                  failsafe_state: &mut Variable<i32>) -> Outputs {
+  let commanded = failsafe.update(now);
+  let tick = Tick { now, state: failsafe.state() };
+  let outputs = stages.iter_mut()
+    .fold(commanded, |outputs, stage| stage.update(outputs, &tick));
+  steering.set_value(outputs.steering);
+  throttle.set_value(outputs.throttle);
+
+  if *failsafe_state.value() != failsafe.state().code() {
+    println!("Failsafe state: {:?}", failsafe.state());
+    failsafe_state.set_value(failsafe.state().code());
+  }
+  outputs
+}
+
+/// Re-reads the configuration file and applies the new channel calibrations, control stages,
+/// loop rate and failsafe settings
+fn reload_config(path: &Path,
+                 config: &mut Config,
+                 device: &PwmDriver,
+                 failsafe: &mut Failsafe,
+                 stages: &mut [Box<dyn ControlStage>],
+                 scheduler: &mut Scheduler) {
+  println!("Reloading configuration from {}", path.display());
+  let new_config = match Config::load(path) {
+    Ok(new_config) => new_config,
+    Err(e) => {
+      println!("Keeping the old configuration. {}", e);
+      return;
+    }
+  };
+
+  if let Some(field) = config.restart_required(&new_config) {
+    println!("Keeping the old configuration. Changes to `{}` require a restart.", field);
+    return;
+  }
+
+  for (name, args) in &new_config.channels {
+    if let Err(e) = device.channel(name).unwrap().borrow_mut().set_config(args.clone()) {
+      println!("Failed to apply the calibration of channel {}: {}", name, e);
+    }
+  }
+  failsafe.set_config(new_config.failsafe.clone());
+  for stage in stages.iter_mut() {
+    stage.set_config(&new_config);
+  }
+  scheduler.set_rate(new_config.control.rate_hz);
+  *config = new_config;
+}
+
+fn load_config(matches: &ArgMatches) -> Config {
+  let config_path = Path::new(matches.value_of("config").unwrap());
+  match Config::load(config_path) {
+    Ok(config) => config,
+    // Without a configuration file, we still know how to drive the prototype car
+    Err(ConfigError::Io(ref e))
+        if e.kind() == io::ErrorKind::NotFound && matches.occurrences_of("config") == 0 => {
+      println!("{} not found, using the built-in configuration.", config_path.display());
+      Config::default()
+    },
+    Err(e) => {
+      println!("{}", e);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn open_driver(simulated: bool, config: &Config) -> PwmDriver {
+  // Create and initialize the PWM driver. It's constructor
+  // will set all servos to their respective neutral position.
+  if simulated {
+    println!("Using the emulated PWM driver.");
+    PwmDriver::new(Box::new(Pca9685Emulator::new()), config.pwm_args()).unwrap()
+  } else {
+    PwmDriver::open(&config.pwm.i2c_device, config.pwm_args()).unwrap()
+  }
+}
+
+fn open_interlock(simulated: bool, config: &Config) -> SafetyInterlock {
+  // The emulated PWM driver has no output enable pin
+  let gpio: Box<dyn Gpio> = if simulated {
+    Box::new(MockGpio::new())
+  } else {
+    Box::new(SysfsGpio::new(config.gpio.enable_pin))
+  };
+  let interlock = match SafetyInterlock::new(gpio) {
+    Ok(interlock) => interlock,
+    Err(e) => {
+      println!("Failed to set up the output enable pin {}: {}", config.gpio.enable_pin, e);
+      std::process::exit(1);
+    }
+  };
+  interlock.install_panic_hook();
+  interlock
+}
+
+fn calibrate(matches: &ArgMatches, sub_matches: &ArgMatches) {
+  let simulated = matches.value_of("bus") == Some("sim");
+  let config = load_config(matches);
+  let name = sub_matches.value_of("channel").unwrap();
+
+  let device = open_driver(simulated, &config);
+  let channel = match device.channel(name) {
+    Some(channel) => channel,
+    None => {
+      println!("Unknown channel {}. Please add it to the configuration file first.", name);
+      std::process::exit(1);
+    }
+  };
+
+  let mut interlock = open_interlock(simulated, &config);
+  interlock.enable().expect("Failed to enable the outputs");
+
+  let result = calibration::run(&mut channel.borrow_mut(), name, sub_matches.is_present("esc"),
+    config.clone(), Path::new(matches.value_of("config").unwrap()));
+  if let Err(e) = result {
+    println!("Calibration failed: {:?}", e);
+  }
+}
+
 fn main() {
+  let matches = App::new("drive-core")
+    .author("David Bauske <david.bauske@googlemail.com>")
+    .about("Low-level driving functions (steering, throttle and braking) of the AICC car.")
+    .arg(Arg::with_name("bus")
+      .long("bus")
+      .help("Selects the I2C bus the PWM driver is attached to. \
+        \"sim\" uses an emulated PWM driver, so drive-core runs without any hardware.")
+      .possible_values(&["linux", "sim"])
+      .default_value("linux")
+      .takes_value(true)
+    )
+    .arg(Arg::with_name("config")
+      .short("c")
+      .long("config")
+      .help("Sets the calibration and wiring configuration file. Send SIGHUP to reload it.")
+      .default_value(DEFAULT_CONFIG_PATH)
+      .takes_value(true)
+    )
+    .subcommand(SubCommand::with_name("calibrate")
+      .about("Interactively calibrates a channel and writes the result to the configuration file. \
+        Don't run this while the drive-core service is running.")
+      .arg(Arg::with_name("channel")
+        .help("Name of the channel to calibrate, e.g. steering")
+        .required(true)
+      )
+      .arg(Arg::with_name("esc")
+        .long("esc")
+        .help("The channel drives an ESC: arm it first and mark reverse/neutral/forward")
+      )
+    )
+    .get_matches();
+
+  if let Err(e) = load_registry() {
+    println!("{}, using the default service addresses.", e);
+  }
+
+  if let Some(sub_matches) = matches.subcommand_matches("calibrate") {
+    calibrate(&matches, sub_matches);
+    return;
+  }
+
+  let simulated = matches.value_of("bus") == Some("sim");
+  let config_path = Path::new(matches.value_of("config").unwrap());
+  let mut config = load_config(&matches);
+
   let mut steering = Variable::new(0f32);
   let mut throttle = Variable::new(0f32);
 
-  // Create and initialize the PWM driver. It's constructor
-  // will set both servos to their respective neutral position.
-  let mut device = PwmDriver::new(I2C_DEVICE_PATH, PwmArgs {
-    address: PWM_DRIVER_ADDRESS,
-    freq: PWM_FREQUENCY,
-    steering: PwmChannelArgs {
-      neutral: 0.085f32,
-      range: 0.01625f32,
-    },
-    throttle: PwmChannelArgs {
-      neutral: 0.075f32,
-      range: 0.025f32,
-    }
-  }).unwrap();
+  // Nothing moves until a client arms us. The failsafe runs on the monotonic clock, which the
+  // network thread shares.
+  let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+  let mut failsafe = Failsafe::new(config.failsafe.clone());
+  let mut failsafe_state = Variable::new(failsafe.state().code());
+  let mut stages: Vec<Box<dyn ControlStage>> = vec![Box::new(OutputFilters::new(&config))];
+  let mut loop_stats = LoopStats::new(clock.monotonic());
+
+  let device = open_driver(simulated, &config);
 
-  let mut log_connection = LogConnection::new().unwrap();
-  log_connection.log_variable(&mut steering, "drive-core_steering").unwrap();
-  log_connection.log_variable(&mut throttle, "drive-core_throttle").unwrap();
+  // Driving doesn't depend on the logger: without it, the values are buffered until it's back
+  let log_connection = LogConnection::new(Service::DriveCore.name(), env!("CARGO_PKG_VERSION"));
+  log_connection.log_variable(&mut steering, "drive-core_steering");
+  log_connection.log_variable(&mut throttle, "drive-core_throttle");
+  log_connection.log_variable(&mut failsafe_state, "drive-core_failsafe_state");
+  loop_stats.register(&log_connection);
 
-  connect_variable_with_channel(&mut steering, &mut device.steering, -1_f32);
-  connect_variable_with_channel(&mut throttle, &mut device.throttle, 1_f32);
+  let output_fault = Rc::new(RefCell::new(None));
+  connect_variable_with_channel(&mut steering, &device.channel(CHANNEL_STEERING).unwrap(),
+    &output_fault);
+  connect_variable_with_channel(&mut throttle, &device.channel(CHANNEL_THROTTLE).unwrap(),
+    &output_fault);
 
   steering.add_listener(|v| { println!("steering: {}", v); Ok(()) });
   throttle.add_listener(|v| { println!("throttle: {}", v); Ok(()) });
@@ -91,13 +294,11 @@ fn main() {
   // Set up a listening network socket to receive new connections on.
   // The drive-core service is controlled by such network
   // connections only (they may come from localhost).
-  let listener = TcpListener::bind("0.0.0.0:".to_string() + &Service::DriveCore.port().to_string())
-    .unwrap();
+  let listener = TcpListener::bind(Service::DriveCore.listen_address()).unwrap();
   listener.set_nonblocking(true).unwrap();
 
-  let enable_pin = Pin::new(255);
-  enable_pin.export().expect("Failed to export enable PIN");
-  enable_pin.set_direction(Direction::Low).expect("Failed to pull enable Pin low");
+  // The outputs stay disabled until the failsafe lets the car move
+  let mut interlock = open_interlock(simulated, &config);
 
   // Set a handler to listen for the Ctrl+C key sequence and perform a
   // clean shutdown if it fires.
@@ -105,58 +306,62 @@ fn main() {
     RUNNING.store(false, Ordering::SeqCst);
   }).unwrap();
 
+  // SIGHUP re-reads the configuration file
+  let reload = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::SIGHUP, reload.clone()).unwrap();
+
+  // The clients are served on their own thread, their messages arrive as events
+  let (events, received) = mpsc::channel();
+  let snapshot = Arc::new(Mutex::new(Snapshot {
+    state: failsafe.state(),
+    outputs: Outputs { steering: 0f32, throttle: 0f32 },
+    max_command_age: failsafe.max_command_age(),
+  }));
+  let network_snapshot = snapshot.clone();
+  let network_log = log_connection.clone();
+  let network_clock = clock.clone();
+  let network = thread::Builder::new().name("network".to_string())
+    .spawn(move || network::run(listener, events, network_snapshot, network_log, network_clock,
+      &RUNNING))
+    .unwrap();
+
   println!("Setup complete. Waiting for connection.");
 
+  let mut scheduler = Scheduler::new(config.control.rate_hz);
   while RUNNING.load(Ordering::Acquire) {
-    std::thread::sleep(std::time::Duration::from_millis(10));
-    let mut socket = match accept_connection(&listener) {
-      Ok(socket) => socket,
-      Err(e) => {
-        if e.kind() != io::ErrorKind::WouldBlock {
-          println!("Failed to accept incoming socket: {:?}", e);
-        }
-        continue
-      }
-    };
+    let (jitter, overrun) = scheduler.wait();
+    let now = clock.monotonic();
 
-    while RUNNING.load(Ordering::Acquire) {
-      // Repeatedly read messages from the socket. If the socket fails,
-      // then we'll drop the client and wait for a new one
-      let msg: MessageType = match deserialize_from(&mut socket) {
-        Ok(msg) => msg,
-        Err(e) => {
-          match *e {
-            bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::TimedOut
-                || e.kind() == io::ErrorKind::WouldBlock => {
-              // Timeout => Disable power
-              steering.set_value(0f32);
-              throttle.set_value(0f32);
-              continue;
-            }
-            e => {
-              println!("Reading from socket failed. Dropping client. {:?}", e);
-              steering.set_value(0f32);
-              throttle.set_value(0f32);
-              break;
-            }
-          }
-        }
-      };
-
-      // Handle the message
-      match msg {
-        MessageType::SetSteering(val) => steering.set_value(val),
-        MessageType::SetThrottle(val) => throttle.set_value(val),
-        MessageType::Bye => {
-          println!("Client logging out.");
-          steering.set_value(0f32);
-          throttle.set_value(0f32);
-          break;
-        },
-      };
+    if reload.swap(false, Ordering::AcqRel) {
+      reload_config(config_path, &mut config, &device, &mut failsafe, &mut stages, &mut scheduler);
     }
+
+    for event in received.try_iter() {
+      apply_event(event, &mut failsafe, now);
+    }
+
+    let outputs = update_outputs(&mut failsafe, &mut stages, now,
+      &mut steering, &mut throttle, &mut failsafe_state);
+
+    // Without working outputs, all we can do is cut them and tell the clients
+    let fault = output_fault.borrow_mut().take();
+    if let Some(reason) = fault {
+      interlock.fault(&reason);
+      failsafe.emergency_stop(now);
+    }
+    if let Err(e) = interlock.update(failsafe.state(), outputs) {
+      interlock.fault(&format!("Failed to switch the outputs: {}", e));
+      failsafe.emergency_stop(now);
+    }
+    *snapshot.lock().unwrap() = Snapshot {
+      state: failsafe.state(),
+      outputs,
+      max_command_age: failsafe.max_command_age(),
+    };
+
+    loop_stats.record(jitter, overrun, now);
   }
 
-  enable_pin.unexport().unwrap();
+  network.join().unwrap();
 }
 
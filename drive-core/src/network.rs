@@ -0,0 +1,277 @@
+// The networking thread of drive-core. It accepts clients, decides who controls the car and turns
+// the controller's messages into events for the control loop. Slow or misbehaving clients can't
+// delay a tick of the control loop this way.
+use arbiter::{ Arbiter, ClientId };
+use client::Client;
+use command::{ self, Verdict };
+use failsafe::Outputs;
+use messages::drive_core::{ DriveState, MessageType, Status };
+use util::logging::LogConnection;
+use util::mesh::{ registry, Service };
+use util::mesh::discovery::Announcer;
+use util::timing::Clock;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::Sender;
+use std::time::{ Duration, Instant };
+
+// Interval of the networking thread. Clients are polled this often.
+const NETWORK_TICK: Duration = Duration::from_millis(2);
+
+// Clients get a status at least this often, besides the ones after changes and heartbeats
+const STATUS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the control loop has to apply to the failsafe
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+  Arm,
+  Disarm,
+  EmergencyStop,
+  Heartbeat,
+  Steering(f32),
+  Throttle(f32),
+  Command(Outputs),
+  LinkLost,                 // The controller disconnected without a goodbye
+}
+
+/// The control loop's side of the story, published for the status messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+  pub state: DriveState,
+  pub outputs: Outputs,
+  pub max_command_age: Duration,
+}
+
+fn accept_clients(listener: &TcpListener,
+                  clients: &mut Vec<Client>,
+                  next_id: &mut ClientId,
+                  now: Duration) {
+  loop {
+    let (socket, addr) = match listener.accept() {
+      Ok(connection) => connection,
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+      Err(e) => {
+        println!("Failed to accept incoming socket: {:?}", e);
+        return;
+      }
+    };
+
+    println!("Client connected from {}", addr);
+    match Client::new(*next_id, socket, addr, now) {
+      Ok(client) => {
+        clients.push(client);
+        *next_id += 1;
+      },
+      Err(e) => println!("Failed to set up the connection to {}: {:?}", addr, e),
+    }
+  }
+}
+
+/// Applies a message of `client`. Only the controller drives the car, but any client may stop it.
+/// Returns false if the client logged out.
+fn handle_message(msg: MessageType,
+                  client: &mut Client,
+                  arbiter: &mut Arbiter,
+                  events: &Sender<Event>,
+                  max_command_age: Duration,
+                  clock: &dyn Clock,
+                  log_connection: &LogConnection) -> bool {
+  let in_control = arbiter.is_controller(client.id);
+  // The control loop only stops before we do
+  let send = |event| { let _ = events.send(event); };
+  match msg {
+    MessageType::Heartbeat(seq) => {
+      client.heartbeat = seq;
+      client.status_requested = true;
+      if in_control {
+        send(Event::Heartbeat);
+      }
+    },
+    MessageType::EmergencyStop => {
+      println!("{} requested an emergency stop!", client.name());
+      send(Event::EmergencyStop);
+    },
+    MessageType::Claim(role) => {
+      let had_controller = arbiter.controller().is_some();
+      if arbiter.claim(client.id, client.name(), role) {
+        println!("{} controls the car as {:?}.", client.name(), role);
+        // The new controller has to arm the car itself instead of inheriting the outputs of the
+        // previous one, its heartbeats would keep them alive
+        if had_controller && !in_control {
+          send(Event::Disarm);
+        }
+      } else {
+        println!("{} can't take control as {:?}.", client.name(), role);
+      }
+    },
+    MessageType::Release => {
+      if arbiter.release(client.id) {
+        println!("{} released control.", client.name());
+        send(Event::Disarm);
+      }
+    },
+    MessageType::Bye => {
+      println!("{} logging out.", client.name());
+      if arbiter.release(client.id) {
+        send(Event::Disarm);
+      }
+      return false;
+    },
+    _ if !in_control => {},
+    MessageType::DriveCommand(command) => {
+      let received = clock.unix_micros();
+      match client.check_command(&command, received, max_command_age, log_connection) {
+        Verdict::Apply(_) => send(Event::Command(command::outputs(&command))),
+        Verdict::Stale => println!("Rejected stale command {} of {}.", command.seq, client.name()),
+        Verdict::TooOld(latency) => println!("Rejected command {} of {}, it is {:?} late.",
+          command.seq, client.name(), latency),
+        Verdict::Invalid => println!("Rejected command {} of {}, its values are out of range.",
+          command.seq, client.name()),
+      }
+    },
+    MessageType::SetSteering(val) => send(Event::Steering(val)),
+    MessageType::SetThrottle(val) => send(Event::Throttle(val)),
+    MessageType::Arm => {
+      println!("{} armed drive-core.", client.name());
+      send(Event::Arm);
+    },
+    MessageType::Disarm => {
+      println!("{} disarmed drive-core.", client.name());
+      send(Event::Disarm);
+    },
+  };
+  true
+}
+
+/// Serves the clients until `running` is cleared
+pub fn run(listener: TcpListener,
+           events: Sender<Event>,
+           snapshot: Arc<Mutex<Snapshot>>,
+           log_connection: LogConnection,
+           clock: Arc<dyn Clock>,
+           running: &AtomicBool) {
+
+  // Tells drive-remote instances on the LAN where to find the car
+  let port = listener.local_addr().map(|addr| addr.port()).unwrap_or(Service::DriveCore.port());
+  let mut announcer =
+    Announcer::new(registry().name(), Service::DriveCore, port, env!("CARGO_PKG_VERSION"))
+      .map_err(|e| println!("Not announcing drive-core on the LAN: {}", e))
+      .ok();
+
+  let mut clients: Vec<Client> = Vec::new();
+  let mut next_id: ClientId = 0;
+  let mut arbiter = Arbiter::new();
+  let mut last_status: Option<Duration> = None;
+  let mut previous: Option<(DriveState, Option<_>)> = None;
+
+  while running.load(Ordering::Acquire) {
+    let now = clock.monotonic();
+    accept_clients(&listener, &mut clients, &mut next_id, now);
+    let max_command_age = snapshot.lock().unwrap().max_command_age;
+
+    // Handle everything the clients sent since the last tick. Clients whose connection failed
+    // are dropped. If that was the controller, the failsafe takes over.
+    clients.retain_mut(|client| {
+      match client.poll(now) {
+        Ok(messages) => messages.into_iter().all(|msg| handle_message(
+          msg, client, &mut arbiter, &events, max_command_age, &*clock, &log_connection)),
+        Err(reason) => {
+          println!("Dropping client {} ({}). {}", client.name(), client.addr, reason);
+          if arbiter.release(client.id) {
+            let _ = events.send(Event::LinkLost);
+          }
+          false
+        }
+      }
+    });
+
+    // Tell the clients who drives and what we're doing
+    let current = *snapshot.lock().unwrap();
+    let controller = arbiter.controller();
+    let changed = previous.as_ref() != Some(&(current.state, controller.clone()));
+    let status_due = changed || last_status.is_none_or(|t| now >= t + STATUS_INTERVAL);
+    let status = Status {
+      state: current.state,
+      heartbeat: 0,
+      steering: current.outputs.steering,
+      throttle: current.outputs.throttle,
+      controller: controller.clone(),
+    };
+    for client in clients.iter_mut().filter(|c| status_due || c.status_requested) {
+      client.send(status.clone());
+    }
+    if status_due {
+      last_status = Some(now);
+    }
+
+    if clients.iter().any(|c| c.ping.is_some()) {
+      let mut detail = format!("{:?}, {} clients", current.state, clients.len());
+      if let Some(ref c) = controller {
+        detail += &format!(", {} in control", c.name);
+      }
+      for client in &mut clients {
+        client.send_health(now, &detail);
+      }
+    }
+    previous = Some((current.state, controller));
+
+    // Fails while the network is down, there's nobody to find us then anyway
+    if let Some(ref mut announcer) = announcer {
+      let _ = announcer.announce(Instant::now());
+    }
+
+    std::thread::sleep(NETWORK_TICK);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use apply_event;
+  use failsafe::{ Failsafe, FailsafeConfig };
+  use messages::drive_core::{ DriveCommand, Role };
+  use std::net::TcpStream;
+  use std::sync::mpsc;
+  use util::timing::MockClock;
+
+  const MAX_AGE: Duration = Duration::from_millis(100);
+
+  /// A client connected over the loopback interface, and the socket of its remote end
+  fn client(listener: &TcpListener, id: ClientId) -> (Client, TcpStream) {
+    let remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (socket, addr) = listener.accept().unwrap();
+    (Client::new(id, socket, addr, Duration::from_secs(0)).unwrap(), remote)
+  }
+
+  #[test]
+  fn a_takeover_disarms_the_car() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut autopilot, _autopilot_remote) = client(&listener, 0);
+    let (mut driver, _driver_remote) = client(&listener, 1);
+    let mut arbiter = Arbiter::new();
+    let (events, received) = mpsc::channel();
+    let clock = MockClock::new(0);
+    let log_connection = LogConnection::new("test", "0.1.0");
+    let handle = |msg, client: &mut Client, arbiter: &mut Arbiter|
+      handle_message(msg, client, arbiter, &events, MAX_AGE, &clock, &log_connection);
+
+    let command = DriveCommand { seq: 1, timestamp: 0, steering: 0.5f32, throttle: 0.8f32,
+      brake: 0f32 };
+    handle(MessageType::Claim(Role::Autopilot), &mut autopilot, &mut arbiter);
+    handle(MessageType::Arm, &mut autopilot, &mut arbiter);
+    handle(MessageType::DriveCommand(command), &mut autopilot, &mut arbiter);
+    handle(MessageType::Claim(Role::Manual), &mut driver, &mut arbiter);
+    handle(MessageType::Heartbeat(1), &mut driver, &mut arbiter);
+
+    let mut failsafe = Failsafe::new(FailsafeConfig::default());
+    let now = Duration::from_millis(10);
+    for event in received.try_iter() {
+      apply_event(event, &mut failsafe, now);
+    }
+    assert_eq!(DriveState::Disarmed, failsafe.state());
+    assert_eq!(Outputs { steering: 0f32, throttle: 0f32 }, failsafe.update(now));
+  }
+}
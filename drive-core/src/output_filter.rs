@@ -0,0 +1,205 @@
+// Smooths the values on their way from the failsafe to the channels. A keyboard jumps from neutral
+// to full throttle in one step, which strips gears and lifts the front wheels. The filters run
+// once per tick of the control loop, so the ramps don't depend on how often clients send commands.
+use config::{ Config, CHANNEL_STEERING, CHANNEL_THROTTLE };
+use control::{ ControlStage, Tick };
+use failsafe::Outputs;
+
+use std::time::Duration;
+
+// Closer than this, a filtered value snaps onto its target instead of creeping towards it forever
+const SNAP_DISTANCE: f32 = 0.001f32;
+
+/// Slew rates and low-pass filtering of one channel, the [filters.<channel>] sections of the
+/// configuration. Rates are in units per second, so a rate of 2 takes the channel from neutral to
+/// its end position in half a second. Channels without a section follow their values directly.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+  /// Limits how fast the value may change in either direction
+  #[serde(default)]
+  pub max_rate: Option<f32>,
+
+  /// Limits how fast the value may move away from neutral (accelerating). Defaults to max_rate.
+  #[serde(default)]
+  pub accel_rate: Option<f32>,
+
+  /// Limits how fast the value may move towards neutral (decelerating). Defaults to max_rate.
+  #[serde(default)]
+  pub decel_rate: Option<f32>,
+
+  /// Time constant of a first-order low-pass in front of the rate limits. 0 disables it.
+  #[serde(default)]
+  pub time_constant_ms: u64,
+}
+
+pub struct OutputFilter {
+  config: FilterConfig,
+  value: f32,
+  last_update: Option<Duration>,
+}
+
+impl OutputFilter {
+  pub fn new(config: FilterConfig) -> OutputFilter {
+    OutputFilter { config, value: 0f32, last_update: None }
+  }
+
+  pub fn set_config(&mut self, config: FilterConfig) {
+    self.config = config;
+  }
+
+  /// Jumps to `value` without any filtering
+  pub fn reset(&mut self, value: f32, now: Duration) {
+    self.value = value;
+    self.last_update = Some(now);
+  }
+
+  /// Moves the filtered value towards `target` by the time passed since the last update
+  pub fn update(&mut self, target: f32, now: Duration) -> f32 {
+    let dt = self.last_update.map_or(0f32, |last| (now.saturating_sub(last)).as_secs_f32());
+    self.last_update = Some(now);
+
+    let mut next = target;
+    if self.config.time_constant_ms > 0 {
+      let time_constant = self.config.time_constant_ms as f32 / 1000f32;
+      next = self.value + (target - self.value) * dt / (time_constant + dt);
+    }
+
+    let step = next - self.value;
+    let towards_neutral = step * self.value < 0f32;
+    let rate = if towards_neutral { self.config.decel_rate } else { self.config.accel_rate };
+    if let Some(rate) = rate.or(self.config.max_rate) {
+      let limit = rate * dt;
+      next = self.value + step.max(-limit).min(limit);
+
+      // Passing neutral turns deceleration into acceleration, so stop there for this tick
+      if towards_neutral && next * self.value < 0f32 {
+        next = 0f32;
+      }
+    }
+    if (target - next).abs() < SNAP_DISTANCE {
+      next = target;
+    }
+    self.value = next;
+    next
+  }
+}
+
+/// The filters of the channels drive-core itself drives. They only act while the car drives: the
+/// stops of the failsafe must not wait for a ramp.
+pub struct OutputFilters {
+  steering: OutputFilter,
+  throttle: OutputFilter,
+}
+
+impl OutputFilters {
+  pub fn new(config: &Config) -> OutputFilters {
+    OutputFilters {
+      steering: OutputFilter::new(config.filter(CHANNEL_STEERING)),
+      throttle: OutputFilter::new(config.filter(CHANNEL_THROTTLE)),
+    }
+  }
+}
+
+impl ControlStage for OutputFilters {
+  fn update(&mut self, outputs: Outputs, tick: &Tick) -> Outputs {
+    if !tick.driving() {
+      self.steering.reset(outputs.steering, tick.now);
+      self.throttle.reset(outputs.throttle, tick.now);
+      return outputs;
+    }
+    Outputs {
+      steering: self.steering.update(outputs.steering, tick.now),
+      throttle: self.throttle.update(outputs.throttle, tick.now),
+    }
+  }
+
+  fn set_config(&mut self, config: &Config) {
+    self.steering.set_config(config.filter(CHANNEL_STEERING));
+    self.throttle.set_config(config.filter(CHANNEL_THROTTLE));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use messages::drive_core::DriveState;
+
+  fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+  }
+
+  fn filter(config: FilterConfig) -> OutputFilter {
+    let mut filter = OutputFilter::new(config);
+    filter.reset(0f32, ms(0));
+    filter
+  }
+
+  fn assert_near(expected: f32, actual: f32) {
+    assert!((expected - actual).abs() < 1e-4, "expected {}, got {}", expected, actual);
+  }
+
+  #[test]
+  fn without_limits_values_pass_unchanged() {
+    let mut filter = filter(FilterConfig::default());
+    assert_eq!(1f32, filter.update(1f32, ms(10)));
+    assert_eq!(-0.5f32, filter.update(-0.5f32, ms(20)));
+  }
+
+  #[test]
+  fn max_rate_limits_both_directions() {
+    let mut filter = filter(FilterConfig { max_rate: Some(2f32), ..FilterConfig::default() });
+    assert_near(0.2f32, filter.update(1f32, ms(100)));
+    assert_near(0.4f32, filter.update(1f32, ms(200)));
+    assert_near(0.2f32, filter.update(-1f32, ms(300)));
+  }
+
+  #[test]
+  fn accelerating_and_decelerating_have_their_own_ramps() {
+    let mut filter = filter(FilterConfig {
+      accel_rate: Some(1f32),
+      decel_rate: Some(10f32),
+      ..FilterConfig::default()
+    });
+    assert_near(0.1f32, filter.update(1f32, ms(100)));
+    assert_near(0.5f32, filter.update(1f32, ms(500)));
+
+    // Letting go of the throttle is quick, but reversing stops at neutral first
+    assert_near(0f32, filter.update(-1f32, ms(550)));
+    assert_near(-0.05f32, filter.update(-1f32, ms(600)));
+  }
+
+  #[test]
+  fn the_low_pass_approaches_its_target() {
+    let mut filter = filter(FilterConfig { time_constant_ms: 100, ..FilterConfig::default() });
+    assert_near(0.5f32, filter.update(1f32, ms(100)));
+    assert_near(0.75f32, filter.update(1f32, ms(200)));
+
+    // Eventually it arrives instead of creeping along
+    let mut now = 200;
+    while filter.update(1f32, ms(now)) != 1f32 {
+      now += 100;
+      assert!(now < 2000);
+    }
+  }
+
+  fn tick(now: u64, state: DriveState) -> Tick {
+    Tick { now: ms(now), state }
+  }
+
+  #[test]
+  fn stops_skip_the_ramps() {
+    let mut config = Config::default();
+    config.filters.insert(CHANNEL_THROTTLE.to_string(),
+      FilterConfig { max_rate: Some(1f32), ..FilterConfig::default() });
+    let mut filters = OutputFilters::new(&config);
+
+    let full = Outputs { steering: 1f32, throttle: 1f32 };
+    filters.update(Outputs { steering: 0f32, throttle: 0f32 }, &tick(0, DriveState::Armed));
+    assert_eq!(Outputs { steering: 1f32, throttle: 0.1f32 },
+      filters.update(full, &tick(100, DriveState::Armed)));
+
+    let brake = Outputs { steering: 0f32, throttle: -0.5f32 };
+    assert_eq!(brake, filters.update(brake, &tick(110, DriveState::EmergencyStop)));
+  }
+}
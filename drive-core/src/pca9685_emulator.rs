@@ -0,0 +1,175 @@
+use error::*;
+use i2c_bus::I2CBus;
+use pwm_driver::{ REG_MODE1, REG_PRESCALER, REG_CHANNEL_BASE, REG_ALL_LED_BASE, CHANNEL_COUNT };
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+// Bits in the MODE1 register
+const MODE1_SLEEP: u8 = 0x10;
+const MODE1_AUTO_INCREMENT: u8 = 0x20;
+
+// Power-on register values according to the PCA9685 datasheet
+const DEFAULT_MODE1: u8 = 0x11;
+const DEFAULT_MODE2: u8 = 0x04;
+const DEFAULT_PRESCALER: u8 = 0x1E;
+
+/// In-memory emulation of the PCA9685 PWM driver chip, used whenever there is no real I2C bus
+/// (on development machines, in CI and in unit tests).
+///
+/// The emulator is a cheap handle to shared register memory: clones observe the same chip,
+/// so a test can keep a clone around to inspect the registers after handing the bus over to
+/// a `PwmDriver` (even after the driver has been dropped).
+#[derive(Clone)]
+pub struct Pca9685Emulator {
+  registers: Rc<RefCell<[u8; 256]>>,
+}
+
+impl Pca9685Emulator {
+  pub fn new() -> Pca9685Emulator {
+    let mut registers = [0u8; 256];
+    registers[REG_MODE1 as usize] = DEFAULT_MODE1;
+    registers[REG_MODE1 as usize + 1] = DEFAULT_MODE2;
+    registers[REG_PRESCALER as usize] = DEFAULT_PRESCALER;
+    Pca9685Emulator { registers: Rc::new(RefCell::new(registers)) }
+  }
+
+  pub fn mode1(&self) -> u8 {
+    self.registers.borrow()[REG_MODE1 as usize]
+  }
+
+  fn store(&mut self, register: u8, value: u8) -> Result<(), I2CError> {
+    let mut registers = self.registers.borrow_mut();
+
+    // The chip ignores writes to the prescaler unless the oscillator is stopped. We report it
+    // instead, since it's always a bug in the driver.
+    if register == REG_PRESCALER && registers[REG_MODE1 as usize] & MODE1_SLEEP == 0 {
+      return Err(I2CError::Emulator(
+        "PRE_SCALE written while the oscillator is running (MODE1 SLEEP bit not set)".to_string()));
+    }
+
+    registers[register as usize] = value;
+
+    // The ALL_LED registers are write-only shortcuts for the same register of every channel
+    if (REG_ALL_LED_BASE..REG_ALL_LED_BASE + 4).contains(&register) {
+      let offset = register - REG_ALL_LED_BASE;
+      for channel in 0..CHANNEL_COUNT {
+        registers[(REG_CHANNEL_BASE + channel * 4 + offset) as usize] = value;
+      }
+    }
+    Ok(())
+  }
+}
+
+// Register inspection, used by the tests to verify what the driver wrote to the chip
+#[cfg(test)]
+impl Pca9685Emulator {
+  pub fn prescaler(&self) -> u8 {
+    self.registers.borrow()[REG_PRESCALER as usize]
+  }
+
+  pub fn is_sleeping(&self) -> bool {
+    self.mode1() & MODE1_SLEEP != 0
+  }
+
+  /// Contents of the LEDn_ON_L / LEDn_ON_H register pair
+  pub fn led_on(&self, channel: u8) -> u16 {
+    self.read_word(REG_CHANNEL_BASE + channel * 4)
+  }
+
+  /// Contents of the LEDn_OFF_L / LEDn_OFF_H register pair
+  pub fn led_off(&self, channel: u8) -> u16 {
+    self.read_word(REG_CHANNEL_BASE + channel * 4 + 2)
+  }
+
+  fn read_word(&self, register: u8) -> u16 {
+    let registers = self.registers.borrow();
+    registers[register as usize] as u16 | (registers[register as usize + 1] as u16) << 8
+  }
+}
+
+impl Default for Pca9685Emulator {
+  fn default() -> Self {
+    Pca9685Emulator::new()
+  }
+}
+
+impl I2CBus for Pca9685Emulator {
+  fn read_byte(&mut self, register: u8) -> Result<u8, I2CError> {
+    Ok(self.registers.borrow()[register as usize])
+  }
+
+  fn write_byte(&mut self, register: u8, value: u8) -> Result<(), I2CError> {
+    self.store(register, value)
+  }
+
+  fn write_word(&mut self, register: u8, value: u16) -> Result<(), I2CError> {
+    // A word write only reaches the second register if register auto-increment is enabled
+    if self.mode1() & MODE1_AUTO_INCREMENT == 0 {
+      return Err(I2CError::Emulator(
+        "Word written while register auto-increment (MODE1 AI bit) is disabled".to_string()));
+    }
+
+    self.store(register, value as u8)?;
+    self.store(register.wrapping_add(1), (value >> 8) as u8)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn it_starts_with_the_power_on_defaults() {
+    let chip = Pca9685Emulator::new();
+    assert_eq!(0x11, chip.mode1());
+    assert_eq!(0x1E, chip.prescaler());
+    assert!(chip.is_sleeping());
+  }
+
+  #[test]
+  fn it_rejects_prescaler_writes_while_awake() {
+    let mut chip = Pca9685Emulator::new();
+    chip.write_byte(REG_MODE1, 0x01).unwrap();
+
+    assert!(chip.write_byte(REG_PRESCALER, 0x79).is_err());
+    assert_eq!(0x1E, chip.prescaler());
+
+    chip.write_byte(REG_MODE1, 0x11).unwrap();
+    chip.write_byte(REG_PRESCALER, 0x79).unwrap();
+    assert_eq!(0x79, chip.prescaler());
+  }
+
+  #[test]
+  fn it_writes_words_little_endian() {
+    let mut chip = Pca9685Emulator::new();
+    let error = chip.write_word(REG_CHANNEL_BASE + 4, 0x1234).unwrap_err();
+    assert_eq!("PCA9685 emulator: Word written while register auto-increment (MODE1 AI bit) is \
+      disabled", error.to_string());
+
+    chip.write_byte(REG_MODE1, 0x21).unwrap();
+    chip.write_word(REG_CHANNEL_BASE + 4, 0x1234).unwrap();
+    assert_eq!(0x34, chip.read_byte(REG_CHANNEL_BASE + 4).unwrap());
+    assert_eq!(0x12, chip.read_byte(REG_CHANNEL_BASE + 5).unwrap());
+    assert_eq!(0x1234, chip.led_on(1));
+  }
+
+  #[test]
+  fn all_led_writes_reach_every_channel() {
+    let mut chip = Pca9685Emulator::new();
+    chip.write_byte(REG_MODE1, 0x21).unwrap();
+    chip.write_word(REG_ALL_LED_BASE + 2, 0x1000).unwrap();
+
+    for channel in 0..CHANNEL_COUNT {
+      assert_eq!(0x1000, chip.led_off(channel));
+    }
+  }
+
+  #[test]
+  fn clones_share_the_same_registers() {
+    let chip = Pca9685Emulator::new();
+    let mut bus = chip.clone();
+    bus.write_byte(REG_MODE1, 0x21).unwrap();
+    assert!(!chip.is_sleeping());
+  }
+}
@@ -1,18 +1,23 @@
 use error::*;
+use i2c_bus::I2CBus;
 
 use std::rc::*;
+use std::collections::HashMap;
 
-use i2cdev::core::*;
 use i2cdev::linux::LinuxI2CDevice;
 use std::cell::RefCell;
 
 // Register numbers specific to the PCA9685 PWM driver
-const REG_MODE1: u8 = 0x00;
-const REG_PRESCALER: u8 = 0xFE;
-const REG_CHANNEL_BASE: u8 = 0x06;
+pub const REG_MODE1: u8 = 0x00;
+pub const REG_PRESCALER: u8 = 0xFE;
+pub const REG_CHANNEL_BASE: u8 = 0x06;
+pub const REG_ALL_LED_BASE: u8 = 0xFA;
 
-const CHANNEL_STEERING: u8 = 0;
-const CHANNEL_THROTTLE: u8 = 1;
+/// Number of PWM outputs on the PCA9685
+pub const CHANNEL_COUNT: u8 = 16;
+
+// Bit 4 of the LEDn_ON_H / LEDn_OFF_H registers switches the output fully on / off
+const LED_FULL: u16 = 0x1000;
 
 fn calc_prescaler(freq: f32) -> u8 {
   (25_000_000f32 / 4096f32 / freq - 1f32).round() as u8
@@ -26,61 +31,136 @@ fn calc_duty_cycle(percentage: f32) -> u16 {
   (percentage * 4096f32) as u16
 }
 
+/// The LEDn_ON and LEDn_OFF values for a duty cycle in the range [0:1]. A full cycle needs the
+/// full-on bit, since 4096 in LEDn_OFF is the full-off bit.
+fn calc_on_off(percentage: f32) -> (u16, u16) {
+  match calc_duty_cycle(percentage) {
+    off if off >= LED_FULL => (LED_FULL, 0x0000),
+    off => (0x0000, off),
+  }
+}
+
+fn default_min() -> f32 { -1f32 }
+fn default_max() -> f32 { 1f32 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct PwmChannelArgs {
+  /// Number of the output on the PWM driver board in the range [0:15]
+  pub channel: u8,
+
   /// Duty cycle in the range [0:1] for the neutral position for this channel.
   pub neutral: f32,
 
   /// Duty cycle range for this channel. If the sum of this value and the neutral value is set,
   /// then the channel shall assume it's extreme position
   pub range: f32,
+
+  /// Values below this limit are clamped before being applied. Must be within [-1:1].
+  #[serde(default = "default_min")]
+  pub min: f32,
+
+  /// Values above this limit are clamped before being applied. Must be within [-1:1].
+  #[serde(default = "default_max")]
+  pub max: f32,
+
+  /// Mirrors the channel around its neutral position (for servos mounted the other way round)
+  #[serde(default)]
+  pub inverted: bool,
+}
+
+impl PwmChannelArgs {
+  /// Channel without clamping or inversion
+  pub fn new(channel: u8, neutral: f32, range: f32) -> PwmChannelArgs {
+    PwmChannelArgs { channel, neutral, range, min: -1f32, max: 1f32, inverted: false }
+  }
+
+  /// Duty cycle in the range [0:1] for the position `value` in the range [-1:1]
+  fn duty_cycle(&self, value: f32) -> f32 {
+    let clamped = value.max(self.min).min(self.max);
+    let directed = if self.inverted { -clamped } else { clamped };
+    self.neutral + directed * self.range
+  }
 }
 
 pub struct PwmArgs {
-  pub address: u16,               // I2C address of the PWM driver board
-  pub freq: f32,                  // PWM frequency to use in [Hz]
-  pub steering: PwmChannelArgs,   // Steering configuration
-  pub throttle: PwmChannelArgs,   // Throttle / Brake configuration
+  pub address: u16,                               // I2C address of the PWM driver board
+  pub freq: f32,                                  // PWM frequency to use in [Hz]
+  pub channels: HashMap<String, PwmChannelArgs>,  // Configuration of the used outputs by name
 }
 
 pub struct PwmChannel {
   config: PwmChannelArgs,
-  channel_num: u8,
-  device: Weak<RefCell<LinuxI2CDevice>>,
+  value: f32,
+  device: Weak<RefCell<Box<dyn I2CBus>>>,
 }
 
 pub struct PwmDriver {
-  device: Rc<RefCell<LinuxI2CDevice>>,
-  pub steering: Rc<RefCell<PwmChannel>>,
-  pub throttle: Rc<RefCell<PwmChannel>>,
+  device: Rc<RefCell<Box<dyn I2CBus>>>,
+  channels: HashMap<String, Rc<RefCell<PwmChannel>>>,
 }
 
 impl PwmChannel {
-  fn new(device: Weak<RefCell<LinuxI2CDevice>>, args: PwmChannelArgs, channel_num: u8) -> Result<PwmChannel, I2CError> {
-    let channel_base_reg = calc_channel_base_reg(channel_num);
-
-    let dev = device.upgrade().expect("device reference invalid during PwmChannel construction!");
-    let mut i2c = dev.borrow_mut();
-
-    // Set the "on" register to 0 for this channel
-    // (we expect the PWM signal to go high at the beginning of each cycle)
-    i2c.smbus_write_word_data(channel_base_reg, 0x0000)?;
-
-    // Set the channel to its neutral position
-    i2c.smbus_write_word_data(channel_base_reg + 2, calc_duty_cycle(args.neutral))?;
+  fn new(device: Weak<RefCell<Box<dyn I2CBus>>>, args: PwmChannelArgs) -> Result<PwmChannel, I2CError> {
+    let mut channel = PwmChannel { config: args, value: 0f32, device };
 
-    Ok(PwmChannel { config: args, channel_num, device })
+    // Start in the neutral position
+    channel.set_value(0f32)?;
+    Ok(channel)
   }
 
   /// Sets the position of the servo connected to this PWM channel
   /// value: The new position for the servo in the range [-1:1]. 0 Means neutral.
-  /// device: The I2C device to set the value on
   pub fn set_value(&mut self, value: f32) -> Result<(), I2CError> {
+    let (on, off) = calc_on_off(self.config.duty_cycle(value));
+    self.write(on, off)?;
+    self.value = value;
+    Ok(())
+  }
+
+  pub fn config(&self) -> &PwmChannelArgs {
+    &self.config
+  }
+
+  /// Replaces the calibration of this channel and re-applies the current position with it.
+  /// The output number can't be changed this way.
+  pub fn set_config(&mut self, args: PwmChannelArgs) -> Result<(), I2CError> {
+    if args.channel != self.config.channel {
+      return Err(I2CError::Setup(SetupError::new("The output of a channel can't be changed")));
+    }
+    self.config = args;
+    let value = self.value;
+    self.set_value(value)
+  }
+
+  /// Sets a raw duty cycle in the range [0:1], bypassing the calibration of this channel
+  pub fn set_duty_cycle(&mut self, duty_cycle: f32) -> Result<(), I2CError> {
+    let (on, off) = calc_on_off(duty_cycle);
+    self.write(on, off)
+  }
+
+  /// Keeps the output permanently high
+  #[allow(dead_code)]
+  pub fn set_full_on(&mut self) -> Result<(), I2CError> {
+    self.write(LED_FULL, 0x0000)
+  }
+
+  /// Keeps the output permanently low
+  #[allow(dead_code)]
+  pub fn set_full_off(&mut self) -> Result<(), I2CError> {
+    self.write(0x0000, LED_FULL)
+  }
+
+  fn write(&mut self, on: u16, off: u16) -> Result<(), I2CError> {
     match self.device.upgrade() {
       Some(dev) => {
         let mut i2c = dev.borrow_mut();
-        i2c.smbus_write_word_data(
-          calc_channel_base_reg(self.channel_num) + 2,
-          calc_duty_cycle(self.config.neutral + value * self.config.range))?;
+        let channel_base_reg = calc_channel_base_reg(self.config.channel);
+
+        // We expect the PWM signal to go high at the beginning of each cycle. Writing the "on"
+        // register every time also clears a previously set full-on bit.
+        i2c.write_word(channel_base_reg, on)?;
+        i2c.write_word(channel_base_reg + 2, off)?;
         Ok(())
       }
       None => Err(I2CError::ReferenceInvalid)
@@ -89,12 +169,28 @@ impl PwmChannel {
 }
 
 impl PwmDriver {
-  pub fn new(path: &str, args: PwmArgs) -> Result<PwmDriver, I2CError> {
-    let device = Rc::new(RefCell::new(LinuxI2CDevice::new(path, args.address)?));
+  /// Opens the PWM driver board connected to the Linux I2C device at `path`
+  pub fn open(path: &str, args: PwmArgs) -> Result<PwmDriver, I2CError> {
+    let device = LinuxI2CDevice::new(path, args.address)?;
+    PwmDriver::new(Box::new(device), args)
+  }
+
+  pub fn new(bus: Box<dyn I2CBus>, args: PwmArgs) -> Result<PwmDriver, I2CError> {
+    // Make sure that no two names are mapped onto the same output
+    let mut used = [false; CHANNEL_COUNT as usize];
+    for (name, channel) in &args.channels {
+      if channel.channel >= CHANNEL_COUNT || used[channel.channel as usize] {
+        return Err(I2CError::Setup(SetupError::new(
+          &format!("Channel {} of \"{}\" is out of range or already in use", channel.channel, name))));
+      }
+      used[channel.channel as usize] = true;
+    }
+
+    let device = Rc::new(RefCell::new(bus));
     {
       let mut i2c = device.borrow_mut();
 
-      match i2c.smbus_read_byte_data(REG_MODE1) {
+      match i2c.read_byte(REG_MODE1) {
         // We don't really care about the actual register contents.
         // This read operation was just done to see whether we're able to communicate at all.
         Ok(_) => (),
@@ -103,26 +199,50 @@ impl PwmDriver {
       };
 
       // Put the driver in sleep mode (the is required to configure the PWM frequency prescaler)
-      i2c.smbus_write_byte_data(REG_MODE1, 0x11)?;
+      i2c.write_byte(REG_MODE1, 0x11)?;
 
       // Configure the PWM frequency prescaler
-      i2c.smbus_write_byte_data(REG_PRESCALER, calc_prescaler(args.freq))?;
+      i2c.write_byte(REG_PRESCALER, calc_prescaler(args.freq))?;
 
       // Start normal device operation
-      i2c.smbus_write_byte_data(REG_MODE1, 0x21)?;
+      i2c.write_byte(REG_MODE1, 0x21)?;
     }
 
-    // Initialize the two PWM channels we need to control our car
-    let steering = Rc::new(RefCell::new(
-      PwmChannel::new(Rc::downgrade(&device), args.steering, CHANNEL_STEERING)?));
-    let throttle = Rc::new(RefCell::new(
-      PwmChannel::new(Rc::downgrade(&device), args.throttle, CHANNEL_THROTTLE)?));
+    let mut driver = PwmDriver { device, channels: HashMap::new() };
+
+    // Outputs that aren't configured stay low
+    driver.set_all_full_off()?;
+
+    // Initialize the PWM channels we need to control our car
+    for (name, channel) in args.channels {
+      let channel = PwmChannel::new(Rc::downgrade(&driver.device), channel)?;
+      driver.channels.insert(name, Rc::new(RefCell::new(channel)));
+    }
 
-    Ok(PwmDriver {
-      device,
-      steering,
-      throttle,
-    })
+    Ok(driver)
+  }
+
+  /// Looks up the configured channel with the given name
+  pub fn channel(&self, name: &str) -> Option<Rc<RefCell<PwmChannel>>> {
+    self.channels.get(name).cloned()
+  }
+
+  /// Sets the same duty cycle in the range [0:1] on all 16 outputs at once
+  #[allow(dead_code)]
+  pub fn set_all_duty_cycle(&mut self, duty_cycle: f32) -> Result<(), I2CError> {
+    let (on, off) = calc_on_off(duty_cycle);
+    self.set_all(on, off)
+  }
+
+  /// Keeps all 16 outputs permanently low
+  pub fn set_all_full_off(&mut self) -> Result<(), I2CError> {
+    self.set_all(0x0000, LED_FULL)
+  }
+
+  fn set_all(&mut self, on: u16, off: u16) -> Result<(), I2CError> {
+    let mut i2c = self.device.borrow_mut();
+    i2c.write_word(REG_ALL_LED_BASE, on)?;
+    i2c.write_word(REG_ALL_LED_BASE + 2, off)
   }
 }
 
@@ -130,6 +250,187 @@ impl Drop for PwmDriver {
   fn drop(&mut self) {
     // Put the driver in sleep mode when we shut down
     println!("Shutting down PWM driver ...");
-    self.device.borrow_mut().smbus_write_byte_data(REG_MODE1, 0x11).unwrap();
+    self.device.borrow_mut().write_byte(REG_MODE1, 0x11).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pca9685_emulator::Pca9685Emulator;
+
+  fn test_args() -> PwmArgs {
+    let mut steering = PwmChannelArgs::new(0, 0.085f32, 0.01625f32);
+    steering.inverted = true;
+
+    let mut throttle = PwmChannelArgs::new(1, 0.075f32, 0.025f32);
+    throttle.min = -0.5f32;
+
+    let mut channels = HashMap::new();
+    channels.insert("steering".to_string(), steering);
+    channels.insert("throttle".to_string(), throttle);
+    channels.insert("lights".to_string(), PwmChannelArgs::new(15, 0.5f32, 0.5f32));
+
+    PwmArgs { address: 0x40, freq: 50f32, channels }
+  }
+
+  #[test]
+  fn new_configures_the_prescaler_and_wakes_the_chip() {
+    let chip = Pca9685Emulator::new();
+    let _driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    assert_eq!(calc_prescaler(50f32), chip.prescaler());
+    assert_eq!(0x21, chip.mode1());
+    assert!(!chip.is_sleeping());
+  }
+
+  #[test]
+  fn new_sets_the_channels_to_neutral() {
+    let chip = Pca9685Emulator::new();
+    let _driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    assert_eq!(0, chip.led_on(0));
+    assert_eq!(calc_duty_cycle(0.085f32), chip.led_off(0));
+    assert_eq!(0, chip.led_on(1));
+    assert_eq!(calc_duty_cycle(0.075f32), chip.led_off(1));
+    assert_eq!(calc_duty_cycle(0.5f32), chip.led_off(15));
+  }
+
+  #[test]
+  fn new_switches_unused_channels_off() {
+    let chip = Pca9685Emulator::new();
+    let _driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    for channel in 2..15 {
+      assert_eq!(LED_FULL, chip.led_off(channel));
+    }
+  }
+
+  #[test]
+  fn new_rejects_invalid_channel_numbers() {
+    let mut args = test_args();
+    args.channels.insert("camera_pan".to_string(), PwmChannelArgs::new(16, 0.075f32, 0.025f32));
+    assert!(PwmDriver::new(Box::new(Pca9685Emulator::new()), args).is_err());
+
+    let mut args = test_args();
+    args.channels.insert("camera_pan".to_string(), PwmChannelArgs::new(1, 0.075f32, 0.025f32));
+    assert!(PwmDriver::new(Box::new(Pca9685Emulator::new()), args).is_err());
+  }
+
+  #[test]
+  fn channels_are_looked_up_by_name() {
+    let driver = PwmDriver::new(Box::new(Pca9685Emulator::new()), test_args()).unwrap();
+    assert!(driver.channel("steering").is_some());
+    assert!(driver.channel("lights").is_some());
+    assert!(driver.channel("gearbox").is_none());
+  }
+
+  #[test]
+  fn set_value_scales_around_the_neutral_position() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    driver.channel("throttle").unwrap().borrow_mut().set_value(1f32).unwrap();
+    assert_eq!(calc_duty_cycle(0.1f32), chip.led_off(1));
+  }
+
+  #[test]
+  fn set_value_clamps_and_inverts() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    driver.channel("throttle").unwrap().borrow_mut().set_value(-1f32).unwrap();
+    assert_eq!(calc_duty_cycle(0.075f32 - 0.5f32 * 0.025f32), chip.led_off(1));
+
+    driver.channel("steering").unwrap().borrow_mut().set_value(-1f32).unwrap();
+    assert_eq!(calc_duty_cycle(0.085f32 + 0.01625f32), chip.led_off(0));
+  }
+
+  #[test]
+  fn full_on_and_full_off_bits_are_cleared_by_set_value() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+    let lights = driver.channel("lights").unwrap();
+
+    lights.borrow_mut().set_full_on().unwrap();
+    assert_eq!(LED_FULL, chip.led_on(15));
+    assert_eq!(0, chip.led_off(15));
+
+    lights.borrow_mut().set_full_off().unwrap();
+    assert_eq!(0, chip.led_on(15));
+    assert_eq!(LED_FULL, chip.led_off(15));
+
+    lights.borrow_mut().set_value(0.5f32).unwrap();
+    assert_eq!(0, chip.led_on(15));
+    assert_eq!(calc_duty_cycle(0.75f32), chip.led_off(15));
+  }
+
+  #[test]
+  fn a_full_duty_cycle_is_fully_on() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+    let lights = driver.channel("lights").unwrap();
+
+    // neutral + range of the lights is a duty cycle of 1
+    lights.borrow_mut().set_full_off().unwrap();
+    lights.borrow_mut().set_value(1f32).unwrap();
+    assert_eq!(0, chip.led_off(15) & LED_FULL);
+    assert_eq!(LED_FULL, chip.led_on(15));
+
+    lights.borrow_mut().set_duty_cycle(1f32).unwrap();
+    assert_eq!((LED_FULL, 0), (chip.led_on(15), chip.led_off(15)));
+  }
+
+  #[test]
+  fn all_led_registers_address_every_channel() {
+    let chip = Pca9685Emulator::new();
+    let mut driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+
+    driver.set_all_duty_cycle(0.25f32).unwrap();
+    for channel in 0..CHANNEL_COUNT {
+      assert_eq!(calc_duty_cycle(0.25f32), chip.led_off(channel));
+    }
+
+    driver.set_all_full_off().unwrap();
+    for channel in 0..CHANNEL_COUNT {
+      assert_eq!(LED_FULL, chip.led_off(channel));
+    }
+  }
+
+  #[test]
+  fn set_config_reapplies_the_current_value() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+    let throttle = driver.channel("throttle").unwrap();
+    throttle.borrow_mut().set_value(1f32).unwrap();
+
+    throttle.borrow_mut().set_config(PwmChannelArgs::new(1, 0.07f32, 0.02f32)).unwrap();
+    assert_eq!(calc_duty_cycle(0.09f32), chip.led_off(1));
+
+    assert!(throttle.borrow_mut().set_config(PwmChannelArgs::new(2, 0.07f32, 0.02f32)).is_err());
+  }
+
+  #[test]
+  fn set_value_fails_once_the_driver_is_gone() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+    let steering = driver.channel("steering").unwrap();
+    drop(driver);
+
+    let result = steering.borrow_mut().set_value(0.5f32);
+    match result {
+      Err(I2CError::ReferenceInvalid) => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn drop_puts_the_chip_to_sleep() {
+    let chip = Pca9685Emulator::new();
+    let driver = PwmDriver::new(Box::new(chip.clone()), test_args()).unwrap();
+    assert!(!chip.is_sleeping());
+
+    drop(driver);
+    assert!(chip.is_sleeping());
   }
 }
@@ -0,0 +1,50 @@
+// Finds the car to drive among the ones that announce their drive-core on the LAN
+use std::io;
+use std::io::{ BufRead, Write };
+use std::time::Duration;
+
+use util::mesh::Service;
+use util::mesh::discovery::{ Discovered, Discovery, ANNOUNCE_INTERVAL };
+
+/// Listens for all cars on the LAN. Waits long enough to hear each of them twice.
+pub fn find() -> io::Result<Vec<Discovered>> {
+  let mut discovery = Discovery::new()?;
+  discovery.browse(ANNOUNCE_INTERVAL * 2 + Duration::from_millis(200))?;
+  Ok(discovery.instances(Service::DriveCore).into_iter().cloned().collect())
+}
+
+pub fn print(cars: &[Discovered]) {
+  for (i, car) in cars.iter().enumerate() {
+    println!("  {}) {} at {} (drive-core {})", i + 1, car.car, car.address, car.software_version);
+  }
+}
+
+/// Picks the car named `name`. Without a name, the only car found is taken, or the user is asked
+/// which one if there are several.
+pub fn choose<'a>(cars: &'a [Discovered], name: Option<&str>) -> Result<&'a Discovered, String> {
+  if let Some(name) = name {
+    return cars.iter().find(|car| car.car == name)
+      .ok_or_else(|| format!("Found no car named {}", name));
+  }
+
+  match cars.len() {
+    0 => Err("Found no cars".to_string()),
+    1 => Ok(&cars[0]),
+    _ => {
+      println!("Found {} cars:", cars.len());
+      print(cars);
+      loop {
+        print!("Which one do you want to drive? ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer).map_err(|e| e.to_string())? == 0 {
+          return Err("No car chosen".to_string());
+        }
+        match answer.trim().parse::<usize>() {
+          Ok(number) if number >= 1 && number <= cars.len() => return Ok(&cars[number - 1]),
+          _ => println!("Please enter a number between 1 and {}.", cars.len()),
+        }
+      }
+    }
+  }
+}
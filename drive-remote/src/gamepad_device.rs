@@ -1,8 +1,13 @@
 use input_device::InputDevice;
-use inputs::Inputs;
+use inputs::{ Inputs, Request };
 
 use joy;
 
+// Button numbers of an Xbox 360 style gamepad
+const BUTTON_B: u8 = 1;
+const BUTTON_BACK: u8 = 6;
+const BUTTON_START: u8 = 7;
+
 pub struct GamepadDevice {
   device: joy::Device,
 }
@@ -35,6 +40,9 @@ impl InputDevice for GamepadDevice {
             _ => {}
           }
         },
+        Button(BUTTON_START, true) => inputs.requests.push(Request::Arm),
+        Button(BUTTON_BACK, true) => inputs.requests.push(Request::Disarm),
+        Button(BUTTON_B, true) => inputs.requests.push(Request::EmergencyStop),
         Button(_, _) => {}
       }
     }
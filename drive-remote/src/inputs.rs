@@ -1,5 +1,14 @@
+/// One-shot requests to drive-core, triggered by a key or button press
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+  Arm,
+  Disarm,
+  EmergencyStop,
+}
+
 pub struct Inputs {
   pub steering: f32,
   pub throttle: f32,
   pub running: bool,
+  pub requests: Vec<Request>,
 }
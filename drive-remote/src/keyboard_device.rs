@@ -1,5 +1,5 @@
 use input_device::InputDevice;
-use inputs::Inputs;
+use inputs::{ Inputs, Request };
 
 use std::io::{ stdout, Cursor };
 use std::os::unix::io::RawFd;
@@ -20,6 +20,9 @@ const KEY_CODE_UP: u16 = 103;
 const KEY_CODE_DOWN: u16 = 108;
 const KEY_CODE_CTRL: u16 = 29;
 const KEY_CODE_C: u16 = 46;
+const KEY_CODE_A: u16 = 30;
+const KEY_CODE_D: u16 = 32;
+const KEY_CODE_SPACE: u16 = 57;
 
 pub struct KeyboardDevice {
   fd: RawFd,
@@ -71,6 +74,10 @@ impl InputDevice for KeyboardDevice {
                   KEY_CODE_UP => inputs.throttle = if key_down { 1f32 } else { 0f32 },
                   KEY_CODE_DOWN => inputs.throttle = if key_down { -1f32 } else { 0f32 },
 
+                  KEY_CODE_A if key_down => inputs.requests.push(Request::Arm),
+                  KEY_CODE_D if key_down => inputs.requests.push(Request::Disarm),
+                  KEY_CODE_SPACE if key_down => inputs.requests.push(Request::EmergencyStop),
+
                   // Handle Ctrl+C to stop the program
                   KEY_CODE_CTRL => self.ctrl_down = key_down,
                   KEY_CODE_C => if self.ctrl_down { inputs.running = false; },
@@ -8,6 +8,7 @@ extern crate clap;
 extern crate joy;
 extern crate util;
 
+mod cars;
 mod input_device;
 mod inputs;
 mod keyboard_device;
@@ -15,20 +16,47 @@ mod gamepad_device;
 
 use std::thread;
 use std::time;
+use std::io;
 use std::net::TcpStream;
-use std::io::{ Write };
 
 use clap::{ Arg, App };
-use bincode::serialize;
 
-use messages::drive_core::MessageType;
+use messages::drive_core::{ DriveCommand, MessageType, Role, Status };
+use messages::frame::{ write_message, FrameError, FrameReader };
+use messages::handshake;
 use input_device::InputDevice;
+use inputs::Request;
 use keyboard_device::KeyboardDevice;
 use gamepad_device::GamepadDevice;
-use util::mesh::Service;
+use util::mesh::{ load_registry, Endpoint, Service };
+use util::timing::unix_micros;
 
 const MIN_SEND_INTERVAL: time::Duration = time::Duration::from_millis(50);
 
+/// The address of the car to drive, chosen among the ones on the LAN. Without any cars there,
+/// drive-core is expected where the mesh configuration says.
+fn find_car(name: Option<&str>) -> String {
+  println!("Searching for cars ...");
+  let found = cars::find().unwrap_or_else(|e| {
+    println!("Failed to search for cars: {}", e);
+    Vec::new()
+  });
+  if found.is_empty() && name.is_none() {
+    return Service::DriveCore.endpoint().to_string();
+  }
+
+  match cars::choose(&found, name) {
+    Ok(car) => {
+      println!("Driving {}", car.car);
+      car.address.to_string()
+    },
+    Err(e) => {
+      println!("{}", e);
+      std::process::exit(1);
+    }
+  }
+}
+
 fn main() {
   let matches = App::new("drive-remote")
     .author("David Bauske <david.bauske@googlemail.com>")
@@ -48,10 +76,22 @@ fn main() {
     .arg(Arg::with_name("host")
       .short("h")
       .long("host")
-      .help("Specify the host to connect to (must run drive-core)")
-      .default_value("localhost")
+      .help("Specify the host to connect to as host[:port] (must run drive-core). Without a host, \
+        the cars on the LAN are searched.")
       .takes_value(true)
     )
+    .arg(Arg::with_name("car")
+      .short("c")
+      .long("car")
+      .help("Drive the car with this name, among the ones found on the LAN")
+      .takes_value(true)
+      .conflicts_with("host")
+    )
+    .arg(Arg::with_name("list")
+      .short("l")
+      .long("list")
+      .help("List the cars found on the LAN and exit")
+    )
     .arg(Arg::with_name("speed")
       .short("s")
       .long("speed")
@@ -61,7 +101,22 @@ fn main() {
     )
     .get_matches();
 
-  let mut inputs = inputs::Inputs { steering: 0f32, throttle: 0f32, running: true };
+  if let Err(e) = load_registry() {
+    println!("{}, using the default service addresses.", e);
+  }
+
+  if matches.is_present("list") {
+    match cars::find() {
+      Ok(ref found) if found.is_empty() => println!("Found no cars."),
+      Ok(found) => cars::print(&found),
+      Err(e) => println!("Failed to search for cars: {}", e),
+    }
+    return;
+  }
+
+  let mut inputs = inputs::Inputs {
+    steering: 0f32, throttle: 0f32, running: true, requests: Vec::new()
+  };
   let mut devices: Vec<Box<InputDevice>> = Vec::new();
 
   // Open the keyboard event device
@@ -79,25 +134,50 @@ fn main() {
     std::process::exit(1);
   }
 
-  let mut host = matches.value_of("host").unwrap().to_owned();
-
-  host.push_str(":");
-  host.push_str(&Service::DriveCore.port().to_string());
+  let host = match matches.value_of("host") {
+    Some(host) => Endpoint::parse(host, Service::DriveCore.endpoint().port).unwrap_or_else(|e| {
+      println!("{}", e);
+      std::process::exit(1);
+    }).to_string(),
+    None => find_car(matches.value_of("car")),
+  };
 
   println!("Connecting to {}", &host);
 
   let mut socket = TcpStream::connect(host).unwrap();
 
+  // Introduce ourselves. drive-core refuses us if we don't speak a common protocol version.
+  let mut reader = FrameReader::new();
+  match handshake::connect(&mut socket, &mut reader, "drive-remote", env!("CARGO_PKG_VERSION")) {
+    Ok(peer) => println!("Connected to {} {}", peer.name, peer.software_version),
+    Err(e) => {
+      println!("Handshake with drive-core failed: {}", e);
+      std::process::exit(1);
+    }
+  }
+
+  // A manual driver always gets control of the car
+  write_message(&mut socket, &MessageType::Claim(Role::Manual)).unwrap();
+
+  // Only peek at the status messages drive-core sends back, never wait for them
+  socket.set_read_timeout(Some(time::Duration::from_millis(1))).unwrap();
+
   let speed_factor: f32 = matches.value_of("speed").unwrap().parse()
     .expect("Invalid number given for speed.");
 
   println!("Connected to AICC.\n\
+    Press A (keyboard) or Start (gamepad) to arm the car, D or Back to disarm it.\n\
+    Space or B is the emergency stop.\n\
     Use the arrow keys to remote-control the car.\n\
     Your motor uses a speed factor of {}.\n\
     Stop the program using Ctrl+C.\n\
     Have fun! :)", speed_factor);
 
   let mut last_send_time = time::Instant::now();
+  let mut heartbeat = 0u32;
+  let mut seq = 0u32;
+  let mut state = None;
+  let mut controller = None;
 
   while inputs.running {
     // Query devices
@@ -113,15 +193,57 @@ fn main() {
     }
     last_send_time = time::Instant::now();
 
-    // Send the steering value
-    let steering_msg = serialize(&MessageType::SetSteering(inputs.steering)).unwrap();
-    socket.write(&steering_msg[..]).unwrap();
+    // Send arm / disarm / emergency stop requests first
+    for request in inputs.requests.drain(..) {
+      let msg = match request {
+        Request::Arm => MessageType::Arm,
+        Request::Disarm => MessageType::Disarm,
+        Request::EmergencyStop => MessageType::EmergencyStop,
+      };
+      write_message(&mut socket, &msg).unwrap();
+    }
 
-    let throttle_msg = serialize(&MessageType::SetThrottle(inputs.throttle * speed_factor)).unwrap();
-    socket.write(&throttle_msg[..]).unwrap();
+    // Send steering and throttle as one command, so drive-core can tell its order and age
+    seq = seq.wrapping_add(1);
+    let command = DriveCommand {
+      seq,
+      timestamp: unix_micros(),
+      steering: inputs.steering,
+      throttle: inputs.throttle * speed_factor,
+      brake: 0f32,
+    };
+    write_message(&mut socket, &MessageType::DriveCommand(command)).unwrap();
+
+    heartbeat = heartbeat.wrapping_add(1);
+    write_message(&mut socket, &MessageType::Heartbeat(heartbeat)).unwrap();
+
+    // Report state changes of drive-core
+    loop {
+      match reader.read_message::<_, Status>(&mut socket) {
+        Ok(status) => {
+          if state != Some(status.state) {
+            println!("drive-core is {:?}", status.state);
+            state = Some(status.state);
+          }
+          if controller != Some(status.controller.clone()) {
+            match status.controller {
+              Some(ref c) => println!("{} controls the car as {:?}", c.name, c.role),
+              None => println!("Nobody controls the car"),
+            }
+            controller = Some(status.controller);
+          }
+        },
+        Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut
+            || e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(FrameError::Io(e)) => {
+          println!("Lost the connection to drive-core: {}", e);
+          std::process::exit(1);
+        },
+        Err(e) => println!("Ignoring an invalid status message: {}", e),
+      }
+    }
   }
 
   // Clean shutdown => Send Bye message
-  let bye_msg = serialize(&MessageType::Bye).unwrap();
-  socket.write(&bye_msg[..]).unwrap();
+  write_message(&mut socket, &MessageType::Bye).unwrap();
 }
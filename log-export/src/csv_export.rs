@@ -0,0 +1,170 @@
+// Merges the streams of a session into one CSV table with a row at a fixed rate
+use std::io::Write;
+
+use csv;
+
+use error::ExportError;
+use logger::reader::{ column_types, columns, Record };
+use messages::log_value::LogValue;
+use session::{ end_time, start_time, Stream };
+use util::timing::NANOS_PER_SECOND;
+
+/// How the value of a stream at a row's time is picked from its records
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resampling {
+  ForwardFill,                      // The latest record at or before the row, none before the first
+  Nearest,                          // The record closest to the row, the earlier one on a tie
+}
+
+/// The name of a column of a stream, like `speed`, `imu.temperature` or `acceleration[2]`
+pub fn column_name(stream: &str, column: &str) -> String {
+  if column.is_empty() {
+    stream.to_string()
+  } else if column.starts_with('[') {
+    format!("{}{}", stream, column)
+  } else {
+    format!("{}.{}", stream, column)
+  }
+}
+
+fn cell(value: &LogValue) -> String {
+  match value {
+    LogValue::Bool(value) => value.to_string(),
+    LogValue::Int(value) => value.to_string(),
+    LogValue::I64(value) => value.to_string(),
+    LogValue::U64(value) => value.to_string(),
+    LogValue::Real(value) => value.to_string(),
+    LogValue::F64(value) => value.to_string(),
+    LogValue::Text(text) => text.clone(),
+    LogValue::Reals(_) | LogValue::Struct(_) => String::new(),   // Split by `columns`
+  }
+}
+
+/// Walks through the records of a stream as the rows advance
+struct Cursor<'a> {
+  records: &'a [Record],
+  index: usize,
+}
+
+impl<'a> Cursor<'a> {
+  fn pick(&mut self, time: u64, resampling: Resampling) -> Option<&'a Record> {
+    while self.index + 1 < self.records.len() && self.records[self.index + 1].time <= time {
+      self.index += 1;
+    }
+    let current = self.records.get(self.index)?;
+    match resampling {
+      Resampling::ForwardFill => if current.time <= time { Some(current) } else { None },
+      Resampling::Nearest => match self.records.get(self.index + 1) {
+        Some(next) if current.time <= time && next.time - time < time - current.time => Some(next),
+        _ => Some(current),
+      },
+    }
+  }
+}
+
+/// Writes the streams as a table with a row every 1 / `rate` seconds from the first to the last
+/// record of the session. The first column is the time in seconds since the first record, then
+/// come the columns of each stream.
+pub fn write_csv<W: Write>(streams: &[Stream], rate: f64, resampling: Resampling, out: W)
+  -> Result<(), ExportError> {
+  let (start, end) = match (start_time(streams), end_time(streams)) {
+    (Some(start), Some(end)) => (start, end),
+    _ => return Err(ExportError::EmptySession),
+  };
+
+  let mut writer = csv::Writer::from_writer(out);
+  let mut header = vec!["time".to_string()];
+  let mut widths = Vec::new();
+  for stream in streams {
+    let types = column_types(&stream.header.log_type);
+    widths.push(types.len());
+    header.extend(types.iter().map(|(column, _)| column_name(&stream.header.name, column)));
+  }
+  writer.write_record(&header)?;
+
+  let mut cursors: Vec<_> = streams.iter()
+    .map(|stream| Cursor { records: &stream.records, index: 0 })
+    .collect();
+  let step = NANOS_PER_SECOND as f64 / rate;
+  let mut row = Vec::with_capacity(header.len());
+  for i in 0.. {
+    let offset = (i as f64 * step) as u64;
+    if start + offset > end {
+      break;
+    }
+    row.clear();
+    row.push(format!("{:.6}", offset as f64 / NANOS_PER_SECOND as f64));
+    for ((stream, cursor), width) in streams.iter().zip(&mut cursors).zip(&widths) {
+      let mut cells = match cursor.pick(start + offset, resampling) {
+        Some(record) => columns(&stream.header.log_type, &record.value).iter()
+          .map(|(_, value)| cell(value))
+          .collect(),
+        None => Vec::new(),
+      };
+      cells.resize(*width, String::new());
+      row.extend(cells);
+    }
+    writer.write_record(&row)?;
+  }
+  writer.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use session::load;
+  use session::tests::write_stream;
+  use tempdir::TempDir;
+
+  fn export(resampling: Resampling) -> String {
+    let tmp = TempDir::new("csv_export").unwrap();
+    write_stream(tmp.path(), "speed", "real",
+      &[(0, LogValue::Real(1.0)), (40, LogValue::Real(2.0))]);
+    write_stream(tmp.path(), "state", "struct State{gear:int,mode:string}",
+      &[(15, LogValue::from_struct(&(3, "sport")))]);
+    let streams = load(tmp.path()).unwrap();
+
+    let mut out = Vec::new();
+    write_csv(&streams, 50.0, resampling, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn forward_fill_keeps_the_latest_value() {
+    assert_eq!("\
+time,speed,state.gear,state.mode
+0.000000,1,,
+0.020000,1,3,sport
+0.040000,2,3,sport
+", export(Resampling::ForwardFill));
+  }
+
+  #[test]
+  fn nearest_takes_the_closest_value() {
+    assert_eq!("\
+time,speed,state.gear,state.mode
+0.000000,1,3,sport
+0.020000,1,3,sport
+0.040000,2,3,sport
+", export(Resampling::Nearest));
+  }
+
+  #[test]
+  fn nearest_switches_at_the_middle() {
+    let records = [
+      Record { time: 0, value: LogValue::Int(1) },
+      Record { time: 10, value: LogValue::Int(2) },
+    ];
+    let mut cursor = Cursor { records: &records, index: 0 };
+    assert_eq!(LogValue::Int(1), cursor.pick(5, Resampling::Nearest).unwrap().value);
+    assert_eq!(LogValue::Int(2), cursor.pick(6, Resampling::Nearest).unwrap().value);
+  }
+
+  #[test]
+  fn columns_are_named_after_the_stream() {
+    assert_eq!("speed", column_name("speed", ""));
+    assert_eq!("imu.temperature", column_name("imu", "temperature"));
+    assert_eq!("acceleration[2]", column_name("acceleration", "[2]"));
+  }
+}
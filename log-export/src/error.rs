@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use csv;
+use mcap::McapError;
+use parquet::errors::ParquetError;
+
+#[derive(Debug)]
+pub enum ExportError {
+  Io(io::Error),
+  Csv(csv::Error),
+  Parquet(ParquetError),
+  Mcap(McapError),
+  EmptySession,
+}
+
+impl Error for ExportError {}
+
+impl fmt::Display for ExportError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ExportError::Io(ref e) => write!(f, "I/O error: {}", e),
+      ExportError::Csv(ref e) => write!(f, "Could not write the CSV file: {}", e),
+      ExportError::Parquet(ref e) => write!(f, "Could not write the Parquet file: {}", e),
+      ExportError::Mcap(ref e) => write!(f, "Could not write the MCAP file: {}", e),
+      ExportError::EmptySession => write!(f, "The session has no records"),
+    }
+  }
+}
+
+impl From<io::Error> for ExportError {
+  fn from(e: io::Error) -> Self {
+    ExportError::Io(e)
+  }
+}
+
+impl From<csv::Error> for ExportError {
+  fn from(e: csv::Error) -> Self {
+    ExportError::Csv(e)
+  }
+}
+
+impl From<ParquetError> for ExportError {
+  fn from(e: ParquetError) -> Self {
+    ExportError::Parquet(e)
+  }
+}
+
+impl From<McapError> for ExportError {
+  fn from(e: McapError) -> Self {
+    ExportError::Mcap(e)
+  }
+}
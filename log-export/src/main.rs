@@ -0,0 +1,135 @@
+// Exports the .ebl files of a logger session for analysis tools
+extern crate clap;
+extern crate csv;
+extern crate mcap;
+extern crate parquet;
+#[macro_use]
+extern crate serde_json;
+
+extern crate logger;
+extern crate messages;
+extern crate util;
+
+#[cfg(test)]
+extern crate tempdir;
+
+mod csv_export;
+mod error;
+mod mcap_export;
+mod parquet_export;
+mod session;
+
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use clap::{ App, AppSettings, Arg, ArgMatches, SubCommand };
+
+use csv_export::Resampling;
+use error::ExportError;
+use session::Stream;
+
+fn load(matches: &ArgMatches) -> Vec<Stream> {
+  let dir = matches.value_of("session").unwrap();
+  match session::load(Path::new(dir)) {
+    Ok(ref streams) if streams.is_empty() => {
+      eprintln!("{}: No .ebl files found", dir);
+      process::exit(1);
+    },
+    Ok(streams) => streams,
+    Err(e) => {
+      eprintln!("{}: {}", dir, e);
+      process::exit(1);
+    },
+  }
+}
+
+fn csv(matches: &ArgMatches) -> Result<(), ExportError> {
+  let rate: f64 = match matches.value_of("rate").unwrap().parse() {
+    Ok(rate) if rate > 0.0 => rate,
+    _ => {
+      eprintln!("Invalid rate, expected a positive number of rows per second");
+      process::exit(1);
+    },
+  };
+  let resampling = match matches.value_of("resample").unwrap() {
+    "nearest" => Resampling::Nearest,
+    _ => Resampling::ForwardFill,
+  };
+  let streams = load(matches);
+  let out = BufWriter::new(File::create(matches.value_of("output").unwrap())?);
+  csv_export::write_csv(&streams, rate, resampling, out)
+}
+
+fn parquet(matches: &ArgMatches) -> Result<(), ExportError> {
+  let streams = load(matches);
+  let dir = Path::new(matches.value_of("output").unwrap());
+  fs::create_dir_all(dir)?;
+  for path in parquet_export::write_session(&streams, dir)? {
+    println!("{}", path.display());
+  }
+  Ok(())
+}
+
+fn mcap(matches: &ArgMatches) -> Result<(), ExportError> {
+  let streams = load(matches);
+  let out = BufWriter::new(File::create(matches.value_of("output").unwrap())?);
+  mcap_export::write_mcap(&streams, out)
+}
+
+fn main() {
+  let session = Arg::with_name("session")
+    .help("The session directory, like /var/log/aicc/<timestamp>")
+    .required(true);
+  let output = |help| Arg::with_name("output")
+    .short("o")
+    .long("output")
+    .help(help)
+    .takes_value(true)
+    .required(true);
+  let matches = App::new("log-export")
+    .about("Exports the .ebl files of a logger session for analysis tools.")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(SubCommand::with_name("csv")
+      .about("Writes all streams to one CSV file, with a row at a fixed rate")
+      .arg(session.clone())
+      .arg(output("The CSV file"))
+      .arg(Arg::with_name("rate")
+        .long("rate")
+        .help("Rows per second")
+        .takes_value(true)
+        .default_value("100")
+      )
+      .arg(Arg::with_name("resample")
+        .long("resample")
+        .help("Use the latest value (ffill) or the closest one (nearest) for each row")
+        .takes_value(true)
+        .possible_values(&["ffill", "nearest"])
+        .default_value("ffill")
+      )
+    )
+    .subcommand(SubCommand::with_name("parquet")
+      .about("Writes each stream to a Parquet file")
+      .arg(session.clone())
+      .arg(output("The directory for the Parquet files"))
+    )
+    .subcommand(SubCommand::with_name("mcap")
+      .about("Writes all streams to one MCAP file, with a channel per stream")
+      .arg(session)
+      .arg(output("The MCAP file"))
+    )
+    .get_matches();
+
+  let result = match matches.subcommand() {
+    ("csv", Some(matches)) => csv(matches),
+    ("parquet", Some(matches)) => parquet(matches),
+    ("mcap", Some(matches)) => mcap(matches),
+    _ => unreachable!(),
+  };
+  if let Err(e) = result {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+}
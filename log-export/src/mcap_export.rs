@@ -0,0 +1,186 @@
+// Writes a session to an MCAP file with a JSON encoded channel per stream
+use std::borrow::Cow;
+use std::collections::{ BTreeMap, BinaryHeap };
+use std::cmp::Reverse;
+use std::io::{ Seek, Write };
+use std::sync::Arc;
+
+use mcap::records::{ MessageHeader, Metadata };
+use mcap::{ Channel, Schema, Writer };
+use serde_json;
+use serde_json::{ Map, Value };
+
+use error::ExportError;
+use logger::reader::Header;
+use messages::log_value::{ LogType, LogValue };
+use session::Stream;
+
+/// The JSON schema of a value of `log_type`
+fn json_schema(log_type: &LogType) -> Value {
+  match log_type {
+    LogType::Bool => json!({ "type": "boolean" }),
+    LogType::Int | LogType::I64 | LogType::U64 => json!({ "type": "integer" }),
+    LogType::Real | LogType::F64 => json!({ "type": "number" }),
+    LogType::Text => json!({ "type": "string" }),
+    LogType::Reals(len) => json!({
+      "type": "array",
+      "items": { "type": "number" },
+      "minItems": len,
+      "maxItems": len,
+    }),
+    LogType::Struct(name, fields) => {
+      let properties: Map<_, _> = fields.iter()
+        .map(|(name, field_type)| (name.clone(), json_schema(field_type)))
+        .collect();
+      json!({ "title": name, "type": "object", "properties": properties })
+    },
+  }
+}
+
+/// A value as JSON. Floats that JSON can't represent (NaN and the infinities) are null.
+fn json_value(log_type: &LogType, value: &LogValue) -> Value {
+  match value {
+    LogValue::Bool(value) => json!(value),
+    LogValue::Int(value) => json!(value),
+    LogValue::I64(value) => json!(value),
+    LogValue::U64(value) => json!(value),
+    LogValue::Real(value) => json!(value),
+    LogValue::F64(value) => json!(value),
+    LogValue::Text(text) => json!(text),
+    LogValue::Reals(values) => json!(values),
+    LogValue::Struct(_) => match (log_type, log_type.fields(value)) {
+      (LogType::Struct(_, fields), Ok(values)) => {
+        let object: Map<_, _> = fields.iter().zip(values)
+          .map(|((name, field_type), (_, field_value))|
+            (name.clone(), json_value(field_type, &field_value)))
+          .collect();
+        Value::Object(object)
+      },
+      _ => Value::Null,
+    },
+  }
+}
+
+/// Messages are objects, so plain values are wrapped in `{"value": ...}`. Structs are their fields.
+fn message_schema(log_type: &LogType) -> Value {
+  match log_type {
+    LogType::Struct(..) => json_schema(log_type),
+    _ => json!({
+      "type": "object",
+      "properties": { "value": json_schema(log_type) },
+    }),
+  }
+}
+
+fn message(log_type: &LogType, value: &LogValue) -> Value {
+  match log_type {
+    LogType::Struct(..) => json_value(log_type, value),
+    _ => json!({ "value": json_value(log_type, value) }),
+  }
+}
+
+/// The header of a stream as the metadata of its channel
+fn channel_metadata(header: &Header) -> BTreeMap<String, String> {
+  let mut metadata = BTreeMap::new();
+  metadata.insert("name".to_string(), header.name.clone());
+  metadata.insert("type".to_string(), header.log_type.to_string());
+  metadata.insert("tags".to_string(), header.tags.join(","));
+  metadata.insert("version".to_string(), format!("{}.{}", header.version.0, header.version.1));
+  metadata.insert("created".to_string(), header.created.to_string());
+  metadata
+}
+
+/// Writes the streams as channels named `/<name>`, with the header of each stream as the channel's
+/// metadata. The messages of all channels are written in time order.
+pub fn write_mcap<W: Write + Seek>(streams: &[Stream], out: W) -> Result<(), ExportError> {
+  let mut writer = Writer::new(out)?;
+
+  let mut channels = Vec::new();
+  for stream in streams {
+    let log_type = &stream.header.log_type;
+    let schema = serde_json::to_vec(&message_schema(log_type)).expect("JSON values serialize");
+    let schema = Schema {
+      name: log_type.to_string(),
+      encoding: "jsonschema".to_string(),
+      data: Cow::from(schema),
+    };
+    channels.push(writer.add_channel(&Channel {
+      topic: format!("/{}", stream.header.name),
+      schema: Some(Arc::new(schema)),
+      message_encoding: "json".to_string(),
+      metadata: channel_metadata(&stream.header),
+    })?);
+  }
+
+  let mut sources: Vec<_> =
+    streams.iter().map(|stream| stream.records.iter().peekable()).collect();
+  let mut next: BinaryHeap<_> = sources.iter_mut().enumerate()
+    .filter_map(|(i, records)| records.peek().map(|record| Reverse((record.time, i))))
+    .collect();
+  let mut sequences = vec![0; streams.len()];
+  while let Some(Reverse((time, i))) = next.pop() {
+    let record = sources[i].next().expect("Peeked before");
+    let data = serde_json::to_vec(&message(&streams[i].header.log_type, &record.value))
+      .expect("JSON values serialize");
+    sequences[i] += 1;
+    writer.write_to_known_channel(&MessageHeader {
+      channel_id: channels[i],
+      sequence: sequences[i],
+      log_time: time,
+      publish_time: time,
+    }, &data)?;
+    if let Some(record) = sources[i].peek() {
+      next.push(Reverse((record.time, i)));
+    }
+  }
+
+  writer.write_metadata(&Metadata {
+    name: "aicc session".to_string(),
+    metadata: streams.iter()
+      .map(|stream| {
+        let paths: Vec<_> = stream.paths.iter().map(|path| path.display().to_string()).collect();
+        (stream.header.name.clone(), paths.join(","))
+      })
+      .collect(),
+  })?;
+  writer.finish()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mcap::MessageStream;
+  use session::load;
+  use session::tests::{ write_stream, START };
+  use std::io::Cursor;
+  use tempdir::TempDir;
+
+  #[test]
+  fn streams_become_channels() {
+    let tmp = TempDir::new("mcap_export").unwrap();
+    write_stream(tmp.path(), "gear", "int", &[(0, LogValue::Int(1)), (30, LogValue::Int(2))]);
+    write_stream(tmp.path(), "fix", "struct Fix{latitude:f64,valid:bool}",
+      &[(10, LogValue::from_struct(&(48.1f64, true)))]);
+    let streams = load(tmp.path()).unwrap();
+
+    let mut out = Cursor::new(Vec::new());
+    write_mcap(&streams, &mut out).unwrap();
+    let data = out.into_inner();
+
+    let messages: Vec<_> = MessageStream::new(&data).unwrap().map(|m| m.unwrap()).collect();
+    let summary: Vec<_> = messages.iter()
+      .map(|m| (m.channel.topic.clone(), m.log_time - START, String::from_utf8_lossy(&m.data)))
+      .collect();
+    assert_eq!(vec![
+      ("/gear".to_string(), 0, Cow::from("{\"value\":1}")),
+      ("/fix".to_string(), 10_000_000, Cow::from("{\"latitude\":48.1,\"valid\":true}")),
+      ("/gear".to_string(), 30_000_000, Cow::from("{\"value\":2}")),
+    ], summary);
+
+    let fix = &messages[1].channel;
+    assert_eq!("json", fix.message_encoding);
+    assert_eq!("struct Fix{latitude:f64,valid:bool}", fix.metadata["type"]);
+    assert_eq!("struct Fix{latitude:f64,valid:bool}", fix.schema.as_ref().unwrap().name);
+  }
+}
@@ -0,0 +1,219 @@
+// Writes each stream of a session to a Parquet file
+use std::fs::File;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use parquet::basic::{ Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType };
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::NanoSeconds;
+use parquet::schema::types::Type;
+
+use error::ExportError;
+use logger::reader::{ column_types, columns };
+use messages::log_value::{ LogType, LogValue };
+use session::Stream;
+
+/// The records written to a row group at once
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// The values of a column, typed like the Parquet column
+enum Values {
+  Bool(Vec<bool>),
+  Int(Vec<i32>),
+  Long(Vec<i64>),
+  Float(Vec<f32>),
+  Double(Vec<f64>),
+  Text(Vec<ByteArray>),
+}
+
+impl Values {
+  fn new(log_type: &LogType) -> Values {
+    match log_type {
+      LogType::Bool => Values::Bool(Vec::new()),
+      LogType::Int => Values::Int(Vec::new()),
+      LogType::I64 | LogType::U64 => Values::Long(Vec::new()),
+      LogType::F64 => Values::Double(Vec::new()),
+      LogType::Text => Values::Text(Vec::new()),
+      _ => Values::Float(Vec::new()),
+    }
+  }
+
+  /// Adds a value. Missing values (of a struct that could not be decoded) are stored as 0.
+  fn push(&mut self, value: Option<&LogValue>) {
+    match (self, value) {
+      (Values::Bool(values), Some(LogValue::Bool(value))) => values.push(*value),
+      (Values::Bool(values), _) => values.push(false),
+      (Values::Int(values), Some(LogValue::Int(value))) => values.push(*value),
+      (Values::Int(values), _) => values.push(0),
+      (Values::Long(values), Some(LogValue::I64(value))) => values.push(*value),
+      // Parquet has no unsigned 64 bit type. The column is annotated as unsigned, so the bits
+      // are kept as they are.
+      (Values::Long(values), Some(LogValue::U64(value))) => values.push(*value as i64),
+      (Values::Long(values), _) => values.push(0),
+      (Values::Float(values), Some(LogValue::Real(value))) => values.push(*value),
+      (Values::Float(values), _) => values.push(0.0),
+      (Values::Double(values), Some(LogValue::F64(value))) => values.push(*value),
+      (Values::Double(values), _) => values.push(0.0),
+      (Values::Text(values), Some(LogValue::Text(text))) => values.push(text.as_str().into()),
+      (Values::Text(values), _) => values.push(ByteArray::from("")),
+    }
+  }
+
+  fn clear(&mut self) {
+    match self {
+      Values::Bool(values) => values.clear(),
+      Values::Int(values) => values.clear(),
+      Values::Long(values) => values.clear(),
+      Values::Float(values) => values.clear(),
+      Values::Double(values) => values.clear(),
+      Values::Text(values) => values.clear(),
+    }
+  }
+
+  fn write(&self, writer: &mut ColumnWriter) -> Result<(), ExportError> {
+    match (self, writer) {
+      (Values::Bool(values), ColumnWriter::BoolColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      (Values::Int(values), ColumnWriter::Int32ColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      (Values::Long(values), ColumnWriter::Int64ColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      (Values::Float(values), ColumnWriter::FloatColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      (Values::Double(values), ColumnWriter::DoubleColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      (Values::Text(values), ColumnWriter::ByteArrayColumnWriter(writer)) =>
+        writer.write_batch(values, None, None)?,
+      _ => unreachable!("The schema is built from the same types"),
+    };
+    Ok(())
+  }
+}
+
+fn column(name: &str, log_type: &LogType) -> Result<Type, ExportError> {
+  let (physical_type, logical_type) = match log_type {
+    LogType::Bool => (PhysicalType::BOOLEAN, None),
+    LogType::Int => (PhysicalType::INT32, None),
+    LogType::I64 => (PhysicalType::INT64, None),
+    LogType::U64 => (PhysicalType::INT64,
+      Some(LogicalType::Integer { bit_width: 64, is_signed: false })),
+    LogType::F64 => (PhysicalType::DOUBLE, None),
+    LogType::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+    _ => (PhysicalType::FLOAT, None),
+  };
+  Ok(Type::primitive_type_builder(name, physical_type)
+    .with_repetition(Repetition::REQUIRED)
+    .with_logical_type(logical_type)
+    .build()?)
+}
+
+/// The schema of a stream: the time, then a column for each column of the values. Version 1.0
+/// files have times relative to the start of the logger, which are no timestamps.
+fn schema(stream: &Stream) -> Result<Type, ExportError> {
+  let time_type = if stream.header.relative_times() {
+    None
+  } else {
+    let unit = TimeUnit::NANOS(NanoSeconds {});
+    Some(LogicalType::Timestamp { is_adjusted_to_u_t_c: true, unit })
+  };
+  let mut fields = vec![Arc::new(Type::primitive_type_builder("time", PhysicalType::INT64)
+    .with_repetition(Repetition::REQUIRED)
+    .with_logical_type(time_type)
+    .build()?)];
+  for (name, log_type) in column_types(&stream.header.log_type) {
+    let name = if name.is_empty() { "value" } else { &name };
+    fields.push(Arc::new(column(name, &log_type)?));
+  }
+  Ok(Type::group_type_builder(&stream.header.name).with_fields(fields).build()?)
+}
+
+/// Writes a stream to a Parquet file with snappy compression
+pub fn write_parquet(stream: &Stream, path: &Path) -> Result<(), ExportError> {
+  let types = column_types(&stream.header.log_type);
+  let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+  let mut writer = SerializedFileWriter::new(File::create(path)?, Arc::new(schema(stream)?),
+    Arc::new(properties))?;
+
+  let mut times = Values::Long(Vec::new());
+  let mut values: Vec<_> = types.iter().map(|(_, log_type)| Values::new(log_type)).collect();
+  for records in stream.records.chunks(ROW_GROUP_SIZE) {
+    times.clear();
+    for column in &mut values {
+      column.clear();
+    }
+    for record in records {
+      times.push(Some(&LogValue::I64(record.time as i64)));
+      let record_columns = columns(&stream.header.log_type, &record.value);
+      for (i, column) in values.iter_mut().enumerate() {
+        column.push(record_columns.get(i).map(|(_, value)| value));
+      }
+    }
+
+    let mut row_group = writer.next_row_group()?;
+    for column in Some(&times).into_iter().chain(&values) {
+      let mut column_writer = row_group.next_column()?.expect("A column per value column");
+      column.write(column_writer.untyped())?;
+      column_writer.close()?;
+    }
+    row_group.close()?;
+  }
+  writer.close()?;
+  Ok(())
+}
+
+/// Writes each stream to `<name>.parquet` in `dir` and returns the paths
+pub fn write_session(streams: &[Stream], dir: &Path) -> Result<Vec<PathBuf>, ExportError> {
+  let mut paths = Vec::new();
+  for stream in streams {
+    let path = dir.join(format!("{}.parquet", stream.header.name));
+    write_parquet(stream, &path)?;
+    paths.push(path);
+  }
+  Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use parquet::file::reader::{ FileReader, SerializedFileReader };
+  use parquet::record::Field;
+  use session::load;
+  use session::tests::{ write_stream, START };
+  use tempdir::TempDir;
+
+  #[test]
+  fn streams_are_written_with_their_columns() {
+    let tmp = TempDir::new("parquet_export").unwrap();
+    write_stream(tmp.path(), "imu", "struct Imu{rate:real[2],count:u64,label:string}", &[
+      (0, LogValue::from_struct(&([0.5f32, 1.5f32], 7u64, "a"))),
+      (10, LogValue::from_struct(&([2.5f32, 3.5f32], u64::MAX, "b"))),
+    ]);
+    let streams = load(tmp.path()).unwrap();
+    let paths = write_session(&streams, tmp.path()).unwrap();
+    assert_eq!(vec![tmp.path().join("imu.parquet")], paths);
+
+    let reader = SerializedFileReader::new(File::open(&paths[0]).unwrap()).unwrap();
+    let names: Vec<_> = reader.metadata().file_metadata().schema_descr().columns().iter()
+      .map(|column| column.name().to_string()).collect();
+    assert_eq!(vec!["time", "rate[0]", "rate[1]", "count", "label"], names);
+    let time = reader.metadata().file_metadata().schema_descr().column(0);
+    assert_eq!(Some(LogicalType::Timestamp {
+      is_adjusted_to_u_t_c: true,
+      unit: TimeUnit::NANOS(NanoSeconds {}),
+    }), time.logical_type());
+
+    let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(2, rows.len());
+    let fields: Vec<_> = rows[1].get_column_iter().map(|(_, field)| field.clone()).collect();
+    assert_eq!(vec![
+      Field::Long((START + 10_000_000) as i64),
+      Field::Float(2.5),
+      Field::Float(3.5),
+      Field::ULong(u64::MAX),
+      Field::Str("b".to_string()),
+    ], fields);
+  }
+}
@@ -0,0 +1,139 @@
+// A logger session: the directory `StreamManager` writes the .ebl files of one run to
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use logger::reader::{ Header, LogFile, Record };
+
+/// The records of a variable, sorted by time
+pub struct Stream {
+  pub paths: Vec<PathBuf>,          // The files of the variable, it continues in a new file when
+                                    // one gets too big or too old
+  pub header: Header,               // The header of the oldest file
+  pub records: Vec<Record>,
+}
+
+/// Reads all .ebl files in `dir` into a stream per variable, sorted by file name. Files with an
+/// unreadable header are skipped and a record cut off at the end of a file is dropped, both with
+/// a warning, since a logger that crashed leaves such files behind.
+pub fn load(dir: &Path) -> io::Result<Vec<Stream>> {
+  let mut paths = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension() == Some("ebl".as_ref()) {
+      paths.push(path);
+    }
+  }
+  paths.sort();
+
+  let mut streams: Vec<Stream> = Vec::new();
+  for path in paths {
+    let file = match LogFile::open(&path) {
+      Ok(file) => file,
+      Err(e) => {
+        eprintln!("Skipping {}: {}", path.display(), e);
+        continue;
+      },
+    };
+    let mut records = Vec::new();
+    for record in file.records() {
+      match record {
+        Ok(record) => records.push(record),
+        Err(e) => eprintln!("{}: {}", path.display(), e),
+      }
+    }
+
+    match streams.iter_mut().find(|stream| stream.header.name == file.header.name) {
+      Some(stream) => {
+        stream.paths.push(path);
+        stream.records.extend(records);
+        if file.header.created < stream.header.created {
+          stream.header = file.header;
+        }
+      },
+      None => streams.push(Stream { paths: vec![path], header: file.header, records }),
+    }
+  }
+  for stream in &mut streams {
+    stream.records.sort_by_key(|record| record.time);
+  }
+  Ok(streams)
+}
+
+/// The time of the earliest record of all streams
+pub fn start_time(streams: &[Stream]) -> Option<u64> {
+  streams.iter().filter_map(|stream| stream.records.first()).map(|record| record.time).min()
+}
+
+/// The time of the latest record of all streams
+pub fn end_time(streams: &[Stream]) -> Option<u64> {
+  streams.iter().filter_map(|stream| stream.records.last()).map(|record| record.time).max()
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::fs::OpenOptions;
+  use std::io::Write;
+
+  use logger::chunk::Compression;
+  use logger::log_stream::{ Format, LogStream };
+  use messages::log_value::{ LogType, LogValue };
+  use tempdir::TempDir;
+  use util::timing::{ MockClock, NANOS_PER_SECOND };
+
+  pub const START: u64 = 1_600_000_000 * NANOS_PER_SECOND;
+
+  /// Writes a stream to `<name>.ebl` in `dir`, with the records at `START` plus their time in ms
+  pub fn write_stream(dir: &Path, name: &str, log_type: &str, records: &[(u64, LogValue)]) {
+    write_file(&dir.join(format!("{}.ebl", name)), name, log_type, Format::Framed, records);
+  }
+
+  fn write_file(path: &Path, name: &str, log_type: &str, format: Format,
+                records: &[(u64, LogValue)]) {
+    let clock = MockClock::new(START);
+    let log_type = LogType::parse(log_type).unwrap();
+    let mut stream = LogStream::new(path, name, &log_type, format, &clock).unwrap();
+    for (millis, value) in records {
+      stream.log(START + millis * 1_000_000, value).unwrap();
+    }
+    stream.finish().unwrap();
+  }
+
+  #[test]
+  fn it_loads_the_streams_of_a_session() {
+    let tmp = TempDir::new("session").unwrap();
+    write_stream(tmp.path(), "speed", "real",
+      &[(20, LogValue::Real(2.0)), (10, LogValue::Real(1.0))]);
+    write_stream(tmp.path(), "gear", "int", &[(15, LogValue::Int(3))]);
+    fs::write(tmp.path().join("broken.ebl"), [1, 0]).unwrap();
+    fs::write(tmp.path().join("notes.txt"), "not a log").unwrap();
+    OpenOptions::new().append(true).open(tmp.path().join("gear.ebl")).unwrap()
+      .write_all(&[1, 2, 3]).unwrap();
+
+    let streams = load(tmp.path()).unwrap();
+    let names: Vec<_> = streams.iter().map(|stream| stream.header.name.as_str()).collect();
+    assert_eq!(vec!["gear", "speed"], names);
+    assert_eq!(1, streams[0].records.len());
+    let times: Vec<_> = streams[1].records.iter().map(|record| record.time).collect();
+    assert_eq!(vec![START + 10_000_000, START + 20_000_000], times);
+    assert_eq!(Some(START + 10_000_000), start_time(&streams));
+    assert_eq!(Some(START + 20_000_000), end_time(&streams));
+  }
+
+  #[test]
+  fn the_files_of_a_variable_are_one_stream() {
+    let tmp = TempDir::new("session").unwrap();
+    write_stream(tmp.path(), "speed", "real", &[(0, LogValue::Real(1.0))]);
+    // Either format can be read
+    write_file(&tmp.path().join("speed.1.ebl"), "speed", "real", Format::Chunked(Compression::Zstd),
+      &[(10, LogValue::Real(2.0))]);
+
+    let streams = load(tmp.path()).unwrap();
+    assert_eq!(1, streams.len());
+    let paths = vec![tmp.path().join("speed.1.ebl"), tmp.path().join("speed.ebl")];
+    assert_eq!(paths, streams[0].paths);
+    let values: Vec<_> = streams[0].records.iter().map(|record| record.value.clone()).collect();
+    assert_eq!(vec![LogValue::Real(1.0), LogValue::Real(2.0)], values);
+  }
+}
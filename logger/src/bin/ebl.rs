@@ -0,0 +1,389 @@
+// Inspects the .ebl files written by the logger
+extern crate chrono;
+extern crate clap;
+
+extern crate logger;
+#[cfg(test)]
+extern crate messages;
+extern crate util;
+
+#[cfg(test)]
+extern crate tempdir;
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process;
+
+use chrono::{ Local, LocalResult, TimeZone };
+use clap::{ App, AppSettings, Arg, ArgMatches, SubCommand };
+
+use logger::reader::{ columns, format_value, Header, LogFile, ReadError, Record };
+use util::timing::NANOS_PER_SECOND;
+
+/// Running statistics of a numeric column
+#[derive(Debug, PartialEq)]
+struct Stats {
+  count: u64,
+  min: f64,
+  max: f64,
+  sum: f64,
+}
+
+impl Stats {
+  fn new() -> Stats {
+    Stats { count: 0, min: f64::INFINITY, max: f64::NEG_INFINITY, sum: 0.0 }
+  }
+
+  fn add(&mut self, value: f64) {
+    self.count += 1;
+    self.min = self.min.min(value);
+    self.max = self.max.max(value);
+    self.sum += value;
+  }
+
+  fn mean(&self) -> f64 {
+    self.sum / self.count as f64
+  }
+}
+
+fn open(path: &str) -> LogFile {
+  LogFile::open(Path::new(path)).unwrap_or_else(|e| {
+    eprintln!("{}: {}", path, e);
+    process::exit(1);
+  })
+}
+
+/// Reads all complete records. A torn record at the end is reported, but doesn't fail the command.
+fn read_records(path: &str, file: &LogFile) -> Vec<Record> {
+  let mut records = Vec::new();
+  for record in file.records() {
+    match record {
+      Ok(record) => records.push(record),
+      Err(e) => eprintln!("{}: {}", path, e),
+    }
+  }
+  records
+}
+
+fn seconds(nanos: u64) -> f64 {
+  nanos as f64 / NANOS_PER_SECOND as f64
+}
+
+/// Records per second over the time the records span. The wall clock may have been stepped back
+/// while they were logged, so the span is that of the earliest and the latest record.
+fn record_rate(records: &[Record]) -> f64 {
+  let earliest = records.iter().map(|record| record.time).min();
+  let latest = records.iter().map(|record| record.time).max();
+  let duration = match (earliest, latest) {
+    (Some(earliest), Some(latest)) => seconds(latest - earliest),
+    _ => 0.0,
+  };
+  if duration > 0.0 { (records.len() - 1) as f64 / duration } else { 0.0 }
+}
+
+/// Local time of `time` in nanoseconds since the UNIX epoch
+fn format_unix(time: u64) -> String {
+  let secs = (time / NANOS_PER_SECOND) as i64;
+  match Local.timestamp_opt(secs, (time % NANOS_PER_SECOND) as u32) {
+    LocalResult::Single(time) => time.format("%Y-%m-%d %H:%M:%S%.6f").to_string(),
+    _ => format!("{:.6} s", seconds(time)),
+  }
+}
+
+/// A record time for people: local time, or seconds since the logger started for version 1.0
+fn format_time(header: &Header, time: u64) -> String {
+  if header.relative_times() {
+    format!("{:.6} s", seconds(time))
+  } else {
+    format_unix(time)
+  }
+}
+
+fn print_header(header: &Header) {
+  println!("  name:     {}", header.name);
+  println!("  type:     {}", header.log_type);
+  println!("  version:  {}.{}", header.version.0, header.version.1);
+  println!("  created:  {}", format_unix(header.created * NANOS_PER_SECOND));
+  if !header.tags.is_empty() {
+    println!("  tags:     {}", header.tags.join(", "));
+  }
+}
+
+fn info(matches: &ArgMatches) {
+  for path in matches.values_of("files").unwrap() {
+    let file = open(path);
+    println!("{}", path);
+    print_header(&file.header);
+    if file.header.chunked() {
+      match file.index() {
+        Some(index) => println!("  chunks:   {}", index.len()),
+        None => println!("  chunks:   not indexed, see `ebl repair`"),
+      }
+    }
+    let records = read_records(path, &file);
+    match (records.first(), records.last()) {
+      (Some(first), Some(last)) => println!("  records:  {} from {} to {} ({:.3} s)",
+        records.len(), format_time(&file.header, first.time), format_time(&file.header, last.time),
+        seconds(last.time.saturating_sub(first.time))),
+      _ => println!("  records:  none"),
+    }
+  }
+}
+
+fn dump(matches: &ArgMatches) {
+  let path = matches.value_of("file").unwrap();
+  let file = open(path);
+  println!("{}", path);
+  print_header(&file.header);
+  for record in file.records() {
+    match record {
+      Ok(record) => println!("{}  {}", format_time(&file.header, record.time),
+        format_value(&file.header.log_type, &record.value)),
+      Err(e) => println!("{}", e),
+    }
+  }
+}
+
+/// Parses a time argument in seconds
+fn time_arg(matches: &ArgMatches, name: &str) -> Option<f64> {
+  matches.value_of(name).map(|value| value.parse().unwrap_or_else(|_| {
+    eprintln!("Invalid time `{}` for --{}, expected seconds", value, name);
+    process::exit(1);
+  }))
+}
+
+/// The records between `from` and `to` (seconds since the first record), for further processing:
+/// one line per record with the time in seconds since the first record and the value
+fn cat(matches: &ArgMatches) {
+  let path = matches.value_of("file").unwrap();
+  let file = open(path);
+  let from = time_arg(matches, "from").unwrap_or(f64::NEG_INFINITY);
+  let to = time_arg(matches, "to").unwrap_or(f64::INFINITY);
+
+  // The index of a chunked file leads right to the records from `from` on
+  let start = file.records().filter_map(Result::ok).next().map_or(0, |record| record.time);
+  let records = if from > 0.0 {
+    file.records_from(start + (from * NANOS_PER_SECOND as f64) as u64)
+  } else {
+    file.records()
+  };
+  for record in records {
+    let record = match record {
+      Ok(record) => record,
+      Err(e) => {
+        eprintln!("{}: {}", path, e);
+        continue;
+      },
+    };
+    let time = seconds(record.time) - seconds(start);
+    if time >= from && time <= to {
+      println!("{:.6}\t{}", time, format_value(&file.header.log_type, &record.value));
+    }
+  }
+}
+
+/// Minimum, maximum, mean and rate of every numeric column
+fn stats(matches: &ArgMatches) {
+  println!("{:<40} {:>8} {:>12} {:>12} {:>12} {:>10}", "variable", "records", "min", "max", "mean",
+    "rate [Hz]");
+  for path in matches.values_of("files").unwrap() {
+    let file = open(path);
+    let records = read_records(path, &file);
+
+    let mut names: Vec<String> = Vec::new();
+    let mut column_stats: Vec<Stats> = Vec::new();
+    for record in &records {
+      for (i, (name, value)) in columns(&file.header.log_type, &record.value).iter().enumerate() {
+        if i == names.len() {
+          names.push(name.clone());
+          column_stats.push(Stats::new());
+        }
+        if let Some(value) = value.as_f64() {
+          column_stats[i].add(value);
+        }
+      }
+    }
+
+    let rate = record_rate(&records);
+    if names.is_empty() {
+      println!("{:<40} {:>8}", file.header.name, 0);
+    }
+    for (name, stats) in names.iter().zip(column_stats) {
+      let variable = if name.is_empty() {
+        file.header.name.clone()
+      } else {
+        format!("{}.{}", file.header.name, name)
+      };
+      if stats.count == 0 {
+        println!("{:<40} {:>8} {:>12} {:>12} {:>12} {:>10.1}", variable, records.len(), "-", "-",
+          "-", rate);
+      } else {
+        println!("{:<40} {:>8} {:>12.6} {:>12.6} {:>12.6} {:>10.1}", variable, stats.count,
+          stats.min, stats.max, stats.mean(), rate);
+      }
+    }
+  }
+}
+
+/// Drops the damaged records of the file at `path` and indexes a chunked file without an index,
+/// unless `dry_run`. A file that is only damaged at its end is truncated, other files are
+/// rewritten. Returns the number of bytes dropped, or None if the file is intact.
+fn repair_file(path: &Path, dry_run: bool) -> Result<Option<usize>, ReadError> {
+  let data = fs::read(path)?;
+  let file = LogFile::from_bytes(data.clone())?;
+  let intact = file.intact_bytes();
+  if intact == data {
+    return Ok(None);
+  }
+  let dropped = file.damaged_bytes();
+  if dry_run {
+    return Ok(Some(dropped));
+  }
+
+  if data.starts_with(&intact) {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(intact.len() as u64)?;
+    file.sync_all()?;
+  } else {
+    // Replace the file at once, so a crash leaves either version
+    let tmp_path = path.with_extension("ebl.tmp");
+    fs::write(&tmp_path, &intact)?;
+    OpenOptions::new().write(true).open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+  }
+  Ok(Some(dropped))
+}
+
+fn repair(matches: &ArgMatches) {
+  let dry_run = matches.is_present("dry-run");
+  let mut failed = false;
+  for path in matches.values_of("files").unwrap() {
+    match repair_file(Path::new(path), dry_run) {
+      Ok(None) => println!("{}: intact", path),
+      Ok(Some(0)) if dry_run => println!("{}: would add the missing chunk index", path),
+      Ok(Some(0)) => println!("{}: added the missing chunk index", path),
+      Ok(Some(dropped)) if dry_run => println!("{}: would drop {} damaged bytes", path, dropped),
+      Ok(Some(dropped)) => println!("{}: dropped {} damaged bytes", path, dropped),
+      Err(e) => {
+        eprintln!("{}: {}", path, e);
+        failed = true;
+      },
+    }
+  }
+  if failed {
+    process::exit(1);
+  }
+}
+
+fn main() {
+  let files = Arg::with_name("files").help("The .ebl files").required(true).multiple(true);
+  let file = Arg::with_name("file").help("The .ebl file").required(true);
+  let matches = App::new("ebl")
+    .about("Inspects the .ebl log files of the AICC logger.")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(SubCommand::with_name("info")
+      .about("Shows the header and the time span of log files")
+      .arg(files.clone())
+    )
+    .subcommand(SubCommand::with_name("dump")
+      .about("Shows the header and all records of a log file")
+      .arg(file.clone())
+    )
+    .subcommand(SubCommand::with_name("cat")
+      .about("Prints the records of a log file as lines of time (seconds since the first record) \
+        and value")
+      .arg(file)
+      .arg(Arg::with_name("from")
+        .long("from")
+        .help("Skips the records before this many seconds")
+        .takes_value(true)
+      )
+      .arg(Arg::with_name("to")
+        .long("to")
+        .help("Skips the records after this many seconds")
+        .takes_value(true)
+      )
+    )
+    .subcommand(SubCommand::with_name("stats")
+      .about("Shows the minimum, maximum, mean and rate of the values in log files")
+      .arg(files.clone())
+    )
+    .subcommand(SubCommand::with_name("repair")
+      .about("Drops the damaged records of log files, like the torn last record after a crash")
+      .arg(files)
+      .arg(Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Only reports how many bytes would be dropped")
+      )
+    )
+    .get_matches();
+
+  match matches.subcommand() {
+    ("info", Some(matches)) => info(matches),
+    ("dump", Some(matches)) => dump(matches),
+    ("cat", Some(matches)) => cat(matches),
+    ("stats", Some(matches)) => stats(matches),
+    ("repair", Some(matches)) => repair(matches),
+    _ => unreachable!(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use messages::log_value::{ LogType, LogValue };
+  use tempdir::TempDir;
+  use logger::log_stream::{ Format, LogStream };
+  use util::timing::MockClock;
+
+  #[test]
+  fn stats_track_the_extremes_and_the_mean() {
+    let mut stats = Stats::new();
+    for value in &[2.0, -1.0, 5.0] {
+      stats.add(*value);
+    }
+    assert_eq!(Stats { count: 3, min: -1.0, max: 5.0, sum: 6.0 }, stats);
+    assert_eq!(2.0, stats.mean());
+  }
+
+  #[test]
+  fn record_rate_survives_a_clock_stepping_back() {
+    let record = |time: u64| Record { time, value: LogValue::Int(0) };
+    let records = vec![record(NANOS_PER_SECOND), record(3 * NANOS_PER_SECOND), record(0)];
+    assert_eq!(2.0 / 3.0, record_rate(&records));
+    assert_eq!(0.0, record_rate(&records[..1]));
+    assert_eq!(0.0, record_rate(&[]));
+  }
+
+  #[test]
+  fn repair_drops_the_damaged_records() {
+    let tmp = TempDir::new("ebl").unwrap();
+    let path = tmp.path().join("speed.ebl");
+    let clock = MockClock::new(0);
+    let mut stream = LogStream::new(&path, "speed", &LogType::Int, Format::Framed, &clock).unwrap();
+    for i in 0..3 {
+      stream.log(i, &LogValue::Int(i as i32)).unwrap();
+    }
+    stream.sync().unwrap();
+    let mut data = fs::read(&path).unwrap();
+    let size = data.len();
+
+    // A torn record at the end is cut off, in place
+    fs::write(&path, &data[..size - 5]).unwrap();
+    assert_eq!(Some(17), repair_file(&path, true).unwrap());
+    assert_eq!(size - 5, fs::metadata(&path).unwrap().len() as usize);
+    assert_eq!(Some(17), repair_file(&path, false).unwrap());
+    assert_eq!(size - 22, fs::metadata(&path).unwrap().len() as usize);
+
+    // A damaged record in between is left out
+    data[size - 30] ^= 0xff;
+    fs::write(&path, &data).unwrap();
+    assert_eq!(Some(22), repair_file(&path, false).unwrap());
+    let values: Vec<_> = LogFile::open(&path).unwrap().records()
+      .map(|record| record.unwrap().value)
+      .collect();
+    assert_eq!(vec![LogValue::Int(0), LogValue::Int(2)], values);
+    assert_eq!(None, repair_file(&path, false).unwrap());
+  }
+}
@@ -0,0 +1,382 @@
+// Version 2.0 of the .ebl format stores the records in chunks, which keeps high-rate streams
+// small. After the header (see `reader`), a file is a sequence of chunks
+//
+//   magic (2 bytes) | compression (u8) | record count (u32) | earliest time (u64)
+//   | latest time (u64) | data length (u32) | stored length (u32) | stored data
+//   | CRC-32 of everything before it (u32)
+//
+// The data holds the records, each its time as the zigzag LEB128 varint of the nanoseconds since
+// the record before (the first since the UNIX epoch), followed by the value stored natively for
+// its type (see `LogType`). It is stored compressed as the chunk says. A closed file ends with the
+// index of its chunks
+//
+//   per chunk: offset (u64) | earliest time (u64) | latest time (u64) | record count (u32)
+//   | chunk count (u32) | offset of the index (u64) | CRC-32 of the index before it (u32)
+//   | magic (4 bytes)
+//
+// so a reader can go right to the chunk of a time. A file without a valid index, like after a
+// crash, is read chunk by chunk.
+use std::fmt;
+use std::io;
+
+use byteorder::{ ByteOrder, LittleEndian, WriteBytesExt };
+use crc::crc32;
+use lz4_flex;
+use zstd;
+
+use messages::log_value::{ LogType, LogValue };
+
+pub const CHUNK_MAGIC: [u8; 2] = [0xEB, 0xC4];
+pub const INDEX_MAGIC: [u8; 4] = *b"EBLX";
+
+/// A chunk is written when its records take this many bytes, or when the stream is synced
+pub const CHUNK_DATA_SIZE: usize = 64 * 1024;
+
+const CHUNK_HEADER_SIZE: usize = 31;
+const CHECKSUM_SIZE: usize = 4;
+const INDEX_ENTRY_SIZE: usize = 28;
+const TRAILER_SIZE: usize = 20;
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the data of a chunk is compressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+  None,
+  Lz4,
+  Zstd,
+}
+
+impl Compression {
+  pub fn parse(name: &str) -> Result<Compression, String> {
+    match name {
+      "none" => Ok(Compression::None),
+      "lz4" => Ok(Compression::Lz4),
+      "zstd" => Ok(Compression::Zstd),
+      _ => Err(format!("Unknown compression {}, expected none, lz4 or zstd", name)),
+    }
+  }
+
+  fn id(self) -> u8 {
+    match self {
+      Compression::None => 0,
+      Compression::Lz4 => 1,
+      Compression::Zstd => 2,
+    }
+  }
+
+  fn from_id(id: u8) -> Option<Compression> {
+    match id {
+      0 => Some(Compression::None),
+      1 => Some(Compression::Lz4),
+      2 => Some(Compression::Zstd),
+      _ => None,
+    }
+  }
+
+  fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+    match self {
+      Compression::None => Ok(data.to_vec()),
+      Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+      Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+    }
+  }
+
+  fn decompress(self, stored: &[u8], length: usize) -> Option<Vec<u8>> {
+    let data = match self {
+      Compression::None => stored.to_vec(),
+      Compression::Lz4 => lz4_flex::block::decompress(stored, length).ok()?,
+      Compression::Zstd => zstd::bulk::decompress(stored, length).ok()?,
+    };
+    if data.len() == length { Some(data) } else { None }
+  }
+}
+
+impl fmt::Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Compression::None => write!(f, "none"),
+      Compression::Lz4 => write!(f, "lz4"),
+      Compression::Zstd => write!(f, "zstd"),
+    }
+  }
+}
+
+/// Where a chunk is in its file and which records it holds
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkInfo {
+  pub offset: u64,
+  pub earliest: u64,                // Nanoseconds since the UNIX epoch
+  pub latest: u64,
+  pub records: u32,
+}
+
+/// A chunk read from a file, with its records decompressed
+pub struct Chunk {
+  pub info: ChunkInfo,
+  pub compression: Compression,
+  pub data: Vec<u8>,
+}
+
+/// Collects records for the next chunk
+pub struct ChunkBuilder {
+  data: Vec<u8>,
+  records: u32,
+  earliest: u64,
+  latest: u64,
+  last_time: u64,
+}
+
+impl Default for ChunkBuilder {
+  fn default() -> ChunkBuilder {
+    ChunkBuilder { data: Vec::new(), records: 0, earliest: u64::MAX, latest: 0, last_time: 0 }
+  }
+}
+
+impl ChunkBuilder {
+  /// Adds a record, unless `value` isn't of type `log_type`
+  pub fn push(&mut self, time: u64, log_type: &LogType, value: &LogValue) -> Result<(), String> {
+    let start = self.data.len();
+    write_varint(&mut self.data, zigzag(time.wrapping_sub(self.last_time) as i64));
+    if let Err(e) = log_type.encode(value, &mut self.data) {
+      self.data.truncate(start);
+      return Err(e);
+    }
+    self.records += 1;
+    self.earliest = self.earliest.min(time);
+    self.latest = self.latest.max(time);
+    self.last_time = time;
+    Ok(())
+  }
+
+  /// The bytes of the records so far, before compression
+  pub fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.records == 0
+  }
+
+  /// Appends the chunk to `out`, which will be at `offset` in the file, and starts the next one.
+  /// The data is stored uncompressed if `compression` doesn't make it smaller.
+  pub fn finish(&mut self, compression: Compression, offset: u64, out: &mut Vec<u8>)
+    -> io::Result<ChunkInfo> {
+    let (compression, stored) = match compression.compress(&self.data)? {
+      ref stored if stored.len() >= self.data.len() => (Compression::None, self.data.clone()),
+      stored => (compression, stored),
+    };
+    let start = out.len();
+    out.extend_from_slice(&CHUNK_MAGIC);
+    out.write_u8(compression.id())?;
+    out.write_u32::<LittleEndian>(self.records)?;
+    out.write_u64::<LittleEndian>(self.earliest)?;
+    out.write_u64::<LittleEndian>(self.latest)?;
+    out.write_u32::<LittleEndian>(self.data.len() as u32)?;
+    out.write_u32::<LittleEndian>(stored.len() as u32)?;
+    out.extend_from_slice(&stored);
+    let checksum = crc32::checksum_ieee(&out[start..]);
+    out.write_u32::<LittleEndian>(checksum)?;
+
+    let info =
+      ChunkInfo { offset, earliest: self.earliest, latest: self.latest, records: self.records };
+    *self = ChunkBuilder::default();
+    Ok(info)
+  }
+}
+
+/// Reads the chunk at `start`. Returns the chunk and where it ends, or None if there is no intact
+/// chunk.
+pub fn read_chunk(data: &[u8], start: usize) -> Option<(Chunk, usize)> {
+  let frame = &data[start..];
+  if frame.len() < CHUNK_HEADER_SIZE || frame[..CHUNK_MAGIC.len()] != CHUNK_MAGIC {
+    return None;
+  }
+  let stored_length = LittleEndian::read_u32(&frame[27..31]) as usize;
+  let end = CHUNK_HEADER_SIZE.checked_add(stored_length)?;
+  if frame.len() < end + CHECKSUM_SIZE {
+    return None;
+  }
+  let checksum = LittleEndian::read_u32(&frame[end..end + CHECKSUM_SIZE]);
+  if checksum != crc32::checksum_ieee(&frame[..end]) {
+    return None;
+  }
+
+  let compression = Compression::from_id(frame[2])?;
+  let length = LittleEndian::read_u32(&frame[23..27]) as usize;
+  let info = ChunkInfo {
+    offset: start as u64,
+    earliest: LittleEndian::read_u64(&frame[7..15]),
+    latest: LittleEndian::read_u64(&frame[15..23]),
+    records: LittleEndian::read_u32(&frame[3..7]),
+  };
+  let data = compression.decompress(&frame[CHUNK_HEADER_SIZE..end], length)?;
+  Some((Chunk { info, compression, data }, start + end + CHECKSUM_SIZE))
+}
+
+/// Reads a record of chunk data, given the time of the record before it
+pub fn read_record(input: &mut &[u8], log_type: &LogType, last_time: u64)
+  -> io::Result<(u64, LogValue)> {
+  let time = last_time.wrapping_add(unzigzag(read_varint(input)?) as u64);
+  Ok((time, log_type.decode(input)?))
+}
+
+/// Appends the index of `chunks`, which will be at `offset` in the file, to `out`
+pub fn write_index(chunks: &[ChunkInfo], offset: u64, out: &mut Vec<u8>) -> io::Result<()> {
+  let start = out.len();
+  for chunk in chunks {
+    out.write_u64::<LittleEndian>(chunk.offset)?;
+    out.write_u64::<LittleEndian>(chunk.earliest)?;
+    out.write_u64::<LittleEndian>(chunk.latest)?;
+    out.write_u32::<LittleEndian>(chunk.records)?;
+  }
+  out.write_u32::<LittleEndian>(chunks.len() as u32)?;
+  out.write_u64::<LittleEndian>(offset)?;
+  let checksum = crc32::checksum_ieee(&out[start..]);
+  out.write_u32::<LittleEndian>(checksum)?;
+  out.extend_from_slice(&INDEX_MAGIC);
+  Ok(())
+}
+
+/// Reads the index at the end of `data`, whose chunks start at `chunks_start`. Returns the chunks
+/// and where the index starts, or None if there is no valid index.
+pub fn read_index(data: &[u8], chunks_start: usize) -> Option<(Vec<ChunkInfo>, usize)> {
+  if data.len() < chunks_start + TRAILER_SIZE || data[data.len() - 4..] != INDEX_MAGIC {
+    return None;
+  }
+  let trailer = &data[data.len() - TRAILER_SIZE..];
+  let count = LittleEndian::read_u32(&trailer[..4]) as usize;
+  let offset = LittleEndian::read_u64(&trailer[4..12]) as usize;
+  let end = data.len() - TRAILER_SIZE;
+  if offset < chunks_start || count.checked_mul(INDEX_ENTRY_SIZE) != Some(end.checked_sub(offset)?)
+  {
+    return None;
+  }
+  if LittleEndian::read_u32(&trailer[12..16]) != crc32::checksum_ieee(&data[offset..end + 12]) {
+    return None;
+  }
+
+  let chunks: Vec<_> = data[offset..end].chunks(INDEX_ENTRY_SIZE)
+    .map(|entry| ChunkInfo {
+      offset: LittleEndian::read_u64(&entry[..8]),
+      earliest: LittleEndian::read_u64(&entry[8..16]),
+      latest: LittleEndian::read_u64(&entry[16..24]),
+      records: LittleEndian::read_u32(&entry[24..28]),
+    })
+    .collect();
+  let in_file = |chunk: &ChunkInfo| chunk.offset >= chunks_start as u64
+    && chunk.offset < offset as u64;
+  if chunks.iter().all(in_file) { Some((chunks, offset)) } else { None }
+}
+
+fn zigzag(value: i64) -> u64 {
+  ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+  (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> io::Result<u64> {
+  let mut value = 0u64;
+  for (i, &byte) in input.iter().enumerate().take(10) {
+    value |= u64::from(byte & 0x7f) << (7 * i);
+    if byte & 0x80 == 0 {
+      *input = &input[i + 1..];
+      return Ok(value);
+    }
+  }
+  Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Invalid varint"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn records(chunk: &Chunk, log_type: &LogType) -> Vec<(u64, LogValue)> {
+    let mut input = &chunk.data[..];
+    let mut last_time = 0;
+    let mut records = Vec::new();
+    while !input.is_empty() {
+      let record = read_record(&mut input, log_type, last_time).unwrap();
+      last_time = record.0;
+      records.push(record);
+    }
+    records
+  }
+
+  #[test]
+  fn chunks_keep_their_records_with_any_compression() {
+    // The clock of a producer can step back
+    let times = [1_600_000_000_000_000_000, 1_600_000_000_001_000_000, 1_599_999_999_000_000_000];
+    for &compression in &[Compression::None, Compression::Lz4, Compression::Zstd] {
+      let mut builder = ChunkBuilder::default();
+      for (i, &time) in times.iter().enumerate() {
+        builder.push(time, &LogType::I64, &LogValue::I64(i as i64)).unwrap();
+      }
+      assert!(builder.push(0, &LogType::I64, &LogValue::Real(1.0)).is_err());
+      let mut out = vec![0; 5];
+      let info = builder.finish(compression, 5, &mut out).unwrap();
+      assert!(builder.is_empty());
+      assert_eq!(ChunkInfo { offset: 5, earliest: times[2], latest: times[1], records: 3 }, info);
+
+      let (chunk, end) = read_chunk(&out, 5).unwrap();
+      assert_eq!(out.len(), end);
+      assert_eq!(info, chunk.info);
+      let expected: Vec<_> = times.iter().enumerate()
+        .map(|(i, &time)| (time, LogValue::I64(i as i64))).collect();
+      assert_eq!(expected, records(&chunk, &LogType::I64));
+
+      // Any damage fails the checksum
+      out[20] ^= 1;
+      assert!(read_chunk(&out, 5).is_none());
+    }
+  }
+
+  #[test]
+  fn repetitive_records_are_compressed() {
+    let mut builder = ChunkBuilder::default();
+    for &compression in &[Compression::Lz4, Compression::Zstd] {
+      for i in 0..1000 {
+        let time = 1_600_000_000_000_000_000 + i * 1_000_000;
+        builder.push(time, &LogType::Real, &LogValue::Real(0.5)).unwrap();
+      }
+      // The time steps take 3 bytes, the values 4
+      assert_eq!(9 + 4 + 999 * 7, builder.len());
+      let mut out = Vec::new();
+      builder.finish(compression, 0, &mut out).unwrap();
+      assert!(out.len() < 200, "{} bytes with {}", out.len(), compression);
+      let (chunk, _) = read_chunk(&out, 0).unwrap();
+      assert_eq!(compression, chunk.compression);
+      assert_eq!(1000, records(&chunk, &LogType::Real).len());
+    }
+
+    // Data that doesn't compress is stored as it is
+    builder.push(1, &LogType::Int, &LogValue::Int(7)).unwrap();
+    let mut out = Vec::new();
+    builder.finish(Compression::Lz4, 0, &mut out).unwrap();
+    assert_eq!(Compression::None, read_chunk(&out, 0).unwrap().0.compression);
+  }
+
+  #[test]
+  fn the_index_is_found_at_the_end() {
+    let chunks = vec![
+      ChunkInfo { offset: 10, earliest: 1, latest: 2, records: 2 },
+      ChunkInfo { offset: 100, earliest: 3, latest: 4, records: 7 },
+    ];
+    let mut data = vec![0; 200];
+    write_index(&chunks, 200, &mut data).unwrap();
+    assert_eq!(Some((chunks, 200)), read_index(&data, 10));
+
+    // Not from a file that was cut off, nor with chunks outside the file
+    assert_eq!(None, read_index(&data[..data.len() - 1], 10));
+    assert_eq!(None, read_index(&data, 20));
+  }
+}
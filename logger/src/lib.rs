@@ -0,0 +1,23 @@
+extern crate byteorder;
+extern crate chrono;
+extern crate crc;
+extern crate lz4_flex;
+extern crate nix;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate zstd;
+
+extern crate messages;
+extern crate util;
+
+#[cfg(test)]
+extern crate tempdir;
+
+pub mod chunk;
+pub mod log_stream;
+pub mod manifest;
+pub mod reader;
+pub mod storage;
+pub mod stream_manager;
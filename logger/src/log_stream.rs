@@ -1,41 +1,67 @@
 use std::fs;
 use std::fs::File;
 use std::path::{ Path, PathBuf };
-use std::time::{ SystemTime, UNIX_EPOCH };
 use std::io;
-use std::io::Write;
-use std::marker::{ PhantomData };
+use std::io::{ BufWriter, Seek, Write };
 
-use serde::Serialize;
-use bincode::serialize;
 use byteorder::*;
-use chrono::Local;
+use chrono::{ DateTime, Local };
+use crc::crc32;
 
-use util::timing::milliseconds;
-use util::logging::data_types::TypeInfo;
+use chunk::{ ChunkBuilder, ChunkInfo, Compression, CHUNK_DATA_SIZE, write_index };
+use messages::log_value::{ LogType, LogValue };
+use util::timing::{ Clock, NANOS_PER_SECOND };
 
-pub trait LogStreamBase {
-  fn log_generic(&mut self, val: f32) -> io::Result<()>;
-}
+// Version 1.2 of the format, which frames the records
+pub const VERSION: i32 = 1 << 16 | 2;
+
+// Version 2.0, which stores the records in chunks (see `chunk`)
+pub const CHUNKED_VERSION: i32 = 2 << 16;
 
-pub struct LogStream<T> {
-  file: File,
-  _p: PhantomData<T>,
+/// Starts every record, so a reader can find the next record after a damaged one
+pub const RECORD_MAGIC: [u8; 2] = [0xEB, 0x1C];
+pub const RECORD_HEADER_SIZE: usize = 6;
+pub const RECORD_CHECKSUM_SIZE: usize = 4;
+
+/// How a stream stores its records
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+  Framed,                           // Version 1.2, each record on its own
+  Chunked(Compression),             // Version 2.0, for high-rate streams
 }
 
-#[derive(Serialize, Debug)]
-struct LogRecord<T> {
-  time: f32,
-  value: T,
+// A log file holds the records of one variable. Each record is framed as
+//
+//   magic (2 bytes) | payload length (u32) | time (u64 nanoseconds since the UNIX epoch) | value
+//   | CRC-32 of everything before it (u32)
+//
+// with the value stored natively for the variable's type (see `LogType`). After a torn write, a
+// reader recognizes the damaged record by its checksum and finds the next one by the magic bytes.
+// Records are buffered until `flush` or `sync`, or until the buffer is full.
+//
+// In the chunked format, the records are collected into a chunk, which is written when it is full
+// or at `sync`. `finish` writes the index of the chunks at the end of the file.
+pub struct LogStream {
+  file: BufWriter<File>,
+  format: Format,
+  log_type: LogType,
+  record: Vec<u8>,
+  size: u64,
+  chunk: ChunkBuilder,
+  chunks: Vec<ChunkInfo>,
 }
 
-impl<T> LogStream<T> where T: TypeInfo + Serialize {
-  pub fn new(path: &Path, name: &str) -> io::Result<LogStream<T>> {
-    let mut file = File::create(path)?;
+impl LogStream {
+  pub fn new(path: &Path, name: &str, log_type: &LogType, format: Format, clock: &dyn Clock)
+    -> io::Result<LogStream> {
+    let mut file = BufWriter::new(File::create(path)?);
 
     // Write file header:
     // Version
-    file.write_i32::<LittleEndian>(1 << 16)?;
+    file.write_i32::<LittleEndian>(match format {
+      Format::Framed => VERSION,
+      Format::Chunked(_) => CHUNKED_VERSION,
+    })?;
 
     // ID (not used by us at the moment)
     file.write_u16::<LittleEndian>(0)?;
@@ -45,44 +71,131 @@ impl<T> LogStream<T> where T: TypeInfo + Serialize {
     file.write_all(name.as_bytes())?;
 
     // Type
-    let type_str = T::type_str();
+    let type_str = log_type.to_string();
     file.write_u16::<LittleEndian>(type_str.len() as u16)?;
     file.write_all(type_str.as_bytes())?;
 
-    // Time
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    file.write_u64::<LittleEndian>(now.as_secs())?;
+    // Time in seconds since the UNIX epoch
+    file.write_u64::<LittleEndian>(clock.unix_nanos() / NANOS_PER_SECOND)?;
 
     // Tags - we don't use them (yet?)
     file.write_u16::<LittleEndian>(0)?;
 
-    Ok(LogStream { file, _p: PhantomData })
+    // Seeking writes the header to the file
+    let size = file.stream_position()?;
+    Ok(LogStream {
+      file,
+      format,
+      log_type: log_type.clone(),
+      record: Vec::new(),
+      size,
+      chunk: ChunkBuilder::default(),
+      chunks: Vec::new(),
+    })
   }
 
-  pub fn log(&mut self, value: T) -> io::Result<()> {
-    let record = LogRecord { time: milliseconds(), value };
-    Ok(self.file.write_all(&serialize(&record).unwrap())?)
+  /// Writes a record. `time` is in nanoseconds since the UNIX epoch. Values of another type than
+  /// the stream's are refused.
+  pub fn log(&mut self, time: u64, value: &LogValue) -> io::Result<()> {
+    if let Format::Chunked(_) = self.format {
+      self.chunk.push(time, &self.log_type, value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+      if self.chunk.len() >= CHUNK_DATA_SIZE {
+        self.write_chunk()?;
+      }
+      return Ok(());
+    }
+
+    self.record.clear();
+    self.record.extend_from_slice(&RECORD_MAGIC);
+    self.record.write_u32::<LittleEndian>(0)?;
+    self.record.write_u64::<LittleEndian>(time)?;
+    self.log_type.encode(value, &mut self.record)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let length = self.record.len() - RECORD_HEADER_SIZE;
+    LittleEndian::write_u32(&mut self.record[RECORD_MAGIC.len()..RECORD_HEADER_SIZE],
+      length as u32);
+    let checksum = crc32::checksum_ieee(&self.record);
+    self.record.write_u32::<LittleEndian>(checksum)?;
+
+    self.file.write_all(&self.record)?;
+    self.size += self.record.len() as u64;
+    Ok(())
+  }
+
+  /// Writes the buffered records to the file. The records of an unfinished chunk stay buffered.
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+
+  /// Writes the buffered records and waits until they are on the disk
+  pub fn sync(&mut self) -> io::Result<()> {
+    self.write_chunk()?;
+    self.file.flush()?;
+    self.file.get_ref().sync_data()
+  }
+
+  /// Writes all records and, in the chunked format, the index, and closes the file. Returns the
+  /// size of the file.
+  pub fn finish(mut self) -> io::Result<u64> {
+    if let Format::Chunked(_) = self.format {
+      self.write_chunk()?;
+      let mut index = Vec::new();
+      write_index(&self.chunks, self.size, &mut index)?;
+      self.file.write_all(&index)?;
+      self.size += index.len() as u64;
+    }
+    self.sync()?;
+    Ok(self.size)
+  }
+
+  /// Bytes of the file, including the header and the records of an unfinished chunk
+  pub fn size(&self) -> u64 {
+    self.size + self.chunk.len() as u64
   }
-}
 
-impl<T> LogStreamBase for LogStream<T> where T: TypeInfo + Serialize {
-  fn log_generic(&mut self, val: f32) -> io::Result<()> {
-    self.log(T::from_f32(val))
+  /// Writes the records collected so far as a chunk
+  fn write_chunk(&mut self) -> io::Result<()> {
+    let compression = match self.format {
+      Format::Chunked(compression) if !self.chunk.is_empty() => compression,
+      _ => return Ok(()),
+    };
+    self.record.clear();
+    let info = self.chunk.finish(compression, self.size, &mut self.record)?;
+    self.file.write_all(&self.record)?;
+    self.size += self.record.len() as u64;
+    self.chunks.push(info);
+    Ok(())
   }
 }
 
-pub fn get_timestamped_path() -> io::Result<PathBuf> {
-  let time = Local::now().format("%Y-%m-%d_%H-%M").to_string();
-  let path = Path::new("/var/log/aicc").join(time);
-  fs::create_dir_all(&path)?;
-  Ok(path)
+/// Creates the directory for a session started at `time` in `root`, like `2018-03-10_14-05-09`.
+/// Sessions started in the same second get a suffix like `_2`.
+pub fn create_session_dir(root: &Path, time: DateTime<Local>) -> io::Result<PathBuf> {
+  fs::create_dir_all(root)?;
+  let name = time.format("%Y-%m-%d_%H-%M-%S").to_string();
+  let mut path = root.join(&name);
+  let mut count = 1;
+  loop {
+    match fs::create_dir(&path) {
+      Ok(()) => return Ok(path),
+      Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+        count += 1;
+        path = root.join(format!("{}_{}", name, count));
+      },
+      Err(e) => return Err(e),
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use chrono::TimeZone;
+  use chunk::{ read_chunk, read_index };
   use std::io::Read;
   use tempdir::TempDir;
+  use util::timing::MockClock;
 
   #[test]
   fn it_creates_and_opens_a_new_file() {
@@ -91,14 +204,18 @@ mod tests {
     let var_name = "test_var";
 
     assert!(!tmp.path().join(file_name).exists());
-    let mut stream: LogStream<i32> = LogStream::new(&tmp.path().join(file_name), var_name).unwrap();
+    let clock = MockClock::new(1_600_000_000 * NANOS_PER_SECOND + 250);
+    let path = tmp.path().join(file_name);
+    let mut stream = LogStream::new(&path, var_name, &LogType::Int, Format::Framed, &clock)
+      .unwrap();
     assert!(tmp.path().join(file_name).exists());
+    assert_eq!(4 + 2 + 2 + 8 + 2 + 3 + 8 + 2, stream.size());
 
     // Make sure that the byte layout is correct
     let mut reader = File::open(tmp.path().join(file_name)).unwrap();
 
     // Version
-    assert_eq!(1 << 16, reader.read_i32::<LittleEndian>().unwrap());
+    assert_eq!(1 << 16 | 2, reader.read_i32::<LittleEndian>().unwrap());
 
     // ID
     assert_eq!(0, reader.read_u16::<LittleEndian>().unwrap());
@@ -116,15 +233,74 @@ mod tests {
     reader.read_exact(&mut type_buffer).unwrap();
     assert_eq!("int".as_bytes(), &type_buffer[..]);
 
-    let timestamp = reader.read_u64::<LittleEndian>().unwrap();
-    assert!(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - timestamp <= 1);
+    assert_eq!(1_600_000_000, reader.read_u64::<LittleEndian>().unwrap());
 
     assert_eq!(0, reader.read_u16::<LittleEndian>().unwrap());
 
+    // Records stay in the buffer until they are flushed
+    stream.log(1_600_000_000_123_456_789, &LogValue::Int(42)).unwrap();
+    assert_eq!(0, reader.read_to_end(&mut Vec::new()).unwrap());
+    stream.flush().unwrap();
+
     // Write something to the file and make sure the layout is correct
-    stream.log(42).unwrap();
+    let mut record = [0; 22];
+    reader.read_exact(&mut record).unwrap();
+    let mut fields = &record[..];
+    assert_eq!(RECORD_MAGIC, [fields.read_u8().unwrap(), fields.read_u8().unwrap()]);
+    assert_eq!(12, fields.read_u32::<LittleEndian>().unwrap());
+    assert_eq!(1_600_000_000_123_456_789, fields.read_u64::<LittleEndian>().unwrap());
+    assert_eq!(42, fields.read_i32::<LittleEndian>().unwrap());
+    assert_eq!(crc32::checksum_ieee(&record[..18]), fields.read_u32::<LittleEndian>().unwrap());
+    assert_eq!(31 + 22, stream.size());
 
-    assert!((reader.read_f32::<LittleEndian>().unwrap() - milliseconds()).abs() <= 10_f32);
-    assert_eq!(42, reader.read_i32::<LittleEndian>().unwrap());
+    // Nothing is written for values of another type
+    assert!(stream.log(1_600_000_000_123_456_790, &LogValue::Real(1.0)).is_err());
+    stream.sync().unwrap();
+    assert_eq!(0, reader.read_to_end(&mut Vec::new()).unwrap());
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn chunks_are_written_at_sync_and_indexed_at_the_end() {
+    let tmp = TempDir::new("log").unwrap();
+    let path = tmp.path().join("speed.ebl");
+    let clock = MockClock::new(1_600_000_000 * NANOS_PER_SECOND);
+    let format = Format::Chunked(Compression::Zstd);
+    let mut stream = LogStream::new(&path, "speed", &LogType::Real, format, &clock).unwrap();
+    let header_size = stream.size();
+    for i in 0..100 {
+      stream.log(1_600_000_000 * NANOS_PER_SECOND + i * 1000, &LogValue::Real(0.5)).unwrap();
+    }
+    assert!(stream.log(0, &LogValue::Int(1)).is_err());
+
+    // The records wait for the chunk to be written
+    stream.flush().unwrap();
+    assert_eq!(header_size, fs::metadata(&path).unwrap().len());
+    stream.sync().unwrap();
+    let data = fs::read(&path).unwrap();
+    assert_eq!(2 << 16, LittleEndian::read_i32(&data));
+    assert_eq!(data.len() as u64, stream.size());
+    let (chunk, end) = read_chunk(&data, header_size as usize).unwrap();
+    assert_eq!((100, Compression::Zstd), (chunk.info.records, chunk.compression));
+    assert_eq!(data.len(), end);
+
+    stream.log(1_700_000_000 * NANOS_PER_SECOND, &LogValue::Real(1.0)).unwrap();
+    stream.finish().unwrap();
+    let data = fs::read(&path).unwrap();
+    let (chunks, index_start) = read_index(&data, header_size as usize).unwrap();
+    assert_eq!(vec![header_size, end as u64],
+      chunks.iter().map(|chunk| chunk.offset).collect::<Vec<_>>());
+    assert_eq!(end + 31 + 4 + 4 + 9, index_start);
+  }
+
+  #[test]
+  fn sessions_started_in_the_same_second_get_their_own_directory() {
+    let tmp = TempDir::new("sessions").unwrap();
+    let root = tmp.path().join("aicc");
+    let time = Local.ymd(2018, 3, 10).and_hms(14, 5, 9);
+    let first = create_session_dir(&root, time).unwrap();
+    let second = create_session_dir(&root, time).unwrap();
+    assert_eq!(root.join("2018-03-10_14-05-09"), first);
+    assert_eq!(root.join("2018-03-10_14-05-09_2"), second);
+    assert!(second.is_dir());
+  }
+}
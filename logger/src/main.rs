@@ -1,29 +1,31 @@
-#[macro_use]
-extern crate serde_derive;
-
-extern crate serde;
-extern crate bincode;
-extern crate byteorder;
-extern crate chrono;
+extern crate clap;
 extern crate mio;
+extern crate signal_hook;
 
+extern crate logger;
 extern crate messages;
 extern crate util;
 
-mod log_stream;
-mod stream_manager;
-
 use std::io;
-use std::io::Write;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use clap::{ App, Arg, ArgMatches };
 
 use messages::logger::MessageType;
-use bincode::{ Result, ErrorKind };
+use messages::frame::{ write_message, Frame, FrameError, FrameReader };
+use messages::handshake::{ Handshake, Peer };
+use messages::health::{ Health, HealthReport };
 
-use util::mesh::Service;
-use stream_manager::StreamManager;
+use util::mesh::{ load_registry, Service };
+use util::timing::unix_micros;
+use logger::chunk::Compression;
+use logger::log_stream::Format;
+use logger::storage::{ Limits, MEBIBYTE };
+use logger::stream_manager::StreamManager;
 
-use bincode::{ serialize, deserialize_from };
 use mio::*;
 use mio::net::{TcpListener, TcpStream};
 
@@ -31,7 +33,17 @@ const MAX_CLIENTS: usize = 64;
 
 const TOKEN_ACCEPT: Token = Token(MAX_CLIENTS);
 
-fn find_unused_token(sockets: &HashMap<Token, TcpStream>) -> Option<Token> {
+// How often the main loop looks for a shutdown request
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Client {
+  socket: TcpStream,
+  reader: FrameReader,
+  peer: Option<Peer>,     // Set once the handshake is complete
+  session_index: Option<usize>, // The client's entry in the session manifest
+}
+
+fn find_unused_token(sockets: &HashMap<Token, Client>) -> Option<Token> {
   let client_count = sockets.len();
   if client_count >= MAX_CLIENTS {
     return None;
@@ -50,28 +62,244 @@ fn register_stream(name: String,
                    typename: String,
                    socket: &mut TcpStream,
                    stream_manager: &mut StreamManager) -> io::Result<i32> {
-  let id = stream_manager.register(name, typename)?;
-  match serialize(&MessageType::Acknowledge(id)) {
-    Ok(msg) => {
-      socket.write(&msg[..])?;
+  match stream_manager.register(name, typename) {
+    Ok(id) => {
+      write_message(socket, &MessageType::Acknowledge(id))?;
       Ok(id)
     },
+    // The client would wait for an answer in vain and try again later
     Err(e) => {
-      match *e {
-        ErrorKind::Io(err) => return Err(err),
-        _ => return Err(io::Error::new(io::ErrorKind::Other, *e))
+      if e.kind() == io::ErrorKind::InvalidInput {
+        write_message(socket, &MessageType::Refuse(e.to_string()))?;
+      }
+      Err(e)
+    },
+  }
+}
+
+/// Answers a health check of the supervisor
+fn answer_health_check(frame: Frame,
+                       client: &mut Client,
+                       stream_manager: &StreamManager,
+                       started: Instant) {
+  match frame.decode() {
+    Ok(Health::Ping(seq)) => {
+      let report = HealthReport {
+        seq,
+        uptime_ms: started.elapsed().as_millis() as u64,
+        detail: match stream_manager.stop_reason() {
+          Some(reason) =>
+            format!("{} streams, not logging: {}", stream_manager.stream_count(), reason),
+          None => format!("{} streams", stream_manager.stream_count()),
+        },
+      };
+      if let Err(e) = write_message(&mut client.socket, &Health::Pong(report)) {
+        println!("Failed to answer a health check: {}", e);
+      }
+    },
+    Ok(msg) => println!("Ignoring unexpected health message {:?}", msg),
+    Err(e) => println!("Ignoring invalid health message: {}", e),
+  }
+}
+
+/// Handles a single frame received from `client`. Returns false if the client has to be dropped.
+fn handle_frame(frame: Frame,
+                client: &mut Client,
+                stream_manager: &mut StreamManager,
+                started: Instant) -> bool {
+  if client.peer.is_none() {
+    let hello: Handshake = match frame.decode() {
+      Ok(hello) => hello,
+      Err(e) => {
+        println!("Expected a handshake from the client: {}", e);
+        return false;
+      }
+    };
+
+    let (reply, peer) = hello.answer(Service::Logger.name(), env!("CARGO_PKG_VERSION"));
+    if let Err(e) = write_message(&mut client.socket, &reply) {
+      println!("Failed to answer the handshake: {}", e);
+      return false;
+    }
+    match peer {
+      Some(peer) => {
+        println!("Client {} {} connected", peer.name, peer.software_version);
+        match stream_manager.client_connected(&peer) {
+          Ok(index) => client.session_index = Some(index),
+          Err(e) => println!("Failed to add the client to the session manifest: {}", e),
+        }
+        client.peer = Some(peer);
+        return true;
+      },
+      None => {
+        println!("Refused client: {:?}", reply);
+        return false;
       }
     }
   }
+
+  if frame.is::<Health>() {
+    answer_health_check(frame, client, stream_manager, started);
+    return true;
+  }
+
+  match frame.decode() {
+    Ok(MessageType::Register(name, typename)) => {
+      println!("Registering variable {}", &name);
+      match register_stream(name, typename, &mut client.socket, stream_manager) {
+        Ok(_) => {},
+        Err(e) => println!("Failed to register log stream: {:?}", e)
+      };
+    },
+    Ok(MessageType::Log(id, timestamp, val)) => {
+      match stream_manager.log(id, timestamp, &val) {
+        Ok(_) => {},
+        Err(e) => println!("Failed to write log message: {:?}", e)
+      }
+    },
+    Ok(MessageType::TimeRequest(sent)) => {
+      // Lets the producer estimate the offset of its clock to ours
+      let received = unix_micros();
+      if let Err(e) = write_message(&mut client.socket,
+          &MessageType::TimeResponse(sent, received, unix_micros())) {
+        println!("Failed to answer a time request: {}", e);
+      }
+    },
+    Ok(msg) => println!("Ignoring unexpected message {:?}", msg),
+    Err(e) => println!("Ignoring invalid message: {}", e),
+  }
+  true
+}
+
+/// A number argument, exiting with a message if it isn't one
+fn number_arg(matches: &ArgMatches, name: &str) -> u64 {
+  let value = matches.value_of(name).unwrap();
+  value.parse().unwrap_or_else(|_| {
+    eprintln!("Invalid value `{}` for --{}, expected a number", value, name);
+    std::process::exit(1);
+  })
+}
+
+fn limits(matches: &ArgMatches) -> Limits {
+  Limits {
+    max_file_size: number_arg(matches, "max-file-size").saturating_mul(MEBIBYTE),
+    max_file_age: Duration::from_secs(number_arg(matches, "max-file-age").saturating_mul(60)),
+    session_quota: number_arg(matches, "session-quota").saturating_mul(MEBIBYTE),
+    total_quota: number_arg(matches, "total-quota").saturating_mul(MEBIBYTE),
+    min_free_space: number_arg(matches, "min-free-space").saturating_mul(MEBIBYTE),
+    flush_interval: Duration::from_millis(number_arg(matches, "flush-interval")),
+    sync_interval: Duration::from_millis(number_arg(matches, "sync-interval")),
+  }
+}
+
+fn format(matches: &ArgMatches) -> Format {
+  match matches.value_of("format").unwrap() {
+    // clap only accepts the known compressions
+    "chunked" => Format::Chunked(Compression::parse(matches.value_of("compression").unwrap())
+      .unwrap()),
+    _ => Format::Framed,
+  }
 }
 
 fn main() {
+  let defaults = Limits::default();
+  let max_file_size = (defaults.max_file_size / MEBIBYTE).to_string();
+  let max_file_age = (defaults.max_file_age.as_secs() / 60).to_string();
+  let session_quota = (defaults.session_quota / MEBIBYTE).to_string();
+  let total_quota = (defaults.total_quota / MEBIBYTE).to_string();
+  let min_free_space = (defaults.min_free_space / MEBIBYTE).to_string();
+  let flush_interval = defaults.flush_interval.as_millis().to_string();
+  let sync_interval = defaults.sync_interval.as_millis().to_string();
+  let matches = App::new("AICC Logger")
+    .about("Logs the variables of the AICC services to /var/log/aicc/<session>/")
+    .arg(Arg::with_name("tag")
+      .long("tag")
+      .help("A tag for the session manifest, like track=hall or calibration=wet. Can be repeated.")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+    )
+    .arg(Arg::with_name("max-file-size")
+      .long("max-file-size")
+      .help("Continues a variable in a new file after this many MiB")
+      .takes_value(true)
+      .default_value(&max_file_size)
+    )
+    .arg(Arg::with_name("max-file-age")
+      .long("max-file-age")
+      .help("Continues a variable in a new file after this many minutes")
+      .takes_value(true)
+      .default_value(&max_file_age)
+    )
+    .arg(Arg::with_name("session-quota")
+      .long("session-quota")
+      .help("Stops logging when the session has this many MiB")
+      .takes_value(true)
+      .default_value(&session_quota)
+    )
+    .arg(Arg::with_name("total-quota")
+      .long("total-quota")
+      .help("Deletes the oldest sessions to keep all of them below this many MiB")
+      .takes_value(true)
+      .default_value(&total_quota)
+    )
+    .arg(Arg::with_name("min-free-space")
+      .long("min-free-space")
+      .help("Stops logging when less MiB are free on the disk")
+      .takes_value(true)
+      .default_value(&min_free_space)
+    )
+    .arg(Arg::with_name("flush-interval")
+      .long("flush-interval")
+      .help("Writes the buffered records to the files every this many ms")
+      .takes_value(true)
+      .default_value(&flush_interval)
+    )
+    .arg(Arg::with_name("sync-interval")
+      .long("sync-interval")
+      .help("Forces the records to the disk every this many ms. A crash loses at most these.")
+      .takes_value(true)
+      .default_value(&sync_interval)
+    )
+    .arg(Arg::with_name("format")
+      .long("format")
+      .help("Stores each record on its own (.ebl 1.2), or in compressed chunks (.ebl 2.0), which \
+        suits high-rate variables")
+      .takes_value(true)
+      .possible_values(&["framed", "chunked"])
+      .default_value("framed")
+    )
+    .arg(Arg::with_name("compression")
+      .long("compression")
+      .help("How the chunks are compressed")
+      .takes_value(true)
+      .possible_values(&["none", "lz4", "zstd"])
+      .default_value("zstd")
+    )
+    .get_matches();
+
+  if let Err(e) = load_registry() {
+    println!("{}, using the default service addresses.", e);
+  }
+
+  let tags: Vec<String> = matches.values_of("tag")
+    .map_or_else(Vec::new, |tags| tags.map(String::from).collect());
+
+  // SIGINT (the supervisor) and SIGTERM (systemctl stop) end the session
+  let shutdown = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::SIGINT, shutdown.clone()).unwrap();
+  signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone()).unwrap();
+
+  let started = Instant::now();
   let mut clients = HashMap::new();
 
-  let addr = (&("0.0.0.0:".to_owned() + &Service::Logger.port().to_string())).parse().unwrap();
-  let server = TcpListener::bind(&addr).unwrap();
+  let server = TcpListener::bind(&Service::Logger.listen_address()).unwrap();
 
-  let mut stream_manager = StreamManager::new().unwrap();
+  let limits = limits(&matches);
+  // Wake up in time to flush the records
+  let poll_interval = SHUTDOWN_POLL_INTERVAL.min(limits.flush_interval);
+  let mut stream_manager = StreamManager::new(&tags, limits, format(&matches)).unwrap();
+  println!("Logging session to {}", stream_manager.path().display());
 
   // Create a poll instance
   let poll = Poll::new().unwrap();
@@ -83,8 +311,15 @@ fn main() {
   // Create storage for events
   let mut events = Events::with_capacity(1024);
 
-  loop {
-    poll.poll(&mut events, None).unwrap();
+  while !shutdown.load(Ordering::Acquire) {
+    stream_manager.maintain();
+    if let Err(e) = poll.poll(&mut events, Some(poll_interval)) {
+      // A signal interrupts the poll
+      if e.kind() == io::ErrorKind::Interrupted {
+        continue;
+      }
+      panic!("Polling failed: {}", e);
+    }
 
     for event in events.iter() {
       match event.token() {
@@ -104,8 +339,14 @@ fn main() {
 
                     println!("New client connected with token {:?}", token);
 
-                    // Store the socket
-                    clients.insert(token, socket);
+                    // Store the socket. The client has to introduce itself with a Hello first.
+                    let client = Client {
+                      socket,
+                      reader: FrameReader::new(),
+                      peer: None,
+                      session_index: None,
+                    };
+                    clients.insert(token, client);
                   },
                   None => {
                     println!("Rejecting client because the client limit has been reached.")
@@ -121,56 +362,50 @@ fn main() {
           }
         },
         token => {
-          // Always operate in a loop
+          // Read frames until the socket would block, we're edge triggered
           let mut remove_socket = false;
+          let client = clients.get_mut(&token).unwrap();
           loop {
-            let mut socket = clients.get_mut(&token).unwrap();
-            let result: Result<MessageType> = deserialize_from(&mut socket);
-            match result {
-              Ok(msg) => {
-                match msg {
-                  MessageType::Register(name, typename) => {
-                    println!("Registering variable {}", &name);
-                    match register_stream(name, typename, socket, &mut stream_manager) {
-                      Ok(_) => {},
-                      Err(e) => println!("Failed to register log stream: {:?}", e)
-                    };
-                  },
-                  MessageType::Log(id, val) => {
-                    match stream_manager.log(id, val) {
-                      Ok(_) => {},
-                      Err(e) => println!("Failed to write log message: {:?}", e)
-                    }
-                  }
-                  _ => unreachable!("Sent an invalid message type to the logger service!")
+            match client.reader.read_frame(&mut client.socket) {
+              Ok(frame) => {
+                if !handle_frame(frame, client, &mut stream_manager, started) {
+                  remove_socket = true;
+                  break;
                 }
+              },
+              Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                // Socket is not ready anymore, stop reading
                 break;
-              }
-              Err(e) => {
-                match *e {
-                  bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // Socket is not ready anymore, stop reading
-                    break;
-                  },
-                  bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    // Socket closed => Drop client
-                    remove_socket = true;
-                    break;
-                  },
-                  e => {
-                    panic!("Unexpected socket error. {:?}", e);
-                  }
+              },
+              Err(FrameError::Io(ref e)) => {
+                // Socket closed or broken => Drop client
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                  println!("Reading from client failed: {}", e);
                 }
-              }
+                remove_socket = true;
+                break;
+              },
+              // The reader has skipped the broken frame, so we can go on with the next one
+              Err(e) => println!("Dropping invalid frame: {}", e),
             }
           }
 
           if remove_socket {
             println!("Dropping socket for token {:?}", &token);
-            clients.remove(&token);
+            let index = clients.remove(&token).and_then(|client| client.session_index);
+            if let Some(index) = index {
+              if let Err(e) = stream_manager.client_disconnected(index) {
+                println!("Failed to update the session manifest: {}", e);
+              }
+            }
           }
         }
       }
     }
   }
+
+  println!("Shutting down");
+  if let Err(e) = stream_manager.stop() {
+    println!("Failed to record the end of the session: {}", e);
+  }
 }
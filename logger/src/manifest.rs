@@ -0,0 +1,223 @@
+// The manifest of a logger session: `manifest.toml` in the session directory, next to the .ebl
+// files. It says which run the files belong to, who logged to it and what was registered.
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::fs::File;
+use std::hash::{ BuildHasher, Hasher };
+use std::io;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use chrono::{ TimeZone, Utc };
+use toml;
+
+use messages::handshake::Peer;
+use util::timing::{ Clock, NANOS_PER_SECOND };
+
+pub const FILE_NAME: &str = "manifest.toml";
+
+/// A variable registered in the session
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamEntry {
+  pub id: i32,
+  pub name: String,
+  #[serde(rename = "type")]
+  pub log_type: String,
+  pub files: Vec<String>,           // In the order they were written
+  pub registered: String,
+}
+
+/// A service that connected to the logger, with the version it sent in the handshake
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientEntry {
+  pub name: String,
+  pub software_version: String,
+  pub protocol_version: u8,
+  pub connected: String,
+  pub disconnected: Option<String>,
+}
+
+// The arrays of tables have to come last in TOML, and an empty one would be written as a value
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub id: String,
+  pub logger_version: String,
+  pub started: String,              // RFC 3339, UTC
+  pub stopped: Option<String>,      // Not set while the logger runs or if it crashed
+  pub logging_stopped: Option<String>, // Why logging stopped before the end of the session
+  pub tags: Vec<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub streams: Vec<StreamEntry>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub clients: Vec<ClientEntry>,
+}
+
+impl Manifest {
+  pub fn read(dir: &Path) -> io::Result<Manifest> {
+    toml::from_str(&fs::read_to_string(dir.join(FILE_NAME))?)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+}
+
+/// Formats a time in nanoseconds since the UNIX epoch as RFC 3339
+fn format_time(nanos: u64) -> String {
+  Utc.timestamp_opt((nanos / NANOS_PER_SECOND) as i64, (nanos % NANOS_PER_SECOND) as u32)
+    .unwrap()
+    .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+    .to_string()
+}
+
+/// A random id for a session that started at `time`, unique even for sessions started at once
+fn session_id(time: u64) -> String {
+  // The keys of `RandomState` are random for every process
+  let random = RandomState::new().build_hasher().finish();
+  format!("{:016x}-{:016x}", time, random)
+}
+
+/// Keeps the manifest of a session up to date. Every change rewrites the whole file, by writing
+/// a temporary file and renaming it over the old one, so readers never see half a manifest.
+pub struct SessionManifest {
+  path: PathBuf,
+  manifest: Manifest,
+  clock: Arc<dyn Clock>,
+}
+
+impl SessionManifest {
+  /// Starts the manifest of a new session in `dir`
+  pub fn create(dir: &Path, tags: &[String], clock: Arc<dyn Clock>) -> io::Result<SessionManifest> {
+    let now = clock.unix_nanos();
+    let manifest = Manifest {
+      id: session_id(now),
+      logger_version: env!("CARGO_PKG_VERSION").to_string(),
+      started: format_time(now),
+      stopped: None,
+      logging_stopped: None,
+      tags: tags.to_vec(),
+      streams: Vec::new(),
+      clients: Vec::new(),
+    };
+    let session = SessionManifest { path: dir.join(FILE_NAME), manifest, clock };
+    session.save()?;
+    Ok(session)
+  }
+
+  pub fn manifest(&self) -> &Manifest {
+    &self.manifest
+  }
+
+  pub fn add_stream(&mut self, id: i32, name: &str, log_type: &str, file: &str) -> io::Result<()> {
+    self.manifest.streams.push(StreamEntry {
+      id,
+      name: name.to_string(),
+      log_type: log_type.to_string(),
+      files: vec![file.to_string()],
+      registered: format_time(self.clock.unix_nanos()),
+    });
+    self.save()
+  }
+
+  /// Adds the file a stream continues in
+  pub fn add_file(&mut self, id: i32, file: &str) -> io::Result<()> {
+    if let Some(stream) = self.manifest.streams.iter_mut().find(|stream| stream.id == id) {
+      stream.files.push(file.to_string());
+    }
+    self.save()
+  }
+
+  /// Records why nothing is logged anymore
+  pub fn logging_stopped(&mut self, reason: &str) -> io::Result<()> {
+    let now = format_time(self.clock.unix_nanos());
+    self.manifest.logging_stopped = Some(format!("{}: {}", now, reason));
+    self.save()
+  }
+
+  /// Adds a client and returns its index for `client_disconnected`
+  pub fn client_connected(&mut self, peer: &Peer) -> io::Result<usize> {
+    self.manifest.clients.push(ClientEntry {
+      name: peer.name.clone(),
+      software_version: peer.software_version.clone(),
+      protocol_version: peer.protocol_version,
+      connected: format_time(self.clock.unix_nanos()),
+      disconnected: None,
+    });
+    self.save()?;
+    Ok(self.manifest.clients.len() - 1)
+  }
+
+  pub fn client_disconnected(&mut self, index: usize) -> io::Result<()> {
+    let now = format_time(self.clock.unix_nanos());
+    if let Some(client) = self.manifest.clients.get_mut(index) {
+      client.disconnected = Some(now);
+    }
+    self.save()
+  }
+
+  /// Records the end of the session
+  pub fn stop(&mut self) -> io::Result<()> {
+    self.manifest.stopped = Some(format_time(self.clock.unix_nanos()));
+    self.save()
+  }
+
+  fn save(&self) -> io::Result<()> {
+    let text = toml::to_string(&self.manifest)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let temporary = self.path.with_extension("toml.tmp");
+    {
+      let mut file = File::create(&temporary)?;
+      file.write_all(text.as_bytes())?;
+      file.sync_all()?;
+    }
+    fs::rename(&temporary, &self.path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempdir::TempDir;
+  use util::timing::MockClock;
+
+  #[test]
+  fn the_manifest_follows_the_session() {
+    let tmp = TempDir::new("manifest").unwrap();
+    let clock = Arc::new(MockClock::new(1_600_000_000 * NANOS_PER_SECOND));
+    let tags = vec!["track=hall".to_string()];
+    let mut session = SessionManifest::create(tmp.path(), &tags, clock.clone()).unwrap();
+    assert_eq!(session.manifest(), &Manifest::read(tmp.path()).unwrap());
+
+    let peer = Peer {
+      name: "drive-core".to_string(),
+      software_version: "0.1.0".to_string(),
+      protocol_version: 8,
+    };
+    clock.advance(Duration::from_millis(1500));
+    let client = session.client_connected(&peer).unwrap();
+    session.add_stream(0, "speed", "real", "speed.ebl").unwrap();
+    session.add_file(0, "speed.1.ebl").unwrap();
+    clock.advance(Duration::from_secs(60));
+    session.client_disconnected(client).unwrap();
+    session.stop().unwrap();
+
+    let manifest = Manifest::read(tmp.path()).unwrap();
+    assert_eq!("2020-09-13T12:26:40.000Z", manifest.started);
+    assert_eq!(Some("2020-09-13T12:27:41.500Z".to_string()), manifest.stopped);
+    assert_eq!(tags, manifest.tags);
+    assert_eq!(StreamEntry {
+      id: 0,
+      name: "speed".to_string(),
+      log_type: "real".to_string(),
+      files: vec!["speed.ebl".to_string(), "speed.1.ebl".to_string()],
+      registered: "2020-09-13T12:26:41.500Z".to_string(),
+    }, manifest.streams[0]);
+    assert_eq!("0.1.0", manifest.clients[0].software_version);
+    assert_eq!(Some("2020-09-13T12:27:41.500Z".to_string()), manifest.clients[0].disconnected);
+    assert!(!tmp.path().join("manifest.toml.tmp").exists());
+  }
+
+  #[test]
+  fn sessions_started_at_once_have_different_ids() {
+    assert_ne!(session_id(0), session_id(0));
+  }
+}
@@ -0,0 +1,716 @@
+// Reads the .ebl files written by `LogStream`. The header is
+//
+//   version (i32: major << 16 | minor) | id (u16) | name (u16 length + UTF-8)
+//   | type (u16 length + UTF-8) | creation time (u64 seconds since the UNIX epoch)
+//   | tags (u16 count, each a u16 length + UTF-8)
+//
+// followed by the records. Version 1.0 records are an f32 of seconds since the logger started and
+// an int, bool or real value. Version 1.1 records are a u64 of nanoseconds since the UNIX epoch
+// and the value stored natively for its type (see `LogType`). Version 1.2 records are framed like
+// that with magic bytes, their length and a checksum (see `LogStream`), so a damaged record can
+// be skipped. Version 2.0 stores the records in chunks, with an index at the end (see `chunk`).
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use byteorder::{ ByteOrder, LittleEndian, ReadBytesExt };
+use crc::crc32;
+
+use chunk;
+use chunk::{ read_chunk, read_index, write_index, ChunkInfo };
+use log_stream::{ RECORD_CHECKSUM_SIZE, RECORD_HEADER_SIZE, RECORD_MAGIC };
+use messages::log_value::{ LogType, LogValue };
+use util::timing::NANOS_PER_SECOND;
+
+#[derive(Debug)]
+pub enum ReadError {
+  Io(io::Error),
+  BadHeader(String),
+  UnsupportedVersion(u16, u16),
+  Truncated { offset: usize },      // The file ends in the middle of the record at `offset`
+  Damaged { offset: usize, length: usize }, // Skipped `length` bytes that aren't a valid record
+}
+
+impl Error for ReadError {}
+
+impl fmt::Display for ReadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ReadError::Io(ref e) => write!(f, "Could not read the log file: {}", e),
+      ReadError::BadHeader(ref reason) => write!(f, "Invalid header: {}", reason),
+      ReadError::UnsupportedVersion(major, minor) =>
+        write!(f, "Unsupported format version {}.{}", major, minor),
+      ReadError::Truncated { offset } => write!(f, "Incomplete record at byte {}", offset),
+      ReadError::Damaged { offset, length } =>
+        write!(f, "Skipped {} damaged bytes at byte {}", length, offset),
+    }
+  }
+}
+
+impl From<io::Error> for ReadError {
+  fn from(e: io::Error) -> Self {
+    ReadError::Io(e)
+  }
+}
+
+/// The header of a log file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+  pub version: (u16, u16),
+  pub id: u16,
+  pub name: String,
+  pub log_type: LogType,
+  pub created: u64,                 // Seconds since the UNIX epoch
+  pub tags: Vec<String>,
+}
+
+impl Header {
+  /// Whether the record times are relative to the start of the logger (version 1.0) instead of
+  /// the UNIX epoch
+  pub fn relative_times(&self) -> bool {
+    self.version < (1, 1)
+  }
+
+  /// Whether the records are framed (version 1.2)
+  pub fn framed_records(&self) -> bool {
+    self.version == (1, 2)
+  }
+
+  /// Whether the records are stored in chunks (version 2.0)
+  pub fn chunked(&self) -> bool {
+    self.version.0 == 2
+  }
+}
+
+/// A record of a log file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+  pub time: u64,                    // Nanoseconds since the UNIX epoch (see `Header`)
+  pub value: LogValue,
+}
+
+/// A log file read into memory
+pub struct LogFile {
+  pub header: Header,
+  data: Vec<u8>,
+  records_start: usize,
+  records_end: usize,               // Where the index of a chunked file starts
+  index: Option<Vec<ChunkInfo>>,
+}
+
+impl LogFile {
+  pub fn open(path: &Path) -> Result<LogFile, ReadError> {
+    LogFile::from_bytes(fs::read(path)?)
+  }
+
+  /// Parses the header of the file contents `data`
+  pub fn from_bytes(data: Vec<u8>) -> Result<LogFile, ReadError> {
+    let (header, records_start) = {
+      let mut input = &data[..];
+      let header = read_header(&mut input)?;
+      (header, data.len() - input.len())
+    };
+    let (index, records_end) = match read_index(&data, records_start) {
+      Some((index, start)) if header.chunked() => (Some(index), start),
+      _ => (None, data.len()),
+    };
+    Ok(LogFile { header, data, records_start, records_end, index })
+  }
+
+  /// The chunks of a chunked file, if it has a valid index
+  pub fn index(&self) -> Option<&[ChunkInfo]> {
+    self.index.as_ref().map(|index| &index[..])
+  }
+
+  /// Iterates over the records. A record cut off at the end of the file ends the iteration with
+  /// an error. Damaged framed records and chunks are skipped with an error, and the iteration goes
+  /// on with the next intact one.
+  pub fn records(&self) -> Records<'_> {
+    Records {
+      header: &self.header,
+      data: &self.data[..self.records_end],
+      offset: self.records_start,
+      from: 0,
+      chunk: Vec::new(),
+      chunk_start: 0,
+      chunk_offset: 0,
+      last_time: 0,
+    }
+  }
+
+  /// Iterates over the records from `time` on, skipping the others. The index of a chunked file
+  /// leads right to the first chunk with such records.
+  pub fn records_from(&self, time: u64) -> Records<'_> {
+    let mut records = self.records();
+    records.from = time;
+    if let Some(ref index) = self.index {
+      records.offset = index.iter().find(|chunk| chunk.latest >= time)
+        .map_or(self.records_end, |chunk| chunk.offset as usize);
+    }
+    records
+  }
+
+  /// The bytes of the damaged records and chunks, which `intact_bytes` leaves out
+  pub fn damaged_bytes(&self) -> usize {
+    self.records()
+      .map(|record| match record {
+        Err(ReadError::Damaged { length, .. }) => length,
+        Err(ReadError::Truncated { offset }) => self.records_end - offset,
+        _ => 0,
+      })
+      .sum()
+  }
+
+  /// The file without its damaged records: the header and the bytes of every intact record. A
+  /// chunked file gets a new index.
+  pub fn intact_bytes(&self) -> Vec<u8> {
+    let mut bytes = self.data[..self.records_start].to_vec();
+    if self.header.chunked() {
+      let data = &self.data[..self.records_end];
+      let mut chunks = Vec::new();
+      let mut offset = self.records_start;
+      while offset < data.len() {
+        match read_chunk(data, offset) {
+          Some((chunk, end)) => {
+            chunks.push(ChunkInfo { offset: bytes.len() as u64, ..chunk.info });
+            bytes.extend_from_slice(&data[offset..end]);
+            offset = end;
+          },
+          None => offset += 1,
+        }
+      }
+      let offset = bytes.len() as u64;
+      write_index(&chunks, offset, &mut bytes).expect("Writing to memory failed");
+      return bytes;
+    }
+
+    let mut records = self.records();
+    loop {
+      let start = records.offset;
+      match records.next() {
+        Some(Ok(_)) => bytes.extend_from_slice(&self.data[start..records.offset]),
+        Some(Err(_)) => {},
+        None => return bytes,
+      }
+    }
+  }
+}
+
+pub struct Records<'a> {
+  header: &'a Header,
+  data: &'a [u8],
+  offset: usize,
+  from: u64,                        // Records before this time are skipped
+  chunk: Vec<u8>,                   // The records of the current chunk...
+  chunk_start: usize,               // ...which is at this offset in the file
+  chunk_offset: usize,              // The next record in the chunk
+  last_time: u64,
+}
+
+impl<'a> Iterator for Records<'a> {
+  type Item = Result<Record, ReadError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      match self.next_record() {
+        Some(Ok(ref record)) if record.time < self.from => {},
+        next => return next,
+      }
+    }
+  }
+}
+
+impl<'a> Records<'a> {
+  fn next_record(&mut self) -> Option<Result<Record, ReadError>> {
+    if self.header.chunked() {
+      return self.next_chunked();
+    }
+    if self.offset >= self.data.len() {
+      return None;
+    }
+    if self.header.framed_records() {
+      return Some(self.next_framed());
+    }
+
+    let mut input = &self.data[self.offset..];
+    match read_record(self.header, &mut input) {
+      Ok(record) => {
+        self.offset = self.data.len() - input.len();
+        Some(Ok(record))
+      },
+      Err(_) => {
+        let offset = self.offset;
+        self.offset = self.data.len();
+        Some(Err(ReadError::Truncated { offset }))
+      },
+    }
+  }
+
+  fn next_framed(&mut self) -> Result<Record, ReadError> {
+    if let Some((record, end)) = read_framed_record(self.header, self.data, self.offset) {
+      self.offset = end;
+      return Ok(record);
+    }
+
+    // Go on with the next intact record, if there is one
+    let offset = self.offset;
+    let next = (offset + 1..self.data.len())
+      .find(|&start| read_framed_record(self.header, self.data, start).is_some());
+    match next {
+      Some(next) => {
+        self.offset = next;
+        Err(ReadError::Damaged { offset, length: next - offset })
+      },
+      None => {
+        self.offset = self.data.len();
+        Err(ReadError::Truncated { offset })
+      },
+    }
+  }
+
+  fn next_chunked(&mut self) -> Option<Result<Record, ReadError>> {
+    loop {
+      if self.chunk_offset < self.chunk.len() {
+        let mut input = &self.chunk[self.chunk_offset..];
+        let record = chunk::read_record(&mut input, &self.header.log_type, self.last_time);
+        self.chunk_offset = self.chunk.len() - input.len();
+        return Some(match record {
+          Ok((time, value)) => {
+            self.last_time = time;
+            Ok(Record { time, value })
+          },
+          Err(_) => {
+            // The chunk is intact, but its records aren't of the stream's type
+            self.chunk_offset = self.chunk.len();
+            let length = self.offset - self.chunk_start;
+            Err(ReadError::Damaged { offset: self.chunk_start, length })
+          },
+        });
+      }
+      if self.offset >= self.data.len() {
+        return None;
+      }
+
+      if let Some((chunk, end)) = read_chunk(self.data, self.offset) {
+        self.chunk = chunk.data;
+        self.chunk_start = self.offset;
+        self.chunk_offset = 0;
+        self.last_time = 0;
+        self.offset = end;
+        continue;
+      }
+
+      // Go on with the next intact chunk, if there is one
+      let offset = self.offset;
+      let next = (offset + 1..self.data.len())
+        .find(|&start| read_chunk(self.data, start).is_some());
+      return Some(match next {
+        Some(next) => {
+          self.offset = next;
+          Err(ReadError::Damaged { offset, length: next - offset })
+        },
+        None => {
+          self.offset = self.data.len();
+          Err(ReadError::Truncated { offset })
+        },
+      });
+    }
+  }
+}
+
+fn read_header(input: &mut &[u8]) -> Result<Header, ReadError> {
+  let truncated = |_| ReadError::BadHeader("File ends in the header".to_string());
+  let version = input.read_i32::<LittleEndian>().map_err(truncated)? as u32;
+  let version = ((version >> 16) as u16, version as u16);
+  if (version.0 != 1 || version.1 > 2) && version != (2, 0) {
+    return Err(ReadError::UnsupportedVersion(version.0, version.1));
+  }
+  let id = input.read_u16::<LittleEndian>().map_err(truncated)?;
+  let name = read_string(input)?;
+  let type_name = read_string(input)?;
+  let log_type = if version < (1, 1) {
+    // Version 1.0 knew int and bool, everything else was stored as real
+    match type_name.as_ref() {
+      "int" => LogType::Int,
+      "bool" => LogType::Bool,
+      _ => LogType::Real,
+    }
+  } else {
+    LogType::parse(&type_name).map_err(ReadError::BadHeader)?
+  };
+  let created = input.read_u64::<LittleEndian>().map_err(truncated)?;
+  let tag_count = input.read_u16::<LittleEndian>().map_err(truncated)?;
+  let tags = (0..tag_count).map(|_| read_string(input)).collect::<Result<_, _>>()?;
+  Ok(Header { version, id, name, log_type, created, tags })
+}
+
+/// Reads a string with its u16 length
+fn read_string(input: &mut &[u8]) -> Result<String, ReadError> {
+  let truncated = || ReadError::BadHeader("File ends in the header".to_string());
+  let length = input.read_u16::<LittleEndian>().map_err(|_| truncated())? as usize;
+  if length > input.len() {
+    return Err(truncated());
+  }
+  let text = String::from_utf8(input[..length].to_vec())
+    .map_err(|_| ReadError::BadHeader("Invalid UTF-8 in a string".to_string()))?;
+  *input = &input[length..];
+  Ok(text)
+}
+
+fn read_record(header: &Header, input: &mut &[u8]) -> io::Result<Record> {
+  let time = if header.relative_times() {
+    let seconds = input.read_f32::<LittleEndian>()?;
+    (f64::from(seconds.max(0.0)) * NANOS_PER_SECOND as f64) as u64
+  } else {
+    input.read_u64::<LittleEndian>()?
+  };
+  Ok(Record { time, value: header.log_type.decode(input)? })
+}
+
+/// Reads the framed record at `start`. Returns the record and where it ends, or None if there is
+/// no intact record.
+fn read_framed_record(header: &Header, data: &[u8], start: usize) -> Option<(Record, usize)> {
+  let frame = &data[start..];
+  if frame.len() < RECORD_HEADER_SIZE || frame[..RECORD_MAGIC.len()] != RECORD_MAGIC {
+    return None;
+  }
+  let length = LittleEndian::read_u32(&frame[RECORD_MAGIC.len()..RECORD_HEADER_SIZE]) as usize;
+  let end = RECORD_HEADER_SIZE.checked_add(length)?;
+  if frame.len() < end + RECORD_CHECKSUM_SIZE {
+    return None;
+  }
+  let checksum = LittleEndian::read_u32(&frame[end..end + RECORD_CHECKSUM_SIZE]);
+  if checksum != crc32::checksum_ieee(&frame[..end]) {
+    return None;
+  }
+
+  // The payload has to be exactly one record
+  let mut payload = &frame[RECORD_HEADER_SIZE..end];
+  let record = read_record(header, &mut payload).ok()?;
+  if !payload.is_empty() {
+    return None;
+  }
+  Some((record, start + end + RECORD_CHECKSUM_SIZE))
+}
+
+/// Splits a value into columns: arrays into their elements and structs into their fields, named
+/// like `acceleration[2]` or `position.x`. Other values are a single column without a name.
+pub fn columns(log_type: &LogType, value: &LogValue) -> Vec<(String, LogValue)> {
+  let mut columns = Vec::new();
+  add_columns(String::new(), log_type, value, &mut columns);
+  columns
+}
+
+fn add_columns(prefix: String,
+               log_type: &LogType,
+               value: &LogValue,
+               columns: &mut Vec<(String, LogValue)>) {
+  match (log_type, value) {
+    (LogType::Reals(_), LogValue::Reals(values)) => {
+      for (i, value) in values.iter().enumerate() {
+        columns.push((format!("{}[{}]", prefix, i), LogValue::Real(*value)));
+      }
+    },
+    (LogType::Struct(_, fields), LogValue::Struct(_)) => {
+      let values = log_type.fields(value).unwrap_or_default();
+      for ((name, field_type), (_, field_value)) in fields.iter().zip(values) {
+        let name = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        add_columns(name, field_type, &field_value, columns);
+      }
+    },
+    _ => columns.push((prefix, value.clone())),
+  }
+}
+
+/// The names and types of the columns `columns` splits the values of `log_type` into
+pub fn column_types(log_type: &LogType) -> Vec<(String, LogType)> {
+  let mut columns = Vec::new();
+  add_column_types(String::new(), log_type, &mut columns);
+  columns
+}
+
+fn add_column_types(prefix: String, log_type: &LogType, columns: &mut Vec<(String, LogType)>) {
+  match log_type {
+    LogType::Reals(len) => {
+      for i in 0..*len {
+        columns.push((format!("{}[{}]", prefix, i), LogType::Real));
+      }
+    },
+    LogType::Struct(_, fields) => {
+      for (name, field_type) in fields {
+        let name = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        add_column_types(name, field_type, columns);
+      }
+    },
+    _ => columns.push((prefix, log_type.clone())),
+  }
+}
+
+/// Formats a value for people, like `3`, `[0.5, 1]` or `{latitude: 48.1, fix: true}`
+pub fn format_value(log_type: &LogType, value: &LogValue) -> String {
+  match (log_type, value) {
+    (_, LogValue::Bool(value)) => value.to_string(),
+    (_, LogValue::Int(value)) => value.to_string(),
+    (_, LogValue::I64(value)) => value.to_string(),
+    (_, LogValue::U64(value)) => value.to_string(),
+    (_, LogValue::Real(value)) => value.to_string(),
+    (_, LogValue::F64(value)) => value.to_string(),
+    (_, LogValue::Text(text)) => format!("{:?}", text),
+    (_, LogValue::Reals(values)) => format!("[{}]",
+      values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")),
+    (LogType::Struct(_, fields), LogValue::Struct(_)) => {
+      let values = log_type.fields(value).unwrap_or_default();
+      let formatted: Vec<_> = fields.iter().zip(values)
+        .map(|((name, field_type), (_, field_value))|
+          format!("{}: {}", name, format_value(field_type, &field_value)))
+        .collect();
+      format!("{{{}}}", formatted.join(", "))
+    },
+    (_, LogValue::Struct(bytes)) => format!("<{} bytes>", bytes.len()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chunk::Compression;
+  use log_stream::{ Format, LogStream };
+  use std::fs::OpenOptions;
+  use std::io::Write;
+  use tempdir::TempDir;
+  use util::timing::MockClock;
+
+  const START: u64 = 1_600_000_000 * NANOS_PER_SECOND;
+
+  fn write_log(tmp: &TempDir, log_type: &str, values: &[LogValue]) -> LogFile {
+    let path = tmp.path().join("test.ebl");
+    let clock = MockClock::new(START);
+    let log_type = LogType::parse(log_type).unwrap();
+    let mut stream = LogStream::new(&path, "test_value", &log_type, Format::Framed, &clock)
+      .unwrap();
+    for (i, value) in values.iter().enumerate() {
+      stream.log(START + i as u64 * 1_000_000, value).unwrap();
+    }
+    stream.sync().unwrap();
+    LogFile::open(&path).unwrap()
+  }
+
+  /// Writes a chunked file with a chunk of 10 reals per second, finished with its index or as
+  /// after a crash
+  fn write_chunked_log(tmp: &TempDir, seconds: u64, finish: bool) -> LogFile {
+    let path = tmp.path().join("test.ebl");
+    let format = Format::Chunked(Compression::Lz4);
+    let mut stream = LogStream::new(&path, "speed", &LogType::Real, format, &MockClock::new(START))
+      .unwrap();
+    for second in 0..seconds {
+      for i in 0..10 {
+        let time = START + second * NANOS_PER_SECOND + i * 100_000_000;
+        stream.log(time, &LogValue::Real(second as f32)).unwrap();
+      }
+      stream.sync().unwrap();
+    }
+    if finish {
+      stream.finish().unwrap();
+    }
+    LogFile::open(&path).unwrap()
+  }
+
+  #[test]
+  fn it_reads_what_the_stream_wrote() {
+    let tmp = TempDir::new("reader").unwrap();
+    let values = vec![LogValue::I64(-1), LogValue::I64(1 << 62)];
+    let file = write_log(&tmp, "i64", &values);
+
+    assert_eq!(Header {
+      version: (1, 2),
+      id: 0,
+      name: "test_value".to_string(),
+      log_type: LogType::I64,
+      created: 1_600_000_000,
+      tags: Vec::new(),
+    }, file.header);
+    let records: Vec<_> = file.records().map(|r| r.unwrap()).collect();
+    assert_eq!(vec![
+      Record { time: START, value: values[0].clone() },
+      Record { time: START + 1_000_000, value: values[1].clone() },
+    ], records);
+  }
+
+  #[test]
+  fn structs_split_into_columns() {
+    let tmp = TempDir::new("reader").unwrap();
+    let log_type = "struct Imu{rate:real[2],hot:bool,pose:struct Pose{x:f64,label:string}}";
+    let value = LogValue::from_struct(&([0.5f32, 1f32], true, (2.5f64, "start")));
+    let file = write_log(&tmp, log_type, ::std::slice::from_ref(&value));
+
+    let record = file.records().next().unwrap().unwrap();
+    assert_eq!(value, record.value);
+    let names: Vec<_> = columns(&file.header.log_type, &record.value).into_iter()
+      .map(|(name, _)| name).collect();
+    assert_eq!(vec!["rate[0]", "rate[1]", "hot", "pose.x", "pose.label"], names);
+    let types: Vec<_> = column_types(&file.header.log_type).into_iter()
+      .map(|(name, log_type)| (name, log_type.to_string())).collect();
+    assert_eq!(("rate[1]".to_string(), "real".to_string()), types[1]);
+    assert_eq!(("pose.label".to_string(), "string".to_string()), types[4]);
+    assert_eq!("{rate: [0.5, 1], hot: true, pose: {x: 2.5, label: \"start\"}}",
+      format_value(&file.header.log_type, &record.value));
+  }
+
+  #[test]
+  fn a_torn_record_ends_the_records() {
+    let tmp = TempDir::new("reader").unwrap();
+    write_log(&tmp, "real", &[LogValue::Real(1.0), LogValue::Real(2.0)]);
+    let path = tmp.path().join("test.ebl");
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+    let file = LogFile::open(&path).unwrap();
+    let records: Vec<_> = file.records().collect();
+    assert_eq!(3, records.len());
+    assert_eq!(LogValue::Real(2.0), records[1].as_ref().unwrap().value);
+    match records[2] {
+      Err(ReadError::Truncated { offset }) => assert_eq!(file.data.len() - 3, offset),
+      ref other => panic!("Unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn damaged_records_are_skipped() {
+    let tmp = TempDir::new("reader").unwrap();
+    let values = [LogValue::Int(1), LogValue::Int(2), LogValue::Int(3)];
+    let file = write_log(&tmp, "int", &values);
+    let intact = file.data.clone();
+
+    // Each record is 22 bytes. Break the value of the second one and the magic of the third.
+    let mut data = intact.clone();
+    let second = file.records_start + 22;
+    data[second + 14] ^= 0xff;
+    data[second + 22] = 0;
+    let file = LogFile::from_bytes(data).unwrap();
+
+    let records: Vec<_> = file.records().collect();
+    assert_eq!(2, records.len());
+    assert_eq!(LogValue::Int(1), records[0].as_ref().unwrap().value);
+    match records[1] {
+      Err(ReadError::Truncated { offset }) => assert_eq!(second, offset),
+      ref other => panic!("Unexpected {:?}", other),
+    }
+
+    // A record that was written over is skipped, the ones after it are read
+    let mut data = intact.clone();
+    data[second + 14] ^= 0xff;
+    let file = LogFile::from_bytes(data).unwrap();
+    let records: Vec<_> = file.records().collect();
+    match records[1] {
+      Err(ReadError::Damaged { offset, length }) => assert_eq!((second, 22), (offset, length)),
+      ref other => panic!("Unexpected {:?}", other),
+    }
+    assert_eq!(LogValue::Int(3), records[2].as_ref().unwrap().value);
+
+    let mut expected = intact[..second].to_vec();
+    expected.extend_from_slice(&intact[second + 22..]);
+    assert_eq!(expected, file.intact_bytes());
+    assert_eq!(22, file.damaged_bytes());
+  }
+
+  #[test]
+  fn chunked_files_are_read_from_any_time_by_their_index() {
+    let tmp = TempDir::new("reader").unwrap();
+    let file = write_chunked_log(&tmp, 3, true);
+    assert_eq!((2, 0), file.header.version);
+    let index = file.index().unwrap();
+    assert_eq!(3, index.len());
+    assert_eq!((START + NANOS_PER_SECOND, START + 1_900_000_000, 10),
+      (index[1].earliest, index[1].latest, index[1].records));
+
+    let records: Vec<_> = file.records().map(Result::unwrap).collect();
+    assert_eq!(30, records.len());
+    assert_eq!(Record { time: START + 1_100_000_000, value: LogValue::Real(1.0) }, records[11]);
+
+    // Seeking starts at the second chunk
+    let mut later = file.records_from(START + 1_050_000_000);
+    assert_eq!(index[1].offset as usize, later.offset);
+    assert_eq!(records[11], later.next().unwrap().unwrap());
+    assert_eq!(18, later.count());
+    assert_eq!(0, file.records_from(START + 3 * NANOS_PER_SECOND).count());
+  }
+
+  #[test]
+  fn chunked_files_without_an_index_are_read_chunk_by_chunk() {
+    let tmp = TempDir::new("reader").unwrap();
+    let file = write_chunked_log(&tmp, 3, false);
+    assert!(file.index().is_none());
+    assert_eq!(30, file.records().map(Result::unwrap).count());
+
+    // A damaged chunk is skipped
+    let index = LogFile::from_bytes(file.intact_bytes()).unwrap().index().unwrap().to_vec();
+    let second = index[1].offset as usize;
+    let mut data = file.data.clone();
+    data[second + 40] ^= 0xff;
+    let file = LogFile::from_bytes(data).unwrap();
+    let records: Vec<_> = file.records().collect();
+    assert_eq!(21, records.len());
+    match records[10] {
+      Err(ReadError::Damaged { offset, length }) =>
+        assert_eq!((second, index[2].offset as usize - second), (offset, length)),
+      ref other => panic!("Unexpected {:?}", other),
+    }
+
+    // Repairing leaves it out of the new index
+    let repaired = LogFile::from_bytes(file.intact_bytes()).unwrap();
+    assert_eq!(2, repaired.index().unwrap().len());
+    let values: Vec<_> = repaired.records().map(|record| record.unwrap().value).collect();
+    assert_eq!(LogValue::Real(2.0), values[10]);
+    assert_eq!(20, values.len());
+  }
+
+  #[test]
+  fn version_1_1_files_are_read() {
+    let mut data = vec![1, 0, 1, 0, 0, 0, 3, 0];
+    data.extend_from_slice(b"foo");
+    data.extend_from_slice(&[3, 0]);
+    data.extend_from_slice(b"int");
+    data.extend_from_slice(&[0; 10]);
+    data.extend_from_slice(&[0x15, 0xcd, 0x5b, 0x07, 0, 0, 0, 0, 42, 0, 0, 0, 1, 2]);
+
+    let file = LogFile::from_bytes(data).unwrap();
+    let records: Vec<_> = file.records().collect();
+    assert_eq!(Record { time: 123_456_789, value: LogValue::Int(42) },
+      *records[0].as_ref().unwrap());
+    assert!(records[1].is_err());
+    assert_eq!(file.data.len() - 2, file.intact_bytes().len());
+  }
+
+  #[test]
+  fn version_1_0_files_are_read() {
+    // An int variable, logged 1.5 s after the logger started
+    let mut data = vec![0, 0, 1, 0, 0, 0, 3, 0];
+    data.extend_from_slice(b"foo");
+    data.extend_from_slice(&[3, 0]);
+    data.extend_from_slice(b"int");
+    data.extend_from_slice(&[0; 10]);
+    data.extend_from_slice(&[0, 0, 0xc0, 0x3f, 42, 0, 0, 0]);
+
+    let file = LogFile::from_bytes(data).unwrap();
+    assert!(file.header.relative_times());
+    assert_eq!(LogType::Int, file.header.log_type);
+    let record = file.records().next().unwrap().unwrap();
+    assert_eq!(Record { time: 1_500_000_000, value: LogValue::Int(42) }, record);
+  }
+
+  #[test]
+  fn unknown_versions_are_refused() {
+    match LogFile::from_bytes(vec![1, 0, 2, 0]) {
+      Err(ReadError::UnsupportedVersion(2, 1)) => {},
+      Err(e) => panic!("Unexpected error {}", e),
+      Ok(_) => panic!("Accepted version 2.1"),
+    }
+    match LogFile::from_bytes(vec![3, 0, 1, 0]) {
+      Err(ReadError::UnsupportedVersion(1, 3)) => {},
+      Err(e) => panic!("Unexpected error {}", e),
+      Ok(_) => panic!("Accepted version 1.3"),
+    }
+    assert!(LogFile::from_bytes(vec![1, 0, 1, 0, 0]).is_err());
+  }
+}
@@ -0,0 +1,161 @@
+// Keeps the logger from filling the disk: the limits for files and sessions, the free space of the
+// disk and the removal of old sessions.
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use nix::sys::statvfs::statvfs;
+
+pub const MEBIBYTE: u64 = 1024 * 1024;
+
+/// The limits for the files of the logger, and for the records that can be lost in a crash
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+  pub max_file_size: u64,           // A stream continues in a new file after this many bytes...
+  pub max_file_age: Duration,       // ...or this long
+  pub session_quota: u64,           // Logging stops when a session gets bigger
+  pub total_quota: u64,             // Old sessions are deleted to keep all of them below this
+  pub min_free_space: u64,          // Logging stops when the disk has less space left
+  pub flush_interval: Duration,     // Buffered records are written to the files this often...
+  pub sync_interval: Duration,      // ...and forced to the disk this often
+}
+
+impl Default for Limits {
+  fn default() -> Limits {
+    Limits {
+      max_file_size: 64 * MEBIBYTE,
+      max_file_age: Duration::from_secs(60 * 60),
+      session_quota: 2048 * MEBIBYTE,
+      total_quota: 8192 * MEBIBYTE,
+      min_free_space: 512 * MEBIBYTE,
+      flush_interval: Duration::from_millis(100),
+      sync_interval: Duration::from_secs(1),
+    }
+  }
+}
+
+/// The disk the logs are stored on
+pub trait Disk: Send + Sync {
+  /// Bytes available to us in the file system of `path`
+  fn free_space(&self, path: &Path) -> io::Result<u64>;
+}
+
+pub struct SystemDisk;
+
+impl Disk for SystemDisk {
+  fn free_space(&self, path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path).map_err(io::Error::other)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+  }
+}
+
+/// Why the logger stopped logging before the end of the session
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+  SessionQuota(u64),
+  TotalQuota(u64),
+  LowDiskSpace { free: u64, min: u64 },
+  WriteFailed(String),
+}
+
+impl Error for StopReason {}
+
+/// `bytes` in MiB, or exactly if that would round a limit below 1 MiB to nothing
+fn format_size(bytes: u64) -> String {
+  if bytes.is_multiple_of(MEBIBYTE) {
+    format!("{} MiB", bytes / MEBIBYTE)
+  } else if bytes < MEBIBYTE {
+    format!("{} bytes", bytes)
+  } else {
+    format!("{:.1} MiB", bytes as f64 / MEBIBYTE as f64)
+  }
+}
+
+impl fmt::Display for StopReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      StopReason::SessionQuota(quota) =>
+        write!(f, "The session reached its quota of {}", format_size(quota)),
+      StopReason::TotalQuota(quota) =>
+        write!(f, "The session alone exceeds the quota of {} for all sessions",
+          format_size(quota)),
+      StopReason::LowDiskSpace { free, min } =>
+        write!(f, "Only {} left on the disk, {} are kept free", format_size(free),
+          format_size(min)),
+      StopReason::WriteFailed(ref e) => write!(f, "Writing failed: {}", e),
+    }
+  }
+}
+
+/// The total size of the files in `path`
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+  let mut size = 0;
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    let metadata = entry.metadata()?;
+    size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+  }
+  Ok(size)
+}
+
+/// Whether `name` is the name of a session directory, like `2018-03-10_14-05-09` or
+/// `2018-03-10_14-05` (before sessions were named to the second)
+fn is_session_name(name: &str) -> bool {
+  let time = name.split('_').take(2).collect::<Vec<_>>().join("_");
+  NaiveDateTime::parse_from_str(&time, "%Y-%m-%d_%H-%M-%S").is_ok()
+    || NaiveDateTime::parse_from_str(&format!("{}-00", time), "%Y-%m-%d_%H-%M-%S").is_ok()
+}
+
+/// The sessions in `root` other than `current` with their size, the oldest first. Directories that
+/// aren't named like a session are left alone.
+pub fn old_sessions(root: &Path, current: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+  let mut sessions = Vec::new();
+  for entry in fs::read_dir(root)? {
+    let path = entry?.path();
+    let is_session = path.file_name().and_then(|name| name.to_str()).is_some_and(is_session_name);
+    if path.is_dir() && is_session && path != current {
+      let size = dir_size(&path)?;
+      sessions.push((path, size));
+    }
+  }
+  // The names sort by time, and a suffix like `_2` after the session it was started next to
+  sessions.sort();
+  Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempdir::TempDir;
+
+  #[test]
+  fn only_session_directories_are_old_sessions() {
+    let tmp = TempDir::new("storage").unwrap();
+    for name in &["2018-03-10_14-05-09_2", "2018-03-10_14-05-09", "2018-03-09_08-00", "backup"] {
+      fs::create_dir(tmp.path().join(name)).unwrap();
+    }
+    fs::write(tmp.path().join("2018-03-10_14-05-09").join("speed.ebl"), [0; 100]).unwrap();
+    fs::write(tmp.path().join("2018-03-11_10-00-00"), [0; 10]).unwrap();
+    let current = tmp.path().join("2018-03-10_14-05-09_2");
+
+    let sessions = old_sessions(tmp.path(), &current).unwrap();
+    assert_eq!(vec![
+      (tmp.path().join("2018-03-09_08-00"), 0),
+      (tmp.path().join("2018-03-10_14-05-09"), 100),
+    ], sessions);
+  }
+
+  #[test]
+  fn stop_reasons_show_sizes_below_a_mebibyte() {
+    assert_eq!("The session reached its quota of 2048 MiB",
+      StopReason::SessionQuota(2048 * MEBIBYTE).to_string());
+    assert_eq!("The session alone exceeds the quota of 4096 bytes for all sessions",
+      StopReason::TotalQuota(4096).to_string());
+    assert_eq!("Only 1.5 MiB left on the disk, 512 MiB are kept free",
+      StopReason::LowDiskSpace { free: 3 * MEBIBYTE / 2, min: 512 * MEBIBYTE }.to_string());
+  }
+}
@@ -1,26 +1,108 @@
 use std::io;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::mem;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use chrono::{ Local, TimeZone };
 
 use log_stream::*;
+use manifest::SessionManifest;
+use messages::handshake::Peer;
+use messages::log_value::{ LogType, LogValue };
+use storage::{ old_sessions, Disk, Limits, StopReason, SystemDisk };
+use util::timing::{ Clock, SystemClock, NANOS_PER_MICRO, NANOS_PER_SECOND };
+
+// How often the free space of the disk is checked, in nanoseconds
+const DISK_CHECK_INTERVAL: u64 = NANOS_PER_SECOND;
+
+/// A variable, written to a new file whenever the current one gets too big or too old
+struct Stream {
+  name: String,
+  log_type: LogType,
+  file: LogStream,
+  part: u32,                        // The number of the file, 0 for the first one
+  opened: u64,                      // When the file was created, monotonic nanoseconds
+}
 
 pub struct StreamManager {
-  stream_by_id: HashMap<i32, Box<LogStreamBase>>,
+  stream_by_id: HashMap<i32, Stream>,
   id_by_name: HashMap<String, i32>,
   next_id: i32,
   base_path: Box<Path>,
+  clock: Arc<dyn Clock>,
+  manifest: SessionManifest,
+  limits: Limits,
+  format: Format,
+  disk: Arc<dyn Disk>,
+  old_sessions: Vec<(PathBuf, u64)>, // The other sessions with their size, the oldest first
+  session_size: u64,
+  next_disk_check: u64,
+  next_flush: u64,
+  next_sync: u64,
+  stop_reason: Option<StopReason>,
 }
 
 impl StreamManager {
-  pub fn new() -> io::Result<StreamManager> {
-    let path = get_timestamped_path()?;
+  /// Starts a new session in /var/log/aicc, with free-form `tags` like `track=hall` in its
+  /// manifest. The variables are stored in `format`.
+  pub fn new(tags: &[String], limits: Limits, format: Format) -> io::Result<StreamManager> {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let now = clock.unix_nanos();
+    let started = Local
+      .timestamp_opt((now / NANOS_PER_SECOND) as i64, (now % NANOS_PER_SECOND) as u32)
+      .unwrap();
+    let path = create_session_dir(Path::new("/var/log/aicc"), started)?;
+    StreamManager::in_directory(path, tags, limits, format, clock, Arc::new(SystemDisk))
+  }
 
-    Ok(StreamManager {
+  /// Logs to the existing directory `path`, taking the time from `clock`. The other sessions next
+  /// to it count towards the total quota of `limits`.
+  pub fn in_directory(path: PathBuf,
+                      tags: &[String],
+                      limits: Limits,
+                      format: Format,
+                      clock: Arc<dyn Clock>,
+                      disk: Arc<dyn Disk>) -> io::Result<StreamManager> {
+    let old_sessions = match path.parent() {
+      Some(root) => old_sessions(root, &path)?,
+      None => Vec::new(),
+    };
+    let manifest = SessionManifest::create(&path, tags, clock.clone())?;
+    let mut manager = StreamManager {
       stream_by_id: HashMap::new(),
       id_by_name: HashMap::new(),
       next_id: 0,
       base_path: path.into(),
-    })
+      clock,
+      manifest,
+      limits,
+      format,
+      disk,
+      old_sessions,
+      session_size: 0,
+      next_disk_check: 0,
+      next_flush: 0,
+      next_sync: 0,
+      stop_reason: None,
+    };
+    manager.check_storage(0);
+    Ok(manager)
+  }
+
+  /// The directory of the session
+  pub fn path(&self) -> &Path {
+    &self.base_path
+  }
+
+  pub fn manifest(&self) -> &SessionManifest {
+    &self.manifest
+  }
+
+  /// Why nothing is logged anymore, if logging stopped
+  pub fn stop_reason(&self) -> Option<&StopReason> {
+    self.stop_reason.as_ref()
   }
 
   pub fn register(&mut self, name: String, typename: String) -> io::Result<i32> {
@@ -31,36 +113,474 @@ impl StreamManager {
       }
     }
 
+    // Values of an unknown type can't be stored
+    let log_type = LogType::parse(&typename)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
     // Does not exist yet. Let's create a new instance.
     let id = self.next_id;
     self.next_id = self.next_id + 1;
     self.id_by_name.insert(name.clone(), id);
 
-    let path = self.base_path.to_path_buf().join(format!("{}.ebl", name));
-
-    let stream: Box<LogStreamBase> = match typename.as_ref() {
-      "int" => {
-        let s: LogStream<i32> = LogStream::new(&path, &name)?;
-        Box::new(s)
-      },
-      "bool" => {
-        let s: LogStream<bool> = LogStream::new(&path, &name)?;
-        Box::new(s)
-      },
-      _ => {
-        let s: LogStream<f32> = LogStream::new(&path, &name)?;
-        Box::new(s)
-      },
-    };
+    // Without a file, the values of the variable are dropped like all others
+    if self.stop_reason.is_some() {
+      return Ok(id);
+    }
+
+    let (file_name, part) = free_file_name(&self.base_path, &name, 0);
+    let path = self.base_path.join(&file_name);
+    let file = LogStream::new(&path, &name, &log_type, self.format, &*self.clock)?;
+    self.session_size += file.size();
+
+    // The stream is logged even if the manifest can't be updated
+    if let Err(e) = self.manifest.add_stream(id, &name, &log_type.to_string(), &file_name) {
+      println!("Failed to add {} to the session manifest: {}", name, e);
+    }
 
-    self.stream_by_id.insert(id.clone(), stream);
+    let opened = self.clock.monotonic_nanos();
+    self.stream_by_id.insert(id, Stream { name, log_type, file, part, opened });
     Ok(id)
   }
 
-  pub fn log(&mut self, id: i32, val: f32) -> io::Result<()> {
-    match self.stream_by_id.get_mut(&id) {
-      Some(stream) => stream.log_generic(val),
-      None => Err(io::Error::new(io::ErrorKind::Other, "Stream not registered."))
+  /// Adds a client to the manifest. Returns its index for `client_disconnected`.
+  pub fn client_connected(&mut self, peer: &Peer) -> io::Result<usize> {
+    self.manifest.client_connected(peer)
+  }
+
+  pub fn client_disconnected(&mut self, index: usize) -> io::Result<()> {
+    self.manifest.client_disconnected(index)
+  }
+
+  /// Ends the session: writes all records to the disk and the stop time to the manifest
+  pub fn stop(&mut self) -> io::Result<()> {
+    self.finish_streams()?;
+    self.manifest.stop()
+  }
+
+  /// Writes the buffered records to the files when the flush interval has passed, and to the
+  /// disk when the sync interval has. To be called regularly, records are only written by the
+  /// buffers when they are full otherwise.
+  pub fn maintain(&mut self) {
+    let now = self.clock.monotonic_nanos();
+    let result = if now >= self.next_sync {
+      self.next_sync = now + self.limits.sync_interval.as_nanos() as u64;
+      self.next_flush = now + self.limits.flush_interval.as_nanos() as u64;
+      self.sync()
+    } else if now >= self.next_flush {
+      self.next_flush = now + self.limits.flush_interval.as_nanos() as u64;
+      self.stream_by_id.values_mut().try_for_each(|stream| stream.file.flush())
+    } else {
+      Ok(())
+    };
+    if let Err(e) = result {
+      self.stop_logging(StopReason::WriteFailed(e.to_string()));
+    }
+  }
+
+  /// Writes all buffered records and waits until they are on the disk
+  pub fn sync(&mut self) -> io::Result<()> {
+    for stream in self.stream_by_id.values_mut() {
+      // Compressing a chunk makes the file smaller than its records
+      let size = stream.file.size();
+      let result = stream.file.sync();
+      self.session_size = (self.session_size + stream.file.size()).saturating_sub(size);
+      result?;
+    }
+    Ok(())
+  }
+
+  /// Writes all records and closes the files. Nothing is logged afterwards.
+  fn finish_streams(&mut self) -> io::Result<()> {
+    let mut result = Ok(());
+    for (_, stream) in self.stream_by_id.drain() {
+      if let Err(e) = stream.file.finish() {
+        println!("Failed to write the last records of {}: {}", stream.name, e);
+        result = Err(e);
+      }
+    }
+    result
+  }
+
+  /// Number of variables logged so far
+  pub fn stream_count(&self) -> usize {
+    self.id_by_name.len()
+  }
+
+  /// Logs a value that changed at `timestamp` (microseconds since the UNIX epoch, on our clock).
+  /// Once logging has stopped, values are dropped without an error.
+  pub fn log(&mut self, id: i32, timestamp: u64, val: &LogValue) -> io::Result<()> {
+    if self.stop_reason.is_some() {
+      return Ok(());
+    }
+    let now = self.clock.monotonic_nanos();
+    if !self.stream_by_id.contains_key(&id) {
+      return Err(io::Error::other("Stream not registered."));
+    }
+
+    self.rotate(id, now)?;
+    if !self.check_storage(now) {
+      return Ok(());
+    }
+
+    let stream = self.stream_by_id.get_mut(&id).unwrap();
+    let size = stream.file.size();
+    let result = stream.file.log(timestamp * NANOS_PER_MICRO, val);
+    self.session_size = (self.session_size + stream.file.size()).saturating_sub(size);
+    match result {
+      // A value of the wrong type is the producer's problem
+      Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {},
+      Err(ref e) => self.stop_logging(StopReason::WriteFailed(e.to_string())),
+      Ok(_) => {},
+    }
+    result
+  }
+
+  /// Continues the stream `id` in a new file if its file is full or old enough
+  fn rotate(&mut self, id: i32, now: u64) -> io::Result<()> {
+    let stream = self.stream_by_id.get_mut(&id).unwrap();
+    if stream.file.size() < self.limits.max_file_size
+      && now.saturating_sub(stream.opened) < self.limits.max_file_age.as_nanos() as u64 {
+      return Ok(());
+    }
+
+    let (file_name, part) = free_file_name(&self.base_path, &stream.name, stream.part + 1);
+    let file = LogStream::new(&self.base_path.join(&file_name), &stream.name, &stream.log_type,
+      self.format, &*self.clock)?;
+    let old_file = mem::replace(&mut stream.file, file);
+    let size = old_file.size();
+    match old_file.finish() {
+      Ok(final_size) => self.session_size = (self.session_size + final_size).saturating_sub(size),
+      Err(e) => println!("Failed to write the last records of {}: {}", stream.name, e),
+    }
+    stream.part = part;
+    stream.opened = now;
+    self.session_size += stream.file.size();
+    if let Err(e) = self.manifest.add_file(id, &file_name) {
+      println!("Failed to add {} to the session manifest: {}", file_name, e);
+    }
+    Ok(())
+  }
+
+  /// Enforces the quotas and keeps the minimum free space on the disk, by deleting old sessions or
+  /// stopping to log. Returns whether we can go on logging.
+  fn check_storage(&mut self, now: u64) -> bool {
+    if self.session_size > self.limits.session_quota {
+      self.stop_logging(StopReason::SessionQuota(self.limits.session_quota));
+      return false;
+    }
+
+    let mut total =
+      self.old_sessions.iter().map(|(_, size)| size).sum::<u64>() + self.session_size;
+    while total > self.limits.total_quota && !self.old_sessions.is_empty() {
+      let (path, size) = self.old_sessions.remove(0);
+      println!("Deleting session {} to stay within the quota", path.display());
+      if let Err(e) = fs::remove_dir_all(&path) {
+        println!("Failed to delete {}: {}", path.display(), e);
+      }
+      total -= size;
+    }
+    if total > self.limits.total_quota {
+      self.stop_logging(StopReason::TotalQuota(self.limits.total_quota));
+      return false;
+    }
+
+    if now >= self.next_disk_check {
+      self.next_disk_check = now + DISK_CHECK_INTERVAL;
+      match self.disk.free_space(&self.base_path) {
+        Ok(free) if free < self.limits.min_free_space => {
+          self.stop_logging(StopReason::LowDiskSpace { free, min: self.limits.min_free_space });
+          return false;
+        },
+        Ok(_) => {},
+        Err(e) => println!("Failed to check the free space on the disk: {}", e),
+      }
+    }
+    true
+  }
+
+  /// Stops logging for the rest of the session, keeping the records logged so far
+  fn stop_logging(&mut self, reason: StopReason) {
+    println!("Logging stopped: {}", reason);
+    let _ = self.finish_streams();
+    if let Err(e) = self.manifest.logging_stopped(&reason.to_string()) {
+      println!("Failed to record why logging stopped in the session manifest: {}", e);
+    }
+    self.stop_reason = Some(reason);
+  }
+}
+
+/// The name of the file `part` of the stream `name` in `dir`, like `speed.ebl` or `speed.1.ebl`.
+/// Stream names may contain dots, so the first file of `speed.1` is named like the second file of
+/// `speed`: parts whose file another stream took are skipped. Returns the name and the part.
+fn free_file_name(dir: &Path, name: &str, mut part: u32) -> (String, u32) {
+  loop {
+    let file_name = match part {
+      0 => format!("{}.ebl", name),
+      _ => format!("{}.{}.ebl", name, part),
+    };
+    if !dir.join(&file_name).exists() {
+      return (file_name, part);
+    }
+    part += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chunk::Compression;
+  use manifest::Manifest;
+  use reader::{ LogFile, Record };
+  use std::fs;
+  use tempdir::TempDir;
+  use std::sync::atomic::{ AtomicU64, Ordering };
+  use std::time::Duration;
+  use storage::MEBIBYTE;
+  use util::timing::MockClock;
+
+  struct FreeSpace(AtomicU64);
+
+  impl Disk for FreeSpace {
+    fn free_space(&self, _path: &Path) -> io::Result<u64> {
+      Ok(self.0.load(Ordering::SeqCst))
+    }
+  }
+
+  fn plenty_of_space() -> Arc<FreeSpace> {
+    Arc::new(FreeSpace(AtomicU64::new(u64::MAX)))
+  }
+
+  fn limits() -> Limits {
+    Limits {
+      max_file_size: 1024,
+      max_file_age: Duration::from_secs(60),
+      session_quota: 4096,
+      total_quota: 8192,
+      min_free_space: MEBIBYTE,
+      flush_interval: Duration::from_millis(100),
+      sync_interval: Duration::from_secs(1),
+    }
+  }
+
+  fn records(path: &Path) -> Vec<Record> {
+    LogFile::open(path).unwrap().records().map(Result::unwrap).collect()
+  }
+
+  fn log_reals(manager: &mut StreamManager, id: i32, count: usize) {
+    for i in 0..count {
+      manager.log(id, i as u64, &LogValue::Real(0.0)).unwrap();
+    }
+  }
+
+  #[test]
+  fn records_keep_the_producers_microseconds() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(1_600_000_000 * NANOS_PER_SECOND));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[],
+      Limits::default(), Format::Framed, clock, plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    assert_eq!(id, manager.register("speed".to_string(), "real".to_string()).unwrap());
+    manager.log(id, 1_600_000_000_000_001, &LogValue::Real(2.5)).unwrap();
+    assert!(manager.log(id + 1, 0, &LogValue::Real(0.0)).is_err());
+
+    manager.sync().unwrap();
+    let record = Record { time: 1_600_000_000_000_001_000, value: LogValue::Real(2.5) };
+    assert_eq!(vec![record], records(&tmp.path().join("speed.ebl")));
+  }
+
+  #[test]
+  fn values_are_stored_with_their_type() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[],
+      Limits::default(), Format::Framed, clock, plenty_of_space()).unwrap();
+    let events = manager.register("events".to_string(), "string".to_string()).unwrap();
+    let gyro = manager.register("gyro".to_string(), "real[3]".to_string()).unwrap();
+    manager.log(events, 1, &LogValue::Text("start".to_string())).unwrap();
+    manager.log(gyro, 2, &LogValue::Reals(vec![0.0, 0.5, 1.0])).unwrap();
+    assert!(manager.log(gyro, 3, &LogValue::Real(0.5)).is_err());
+
+    manager.sync().unwrap();
+    let event = Record { time: 1000, value: LogValue::Text("start".to_string()) };
+    assert_eq!(vec![event], records(&tmp.path().join("events.ebl")));
+    let gyro = Record { time: 2000, value: LogValue::Reals(vec![0.0, 0.5, 1.0]) };
+    assert_eq!(vec![gyro], records(&tmp.path().join("gyro.ebl")));
+  }
+
+  #[test]
+  fn records_are_flushed_and_synced_on_schedule() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits(),
+      Format::Framed, clock.clone(), plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    let path = tmp.path().join("speed.ebl");
+
+    // The first call syncs
+    manager.maintain();
+    log_reals(&mut manager, id, 2);
+    assert_eq!(0, records(&path).len());
+    clock.advance(Duration::from_millis(99));
+    manager.maintain();
+    assert_eq!(0, records(&path).len());
+    clock.advance(Duration::from_millis(1));
+    manager.maintain();
+    assert_eq!(2, records(&path).len());
+
+    // Stopping the session writes everything
+    log_reals(&mut manager, id, 1);
+    manager.stop().unwrap();
+    assert_eq!(3, records(&path).len());
+  }
+
+  #[test]
+  fn registered_streams_are_in_the_manifest() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let tags = vec!["car=red".to_string()];
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &tags,
+      Limits::default(), Format::Framed, clock, plenty_of_space()).unwrap();
+    manager.register("gyro".to_string(), "real[3]".to_string()).unwrap();
+    let refused = manager.register("mode".to_string(), "no such type".to_string()).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, refused.kind());
+    manager.register("mode".to_string(), "int".to_string()).unwrap();
+
+    let manifest = Manifest::read(tmp.path()).unwrap();
+    assert_eq!(tags, manifest.tags);
+    let streams: Vec<_> = manifest.streams.iter()
+      .map(|stream|
+        (stream.id, stream.name.as_str(), stream.log_type.as_str(), stream.files[0].as_str()))
+      .collect();
+    assert_eq!(vec![(0, "gyro", "real[3]", "gyro.ebl"), (1, "mode", "int", "mode.ebl")], streams);
+  }
+
+  #[test]
+  fn streams_continue_in_a_new_file_when_theirs_is_full_or_old() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits(),
+      Format::Framed, clock.clone(), plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+
+    // The header is 29 bytes, each record 22. The full file is written when it is closed.
+    log_reals(&mut manager, id, 46);
+    assert!(!tmp.path().join("speed.1.ebl").exists());
+    log_reals(&mut manager, id, 1);
+    assert_eq!(1041, fs::metadata(tmp.path().join("speed.ebl")).unwrap().len());
+    manager.sync().unwrap();
+    assert_eq!(51, fs::metadata(tmp.path().join("speed.1.ebl")).unwrap().len());
+
+    clock.advance(Duration::from_secs(60));
+    log_reals(&mut manager, id, 1);
+    assert!(tmp.path().join("speed.2.ebl").exists());
+    let manifest = Manifest::read(tmp.path()).unwrap();
+    assert_eq!(vec!["speed.ebl", "speed.1.ebl", "speed.2.ebl"], manifest.streams[0].files);
+  }
+
+  #[test]
+  fn rotation_skips_the_files_of_streams_with_dotted_names() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits(),
+      Format::Framed, clock.clone(), plenty_of_space()).unwrap();
+    let speed = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    let dotted = manager.register("speed.1".to_string(), "real".to_string()).unwrap();
+
+    clock.advance(Duration::from_secs(60));
+    log_reals(&mut manager, speed, 1);
+    log_reals(&mut manager, dotted, 1);
+    manager.sync().unwrap();
+
+    let manifest = Manifest::read(tmp.path()).unwrap();
+    assert_eq!(vec!["speed.ebl", "speed.2.ebl"], manifest.streams[0].files);
+    assert_eq!(vec!["speed.1.ebl", "speed.1.1.ebl"], manifest.streams[1].files);
+    let name = |file: &str| LogFile::open(&tmp.path().join(file)).unwrap().header.name;
+    assert_eq!("speed.1", name("speed.1.ebl"));
+    assert_eq!("speed", name("speed.2.ebl"));
+  }
+
+  #[test]
+  fn logging_stops_at_the_session_quota() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits(),
+      Format::Framed, clock, plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    log_reals(&mut manager, id, 400);
+
+    assert_eq!(Some(&StopReason::SessionQuota(4096)), manager.stop_reason());
+    assert!(fs::read_dir(tmp.path()).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len())
+      .sum::<u64>() < 4096 + 1024);
+    let manifest = Manifest::read(tmp.path()).unwrap();
+    assert!(manifest.logging_stopped.unwrap()
+      .ends_with("The session reached its quota of 4096 bytes"));
+  }
+
+  #[test]
+  fn compressed_chunks_count_towards_the_quota_with_their_size() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let limits = Limits { max_file_size: MEBIBYTE, ..limits() };
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits,
+      Format::Chunked(Compression::Zstd), clock, plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+
+    // 20 chunks of records that would take 14000 bytes uncompressed
+    for _ in 0..20 {
+      log_reals(&mut manager, id, 100);
+      manager.sync().unwrap();
     }
+    assert_eq!(None, manager.stop_reason());
+    manager.stop().unwrap();
+    let file = LogFile::open(&tmp.path().join("speed.ebl")).unwrap();
+    assert_eq!(20, file.index().unwrap().len());
+    assert_eq!(2000, file.records().count());
+    assert!(fs::metadata(tmp.path().join("speed.ebl")).unwrap().len() < 4096);
+  }
+
+  #[test]
+  fn old_sessions_are_deleted_to_stay_within_the_total_quota() {
+    let tmp = TempDir::new("streams").unwrap();
+    for (name, size) in &[("2018-03-10_14-05-09", 3000), ("2018-03-11_09-00-00", 3000)] {
+      fs::create_dir(tmp.path().join(name)).unwrap();
+      fs::write(tmp.path().join(name).join("speed.ebl"), vec![0; *size]).unwrap();
+    }
+    let path = tmp.path().join("2018-03-12_09-00-00");
+    fs::create_dir(&path).unwrap();
+
+    let clock = Arc::new(MockClock::new(0));
+    let mut manager = StreamManager::in_directory(path, &[], limits(), Format::Framed, clock,
+      plenty_of_space()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    log_reals(&mut manager, id, 150);
+
+    assert!(!tmp.path().join("2018-03-10_14-05-09").exists());
+    assert!(tmp.path().join("2018-03-11_09-00-00").exists());
+    assert_eq!(None, manager.stop_reason());
+  }
+
+  #[test]
+  fn logging_stops_when_the_disk_fills_up() {
+    let tmp = TempDir::new("streams").unwrap();
+    let clock = Arc::new(MockClock::new(0));
+    let disk = plenty_of_space();
+    let mut manager = StreamManager::in_directory(tmp.path().to_path_buf(), &[], limits(),
+      Format::Framed, clock.clone(), disk.clone()).unwrap();
+    let id = manager.register("speed".to_string(), "real".to_string()).unwrap();
+    log_reals(&mut manager, id, 1);
+
+    // The disk is checked once a second
+    disk.0.store(MEBIBYTE - 1, Ordering::SeqCst);
+    log_reals(&mut manager, id, 1);
+    assert_eq!(None, manager.stop_reason());
+    clock.advance(Duration::from_secs(1));
+    log_reals(&mut manager, id, 1);
+    assert_eq!(Some(&StopReason::LowDiskSpace { free: MEBIBYTE - 1, min: MEBIBYTE }),
+      manager.stop_reason());
+
+    // Values are dropped from now on, the file keeps the records before
+    log_reals(&mut manager, id, 5);
+    assert_eq!(29 + 2 * 22, fs::metadata(tmp.path().join("speed.ebl")).unwrap().len());
+    assert_eq!(1, manager.stream_count());
   }
 }
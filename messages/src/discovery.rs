@@ -0,0 +1,37 @@
+// Announcements of running services on the LAN. Every announcement is a single frame in a UDP
+// multicast datagram, so tools like drive-remote can find cars without knowing their address.
+use frame::Message;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+  pub car: String,              // Name of the car the service runs on
+  pub service: String,          // Name of the service, like "drive-core"
+  pub port: u16,                // TCP port the service listens on, at the sender's address
+  pub software_version: String,
+}
+
+impl Message for Announcement {
+  const TYPE_ID: u16 = 5;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use frame::{ FrameReader, write_message };
+
+  #[test]
+  fn announcement_fits_in_one_datagram() {
+    let announcement = Announcement {
+      car: "aicc".to_string(),
+      service: "drive-core".to_string(),
+      port: 41330,
+      software_version: "0.1.0".to_string(),
+    };
+    let mut datagram = Vec::new();
+    write_message(&mut datagram, &announcement).unwrap();
+    assert!(datagram.len() < 512);
+
+    let frame = FrameReader::new().read_frame(&mut &datagram[..]).unwrap();
+    assert_eq!(announcement, frame.decode().unwrap());
+  }
+}
@@ -1,9 +1,96 @@
+use frame::Message;
+
 // Enum of the messages this service accepts
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageType {
   SetSteering(f32),
   SetThrottle(f32),
   Bye,
+  Arm,                        // Allows drive-core to apply throttle commands
+  Heartbeat(u32),             // Sequence number, echoed in the next Status
+  Disarm,                     // Throttle goes to neutral and stays there until the next Arm
+  EmergencyStop,              // Brake now. Only a new Arm lets the car drive again.
+  Claim(Role),                // Asks for control of the car
+  Release,                    // Gives up control of the car
+  DriveCommand(DriveCommand), // Replaces SetSteering and SetThrottle
+}
+
+/// Steering, throttle and brake from the same moment, applied together
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DriveCommand {
+  pub seq: u32,               // Increases with every command. Older commands are rejected.
+  pub timestamp: u64,         // Creation time in microseconds since the UNIX epoch (client clock)
+  pub steering: f32,          // [-1:1]
+  pub throttle: f32,          // [-1:1]
+  pub brake: f32,             // [0:1], takes precedence over the throttle
+}
+
+impl Message for MessageType {
+  const TYPE_ID: u16 = 1;
+}
+
+/// What a client is to the car. A client takes control from clients with a lower priority, manual
+/// drivers always get control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+  Autopilot,
+  Safety,                     // Driving aids
+  Manual,
+}
+
+impl Role {
+  pub fn priority(&self) -> u8 {
+    match *self {
+      Role::Autopilot => 0,
+      Role::Safety => 1,
+      Role::Manual => 2,
+    }
+  }
+}
+
+/// The client currently in control of the car
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Controller {
+  pub name: String,
+  pub role: Role,
+}
+
+/// Whether drive-core applies the controlling client's commands
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DriveState {
+  Disarmed,       // Throttle neutral, only steering commands are applied
+  Armed,          // Commands are applied
+  Degraded,       // Commands are late: throttle neutral, steering held. The next command re-arms.
+  Failsafe,       // Link lost: brake, then neutral. Commands are ignored until the next Arm.
+  EmergencyStop,  // Like Failsafe, but requested by the client
+}
+
+impl DriveState {
+  /// Number of the state in the log
+  pub fn code(&self) -> i32 {
+    match *self {
+      DriveState::Disarmed => 0,
+      DriveState::Armed => 1,
+      DriveState::Degraded => 2,
+      DriveState::Failsafe => 3,
+      DriveState::EmergencyStop => 4,
+    }
+  }
+}
+
+/// Sent by drive-core to every client on every state or controller change, after every heartbeat
+/// and regularly in between
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+  pub state: DriveState,
+  pub heartbeat: u32,         // Sequence number of the last heartbeat received
+  pub steering: f32,          // Values currently applied to the channels
+  pub throttle: f32,
+  pub controller: Option<Controller>,
+}
+
+impl Message for Status {
+  const TYPE_ID: u16 = 3;
 }
 
 #[cfg(test)]
@@ -76,4 +163,71 @@ mod tests {
       _ => panic!("Deserialized the wrong value")
     }
   }
+
+  #[test]
+  fn deserialize_heartbeat() {
+    let raw = [4, 0, 0, 0, 0x2a, 0, 0, 0];
+    let msg = deserialize(&raw).unwrap();
+    match msg {
+      MessageType::Heartbeat(seq) => assert_eq!(42, seq),
+      _ => panic!("Deserialized the wrong value")
+    }
+  }
+
+  #[test]
+  fn serialize_status() {
+    let status = Status {
+      state: DriveState::EmergencyStop,
+      heartbeat: 7,
+      steering: 0.25f32,
+      throttle: -0.5f32,
+      controller: Some(Controller { name: "drive-remote".to_string(), role: Role::Manual }),
+    };
+    let vec = serialize(&status).unwrap();
+    assert_eq!(41, vec.len());
+    assert_eq!(status, deserialize(&vec[..]).unwrap());
+  }
+
+  #[test]
+  fn deserialize_claim() {
+    let raw = [7, 0, 0, 0, 2, 0, 0, 0];
+    let msg = deserialize(&raw).unwrap();
+    match msg {
+      MessageType::Claim(role) => assert_eq!(Role::Manual, role),
+      _ => panic!("Deserialized the wrong value")
+    }
+  }
+
+  #[test]
+  fn manual_has_the_highest_priority() {
+    assert!(Role::Manual.priority() > Role::Safety.priority());
+    assert!(Role::Safety.priority() > Role::Autopilot.priority());
+  }
+
+  #[test]
+  fn serialize_drive_command() {
+    let command = DriveCommand {
+      seq: 12,
+      timestamp: 1_500_000_000_000_000,
+      steering: -0.25f32,
+      throttle: 0.5f32,
+      brake: 0f32,
+    };
+    let vec = serialize(&MessageType::DriveCommand(command.clone())).unwrap();
+    assert_eq!(4 + 4 + 8 + 3 * 4, vec.len());
+    match deserialize(&vec[..]).unwrap() {
+      MessageType::DriveCommand(actual) => assert_eq!(command, actual),
+      _ => panic!("Deserialized the wrong value")
+    }
+  }
+
+  #[test]
+  fn deserialize_arm() {
+    let raw = [3, 0, 0, 0];
+    let msg = deserialize(&raw).unwrap();
+    match msg {
+      MessageType::Arm => {},
+      _ => panic!("Deserialized the wrong value")
+    }
+  }
 }
\ No newline at end of file
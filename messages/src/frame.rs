@@ -0,0 +1,391 @@
+// Framing of all messages sent between the AICC services. Every message travels in a frame:
+//
+//   magic (2 bytes) | protocol version (u8) | message type id (u16) | payload length (u32)
+//   | bincode payload | CRC-32 of everything before it (u32)
+//
+// All integers are little endian. The magic bytes let a reader find the start of the next frame
+// after a corrupt byte, instead of misinterpreting the rest of the stream.
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{ Read, Write };
+
+use bincode::{ serialize, deserialize };
+use byteorder::{ ByteOrder, LittleEndian };
+use crc::crc32;
+use handshake::Handshake;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub const MAGIC: [u8; 2] = [0xA1, 0xCC];
+
+/// Protocol version spoken by this build of the messages crate. History:
+///   1: Initial framed protocol
+///   2: drive-core only accepts commands after an Arm message
+///   3: Heartbeats, Disarm, EmergencyStop and drive-core Status replies
+///   4: Multiple drive-core clients with roles, controller in the Status
+///   5: DriveCommand
+///   6: Health checks
+///   7: Producer timestamps in logger messages, clock offset requests
+///   8: Typed log values
+pub const PROTOCOL_VERSION: u8 = 8;
+
+/// Oldest protocol version this build is still able to talk
+pub const MIN_PROTOCOL_VERSION: u8 = 8;
+
+pub const HEADER_SIZE: usize = 9;
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Upper limit for payloads, so a corrupt length can't make a reader wait for gigabytes
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// A message type that can be sent in a frame. Every type has its own id, so a reader can tell
+/// which enum a payload has to be decoded to.
+pub trait Message: Serialize + DeserializeOwned {
+  const TYPE_ID: u16;
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+  Io(io::Error),
+  Serialization(String),
+  UnsupportedVersion(u8),
+  TooLarge(usize),
+  BadChecksum,
+  UnexpectedType(u16),
+  Refused(String),
+}
+
+impl Error for FrameError {}
+
+impl fmt::Display for FrameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      FrameError::Io(ref e) => write!(f, "I/O error: {}", e),
+      FrameError::Serialization(ref msg) => write!(f, "Invalid message payload: {}", msg),
+      FrameError::UnsupportedVersion(version) =>
+        write!(f, "Unsupported protocol version {} (supported: {} to {})",
+          version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+      FrameError::TooLarge(size) => write!(f, "Frame payload of {} bytes is too large", size),
+      FrameError::BadChecksum => write!(f, "Frame checksum mismatch"),
+      FrameError::UnexpectedType(type_id) => write!(f, "Unexpected message type {}", type_id),
+      FrameError::Refused(ref reason) => write!(f, "Connection refused: {}", reason),
+    }
+  }
+}
+
+impl From<io::Error> for FrameError {
+  fn from(e: io::Error) -> Self {
+    FrameError::Io(e)
+  }
+}
+
+impl From<bincode::Error> for FrameError {
+  fn from(e: bincode::Error) -> Self {
+    match *e {
+      bincode::ErrorKind::Io(err) => FrameError::Io(err),
+      e => FrameError::Serialization(e.to_string()),
+    }
+  }
+}
+
+impl From<FrameError> for io::Error {
+  fn from(e: FrameError) -> Self {
+    match e {
+      FrameError::Io(err) => err,
+      e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+  }
+}
+
+/// A complete frame whose checksum has been verified
+#[derive(Debug)]
+pub struct Frame {
+  pub version: u8,
+  pub type_id: u16,
+  pub payload: Vec<u8>,
+}
+
+impl Frame {
+  pub fn is<M: Message>(&self) -> bool {
+    self.type_id == M::TYPE_ID
+  }
+
+  /// Decodes the payload, which has to be of message type `M`
+  pub fn decode<M: Message>(&self) -> Result<M, FrameError> {
+    if !self.is::<M>() {
+      return Err(FrameError::UnexpectedType(self.type_id));
+    }
+    Ok(deserialize(&self.payload)?)
+  }
+}
+
+/// Serializes `msg` into a complete frame
+pub fn encode<M: Message>(msg: &M) -> Result<Vec<u8>, FrameError> {
+  let payload = serialize(msg)?;
+  if payload.len() > MAX_PAYLOAD_SIZE {
+    return Err(FrameError::TooLarge(payload.len()));
+  }
+
+  let mut frame = vec![0u8; HEADER_SIZE];
+  frame[0..2].copy_from_slice(&MAGIC);
+  frame[2] = PROTOCOL_VERSION;
+  LittleEndian::write_u16(&mut frame[3..5], M::TYPE_ID);
+  LittleEndian::write_u32(&mut frame[5..9], payload.len() as u32);
+  frame.extend_from_slice(&payload);
+
+  let mut checksum = [0u8; CHECKSUM_SIZE];
+  LittleEndian::write_u32(&mut checksum, crc32::checksum_ieee(&frame));
+  frame.extend_from_slice(&checksum);
+  Ok(frame)
+}
+
+/// Sends `msg` as a single frame
+pub fn write_message<W: Write, M: Message>(writer: &mut W, msg: &M) -> Result<(), FrameError> {
+  writer.write_all(&encode(msg)?)?;
+  Ok(())
+}
+
+/// Collects the bytes received on a stream and cuts them into frames. Works with blocking
+/// sockets, sockets with a read timeout and non-blocking sockets alike: incomplete frames stay
+/// buffered until the rest arrives.
+pub struct FrameReader {
+  buffer: Vec<u8>,
+}
+
+impl FrameReader {
+  pub fn new() -> FrameReader {
+    FrameReader { buffer: Vec::new() }
+  }
+
+  /// Returns the next frame received on `source`, reading from it as often as necessary.
+  /// Errors of the source (such as `WouldBlock` or `TimedOut`) are passed on as `FrameError::Io`,
+  /// a closed stream is reported as `UnexpectedEof`. After a `BadChecksum` or `TooLarge` error,
+  /// the reader has skipped the broken frame and can be used again.
+  pub fn read_frame<R: Read>(&mut self, source: &mut R) -> Result<Frame, FrameError> {
+    loop {
+      if let Some(frame) = self.next_frame()? {
+        return Ok(frame);
+      }
+      self.fill(source)?;
+    }
+  }
+
+  /// Reads the next frame, which has to be of message type `M`
+  pub fn read_message<R: Read, M: Message>(&mut self, source: &mut R) -> Result<M, FrameError> {
+    self.read_frame(source)?.decode()
+  }
+
+  /// Performs a single read on `source`
+  pub fn fill<R: Read>(&mut self, source: &mut R) -> Result<usize, FrameError> {
+    let mut chunk = [0u8; 4096];
+    let count = source.read(&mut chunk)?;
+    if count == 0 {
+      return Err(FrameError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")));
+    }
+    self.buffer.extend_from_slice(&chunk[..count]);
+    Ok(count)
+  }
+
+  /// Extracts the next complete frame from the bytes received so far
+  pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+    self.skip_to_magic();
+    if self.buffer.len() < HEADER_SIZE {
+      return Ok(None);
+    }
+
+    let version = self.buffer[2];
+    let type_id = LittleEndian::read_u16(&self.buffer[3..5]);
+    let length = LittleEndian::read_u32(&self.buffer[5..9]) as usize;
+    if length > MAX_PAYLOAD_SIZE {
+      self.buffer.drain(..MAGIC.len());
+      return Err(FrameError::TooLarge(length));
+    }
+
+    let total = HEADER_SIZE + length + CHECKSUM_SIZE;
+    if self.buffer.len() < total {
+      return Ok(None);
+    }
+
+    let checksum = LittleEndian::read_u32(&self.buffer[HEADER_SIZE + length..total]);
+    if checksum != crc32::checksum_ieee(&self.buffer[..HEADER_SIZE + length]) {
+      // Skip the magic bytes, so we resynchronize on the next frame
+      self.buffer.drain(..MAGIC.len());
+      return Err(FrameError::BadChecksum);
+    }
+
+    let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + length].to_vec();
+    self.buffer.drain(..total);
+
+    // Handshake frames are always accepted, so we can tell a peer of any version why we refuse it
+    let supported = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+    if type_id != Handshake::TYPE_ID && !supported.contains(&version) {
+      return Err(FrameError::UnsupportedVersion(version));
+    }
+    Ok(Some(Frame { version, type_id, payload }))
+  }
+
+  /// Drops any garbage in front of the next magic bytes
+  fn skip_to_magic(&mut self) {
+    let start = self.buffer.windows(MAGIC.len()).position(|w| w == MAGIC);
+    match start {
+      Some(0) => {},
+      Some(start) => { self.buffer.drain(..start); },
+      // Keep the last byte, it might be the first half of the magic
+      None => {
+        let keep = if self.buffer.last() == Some(&MAGIC[0]) { 1 } else { 0 };
+        let len = self.buffer.len();
+        self.buffer.drain(..len - keep);
+      }
+    }
+  }
+}
+
+impl Default for FrameReader {
+  fn default() -> Self {
+    FrameReader::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  enum TestMessage {
+    Ping(u32),
+    Text(String),
+  }
+
+  impl Message for TestMessage {
+    const TYPE_ID: u16 = 0xFFFF;
+  }
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct OtherMessage(u8);
+
+  impl Message for OtherMessage {
+    const TYPE_ID: u16 = 0xFFFE;
+  }
+
+  /// Returns its data in chunks of at most `chunk` bytes, then reports `WouldBlock`
+  struct SlowReader {
+    data: Vec<u8>,
+    chunk: usize,
+  }
+
+  impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.data.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"));
+      }
+      let count = self.chunk.min(buf.len()).min(self.data.len());
+      buf[..count].copy_from_slice(&self.data[..count]);
+      self.data.drain(..count);
+      Ok(count)
+    }
+  }
+
+  #[test]
+  fn encode_writes_the_header() {
+    let frame = encode(&TestMessage::Ping(7)).unwrap();
+    assert_eq!(&MAGIC, &frame[0..2]);
+    assert_eq!(PROTOCOL_VERSION, frame[2]);
+    assert_eq!([0xFF, 0xFF], frame[3..5]);
+    assert_eq!([8, 0, 0, 0], frame[5..9]);
+    assert_eq!(HEADER_SIZE + 8 + CHECKSUM_SIZE, frame.len());
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    let mut data = encode(&TestMessage::Ping(42)).unwrap();
+    data.extend(encode(&TestMessage::Text("hello".to_string())).unwrap());
+
+    let mut reader = FrameReader::new();
+    let mut source = Cursor::new(data);
+    assert_eq!(TestMessage::Ping(42), reader.read_message(&mut source).unwrap());
+    assert_eq!(TestMessage::Text("hello".to_string()), reader.read_message(&mut source).unwrap());
+
+    match reader.read_frame(&mut source) {
+      Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn partial_frames_stay_buffered() {
+    let mut source = SlowReader { data: encode(&TestMessage::Ping(1)).unwrap(), chunk: 5 };
+    let mut reader = FrameReader::new();
+
+    for _ in 0..3 {
+      match reader.read_frame(&mut source) {
+        Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+          source.data.extend(encode(&TestMessage::Ping(2)).unwrap());
+          break;
+        },
+        Ok(frame) => assert_eq!(TestMessage::Ping(1), frame.decode().unwrap()),
+        r => panic!("Unexpected result {:?}", r)
+      }
+    }
+    assert_eq!(TestMessage::Ping(2), reader.read_message(&mut source).unwrap());
+  }
+
+  #[test]
+  fn it_resynchronizes_after_a_corrupt_byte() {
+    let mut data = vec![0x00, 0x13, 0xA1];
+    let mut corrupt = encode(&TestMessage::Ping(1)).unwrap();
+    corrupt[10] ^= 0x40;
+    data.extend(corrupt);
+    data.extend(encode(&TestMessage::Ping(2)).unwrap());
+
+    let mut reader = FrameReader::new();
+    let mut source = Cursor::new(data);
+    match reader.read_frame(&mut source) {
+      Err(FrameError::BadChecksum) => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+    assert_eq!(TestMessage::Ping(2), reader.read_message(&mut source).unwrap());
+  }
+
+  #[test]
+  fn it_rejects_oversized_frames() {
+    let mut data = encode(&TestMessage::Ping(1)).unwrap();
+    LittleEndian::write_u32(&mut data[5..9], (MAX_PAYLOAD_SIZE + 1) as u32);
+    data.extend(encode(&TestMessage::Ping(2)).unwrap());
+
+    let mut reader = FrameReader::new();
+    let mut source = Cursor::new(data);
+    match reader.read_frame(&mut source) {
+      Err(FrameError::TooLarge(_)) => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+    assert_eq!(TestMessage::Ping(2), reader.read_message(&mut source).unwrap());
+  }
+
+  #[test]
+  fn it_rejects_unknown_versions() {
+    let mut data = encode(&TestMessage::Ping(1)).unwrap();
+    data[2] = PROTOCOL_VERSION + 1;
+    let length = data.len() - CHECKSUM_SIZE;
+    let checksum = crc32::checksum_ieee(&data[..length]);
+    LittleEndian::write_u32(&mut data[length..], checksum);
+
+    match FrameReader::new().read_frame(&mut Cursor::new(data)) {
+      Err(FrameError::UnsupportedVersion(version)) => assert_eq!(PROTOCOL_VERSION + 1, version),
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn decode_checks_the_message_type() {
+    let data = encode(&OtherMessage(3)).unwrap();
+    let frame = FrameReader::new().read_frame(&mut Cursor::new(data)).unwrap();
+
+    assert!(frame.is::<OtherMessage>());
+    match frame.decode::<TestMessage>() {
+      Err(FrameError::UnexpectedType(type_id)) => assert_eq!(OtherMessage::TYPE_ID, type_id),
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+}
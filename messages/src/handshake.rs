@@ -0,0 +1,211 @@
+// The first frames exchanged on every connection. The client introduces itself with a Hello
+// listing the protocol versions it speaks, the service answers with Welcome (naming the version
+// both sides will use) or Refuse (with the reason). The framing of these messages must never
+// change, so that old and new builds can at least tell each other why they don't get along.
+use frame::{ write_message, FrameError, FrameReader, Message };
+use frame::{ MIN_PROTOCOL_VERSION, PROTOCOL_VERSION };
+
+use std::io::{ Read, Write };
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Handshake {
+  Hello { name: String, software_version: String, min_version: u8, max_version: u8 },
+  Welcome { name: String, software_version: String, version: u8 },
+  Refuse(String),             // Reason
+}
+
+impl Message for Handshake {
+  const TYPE_ID: u16 = 0;
+}
+
+/// The other end of an established connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+  pub name: String,
+  pub software_version: String,
+  pub protocol_version: u8,
+}
+
+impl Handshake {
+  pub fn hello(name: &str, software_version: &str) -> Handshake {
+    Handshake::Hello {
+      name: name.to_string(),
+      software_version: software_version.to_string(),
+      min_version: MIN_PROTOCOL_VERSION,
+      max_version: PROTOCOL_VERSION,
+    }
+  }
+
+  /// The service's response to this message. Returns the reply to send and, if the connection
+  /// is accepted, the client it came from.
+  pub fn answer(&self, name: &str, software_version: &str) -> (Handshake, Option<Peer>) {
+    let (client, client_version, min_version, max_version) = match *self {
+      Handshake::Hello { ref name, ref software_version, min_version, max_version } =>
+        (name, software_version, min_version, max_version),
+      _ => return (Handshake::Refuse("Expected a Hello message".to_string()), None),
+    };
+
+    let version = max_version.min(PROTOCOL_VERSION);
+    if version < min_version || version < MIN_PROTOCOL_VERSION {
+      let reason = format!("{} {} speaks protocol versions {} to {}, {} {} speaks {} to {}",
+        name, software_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        client, client_version, min_version, max_version);
+      return (Handshake::Refuse(reason), None);
+    }
+
+    let welcome = Handshake::Welcome {
+      name: name.to_string(),
+      software_version: software_version.to_string(),
+      version,
+    };
+    let peer = Peer {
+      name: client.clone(),
+      software_version: client_version.clone(),
+      protocol_version: version,
+    };
+    (welcome, Some(peer))
+  }
+}
+
+/// Client side of the handshake: introduces ourselves as `name` and waits for the service's
+/// answer. A refusal is reported as `FrameError::Refused`.
+pub fn connect<S: Read + Write>(stream: &mut S,
+                                reader: &mut FrameReader,
+                                name: &str,
+                                software_version: &str) -> Result<Peer, FrameError> {
+  write_message(stream, &Handshake::hello(name, software_version))?;
+  stream.flush()?;
+
+  match reader.read_message(stream)? {
+    Handshake::Welcome { name, software_version, version } =>
+      Ok(Peer { name, software_version, protocol_version: version }),
+    Handshake::Refuse(reason) => Err(FrameError::Refused(reason)),
+    Handshake::Hello { .. } => Err(FrameError::Refused("Peer answered with a Hello".to_string())),
+  }
+}
+
+/// Service side of the handshake: waits for the client's Hello and accepts or refuses it
+pub fn accept<S: Read + Write>(stream: &mut S,
+                               reader: &mut FrameReader,
+                               name: &str,
+                               software_version: &str) -> Result<Peer, FrameError> {
+  let hello: Handshake = reader.read_message(stream)?;
+  let (reply, peer) = hello.answer(name, software_version);
+  write_message(stream, &reply)?;
+  stream.flush()?;
+
+  match (peer, reply) {
+    (Some(peer), _) => Ok(peer),
+    (None, Handshake::Refuse(reason)) => Err(FrameError::Refused(reason)),
+    (None, _) => unreachable!(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use frame::encode;
+  use std::io;
+  use std::io::Cursor;
+
+  /// Replays prepared input and records everything written to it
+  struct Pipe {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+  }
+
+  impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn pipe(input: &Handshake) -> Pipe {
+    Pipe { input: Cursor::new(encode(input).unwrap()), output: Vec::new() }
+  }
+
+  fn hello(min_version: u8, max_version: u8) -> Handshake {
+    Handshake::Hello {
+      name: "old-remote".to_string(),
+      software_version: "0.0.1".to_string(),
+      min_version,
+      max_version,
+    }
+  }
+
+  #[test]
+  fn answer_agrees_on_the_highest_common_version() {
+    let (reply, peer) = hello(MIN_PROTOCOL_VERSION, 200).answer("drive-core", "1.0");
+    assert_eq!(Handshake::Welcome {
+      name: "drive-core".to_string(),
+      software_version: "1.0".to_string(),
+      version: PROTOCOL_VERSION,
+    }, reply);
+
+    let peer = peer.unwrap();
+    assert_eq!("old-remote", peer.name);
+    assert_eq!(PROTOCOL_VERSION, peer.protocol_version);
+  }
+
+  #[test]
+  fn answer_refuses_incompatible_versions() {
+    let (reply, peer) = hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2).answer("logger", "1.0");
+    assert_eq!(None, peer);
+    match reply {
+      Handshake::Refuse(reason) => assert!(reason.contains("old-remote 0.0.1")),
+      r => panic!("Unexpected reply {:?}", r)
+    }
+  }
+
+  #[test]
+  fn answer_refuses_anything_but_hello() {
+    let (reply, peer) = Handshake::Refuse("no".to_string()).answer("logger", "1.0");
+    assert_eq!(None, peer);
+    assert_eq!(Handshake::Refuse("Expected a Hello message".to_string()), reply);
+  }
+
+  #[test]
+  fn connect_sends_hello_and_reads_welcome() {
+    let (welcome, _) = Handshake::hello("drive-remote", "0.1").answer("drive-core", "0.2");
+    let mut stream = pipe(&welcome);
+
+    let peer = connect(&mut stream, &mut FrameReader::new(), "drive-remote", "0.1").unwrap();
+    assert_eq!("drive-core", peer.name);
+    assert_eq!("0.2", peer.software_version);
+    assert_eq!(encode(&Handshake::hello("drive-remote", "0.1")).unwrap(), stream.output);
+  }
+
+  #[test]
+  fn connect_reports_refusals() {
+    let mut stream = pipe(&Handshake::Refuse("too old".to_string()));
+    match connect(&mut stream, &mut FrameReader::new(), "drive-remote", "0.1") {
+      Err(FrameError::Refused(reason)) => assert_eq!("too old", reason),
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn accept_answers_the_hello() {
+    let mut stream = pipe(&hello(0, 0));
+    match accept(&mut stream, &mut FrameReader::new(), "drive-core", "0.1") {
+      Err(FrameError::Refused(_)) => {},
+      r => panic!("Unexpected result {:?}", r)
+    }
+
+    let reply = FrameReader::new().read_message(&mut Cursor::new(stream.output)).unwrap();
+    match reply {
+      Handshake::Refuse(_) => {},
+      r => panic!("Unexpected reply {:?}", r)
+    }
+  }
+}
@@ -0,0 +1,37 @@
+// Health checks of the AICC services. Every service answers a Ping on its regular port with a
+// Pong, so the supervisor can tell a hung service from a busy one.
+use frame::Message;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Health {
+  Ping(u32),                  // Sequence number, echoed in the Pong
+  Pong(HealthReport),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+  pub seq: u32,               // Sequence number of the Ping this answers
+  pub uptime_ms: u64,         // Time since the service started
+  pub detail: String,         // One line about what the service is doing, for the status table
+}
+
+impl Message for Health {
+  const TYPE_ID: u16 = 4;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use frame::{ FrameReader, write_message };
+
+  #[test]
+  fn pong_round_trips_through_a_frame() {
+    let pong = Health::Pong(HealthReport { seq: 7, uptime_ms: 1234, detail: "Armed".to_string() });
+    let mut buffer = Vec::new();
+    write_message(&mut buffer, &pong).unwrap();
+
+    let frame = FrameReader::new().read_frame(&mut &buffer[..]).unwrap();
+    assert!(frame.is::<Health>());
+    assert_eq!(pong, frame.decode().unwrap());
+  }
+}
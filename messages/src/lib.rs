@@ -2,6 +2,13 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate bincode;
+extern crate byteorder;
+extern crate crc;
 
+pub mod frame;
+pub mod handshake;
+pub mod health;
+pub mod discovery;
 pub mod drive_core;
-pub mod logger;
\ No newline at end of file
+pub mod logger;
+pub mod log_value;
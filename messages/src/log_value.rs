@@ -0,0 +1,352 @@
+// Values of logged variables and the types that describe them. Every variable has one type for
+// its whole life, named by a string when it's registered:
+//
+//   bool, int (i32), i64, u64, real (f32), f64, string
+//   real[N]                          N floats, like real[3] for a 3-vector
+//   struct Name{field:type,...}      A serde struct, e.g. struct GpsFix{latitude:f64,longitude:f64}
+//
+// In the log files, values are stored natively, the way bincode encodes them: little endian
+// numbers, bools as a byte, strings with their u64 length. Arrays and structs have a fixed layout
+// given by their type, so they're stored without a length.
+use bincode;
+use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
+use serde::Serialize;
+
+use std::fmt;
+use std::io;
+
+/// A value of a logged variable
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LogValue {
+  Bool(bool),
+  Int(i32),
+  I64(i64),
+  U64(u64),
+  Real(f32),
+  F64(f64),
+  Text(String),
+  Reals(Vec<f32>),
+  Struct(Vec<u8>),              // The bincode encoding of the struct
+}
+
+impl LogValue {
+  /// Encodes a serde struct. Its fields have to match the `LogType::Struct` it's logged as.
+  pub fn from_struct<T: Serialize>(value: &T) -> LogValue {
+    LogValue::Struct(bincode::serialize(value).expect("Structs always serialize"))
+  }
+
+  /// The value as a number, for plotting and statistics. Strings, arrays and structs have none.
+  pub fn as_f64(&self) -> Option<f64> {
+    match *self {
+      LogValue::Bool(value) => Some(if value { 1.0 } else { 0.0 }),
+      LogValue::Int(value) => Some(f64::from(value)),
+      LogValue::I64(value) => Some(value as f64),
+      LogValue::U64(value) => Some(value as f64),
+      LogValue::Real(value) => Some(f64::from(value)),
+      LogValue::F64(value) => Some(value),
+      LogValue::Text(_) | LogValue::Reals(_) | LogValue::Struct(_) => None,
+    }
+  }
+}
+
+impl From<bool> for LogValue {
+  fn from(value: bool) -> LogValue { LogValue::Bool(value) }
+}
+
+impl From<i32> for LogValue {
+  fn from(value: i32) -> LogValue { LogValue::Int(value) }
+}
+
+impl From<i64> for LogValue {
+  fn from(value: i64) -> LogValue { LogValue::I64(value) }
+}
+
+impl From<u64> for LogValue {
+  fn from(value: u64) -> LogValue { LogValue::U64(value) }
+}
+
+impl From<f32> for LogValue {
+  fn from(value: f32) -> LogValue { LogValue::Real(value) }
+}
+
+impl From<f64> for LogValue {
+  fn from(value: f64) -> LogValue { LogValue::F64(value) }
+}
+
+impl From<String> for LogValue {
+  fn from(value: String) -> LogValue { LogValue::Text(value) }
+}
+
+impl<'a> From<&'a str> for LogValue {
+  fn from(value: &'a str) -> LogValue { LogValue::Text(value.to_string()) }
+}
+
+/// The type of a logged variable
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogType {
+  Bool,
+  Int,
+  I64,
+  U64,
+  Real,
+  F64,
+  Text,
+  Reals(usize),
+  Struct(String, Vec<(String, LogType)>),   // Name and fields
+}
+
+impl LogType {
+  /// Parses a type name like `real` or `struct Imu{acceleration:real[3],rate:real[3]}`
+  pub fn parse(text: &str) -> Result<LogType, String> {
+    let mut rest = text.trim();
+    let log_type = parse_type(&mut rest)?;
+    if !rest.trim().is_empty() {
+      return Err(format!("Unexpected `{}` after the type in `{}`", rest.trim(), text));
+    }
+    Ok(log_type)
+  }
+
+  /// Appends `value` the way it's stored in the log files. Fails if the value isn't of this type.
+  pub fn encode(&self, value: &LogValue, out: &mut Vec<u8>) -> Result<(), String> {
+    match (self, value) {
+      (LogType::Bool, LogValue::Bool(value)) => out.push(*value as u8),
+      (LogType::Int, LogValue::Int(value)) => out.write_i32::<LittleEndian>(*value).unwrap(),
+      (LogType::I64, LogValue::I64(value)) => out.write_i64::<LittleEndian>(*value).unwrap(),
+      (LogType::U64, LogValue::U64(value)) => out.write_u64::<LittleEndian>(*value).unwrap(),
+      (LogType::Real, LogValue::Real(value)) => out.write_f32::<LittleEndian>(*value).unwrap(),
+      (LogType::F64, LogValue::F64(value)) => out.write_f64::<LittleEndian>(*value).unwrap(),
+      (LogType::Text, LogValue::Text(text)) => {
+        out.write_u64::<LittleEndian>(text.len() as u64).unwrap();
+        out.extend_from_slice(text.as_bytes());
+      },
+      (LogType::Reals(count), LogValue::Reals(values)) => {
+        if values.len() != *count {
+          return Err(format!("Expected {} values, got {}", count, values.len()));
+        }
+        for value in values {
+          out.write_f32::<LittleEndian>(*value).unwrap();
+        }
+      },
+      (LogType::Struct(..), LogValue::Struct(bytes)) => {
+        // The struct has to decode with our fields, or nobody can read the file later
+        let mut input = &bytes[..];
+        self.decode(&mut input).map_err(|e| format!("Struct doesn't match {}: {}", self, e))?;
+        if !input.is_empty() {
+          return Err(format!("Struct doesn't match {}: {} bytes left", self, input.len()));
+        }
+        out.extend_from_slice(bytes);
+      },
+      _ => return Err(format!("Can't store {:?} as {}", value, self)),
+    }
+    Ok(())
+  }
+
+  /// Reads a value stored by `encode`
+  pub fn decode(&self, input: &mut &[u8]) -> io::Result<LogValue> {
+    Ok(match *self {
+      LogType::Bool => LogValue::Bool(input.read_u8()? != 0),
+      LogType::Int => LogValue::Int(input.read_i32::<LittleEndian>()?),
+      LogType::I64 => LogValue::I64(input.read_i64::<LittleEndian>()?),
+      LogType::U64 => LogValue::U64(input.read_u64::<LittleEndian>()?),
+      LogType::Real => LogValue::Real(input.read_f32::<LittleEndian>()?),
+      LogType::F64 => LogValue::F64(input.read_f64::<LittleEndian>()?),
+      LogType::Text => {
+        let length = input.read_u64::<LittleEndian>()? as usize;
+        if length > input.len() {
+          return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let text = String::from_utf8(input[..length].to_vec())
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        *input = &input[length..];
+        LogValue::Text(text)
+      },
+      LogType::Reals(count) => LogValue::Reals((0..count)
+        .map(|_| input.read_f32::<LittleEndian>())
+        .collect::<io::Result<_>>()?),
+      LogType::Struct(_, ref fields) => {
+        let start = *input;
+        for (_, field_type) in fields {
+          field_type.decode(input)?;
+        }
+        LogValue::Struct(start[..start.len() - input.len()].to_vec())
+      },
+    })
+  }
+
+  /// The fields of a struct value, or the value itself for the other types
+  pub fn fields(&self, value: &LogValue) -> io::Result<Vec<(String, LogValue)>> {
+    match (self, value) {
+      (LogType::Struct(_, fields), LogValue::Struct(bytes)) => {
+        let mut input = &bytes[..];
+        fields.iter()
+          .map(|(name, field_type)| Ok((name.clone(), field_type.decode(&mut input)?)))
+          .collect()
+      },
+      _ => Ok(vec![(String::new(), value.clone())]),
+    }
+  }
+}
+
+impl fmt::Display for LogType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      LogType::Bool => write!(f, "bool"),
+      LogType::Int => write!(f, "int"),
+      LogType::I64 => write!(f, "i64"),
+      LogType::U64 => write!(f, "u64"),
+      LogType::Real => write!(f, "real"),
+      LogType::F64 => write!(f, "f64"),
+      LogType::Text => write!(f, "string"),
+      LogType::Reals(count) => write!(f, "real[{}]", count),
+      LogType::Struct(ref name, ref fields) => {
+        write!(f, "struct {}{{", name)?;
+        for (i, (field, field_type)) in fields.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{}:{}", field, field_type)?;
+        }
+        write!(f, "}}")
+      },
+    }
+  }
+}
+
+/// Parses the type at the start of `text` and advances it past the type
+fn parse_type(text: &mut &str) -> Result<LogType, String> {
+  let word = take_word(text);
+  let log_type = match word {
+    "bool" => LogType::Bool,
+    "int" => LogType::Int,
+    "i64" => LogType::I64,
+    "u64" => LogType::U64,
+    "real" if text.starts_with('[') => {
+      let end = text.find(']').ok_or_else(|| "Missing `]`".to_string())?;
+      let count = text[1..end].trim().parse()
+        .map_err(|_| format!("Invalid array length `{}`", &text[1..end]))?;
+      *text = &text[end + 1..];
+      LogType::Reals(count)
+    },
+    "real" => LogType::Real,
+    "f64" => LogType::F64,
+    "string" => LogType::Text,
+    "struct" => {
+      let name = take_word(text).to_string();
+      if name.is_empty() {
+        return Err("Missing struct name".to_string());
+      }
+      expect(text, '{')?;
+      let mut fields = Vec::new();
+      while !text.trim_start().starts_with('}') {
+        if !fields.is_empty() {
+          expect(text, ',')?;
+        }
+        let field = take_word(text).to_string();
+        if field.is_empty() {
+          return Err(format!("Missing field name in struct {}", name));
+        }
+        expect(text, ':')?;
+        fields.push((field, parse_type(text)?));
+      }
+      expect(text, '}')?;
+      LogType::Struct(name, fields)
+    },
+    "" => return Err("Missing type".to_string()),
+    _ => return Err(format!("Unknown type `{}`", word)),
+  };
+  Ok(log_type)
+}
+
+/// Takes the identifier at the start of `text`, skipping whitespace before it
+fn take_word<'a>(text: &mut &'a str) -> &'a str {
+  let trimmed = text.trim_start();
+  let end = trimmed.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(trimmed.len());
+  *text = &trimmed[end..];
+  &trimmed[..end]
+}
+
+fn expect(text: &mut &str, c: char) -> Result<(), String> {
+  let trimmed = text.trim_start();
+  if !trimmed.starts_with(c) {
+    return Err(format!("Expected `{}` at `{}`", c, trimmed));
+  }
+  *text = &trimmed[1..];
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize)]
+  struct GpsFix {
+    latitude: f64,
+    longitude: f64,
+    satellites: i32,
+    source: String,
+  }
+
+  fn gps_fix_type() -> LogType {
+    LogType::parse("struct GpsFix{latitude:f64, longitude:f64, satellites:int, source:string}")
+      .unwrap()
+  }
+
+  #[test]
+  fn type_names_round_trip() {
+    for name in &["bool", "int", "i64", "u64", "real", "f64", "string", "real[3]",
+                  "struct Imu{acceleration:real[3],rate:real[3],temperature:real}",
+                  "struct Pose{position:struct Point{x:f64,y:f64},heading:real}"] {
+      assert_eq!(*name, LogType::parse(name).unwrap().to_string());
+    }
+    assert_eq!(LogType::Reals(4), LogType::parse(" real[ 4 ] ").unwrap());
+  }
+
+  #[test]
+  fn invalid_type_names_are_rejected() {
+    for name in &["", "float", "real[x]", "real 3", "struct {x:int}", "struct A{x:int",
+                  "struct A{x}", "struct A{x:int y:int}"] {
+      assert!(LogType::parse(name).is_err(), "`{}` was accepted", name);
+    }
+  }
+
+  #[test]
+  fn values_are_stored_natively() {
+    let mut out = Vec::new();
+    LogType::Int.encode(&LogValue::Int(-2), &mut out).unwrap();
+    LogType::Text.encode(&"hi".into(), &mut out).unwrap();
+    LogType::Reals(2).encode(&LogValue::Reals(vec![1.0, 2.0]), &mut out).unwrap();
+    assert_eq!(bincode::serialize(&(-2i32, "hi", [1f32, 2f32])).unwrap(), out);
+
+    let mut input = &out[..];
+    assert_eq!(LogValue::Int(-2), LogType::Int.decode(&mut input).unwrap());
+    assert_eq!(LogValue::Text("hi".to_string()), LogType::Text.decode(&mut input).unwrap());
+    assert_eq!(LogValue::Reals(vec![1.0, 2.0]), LogType::Reals(2).decode(&mut input).unwrap());
+    assert!(input.is_empty());
+  }
+
+  #[test]
+  fn mismatching_values_are_refused() {
+    let mut out = Vec::new();
+    assert!(LogType::Int.encode(&LogValue::Real(1.0), &mut out).is_err());
+    assert!(LogType::Reals(3).encode(&LogValue::Reals(vec![1.0]), &mut out).is_err());
+    let short = LogValue::from_struct(&(1.0f64, 2.0f64));
+    assert!(gps_fix_type().encode(&short, &mut out).is_err());
+    assert!(out.is_empty());
+  }
+
+  #[test]
+  fn structs_decode_into_their_fields() {
+    let fix = GpsFix { latitude: 48.1, longitude: 11.5, satellites: 7, source: "rtk".into() };
+    let value = LogValue::from_struct(&fix);
+    let log_type = gps_fix_type();
+
+    let mut out = Vec::new();
+    log_type.encode(&value, &mut out).unwrap();
+    assert_eq!(value, log_type.decode(&mut &out[..]).unwrap());
+
+    let fields = log_type.fields(&value).unwrap();
+    assert_eq!(("latitude".to_string(), LogValue::F64(48.1)), fields[0]);
+    assert_eq!(("satellites".to_string(), LogValue::Int(7)), fields[2]);
+    assert_eq!(("source".to_string(), LogValue::Text("rtk".to_string())), fields[3]);
+  }
+}
@@ -1,8 +1,22 @@
+use frame::Message;
+use log_value::LogValue;
+
+// Types are registered by name, as `LogType` prints them. Timestamps are microseconds since the
+// UNIX epoch. Log records are stamped by the producer when the value changes, on the logger's
+// clock: producers estimate the offset of their clock to the logger's with a TimeRequest now and
+// then (like NTP does).
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageType {
   Register(String, String),   // Name and type
   Acknowledge(i32),           // Log ID
-  Log(i32, f32),              // Log ID and value
+  Log(i32, u64, LogValue),    // Log ID, timestamp and value
+  TimeRequest(u64),           // Producer's time of sending
+  TimeResponse(u64, u64, u64),  // The request's time, logger's time of receiving and answering
+  Refuse(String),             // Answers a Register the logger can't log, with the reason
+}
+
+impl Message for MessageType {
+  const TYPE_ID: u16 = 2;
 }
 
 #[cfg(test)]
@@ -39,16 +53,31 @@ mod tests {
     }
   }
 
+  #[test]
+  fn serialize_refuse() {
+    let out = MessageType::Refuse("Unknown type `float`".to_string());
+    let vec = serialize(&out).unwrap();
+
+    let msg = deserialize(&vec[..]).unwrap();
+    match msg {
+      MessageType::Refuse(reason) => {
+        assert_eq!("Unknown type `float`", reason);
+      },
+      _ => panic!("Deserialized the wrong value")
+    }
+  }
+
   #[test]
   fn serialize_log_message() {
-    let out = MessageType::Log(42, 12.34f32);
+    let out = MessageType::Log(42, 1_500_000_000_000_000, LogValue::Reals(vec![1.5, -2.0]));
     let vec = serialize(&out).unwrap();
 
     let msg = deserialize(&vec[..]).unwrap();
     match msg {
-      MessageType::Log(log_id, val) => {
+      MessageType::Log(log_id, timestamp, val) => {
         assert_eq!(42, log_id);
-        assert_eq!(12.34f32, val);
+        assert_eq!(1_500_000_000_000_000, timestamp);
+        assert_eq!(LogValue::Reals(vec![1.5, -2.0]), val);
       },
       _ => panic!("Deserialized the wrong value")
     }
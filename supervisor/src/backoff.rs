@@ -0,0 +1,52 @@
+// Exponential backoff between restarts, so a service that crashes right away doesn't keep the
+// machine busy with restarting it
+use std::time::Duration;
+
+pub struct Backoff {
+  initial: Duration,
+  max: Duration,
+  next: Duration,
+}
+
+impl Backoff {
+  pub fn new(initial: Duration, max: Duration) -> Backoff {
+    Backoff { initial, max, next: initial }
+  }
+
+  /// The wait before the next restart. Every call doubles the following one.
+  pub fn next_delay(&mut self) -> Duration {
+    let delay = self.next;
+    self.next = (self.next * 2).min(self.max);
+    delay
+  }
+
+  /// The service runs fine again, the next failure starts with the initial wait
+  pub fn reset(&mut self) {
+    self.next = self.initial;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+  }
+
+  #[test]
+  fn delays_double_up_to_the_limit() {
+    let mut backoff = Backoff::new(ms(500), ms(3000));
+    let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+    assert_eq!(vec![ms(500), ms(1000), ms(2000), ms(3000), ms(3000)], delays);
+  }
+
+  #[test]
+  fn reset_starts_over() {
+    let mut backoff = Backoff::new(ms(500), ms(3000));
+    backoff.next_delay();
+    backoff.next_delay();
+    backoff.reset();
+    assert_eq!(ms(500), backoff.next_delay());
+  }
+}
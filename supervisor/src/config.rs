@@ -0,0 +1,223 @@
+use util::mesh::Service;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use toml;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/aicc/supervisor.toml";
+
+fn default_health_interval_ms() -> u64 { 500 }
+fn default_health_timeout_ms() -> u64 { 3000 }
+fn default_startup_timeout_ms() -> u64 { 10000 }
+fn default_backoff_initial_ms() -> u64 { 500 }
+fn default_backoff_max_ms() -> u64 { 30000 }
+fn default_status_interval_ms() -> u64 { 60000 }
+
+/// Timeouts of the supervisor and how to start the services, loaded from a TOML file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+  /// Running services are pinged this often
+  #[serde(default = "default_health_interval_ms")]
+  pub health_interval_ms: u64,
+
+  /// A service that didn't answer a health check for this long gets restarted
+  #[serde(default = "default_health_timeout_ms")]
+  pub health_timeout_ms: u64,
+
+  /// Like health_timeout_ms, but for the first answer after a start
+  #[serde(default = "default_startup_timeout_ms")]
+  pub startup_timeout_ms: u64,
+
+  /// Wait before the first restart of a failed service
+  #[serde(default = "default_backoff_initial_ms")]
+  pub backoff_initial_ms: u64,
+
+  /// The wait doubles with every failure in a row, up to this limit
+  #[serde(default = "default_backoff_max_ms")]
+  pub backoff_max_ms: u64,
+
+  /// The status table is printed at least this often
+  #[serde(default = "default_status_interval_ms")]
+  pub status_interval_ms: u64,
+
+  /// How to start the services, by service name
+  #[serde(default)]
+  pub services: BTreeMap<String, ServiceConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+  pub command: PathBuf,     // Executable of the service
+  #[serde(default)]
+  pub args: Vec<String>,    // Command line arguments to start it with
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(io::Error),
+  Parse(toml::de::Error),
+  Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+  fn invalid(field: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid { field: field.to_string(), reason: reason.to_string() }
+  }
+}
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigError::Io(ref e) => write!(f, "Could not read configuration: {}", e),
+      ConfigError::Parse(ref e) => write!(f, "Could not parse configuration: {}", e),
+      ConfigError::Invalid { ref field, ref reason } =>
+        write!(f, "Invalid configuration value for `{}`: {}", field, reason),
+    }
+  }
+}
+
+impl From<io::Error> for ConfigError {
+  fn from(e: io::Error) -> Self {
+    ConfigError::Io(e)
+  }
+}
+
+impl From<toml::de::Error> for ConfigError {
+  fn from(e: toml::de::Error) -> Self {
+    ConfigError::Parse(e)
+  }
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      health_interval_ms: default_health_interval_ms(),
+      health_timeout_ms: default_health_timeout_ms(),
+      startup_timeout_ms: default_startup_timeout_ms(),
+      backoff_initial_ms: default_backoff_initial_ms(),
+      backoff_max_ms: default_backoff_max_ms(),
+      status_interval_ms: default_status_interval_ms(),
+      services: BTreeMap::new(),
+    }
+  }
+}
+
+impl Config {
+  /// Reads and validates the configuration file at `path`
+  pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Config::parse(&contents)
+  }
+
+  pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+    let config: Config = toml::from_str(contents)?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    if self.health_interval_ms == 0 {
+      return Err(ConfigError::invalid("health_interval_ms", "must be positive"));
+    }
+    if self.health_timeout_ms < self.health_interval_ms {
+      return Err(ConfigError::invalid(
+        "health_timeout_ms", "must not be shorter than health_interval_ms"));
+    }
+    if self.startup_timeout_ms < self.health_interval_ms {
+      return Err(ConfigError::invalid(
+        "startup_timeout_ms", "must not be shorter than health_interval_ms"));
+    }
+    if self.backoff_max_ms < self.backoff_initial_ms {
+      return Err(ConfigError::invalid(
+        "backoff_max_ms", "must not be shorter than backoff_initial_ms"));
+    }
+
+    for name in self.services.keys() {
+      if !Service::all().iter().any(|service| service.name() == name) {
+        return Err(ConfigError::invalid(&format!("services.{}", name), "no such service"));
+      }
+    }
+    Ok(())
+  }
+
+  /// How to start `service`. Without a section in the configuration, the executable is expected
+  /// where `make flash` puts it: in a directory named like the service, next to ours.
+  pub fn service(&self, service: Service) -> ServiceConfig {
+    if let Some(config) = self.services.get(service.name()) {
+      return config.clone();
+    }
+    let base_dir = env::current_exe().ok()
+      .and_then(|exe| exe.parent().and_then(Path::parent).map(Path::to_path_buf))
+      .unwrap_or_default();
+    ServiceConfig { command: base_dir.join(service.name()).join(service.name()), args: Vec::new() }
+  }
+
+  pub fn health_interval(&self) -> Duration {
+    Duration::from_millis(self.health_interval_ms)
+  }
+
+  pub fn health_timeout(&self) -> Duration {
+    Duration::from_millis(self.health_timeout_ms)
+  }
+
+  pub fn startup_timeout(&self) -> Duration {
+    Duration::from_millis(self.startup_timeout_ms)
+  }
+
+  pub fn status_interval(&self) -> Duration {
+    Duration::from_millis(self.status_interval_ms)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_invalid(contents: &str, expected_field: &str) {
+    match Config::parse(contents) {
+      Err(ConfigError::Invalid { field, .. }) => assert_eq!(expected_field, field),
+      r => panic!("Unexpected result {:?}", r)
+    }
+  }
+
+  #[test]
+  fn it_parses_the_example() {
+    let mut contents = String::new();
+    File::open("config/supervisor.toml").unwrap().read_to_string(&mut contents).unwrap();
+    let config = Config::parse(&contents).unwrap();
+    assert_eq!(500, config.health_interval_ms);
+    assert_eq!(PathBuf::from("/home/nvidia/aicc/logger/logger"),
+      config.service(Service::Logger).command);
+  }
+
+  #[test]
+  fn everything_has_defaults() {
+    assert_eq!(Config::default(), Config::parse("").unwrap());
+  }
+
+  #[test]
+  fn services_default_to_the_flashed_layout() {
+    let command = Config::default().service(Service::DriveCore).command;
+    assert!(command.ends_with("drive-core/drive-core"));
+  }
+
+  #[test]
+  fn it_names_the_invalid_field() {
+    assert_invalid("health_timeout_ms = 100", "health_timeout_ms");
+    assert_invalid("backoff_max_ms = 10", "backoff_max_ms");
+    assert_invalid("[services.planner]\ncommand = \"/bin/true\"", "services.planner");
+  }
+}
@@ -0,0 +1,83 @@
+// Pings a service on its regular port. The connection stays open between the checks, so a
+// check costs one small frame in each direction.
+use messages::frame::{ write_message, FrameError, FrameReader };
+use messages::handshake;
+use messages::health::{ Health, HealthReport };
+
+use util::mesh::Service;
+
+use std::io;
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::time::{ Duration, Instant };
+
+// How long a single check may block the supervisor
+const CHECK_TIMEOUT: Duration = Duration::from_millis(250);
+
+pub struct HealthCheck {
+  service: Service,
+  connection: Option<(TcpStream, FrameReader)>,
+  seq: u32,
+}
+
+impl HealthCheck {
+  pub fn new(service: Service) -> HealthCheck {
+    HealthCheck { service, connection: None, seq: 0 }
+  }
+
+  /// Forgets the connection, e.g. because the service was restarted
+  pub fn reset(&mut self) {
+    self.connection = None;
+  }
+
+  /// Pings the service and waits for its answer. Any failure closes the connection, the next
+  /// check connects again.
+  pub fn check(&mut self) -> Result<HealthReport, String> {
+    let result = self.ping();
+    if result.is_err() {
+      self.reset();
+    }
+    result
+  }
+
+  fn ping(&mut self) -> Result<HealthReport, String> {
+    if self.connection.is_none() {
+      self.connection = Some(self.connect().map_err(|e| e.to_string())?);
+    }
+    let (ref mut socket, ref mut reader) = *self.connection.as_mut().unwrap();
+
+    self.seq = self.seq.wrapping_add(1);
+    write_message(socket, &Health::Ping(self.seq)).map_err(|e| e.to_string())?;
+
+    // Services may send other messages to all clients, like the status of drive-core
+    let deadline = Instant::now() + CHECK_TIMEOUT;
+    while Instant::now() < deadline {
+      let frame = match reader.read_frame(socket) {
+        Ok(frame) => frame,
+        Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock
+            || e.kind() == io::ErrorKind::TimedOut => continue,
+        Err(FrameError::BadChecksum) => continue,
+        Err(e) => return Err(e.to_string()),
+      };
+      if let Ok(Health::Pong(report)) = frame.decode() {
+        if report.seq == self.seq {
+          return Ok(report);
+        }
+      }
+    }
+    Err("No answer".to_string())
+  }
+
+  fn connect(&self) -> io::Result<(TcpStream, FrameReader)> {
+    let endpoint = self.service.endpoint();
+    let addr = endpoint.to_socket_addrs()?.next().ok_or_else(||
+      io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", endpoint)))?;
+    let mut socket = TcpStream::connect_timeout(&addr, CHECK_TIMEOUT)?;
+    socket.set_nodelay(true)?;
+    socket.set_read_timeout(Some(CHECK_TIMEOUT / 5))?;
+
+    let mut reader = FrameReader::new();
+    handshake::connect(&mut socket, &mut reader, "aicc-supervisor", env!("CARGO_PKG_VERSION"))
+      .map_err(io::Error::from)?;
+    Ok((socket, reader))
+  }
+}
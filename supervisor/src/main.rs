@@ -0,0 +1,180 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate signal_hook;
+extern crate nix;
+extern crate clap;
+
+extern crate messages;
+extern crate util;
+
+mod backoff;
+mod config;
+mod health;
+mod status;
+mod supervised;
+
+use config::*;
+use health::HealthCheck;
+use supervised::{ Action, State, Supervised };
+use util::mesh::{ load_registry, Service };
+
+use std::io;
+use std::path::Path;
+use std::process::{ Child, Command };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use clap::{ Arg, App };
+use nix::sys::signal::{ self, Signal };
+use nix::unistd::Pid;
+
+// Interval of the main loop. Exited services are noticed this quickly.
+const TICK: Duration = Duration::from_millis(100);
+
+// Services get this long to shut down cleanly before they are killed
+const STOP_GRACE: Duration = Duration::from_secs(3);
+
+fn load_config(path: &Path, explicit: bool) -> Config {
+  match Config::load(path) {
+    Ok(config) => config,
+    // Without a configuration file, the services are started from where `make flash` puts them
+    Err(ConfigError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && !explicit => {
+      println!("{} not found, using the built-in configuration.", path.display());
+      Config::default()
+    },
+    Err(e) => {
+      println!("{}", e);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn spawn(config: &ServiceConfig) -> io::Result<Child> {
+  Command::new(&config.command).args(&config.args).spawn()
+}
+
+/// Asks `child` to quit with SIGINT, so it can shut down cleanly (drive-core switches off its
+/// outputs), and kills it if it doesn't in time. Returns how it ended.
+fn stop(child: &mut Child) -> String {
+  let _ = signal::kill(Pid::from_raw(child.id() as i32), Signal::SIGINT);
+  let deadline = Instant::now() + STOP_GRACE;
+  while Instant::now() < deadline {
+    match child.try_wait() {
+      Ok(Some(status)) => return status.to_string(),
+      Ok(None) => thread::sleep(TICK),
+      Err(_) => break,
+    }
+  }
+  let _ = child.kill();
+  child.wait().map(|status| status.to_string()).unwrap_or_else(|e| e.to_string())
+}
+
+fn main() {
+  let matches = App::new("aicc-supervisor")
+    .author("David Bauske <david.bauske@googlemail.com>")
+    .about("Starts the AICC services in dependency order, checks their health and restarts them \
+      when they fail.")
+    .arg(Arg::with_name("config")
+      .short("c")
+      .long("config")
+      .help("Sets the configuration file")
+      .default_value(DEFAULT_CONFIG_PATH)
+      .takes_value(true)
+    )
+    .get_matches();
+
+  if let Err(e) = load_registry() {
+    println!("{}, using the default service addresses.", e);
+  }
+
+  let config = load_config(Path::new(matches.value_of("config").unwrap()),
+    matches.occurrences_of("config") > 0);
+
+  // SIGINT and SIGTERM (systemctl stop) shut down the services and the supervisor
+  let shutdown = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::SIGINT, shutdown.clone()).unwrap();
+  signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone()).unwrap();
+
+  let start = Instant::now();
+  let mut services: Vec<Supervised> =
+    Service::all().iter().map(|service| Supervised::new(*service, &config)).collect();
+  let mut children: Vec<Option<Child>> = services.iter().map(|_| None).collect();
+  let mut checks: Vec<HealthCheck> =
+    Service::all().iter().map(|service| HealthCheck::new(*service)).collect();
+
+  let mut next_check = Duration::from_secs(0);
+  let mut last_summary = Vec::new();
+  let mut next_table = Duration::from_secs(0);
+
+  while !shutdown.load(Ordering::Acquire) {
+    let now = start.elapsed();
+    let check_due = now >= next_check;
+    if check_due {
+      next_check = now + config.health_interval();
+    }
+
+    // Services come in dependency order, so a dependency is always handled first
+    for i in 0..services.len() {
+      let exit_status =
+        children[i].as_mut().and_then(|child| child.try_wait().ok()).and_then(|status| status);
+      if let Some(status) = exit_status {
+        children[i] = None;
+        checks[i].reset();
+        services[i].exited(now, &status.to_string());
+      }
+
+      if check_due && children[i].is_some() {
+        if let Ok(report) = checks[i].check() {
+          services[i].healthy(start.elapsed(), &report.detail);
+        }
+      }
+
+      let dependencies_running = services[i].service.dependencies().iter().all(|dependency|
+        services.iter().any(|s| s.service == *dependency && s.state == State::Running));
+      match services[i].update(start.elapsed(), dependencies_running, &config) {
+        Action::Start => {
+          let service_config = config.service(services[i].service);
+          match spawn(&service_config) {
+            Ok(child) => {
+              services[i].started(start.elapsed(), child.id());
+              children[i] = Some(child);
+            },
+            Err(e) => services[i].start_failed(start.elapsed(),
+              &format!("Failed to start {}: {}", service_config.command.display(), e)),
+          }
+        },
+        Action::Stop => {
+          if let Some(mut child) = children[i].take() {
+            println!("Stopping {}.", services[i].service.name());
+            let status = stop(&mut child);
+            checks[i].reset();
+            services[i].exited(start.elapsed(), &status);
+          }
+        },
+        Action::Nothing => {},
+      }
+    }
+
+    // Print the table whenever a service changes its state, and every once in a while
+    let summary: Vec<_> = services.iter().map(|s| (s.state, s.pid)).collect();
+    if summary != last_summary || now >= next_table {
+      print!("{}", status::table(&services, now));
+      last_summary = summary;
+      next_table = now + config.status_interval();
+    }
+
+    thread::sleep(TICK);
+  }
+
+  // Dependents go first, so drive-core doesn't lose its logger while shutting down
+  for i in (0..services.len()).rev() {
+    if let Some(mut child) = children[i].take() {
+      println!("Stopping {}.", services[i].service.name());
+      println!("{} {}", services[i].service.name(), stop(&mut child));
+    }
+  }
+}
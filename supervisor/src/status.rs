@@ -0,0 +1,61 @@
+// The status table of all supervised services
+use supervised::Supervised;
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Formats `duration` like 1h02m, 3m12s or 45s
+fn format_uptime(duration: Duration) -> String {
+  let secs = duration.as_secs();
+  if secs >= 3600 {
+    format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+  } else if secs >= 60 {
+    format!("{}m{:02}s", secs / 60, secs % 60)
+  } else {
+    format!("{}s", secs)
+  }
+}
+
+pub fn table(services: &[Supervised], now: Duration) -> String {
+  let mut table = format!("{:<11} {:<9} {:<6} {:<9} {:<7} {}\n",
+    "SERVICE", "STATE", "PID", "RESTARTS", "UPTIME", "DETAIL");
+  for supervised in services {
+    let pid = supervised.pid.map_or("-".to_string(), |pid| pid.to_string());
+    let uptime = match supervised.pid {
+      Some(_) => supervised.uptime(now).map_or("-".to_string(), format_uptime),
+      None => "-".to_string(),
+    };
+    let _ = writeln!(table, "{:<11} {:<9} {:<6} {:<9} {:<7} {}", supervised.service.name(),
+      format!("{:?}", supervised.state), pid, supervised.restarts, uptime, supervised.detail);
+  }
+  table
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use config::Config;
+  use util::mesh::Service;
+
+  #[test]
+  fn uptimes_are_short() {
+    assert_eq!("45s", format_uptime(Duration::from_secs(45)));
+    assert_eq!("3m12s", format_uptime(Duration::from_secs(192)));
+    assert_eq!("1h02m", format_uptime(Duration::from_secs(3720)));
+  }
+
+  #[test]
+  fn it_lists_every_service() {
+    let config = Config::default();
+    let mut logger = Supervised::new(Service::Logger, &config);
+    logger.started(Duration::from_secs(0), 812);
+    logger.healthy(Duration::from_secs(1), "4 streams");
+    let drive_core = Supervised::new(Service::DriveCore, &config);
+
+    let table = table(&[logger, drive_core], Duration::from_secs(192));
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!("SERVICE     STATE     PID    RESTARTS  UPTIME  DETAIL", lines[0]);
+    assert_eq!("logger      Running   812    0         3m12s   4 streams", lines[1]);
+    assert_eq!("drive-core  Waiting   -      0         -       ", lines[2]);
+  }
+}
@@ -0,0 +1,217 @@
+// The life cycle of one supervised service. This only decides what to do: starting and stopping
+// the process and the health checks are up to the caller, which reports back what happened. All
+// methods get the current time passed in, so the decisions can be tested against a simulated
+// clock.
+use backoff::Backoff;
+use config::Config;
+
+use util::mesh::Service;
+
+use std::time::Duration;
+
+// A service that ran this long without a failure gets its backoff reset
+const STABLE_RUN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+  Waiting,                  // For its dependencies to run
+  Starting,                 // Launched, but didn't answer a health check yet
+  Running,                  // Answers health checks
+  Backoff,                  // Failed, waiting before the next start
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+  Nothing,
+  Start,
+  Stop,                     // The process has to go, report back with exited()
+}
+
+pub struct Supervised {
+  pub service: Service,
+  pub state: State,
+  pub pid: Option<u32>,
+  pub restarts: u32,
+  pub detail: String,       // From the last health report, or why the service failed
+  stop_reason: Option<String>,  // Why we asked for a Stop
+  started_at: Option<Duration>,
+  last_healthy: Option<Duration>,
+  retry_at: Duration,
+  backoff: Backoff,
+}
+
+impl Supervised {
+  pub fn new(service: Service, config: &Config) -> Supervised {
+    Supervised {
+      service,
+      state: State::Waiting,
+      pid: None,
+      restarts: 0,
+      detail: String::new(),
+      stop_reason: None,
+      started_at: None,
+      last_healthy: None,
+      retry_at: Duration::from_secs(0),
+      backoff: Backoff::new(Duration::from_millis(config.backoff_initial_ms),
+        Duration::from_millis(config.backoff_max_ms)),
+    }
+  }
+
+  /// How long the current process has been running
+  pub fn uptime(&self, now: Duration) -> Option<Duration> {
+    self.started_at.map(|started_at| now.checked_sub(started_at).unwrap_or_default())
+  }
+
+  /// Decides what to do next. `dependencies_running` tells whether all services this one
+  /// depends on answer their health checks.
+  pub fn update(&mut self, now: Duration, dependencies_running: bool, config: &Config) -> Action {
+    match self.state {
+      State::Waiting if dependencies_running => Action::Start,
+      State::Waiting => Action::Nothing,
+      State::Starting if now >= self.started_at.unwrap_or_default() + config.startup_timeout() => {
+        self.stop_reason = Some("No answer after the start".to_string());
+        Action::Stop
+      },
+      State::Running if now >= self.last_healthy.unwrap_or_default() + config.health_timeout() => {
+        self.stop_reason = Some("Stopped answering health checks".to_string());
+        Action::Stop
+      },
+      State::Starting | State::Running => Action::Nothing,
+      State::Backoff if now >= self.retry_at => {
+        self.state = State::Waiting;
+        self.update(now, dependencies_running, config)
+      },
+      State::Backoff => Action::Nothing,
+    }
+  }
+
+  /// The process of the service was launched
+  pub fn started(&mut self, now: Duration, pid: u32) {
+    if self.started_at.is_some() {
+      self.restarts += 1;
+    }
+    self.state = State::Starting;
+    self.pid = Some(pid);
+    self.started_at = Some(now);
+    self.last_healthy = None;
+    self.detail = String::new();
+  }
+
+  /// The process couldn't be launched
+  pub fn start_failed(&mut self, now: Duration, reason: &str) {
+    self.detail = reason.to_string();
+    self.fail(now);
+  }
+
+  /// The service answered a health check
+  pub fn healthy(&mut self, now: Duration, detail: &str) {
+    if self.state != State::Starting && self.state != State::Running {
+      return;
+    }
+    self.state = State::Running;
+    self.last_healthy = Some(now);
+    self.detail = detail.to_string();
+    if self.uptime(now).is_some_and(|uptime| uptime >= STABLE_RUN) {
+      self.backoff.reset();
+    }
+  }
+
+  /// The process is gone, either on its own or after a Stop
+  pub fn exited(&mut self, now: Duration, reason: &str) {
+    self.detail = self.stop_reason.take().unwrap_or_else(|| reason.to_string());
+    self.pid = None;
+    self.fail(now);
+  }
+
+  fn fail(&mut self, now: Duration) {
+    self.state = State::Backoff;
+    self.retry_at = now + self.backoff.next_delay();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+  }
+
+  fn config() -> Config {
+    Config {
+      health_timeout_ms: 1000,
+      startup_timeout_ms: 2000,
+      backoff_initial_ms: 500,
+      backoff_max_ms: 4000,
+      ..Config::default()
+    }
+  }
+
+  fn running(config: &Config) -> Supervised {
+    let mut supervised = Supervised::new(Service::Logger, config);
+    supervised.started(ms(0), 42);
+    supervised.healthy(ms(100), "3 streams");
+    supervised
+  }
+
+  #[test]
+  fn it_waits_for_its_dependencies() {
+    let config = config();
+    let mut supervised = Supervised::new(Service::DriveCore, &config);
+    assert_eq!(Action::Nothing, supervised.update(ms(0), false, &config));
+    assert_eq!(Action::Start, supervised.update(ms(10), true, &config));
+
+    supervised.started(ms(10), 42);
+    assert_eq!(State::Starting, supervised.state);
+    supervised.healthy(ms(300), "Disarmed, 1 clients");
+    assert_eq!(State::Running, supervised.state);
+    assert_eq!(Some(ms(290)), supervised.uptime(ms(300)));
+  }
+
+  #[test]
+  fn silent_services_are_stopped() {
+    let config = config();
+    let mut supervised = running(&config);
+    assert_eq!(Action::Nothing, supervised.update(ms(1000), true, &config));
+    assert_eq!(Action::Stop, supervised.update(ms(1100), true, &config));
+    supervised.exited(ms(1150), "signal: 2");
+    assert_eq!("Stopped answering health checks", supervised.detail);
+
+    let mut starting = Supervised::new(Service::Logger, &config);
+    starting.started(ms(0), 42);
+    assert_eq!(Action::Nothing, starting.update(ms(1500), true, &config));
+    assert_eq!(Action::Stop, starting.update(ms(2000), true, &config));
+  }
+
+  #[test]
+  fn restarts_back_off() {
+    let config = config();
+    let mut supervised = running(&config);
+    supervised.exited(ms(200), "exit code: 101");
+    assert_eq!(State::Backoff, supervised.state);
+    assert_eq!("exit code: 101", supervised.detail);
+
+    assert_eq!(Action::Nothing, supervised.update(ms(600), true, &config));
+    assert_eq!(Action::Start, supervised.update(ms(700), true, &config));
+    supervised.started(ms(700), 43);
+    assert_eq!(1, supervised.restarts);
+
+    // The second failure in a row waits twice as long
+    supervised.exited(ms(800), "exit code: 101");
+    assert_eq!(Action::Nothing, supervised.update(ms(1700), true, &config));
+    assert_eq!(Action::Start, supervised.update(ms(1800), true, &config));
+  }
+
+  #[test]
+  fn a_stable_run_resets_the_backoff() {
+    let config = config();
+    let mut supervised = running(&config);
+    supervised.exited(ms(200), "killed");
+    supervised.start_failed(ms(700), "No such file");
+
+    supervised.started(ms(2000), 43);
+    supervised.healthy(ms(2000) + STABLE_RUN, "");
+    supervised.exited(ms(2000) + STABLE_RUN, "killed");
+    assert_eq!(Action::Start, supervised.update(ms(2500) + STABLE_RUN, true, &config));
+  }
+}
@@ -1,7 +1,11 @@
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate bincode;
 extern crate byteorder;
 extern crate chrono;
+extern crate toml;
+extern crate crossbeam_queue;
 
 #[macro_use]
 extern crate lazy_static;
@@ -1,32 +1,86 @@
+use messages::log_value::{ LogType, LogValue };
+
+/// A type that can be logged. Structs implement it with a `LogType::Struct` that lists their
+/// fields in declaration order, and `LogValue::from_struct`.
 pub trait TypeInfo {
-  fn type_str() -> &'static str;
-  fn from_f32(val: f32) -> Self;
+  fn log_type() -> LogType;
+  fn to_value(&self) -> LogValue;
+
+  /// The name the type is registered with
+  fn type_str() -> String {
+    Self::log_type().to_string()
+  }
+}
+
+impl TypeInfo for bool {
+  fn log_type() -> LogType {
+    LogType::Bool
+  }
+  fn to_value(&self) -> LogValue {
+    LogValue::Bool(*self)
+  }
 }
 
 impl TypeInfo for i32 {
-  fn type_str() -> &'static str {
-    "int"
+  fn log_type() -> LogType {
+    LogType::Int
+  }
+  fn to_value(&self) -> LogValue {
+    LogValue::Int(*self)
+  }
+}
+
+impl TypeInfo for i64 {
+  fn log_type() -> LogType {
+    LogType::I64
+  }
+  fn to_value(&self) -> LogValue {
+    LogValue::I64(*self)
+  }
+}
+
+impl TypeInfo for u64 {
+  fn log_type() -> LogType {
+    LogType::U64
   }
-  fn from_f32(val: f32) -> Self {
-    val as i32
+  fn to_value(&self) -> LogValue {
+    LogValue::U64(*self)
   }
 }
 
 impl TypeInfo for f32 {
-  fn type_str() -> &'static str {
-    "real"
+  fn log_type() -> LogType {
+    LogType::Real
   }
-  fn from_f32(val: f32) -> Self {
-    val
+  fn to_value(&self) -> LogValue {
+    LogValue::Real(*self)
   }
 }
 
-impl TypeInfo for bool {
-  fn type_str() -> &'static str {
-    "bool"
+impl TypeInfo for f64 {
+  fn log_type() -> LogType {
+    LogType::F64
   }
-  fn from_f32(val: f32) -> Self {
-    val != 0.0
+  fn to_value(&self) -> LogValue {
+    LogValue::F64(*self)
+  }
+}
+
+impl TypeInfo for String {
+  fn log_type() -> LogType {
+    LogType::Text
+  }
+  fn to_value(&self) -> LogValue {
+    LogValue::Text(self.clone())
+  }
+}
+
+impl<const N: usize> TypeInfo for [f32; N] {
+  fn log_type() -> LogType {
+    LogType::Reals(N)
+  }
+  fn to_value(&self) -> LogValue {
+    LogValue::Reals(self.to_vec())
   }
 }
 
@@ -34,6 +88,24 @@ impl TypeInfo for bool {
 mod tests {
   use super::*;
 
+  #[derive(Serialize)]
+  struct Imu {
+    acceleration: [f32; 3],
+    temperature: f32,
+  }
+
+  impl TypeInfo for Imu {
+    fn log_type() -> LogType {
+      LogType::Struct("Imu".to_string(), vec![
+        ("acceleration".to_string(), <[f32; 3]>::log_type()),
+        ("temperature".to_string(), f32::log_type()),
+      ])
+    }
+    fn to_value(&self) -> LogValue {
+      LogValue::from_struct(self)
+    }
+  }
+
   #[test]
   fn it_provides_type_info_for_i32() {
     assert_eq!("int", i32::type_str());
@@ -48,4 +120,28 @@ mod tests {
   fn it_provides_type_info_for_bool() {
     assert_eq!("bool", bool::type_str());
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn it_provides_type_info_for_wide_numbers_and_text() {
+    assert_eq!("i64", i64::type_str());
+    assert_eq!("u64", u64::type_str());
+    assert_eq!("f64", f64::type_str());
+    assert_eq!("string", String::type_str());
+    assert_eq!("real[3]", <[f32; 3]>::type_str());
+  }
+
+  #[test]
+  fn values_keep_their_precision() {
+    assert_eq!(LogValue::I64(1 << 60), (1i64 << 60).to_value());
+    assert_eq!(LogValue::F64(0.1), 0.1f64.to_value());
+    assert_eq!(LogValue::Reals(vec![1.0, 2.0, 3.0]), [1f32, 2f32, 3f32].to_value());
+  }
+
+  #[test]
+  fn structs_describe_their_fields() {
+    assert_eq!("struct Imu{acceleration:real[3],temperature:real}", Imu::type_str());
+    let imu = Imu { acceleration: [0.0, 0.0, 9.81], temperature: 31.5 };
+    let fields = Imu::log_type().fields(&imu.to_value()).unwrap();
+    assert_eq!(("temperature".to_string(), LogValue::Real(31.5)), fields[1]);
+  }
+}
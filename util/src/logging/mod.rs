@@ -1,105 +1,523 @@
 pub mod data_types;
 
 use std::io;
-use std::io::{ Write };
+use std::io::Write;
 use std::net::{ TcpStream };
-use std::fmt::Display;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
 
-use serde::Serialize;
-use bincode::{ serialize, ErrorKind, deserialize_from };
-
-use mesh::Service;
+use crossbeam_queue::ArrayQueue;
+use mesh::{ Endpoint, Service };
 use logging::data_types::TypeInfo;
-use variable::{ Variable, ListenerError };
+use timing::{ unix_micros, ClockSync };
+use variable::Variable;
+use messages::frame::{ encode, write_message, FrameReader };
+use messages::handshake;
+use messages::log_value::LogValue;
 use messages::logger::MessageType;
 
+// Records kept while the logger is unreachable. When the queue is full, the oldest records are
+// dropped: the newest ones tell more about why the logger was missed.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 8192;
+
+// Wait between two attempts to reach the logger
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the logger may take to acknowledge a registration or to accept a batch
+const LOGGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The writer sends whatever was queued at most this long ago
+const FLUSH_INTERVAL: Duration = Duration::from_millis(5);
+
+// Upper limit of records sent in one write
+const MAX_BATCH: usize = 512;
+
+// Clock offset exchanges right after connecting, and the interval of the following ones
+const INITIAL_CLOCK_SYNCS: usize = 4;
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A variable registered with `LogConnection::register`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogId(usize);
+
+/// Counters of a connection to the logger
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LogStats {
+  pub sent: u64,              // Records written to the logger
+  pub batches: u64,           // Writes they took
+  pub buffered: usize,        // Records waiting to be sent
+  pub dropped: u64,           // Records lost because the queue was full
+  pub reconnects: u64,        // Connections made after the first one
+}
+
+#[derive(Clone)]
+struct Registration {
+  name: String,
+  type_name: String,
+}
+
+// A record: the variable, when it changed (on our clock) and its value
+type Record = (LogId, u64, LogValue);
+
+// A live connection: the socket, and the logger's ID for each registered variable
+// The logger's IDs of the registered variables, None for those it refused
+type Connected = (TcpStream, FrameReader, Vec<Option<i32>>);
+
+// State shared by all handles of a connection and its writer thread
+struct Shared {
+  endpoint: Endpoint,
+  name: String,
+  software_version: String,
+  registrations: Mutex<Vec<Registration>>,  // Indexed by LogId
+  queue: ArrayQueue<Record>,
+  retry_interval: Duration,
+  stopping: AtomicBool,
+  sent: AtomicU64,
+  batches: AtomicU64,
+  dropped: AtomicU64,
+  reconnects: AtomicU64,
+  in_flight: AtomicU64,                     // Records taken from the queue, but not written yet
+}
+
+// Stops the writer thread once the last handle of the connection is gone
+struct Writer {
+  shared: Arc<Shared>,
+  thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+  fn drop(&mut self) {
+    self.shared.stopping.store(true, Ordering::Release);
+    if let Some(thread) = self.thread.take() {
+      thread.thread().unpark();
+      let _ = thread.join();
+    }
+  }
+}
+
+/// A connection to the logging service that can be shared by all threads of a program. Logging
+/// stamps the record and puts it into a lock-free queue, so it never blocks; a writer thread sends
+/// the records to the logger in batches, with their timestamps converted to the logger's clock.
+/// While the logger is unreachable, the queue keeps the newest records and the writer tries to
+/// reach it again. Once it is back, the variables are registered again and the queue is replayed.
+#[derive(Clone)]
 pub struct LogConnection {
-  socket: Rc<RefCell<TcpStream>>,
+  shared: Arc<Shared>,
+  _writer: Arc<Writer>,
 }
 
 impl LogConnection {
-  pub fn new() -> io::Result<LogConnection> {
-    let addr = "localhost:".to_owned() + &Service::Logger.port().to_string();
-    let socket = TcpStream::connect(addr)?;
+  /// Connects to the logging service and introduces this program as `name`. The connection is
+  /// made in the background, so this doesn't wait for (or even need) the logger.
+  pub fn new(name: &str, software_version: &str) -> LogConnection {
+    LogConnection::with_endpoint(Service::Logger.endpoint(), name, software_version,
+      DEFAULT_BUFFER_CAPACITY, RECONNECT_INTERVAL)
+  }
+
+  fn with_endpoint(endpoint: Endpoint,
+                   name: &str,
+                   software_version: &str,
+                   capacity: usize,
+                   retry_interval: Duration) -> LogConnection {
+    let shared = Arc::new(Shared {
+      endpoint,
+      name: name.to_string(),
+      software_version: software_version.to_string(),
+      registrations: Mutex::new(Vec::new()),
+      queue: ArrayQueue::new(capacity),
+      retry_interval,
+      stopping: AtomicBool::new(false),
+      sent: AtomicU64::new(0),
+      batches: AtomicU64::new(0),
+      dropped: AtomicU64::new(0),
+      reconnects: AtomicU64::new(0),
+      in_flight: AtomicU64::new(0),
+    });
 
-    Ok(LogConnection { socket: Rc::new(RefCell::new(socket)) })
+    let writer_shared = shared.clone();
+    let thread = thread::Builder::new()
+      .name("log-writer".to_string())
+      .spawn(move || write_records(&writer_shared))
+      .expect("Failed to start the log writer");
+    let writer = Writer { shared: shared.clone(), thread: Some(thread) };
+    LogConnection { shared, _writer: Arc::new(writer) }
   }
 
-  pub fn log_variable<'a, T>(&mut self, var: &mut Variable<'a, T>, name: &str) -> io::Result<()>
-    where T: PartialEq + TypeInfo + Serialize + Display + Copy + Into<f32> + 'a {
-    // Attempt to register our log variable with the logging service
-    match serialize(&MessageType::Register(name.to_string(), T::type_str().to_string())) {
-      Ok(msg) => {
-        self.socket.borrow_mut().write(&msg[..])?;
-      },
-      Err(e) => {
-        match *e {
-          ErrorKind::Io(err) => return Err(err),
-          _ => return Err(io::Error::new(io::ErrorKind::Other, *e))
-        }
+  /// Registers a variable called `name` of type `type_name` (see `LogType`). The logger learns
+  /// about it with the next batch. If it refuses the variable, e.g. for an unknown type, the
+  /// values of the variable are dropped.
+  pub fn register(&self, name: &str, type_name: &str) -> LogId {
+    let mut registrations = self.shared.registrations.lock().unwrap();
+    registrations.push(Registration { name: name.to_string(), type_name: type_name.to_string() });
+    LogId(registrations.len() - 1)
+  }
+
+  /// Queues a value of a registered variable, stamped with the current time. Never blocks: if
+  /// the queue is full, the oldest record is dropped. The value has to be of the registered type,
+  /// the logger drops it otherwise.
+  pub fn log<V: Into<LogValue>>(&self, id: LogId, value: V) {
+    if self.shared.queue.force_push((id, unix_micros(), value.into())).is_some() {
+      self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  /// Logs every change of `var` as `name`
+  pub fn log_variable<'a, T>(&self, var: &mut Variable<'a, T>, name: &str)
+    where T: PartialEq + TypeInfo + 'a {
+    let id = self.register(name, &T::type_str());
+    let connection = self.clone();
+    var.add_listener(move |val| {
+      connection.log(id, val.to_value());
+      Ok(())
+    });
+  }
+
+  pub fn stats(&self) -> LogStats {
+    let shared = &self.shared;
+    LogStats {
+      sent: shared.sent.load(Ordering::Relaxed),
+      batches: shared.batches.load(Ordering::Relaxed),
+      buffered: shared.queue.len() + shared.in_flight.load(Ordering::Relaxed) as usize,
+      dropped: shared.dropped.load(Ordering::Relaxed),
+      reconnects: shared.reconnects.load(Ordering::Relaxed),
+    }
+  }
+}
+
+/// The writer thread. Runs until the last handle of the connection is dropped, and sends what's
+/// still queued then if the logger is connected.
+fn write_records(shared: &Shared) {
+  let mut link: Option<Connected> = None;
+  let mut batch: Vec<Record> = Vec::with_capacity(MAX_BATCH);
+  let mut connected_before = false;
+  let mut clock = ClockSync::new();
+  let mut next_clock_sync = Instant::now();
+
+  loop {
+    let stopping = shared.stopping.load(Ordering::Acquire);
+    if link.is_none() {
+      if stopping {
+        break;
+      }
+      match connect(shared, &mut clock) {
+        Ok(connected) => {
+          if connected_before {
+            shared.reconnects.fetch_add(1, Ordering::Relaxed);
+            println!("Reconnected to the logger, replaying {} records ({} records were dropped)",
+              shared.queue.len() + batch.len(), shared.dropped.load(Ordering::Relaxed));
+          }
+          connected_before = true;
+          link = Some(connected);
+          next_clock_sync = Instant::now() + CLOCK_SYNC_INTERVAL;
+        },
+        Err(_) => thread::park_timeout(shared.retry_interval),
       }
+      continue;
     }
 
-    // Wait for the response
-    let msg: MessageType = match deserialize_from(&mut *self.socket.borrow_mut()) {
-      Ok(msg) => msg,
+    let result = if Instant::now() >= next_clock_sync {
+      next_clock_sync += CLOCK_SYNC_INTERVAL;
+      let (ref mut socket, ref mut reader, _) = *link.as_mut().unwrap();
+      sync_clock(socket, reader, &mut clock).map(|_| true)
+    } else {
+      send_queued(shared, link.as_mut().unwrap(), &clock, &mut batch)
+    };
+    match result {
+      Ok(true) => {},
+      Ok(false) if stopping => break,
+      Ok(false) => thread::park_timeout(FLUSH_INTERVAL),
       Err(e) => {
-        match *e {
-          ErrorKind::Io(err) => return Err(err),
-          _ => return Err(io::Error::new(io::ErrorKind::Other, *e))
+        println!("Lost the connection to the logger: {}. Buffering up to {} records.",
+          e, shared.queue.capacity());
+        link = None;
+      }
+    }
+  }
+}
+
+/// Registers the variables that are new since the last call and sends the next batch. A batch
+/// that fails stays in `batch`, to be sent again after reconnecting. Returns false if there was
+/// nothing to send.
+fn send_queued(shared: &Shared,
+               &mut (ref mut socket, ref mut reader, ref mut ids): &mut Connected,
+               clock: &ClockSync,
+               batch: &mut Vec<Record>) -> io::Result<bool> {
+  while batch.len() < MAX_BATCH {
+    match shared.queue.pop() {
+      Some(record) => batch.push(record),
+      None => break,
+    }
+  }
+  shared.in_flight.store(batch.len() as u64, Ordering::Relaxed);
+
+  // Records can't be queued before their variable is registered, so every record of the batch
+  // has an ID after this
+  let new_registrations = shared.registrations.lock().unwrap()[ids.len()..].to_vec();
+  for registration in &new_registrations {
+    ids.push(register(socket, reader, registration)?);
+  }
+  if batch.is_empty() {
+    return Ok(false);
+  }
+
+  let sent = write_batch(socket, ids, clock, batch)?;
+  shared.sent.fetch_add(sent as u64, Ordering::Relaxed);
+  shared.batches.fetch_add(1, Ordering::Relaxed);
+  batch.clear();
+  shared.in_flight.store(0, Ordering::Relaxed);
+  Ok(true)
+}
+
+/// Sends all records of `batch` with a single write, except those of refused variables. Returns
+/// the number of records sent.
+fn write_batch(socket: &mut TcpStream,
+               ids: &[Option<i32>],
+               clock: &ClockSync,
+               batch: &[Record]) -> io::Result<usize> {
+  let mut bytes = Vec::new();
+  let mut sent = 0;
+  for &(LogId(index), timestamp, ref value) in batch {
+    if let Some(id) = ids[index] {
+      let msg = MessageType::Log(id, clock.to_remote(timestamp), value.clone());
+      bytes.extend_from_slice(&encode(&msg)?);
+      sent += 1;
+    }
+  }
+  socket.write_all(&bytes)?;
+  Ok(sent)
+}
+
+/// Connects to the logger, registers the variables known so far and estimates the offset of the
+/// logger's clock
+fn connect(shared: &Shared, clock: &mut ClockSync) -> io::Result<Connected> {
+  let mut socket = TcpStream::connect(&shared.endpoint)?;
+  socket.set_read_timeout(Some(LOGGER_TIMEOUT))?;
+  socket.set_write_timeout(Some(LOGGER_TIMEOUT))?;
+
+  let mut reader = FrameReader::new();
+  handshake::connect(&mut socket, &mut reader, &shared.name, &shared.software_version)?;
+
+  let registrations = shared.registrations.lock().unwrap().clone();
+  let ids = registrations.iter()
+    .map(|registration| register(&mut socket, &mut reader, registration))
+    .collect::<io::Result<_>>()?;
+
+  for _ in 0..INITIAL_CLOCK_SYNCS {
+    sync_clock(&mut socket, &mut reader, clock)?;
+  }
+  Ok((socket, reader, ids))
+}
+
+/// Performs one exchange with the logger to estimate the offset of its clock
+fn sync_clock(socket: &mut TcpStream,
+              reader: &mut FrameReader,
+              clock: &mut ClockSync) -> io::Result<()> {
+  let sent = unix_micros();
+  write_message(socket, &MessageType::TimeRequest(sent))?;
+  match reader.read_message(socket)? {
+    MessageType::TimeResponse(request, received, answered) if request == sent => {
+      clock.add(sent, received, answered, unix_micros());
+      Ok(())
+    },
+    _ => Err(io::Error::other("Unexpected response from logging service")),
+  }
+}
+
+/// Registers a variable with the logging service and returns the ID the logger gave it, or None
+/// if the logger refused it
+fn register(socket: &mut TcpStream,
+            reader: &mut FrameReader,
+            registration: &Registration) -> io::Result<Option<i32>> {
+  write_message(socket,
+    &MessageType::Register(registration.name.clone(), registration.type_name.clone()))?;
+
+  match reader.read_message(socket)? {
+    // All fine, we got accepted and here is our ID
+    MessageType::Acknowledge(id) => Ok(Some(id)),
+    // Its records are dropped, the other variables are logged as usual
+    MessageType::Refuse(reason) => {
+      println!("The logger refused {}: {}", registration.name, reason);
+      Ok(None)
+    },
+    _ => Err(io::Error::other("Unexpected response from logging service")),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use messages::handshake::Handshake;
+  use std::net::TcpListener;
+  use std::time::Instant;
+
+  /// Accepts one client like the logger does and returns the first `count` records it logs. The
+  /// variables get the IDs 0, 1, ... in the order of their registration, those of the type
+  /// `no such type` are refused. The logger's clock is `clock_offset` microseconds ahead of ours.
+  fn fake_logger(listener: TcpListener, count: usize, clock_offset: i64)
+    -> thread::JoinHandle<Vec<(i32, u64, LogValue)>> {
+    thread::spawn(move || {
+      let clock = || (unix_micros() as i64 + clock_offset) as u64;
+      let (mut socket, _) = listener.accept().unwrap();
+      let mut reader = FrameReader::new();
+      let hello: Handshake = reader.read_message(&mut socket).unwrap();
+      write_message(&mut socket, &hello.answer("logger", "0.1.0").0).unwrap();
+
+      let mut registered = 0;
+      let mut values = Vec::new();
+      while values.len() < count {
+        match reader.read_message(&mut socket).unwrap() {
+          MessageType::Register(_, ref type_name) if type_name == "no such type" => {
+            write_message(&mut socket, &MessageType::Refuse("Unknown type".to_string())).unwrap();
+          },
+          MessageType::Register(..) => {
+            write_message(&mut socket, &MessageType::Acknowledge(registered)).unwrap();
+            registered += 1;
+          },
+          MessageType::TimeRequest(sent) => {
+            let response = MessageType::TimeResponse(sent, clock(), clock());
+            write_message(&mut socket, &response).unwrap();
+          },
+          MessageType::Log(id, timestamp, value) => values.push((id, timestamp, value)),
+          msg => panic!("Unexpected message {:?}", msg),
         }
       }
-    };
+      values
+    })
+  }
 
-    // Got the response => handle it
-    let log_id = match msg {
-      // All fine, we got accepted and here is our ID
-      MessageType::Acknowledge(id) => id,
-      _ => return Err(io::Error::new(
-        io::ErrorKind::Other, "Unexpected response from logging service"))
-    };
+  fn values(records: Vec<(i32, u64, LogValue)>) -> Vec<(i32, f32)> {
+    records.into_iter().map(|(id, _, value)| (id, value.as_f64().unwrap() as f32)).collect()
+  }
 
-    let socket = self.socket.clone();
+  fn wait_for<F: Fn(&LogStats) -> bool>(connection: &LogConnection, condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition(&connection.stats()) {
+      assert!(Instant::now() < deadline, "Gave up waiting, stats: {:?}", connection.stats());
+      thread::sleep(Duration::from_millis(5));
+    }
+  }
 
-    // Attach a logging listener to our variable
-    var.add_listener(move |val| {
-      match serialize(&MessageType::Log(log_id, (*val).into())) {
-        Ok(msg) => {
-          match socket.borrow_mut().write(&msg[..]) {
-            Ok(_) => {},
-            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset
-              || e.kind() == io::ErrorKind::BrokenPipe => {
-              return Err(ListenerError::RemoveListener);
-            }
-            // Don't crash the program in case of an error, just write something to the console.
-            // Logging is not ciritcal.
-            Err(e) => println!("Failed to send log message: {:?}", e),
-          };
-        },
-        Err(e) => {
-          println!("Failed to serialize log message: {:?}", e);
+  fn connection(port: u16, capacity: usize) -> LogConnection {
+    LogConnection::with_endpoint(Endpoint::new("127.0.0.1", port), "test", "0.1.0", capacity,
+      Duration::from_millis(10))
+  }
+
+  #[test]
+  fn values_are_sent_to_the_logger() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connection = connection(listener.local_addr().unwrap().port(), 16);
+    let logger = fake_logger(listener, 2, 0);
+
+    let mut var = Variable::new(0f32);
+    connection.log_variable(&mut var, "test_value");
+    var.set_value(1f32);
+    var.set_value(2f32);
+
+    wait_for(&connection, |stats| stats.sent == 2);
+    assert_eq!(vec![(0, 1f32), (0, 2f32)], values(logger.join().unwrap()));
+  }
+
+  #[test]
+  fn typed_values_arrive_unchanged() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connection = connection(listener.local_addr().unwrap().port(), 16);
+    let logger = fake_logger(listener, 3, 0);
+
+    let mut event = Variable::new(String::new());
+    connection.log_variable(&mut event, "test_event");
+    event.set_value("lap started".to_string());
+    let id = connection.register("test_count", "u64");
+    connection.log(id, u64::MAX);
+    let mut acceleration = Variable::new([0f32; 3]);
+    connection.log_variable(&mut acceleration, "test_acceleration");
+    acceleration.set_value([0.5, -0.25, 9.81]);
+
+    let logged: Vec<_> = logger.join().unwrap().into_iter().map(|(_, _, value)| value).collect();
+    assert_eq!(vec![LogValue::Text("lap started".to_string()), LogValue::U64(u64::MAX),
+      LogValue::Reals(vec![0.5, -0.25, 9.81])], logged);
+  }
+
+  #[test]
+  fn records_are_buffered_until_the_logger_is_back() {
+    // Find a port nobody listens on
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let connection = connection(port, 3);
+
+    let id = connection.register("test_count", "real");
+    for i in 1..6 {
+      connection.log(id, i as f32);
+    }
+    let stats = connection.stats();
+    assert_eq!((0, 3, 2), (stats.sent, stats.buffered, stats.dropped));
+
+    let logger = fake_logger(TcpListener::bind(("127.0.0.1", port)).unwrap(), 3, 0);
+    wait_for(&connection, |stats| stats.buffered == 0);
+    assert_eq!(vec![(0, 3f32), (0, 4f32), (0, 5f32)], values(logger.join().unwrap()));
+    assert_eq!(1, connection.stats().batches);
+  }
+
+  #[test]
+  fn records_of_refused_variables_are_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connection = connection(listener.local_addr().unwrap().port(), 16);
+    let logger = fake_logger(listener, 2, 0);
+
+    let refused = connection.register("test_mode", "no such type");
+    let id = connection.register("test_value", "real");
+    connection.log(refused, 1f32);
+    connection.log(id, 2f32);
+    connection.log(refused, 3f32);
+    connection.log(id, 4f32);
+
+    assert_eq!(vec![(0, 2f32), (0, 4f32)], values(logger.join().unwrap()));
+    wait_for(&connection, |stats| stats.buffered == 0);
+    assert_eq!(2, connection.stats().sent);
+  }
+
+  #[test]
+  fn threads_share_a_connection() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let connection = connection(port, 1000);
+
+    let threads: Vec<_> = (0..4).map(|i| {
+      let connection = connection.clone();
+      thread::spawn(move || {
+        let id = connection.register(&format!("test_thread{}", i), "real");
+        for value in 0..100 {
+          connection.log(id, value as f32);
         }
-      };
-      Ok(())
-    });
+      })
+    }).collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+
+    let logger = fake_logger(TcpListener::bind(("127.0.0.1", port)).unwrap(), 400, 0);
+    let values = values(logger.join().unwrap());
+    for id in 0..4 {
+      let logged: Vec<_> = values.iter().filter(|v| v.0 == id).map(|v| v.1).collect();
+      assert_eq!((0..100).map(|v| v as f32).collect::<Vec<_>>(), logged);
+    }
+    wait_for(&connection, |stats| stats.sent == 400);
+    assert!(connection.stats().batches < 10);
+  }
+
+  #[test]
+  fn records_are_stamped_on_the_loggers_clock() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let connection = connection(port, 16);
+    let id = connection.register("test_value", "real");
+
+    // Stamped while the logger was away, an hour ahead of us
+    let logged_at = unix_micros();
+    connection.log(id, 1f32);
+    let hour = 3_600_000_000;
+    let logger = fake_logger(TcpListener::bind(("127.0.0.1", port)).unwrap(), 1, hour);
 
-//  let path = logging::get_timestamped_path()?;
-//  let mut log_stream: LogStream<T> = LogStream::new(
-//    &path.join(format!("{}.ebl", name)),
-//    &format!("drive-core_{}", name))?;
-//
-//  // Log the variable's initial value
-//  log_stream.log(*var.value())?;
-//
-//  var.add_listener(move |val| {
-//    match log_stream.log(*val) {
-//      Ok(()) => {},
-//      Err(e) => println!("Failed to log value {}: {:?}", val, e)
-//    }
-//  });
-
-    Ok(())
+    let (_, timestamp, _) = logger.join().unwrap()[0];
+    let error = timestamp as i64 - (logged_at as i64 + hour);
+    assert!(error.abs() < 50_000, "Timestamp is off by {} us", error);
   }
 }
@@ -0,0 +1,184 @@
+// Finds services on the LAN. Services announce themselves every second with a small UDP multicast
+// datagram; listeners collect the announcements and forget services that fell silent. Multicast
+// datagrams are sent with a TTL of 1, so they never leave the local network.
+use super::Service;
+
+use messages::discovery::Announcement;
+use messages::frame::{ encode, FrameReader };
+
+use std::io;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr, UdpSocket };
+use std::time::{ Duration, Instant };
+
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 41, 33);
+pub const DISCOVERY_PORT: u16 = 41332;
+
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// A service that wasn't heard of for this long is considered gone
+const EXPIRY: Duration = Duration::from_secs(3);
+
+/// Sends the announcements of one service
+pub struct Announcer {
+  socket: UdpSocket,
+  datagram: Vec<u8>,
+  next: Instant,
+}
+
+impl Announcer {
+  /// Prepares announcing `service` of `car`, listening on `port`
+  pub fn new(car: &str, service: Service, port: u16, software_version: &str)
+    -> io::Result<Announcer> {
+    let announcement = Announcement {
+      car: car.to_string(),
+      service: service.name().to_string(),
+      port,
+      software_version: software_version.to_string(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_nonblocking(true)?;
+    Ok(Announcer { socket, datagram: encode(&announcement)?, next: Instant::now() })
+  }
+
+  /// Sends the announcement if the last one is ANNOUNCE_INTERVAL ago
+  pub fn announce(&mut self, now: Instant) -> io::Result<()> {
+    if now < self.next {
+      return Ok(());
+    }
+    self.next = now + ANNOUNCE_INTERVAL;
+    self.socket.send_to(&self.datagram, (DISCOVERY_GROUP, DISCOVERY_PORT))?;
+    Ok(())
+  }
+}
+
+/// A service that announced itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discovered {
+  pub car: String,
+  pub service: Service,
+  pub address: SocketAddr,        // Where to connect to the service
+  pub software_version: String,
+  pub last_seen: Instant,
+}
+
+/// Collects the announcements on the LAN
+pub struct Discovery {
+  socket: UdpSocket,
+  found: Vec<Discovered>,
+}
+
+impl Discovery {
+  pub fn new() -> io::Result<Discovery> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], DISCOVERY_PORT)))?;
+    socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::new(0, 0, 0, 0))?;
+    socket.set_nonblocking(true)?;
+    Ok(Discovery { socket, found: Vec::new() })
+  }
+
+  /// Processes all announcements received since the last call
+  pub fn poll(&mut self, now: Instant) -> io::Result<()> {
+    let mut datagram = [0u8; 1024];
+    loop {
+      match self.socket.recv_from(&mut datagram) {
+        Ok((length, sender)) => self.received(&datagram[..length], sender.ip(), now),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => return Err(e),
+      }
+    }
+    self.found.retain(|discovered| now.duration_since(discovered.last_seen) < EXPIRY);
+    Ok(())
+  }
+
+  /// Listens for `duration` and returns everything that was found
+  pub fn browse(&mut self, duration: Duration) -> io::Result<&[Discovered]> {
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+      self.poll(Instant::now())?;
+      ::std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(&self.found)
+  }
+
+  /// The instances of `service` found so far, sorted by car
+  pub fn instances(&self, service: Service) -> Vec<&Discovered> {
+    let mut instances: Vec<_> = self.found.iter().filter(|d| d.service == service).collect();
+    instances.sort_by(|a, b| a.car.cmp(&b.car));
+    instances
+  }
+
+  /// Handles one datagram. Anything that isn't a valid announcement is ignored, there may be
+  /// other programs using the same group.
+  fn received(&mut self, datagram: &[u8], sender: IpAddr, now: Instant) {
+    let announcement: Announcement = match FrameReader::new().read_message(&mut &datagram[..]) {
+      Ok(announcement) => announcement,
+      Err(_) => return,
+    };
+    let service = match Service::from_name(&announcement.service) {
+      Some(service) => service,
+      None => return,
+    };
+
+    let discovered = Discovered {
+      car: announcement.car,
+      service,
+      address: SocketAddr::new(sender, announcement.port),
+      software_version: announcement.software_version,
+      last_seen: now,
+    };
+    match self.found.iter_mut().find(|d| d.service == service && d.address == discovered.address) {
+      Some(known) => *known = discovered,
+      None => self.found.push(discovered),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn datagram(car: &str, service: &str) -> Vec<u8> {
+    encode(&Announcement {
+      car: car.to_string(),
+      service: service.to_string(),
+      port: 41330,
+      software_version: "0.1.0".to_string(),
+    }).unwrap()
+  }
+
+  fn discovery() -> Discovery {
+    // Not joined to the group, the tests feed the datagrams directly
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    Discovery { socket, found: Vec::new() }
+  }
+
+  #[test]
+  fn announcements_are_collected_per_sender() {
+    let mut discovery = discovery();
+    let now = Instant::now();
+    let red = IpAddr::from([192, 168, 1, 10]);
+    let blue = IpAddr::from([192, 168, 1, 11]);
+    discovery.received(&datagram("red", "drive-core"), red, now);
+    discovery.received(&datagram("red", "drive-core"), red, now);
+    discovery.received(&datagram("blue", "drive-core"), blue, now);
+    discovery.received(&datagram("blue", "camera"), blue, now);
+    discovery.received(b"not a frame", blue, now);
+
+    let cars = discovery.instances(Service::DriveCore);
+    assert_eq!(2, cars.len());
+    assert_eq!("blue", cars[0].car);
+    assert_eq!(SocketAddr::new(red, 41330), cars[1].address);
+  }
+
+  #[test]
+  fn silent_services_expire() {
+    let mut discovery = discovery();
+    let start = Instant::now();
+    discovery.received(&datagram("red", "drive-core"), IpAddr::from([192, 168, 1, 10]), start);
+    discovery.poll(start + Duration::from_secs(1)).unwrap();
+    assert_eq!(1, discovery.instances(Service::DriveCore).len());
+    discovery.poll(start + EXPIRY).unwrap();
+    assert!(discovery.instances(Service::DriveCore).is_empty());
+  }
+}
@@ -1,10 +1,37 @@
 // Contains information about the organization of the mesh of microservices that makes up the AICC
+pub mod discovery;
+pub mod registry;
+
+pub use self::registry::{ load_registry, registry, Endpoint };
+
+use std::net::SocketAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Service {
   DriveCore,
   Logger,
 }
 
 impl Service {
+  /// All services, in an order in which each one comes after its dependencies
+  pub fn all() -> &'static [Service] {
+    &[Service::Logger, Service::DriveCore]
+  }
+
+  /// Services that have to run before this one can start
+  pub fn dependencies(&self) -> &'static [Service] {
+    match *self {
+      Service::DriveCore => &[Service::Logger],
+      Service::Logger => &[],
+    }
+  }
+
+  /// Finds a service by its name
+  pub fn from_name(name: &str) -> Option<Service> {
+    Service::all().iter().cloned().find(|service| service.name() == name)
+  }
+
+  /// Default port of the service. The mesh registry may move it to another one.
   pub fn port(&self) -> u16 {
     match *self {
       Service::DriveCore => 41330,
@@ -12,12 +39,22 @@ impl Service {
     }
   }
 
-  pub fn name(&self) -> &str {
+  pub fn name(&self) -> &'static str {
     match *self {
       Service::DriveCore => "drive-core",
       Service::Logger => "logger",
     }
   }
+
+  /// Where clients reach the service, according to the mesh registry
+  pub fn endpoint(&self) -> Endpoint {
+    registry().endpoint(*self).clone()
+  }
+
+  /// The address the service itself listens on: all interfaces, at the port of its endpoint
+  pub fn listen_address(&self) -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], registry().endpoint(*self).port))
+  }
 }
 
 #[cfg(test)]
@@ -35,4 +72,21 @@ mod tests {
     assert_eq!("drive-core", Service::DriveCore.name());
     assert_eq!("logger", Service::Logger.name());
   }
+
+  #[test]
+  fn from_name_finds_every_service() {
+    for service in Service::all() {
+      assert_eq!(Some(*service), Service::from_name(service.name()));
+    }
+    assert_eq!(None, Service::from_name("camera"));
+  }
+
+  #[test]
+  fn all_lists_dependencies_first() {
+    for (i, service) in Service::all().iter().enumerate() {
+      for dependency in service.dependencies() {
+        assert!(Service::all()[..i].contains(dependency), "{:?} before {:?}", service, dependency);
+      }
+    }
+  }
 }
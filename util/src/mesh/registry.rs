@@ -0,0 +1,321 @@
+// Resolves services to the address they can be reached at. The defaults put every service on
+// this machine, the mesh configuration file and environment variables move them elsewhere:
+//
+//   /etc/aicc/mesh.toml (or the file named by AICC_MESH_CONFIG)
+//   AICC_<SERVICE>_ADDR=host[:port], like AICC_LOGGER_ADDR=logbox:41331
+//
+// Environment variables win over the file, so a single program can be pointed somewhere else
+// for a test.
+use super::Service;
+
+use std::collections::{ BTreeMap, HashMap };
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{ SocketAddr, ToSocketAddrs };
+use std::path::Path;
+use std::sync::OnceLock;
+use std::vec;
+
+use toml;
+
+pub const DEFAULT_MESH_CONFIG_PATH: &str = "/etc/aicc/mesh.toml";
+
+// Environment variable naming another mesh configuration file
+pub const MESH_CONFIG_VAR: &str = "AICC_MESH_CONFIG";
+
+// Name of the car if neither the configuration nor the host name tell it
+const DEFAULT_CAR_NAME: &str = "aicc";
+
+/// A host and a TCP port a service listens on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+  pub host: String,
+  pub port: u16,
+}
+
+impl Endpoint {
+  pub fn new(host: &str, port: u16) -> Endpoint {
+    Endpoint { host: host.to_string(), port }
+  }
+
+  /// Parses `host[:port]`. Without a port, `default_port` is used.
+  pub fn parse(text: &str, default_port: u16) -> Result<Endpoint, String> {
+    let (host, port) = match text.rfind(':') {
+      Some(colon) => {
+        let port = text[colon + 1..].parse()
+          .map_err(|_| format!("Invalid port in `{}`", text))?;
+        (&text[..colon], port)
+      },
+      None => (text, default_port),
+    };
+    if host.is_empty() {
+      return Err(format!("Missing host in `{}`", text));
+    }
+    if port == 0 {
+      return Err(format!("Invalid port in `{}`", text));
+    }
+    Ok(Endpoint::new(host, port))
+  }
+}
+
+impl fmt::Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.host, self.port)
+  }
+}
+
+impl ToSocketAddrs for Endpoint {
+  type Iter = vec::IntoIter<SocketAddr>;
+
+  fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+    (self.host.as_str(), self.port).to_socket_addrs()
+  }
+}
+
+#[derive(Debug)]
+pub enum MeshError {
+  Io(io::Error),
+  Parse(toml::de::Error),
+  Invalid { field: String, reason: String },
+}
+
+impl MeshError {
+  fn invalid(field: &str, reason: &str) -> MeshError {
+    MeshError::Invalid { field: field.to_string(), reason: reason.to_string() }
+  }
+}
+
+impl Error for MeshError {}
+
+impl fmt::Display for MeshError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      MeshError::Io(ref e) => write!(f, "Could not read mesh configuration: {}", e),
+      MeshError::Parse(ref e) => write!(f, "Could not parse mesh configuration: {}", e),
+      MeshError::Invalid { ref field, ref reason } =>
+        write!(f, "Invalid mesh configuration value for `{}`: {}", field, reason),
+    }
+  }
+}
+
+impl From<io::Error> for MeshError {
+  fn from(e: io::Error) -> Self {
+    MeshError::Io(e)
+  }
+}
+
+impl From<toml::de::Error> for MeshError {
+  fn from(e: toml::de::Error) -> Self {
+    MeshError::Parse(e)
+  }
+}
+
+// Layout of the mesh configuration file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshFile {
+  name: Option<String>,
+  #[serde(default)]
+  services: BTreeMap<String, EndpointFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndpointFile {
+  host: Option<String>,
+  port: Option<u16>,
+}
+
+/// Where each service of the mesh can be reached, and the name of the car
+#[derive(Clone, Debug)]
+pub struct Registry {
+  name: String,
+  endpoints: HashMap<Service, Endpoint>,
+}
+
+impl Registry {
+  /// Every service on localhost, at its default port
+  pub fn new() -> Registry {
+    let endpoints = Service::all().iter()
+      .map(|service| (*service, Endpoint::new("localhost", service.port())))
+      .collect();
+    Registry { name: host_name().unwrap_or_else(|| DEFAULT_CAR_NAME.to_string()), endpoints }
+  }
+
+  /// Reads the contents of a mesh configuration file. Services it doesn't mention keep their
+  /// defaults.
+  pub fn parse(text: &str) -> Result<Registry, MeshError> {
+    let file: MeshFile = toml::from_str(text)?;
+    let mut registry = Registry::new();
+
+    if let Some(name) = file.name {
+      if name.is_empty() {
+        return Err(MeshError::invalid("name", "Is empty"));
+      }
+      registry.name = name;
+    }
+
+    for (name, endpoint) in file.services {
+      let field = format!("services.{}", name);
+      let service = Service::from_name(&name)
+        .ok_or_else(|| MeshError::invalid(&field, "Unknown service"))?;
+      let entry = registry.endpoints.get_mut(&service).unwrap();
+      if let Some(host) = endpoint.host {
+        if host.is_empty() {
+          return Err(MeshError::invalid(&(field + ".host"), "Is empty"));
+        }
+        entry.host = host;
+      }
+      if let Some(port) = endpoint.port {
+        if port == 0 {
+          return Err(MeshError::invalid(&(field + ".port"), "Must not be 0"));
+        }
+        entry.port = port;
+      }
+    }
+    Ok(registry)
+  }
+
+  /// Loads the mesh configuration file at `path`. A missing file means the defaults.
+  pub fn load(path: &Path) -> Result<Registry, MeshError> {
+    match fs::read_to_string(path) {
+      Ok(text) => Registry::parse(&text),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Registry::new()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Loads the mesh configuration file and applies the overrides from the environment
+  pub fn from_environment() -> Result<Registry, MeshError> {
+    let path = env::var(MESH_CONFIG_VAR).unwrap_or_else(|_| DEFAULT_MESH_CONFIG_PATH.to_string());
+    let mut registry = Registry::load(Path::new(&path))?;
+    registry.apply_overrides(|var| env::var(var).ok())?;
+    Ok(registry)
+  }
+
+  /// Moves every service for which `lookup` knows its override variable
+  pub fn apply_overrides<F>(&mut self, lookup: F) -> Result<(), MeshError>
+    where F: Fn(&str) -> Option<String> {
+    for service in Service::all() {
+      let var = override_var(*service);
+      if let Some(value) = lookup(&var) {
+        let endpoint = Endpoint::parse(&value, service.port())
+          .map_err(|reason| MeshError::invalid(&var, &reason))?;
+        self.endpoints.insert(*service, endpoint);
+      }
+    }
+    Ok(())
+  }
+
+  /// Name of the car this machine belongs to, as it is announced on the LAN
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn endpoint(&self, service: Service) -> &Endpoint {
+    &self.endpoints[&service]
+  }
+}
+
+impl Default for Registry {
+  fn default() -> Registry {
+    Registry::new()
+  }
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Loads the registry of this process from the mesh configuration and the environment. Programs
+/// call this at startup and decide themselves what a broken configuration means; without it,
+/// `registry` has the default service addresses.
+pub fn load_registry() -> Result<&'static Registry, MeshError> {
+  let loaded = Registry::from_environment()?;
+  if REGISTRY.set(loaded).is_err() {
+    return Err(MeshError::invalid(MESH_CONFIG_VAR, "has to be loaded before the first lookup"));
+  }
+  Ok(registry())
+}
+
+/// The registry of this process, see `load_registry`
+pub fn registry() -> &'static Registry {
+  REGISTRY.get_or_init(Registry::new)
+}
+
+/// The environment variable that overrides the address of `service`, like AICC_DRIVE_CORE_ADDR
+fn override_var(service: Service) -> String {
+  format!("AICC_{}_ADDR", service.name().to_uppercase().replace('-', "_"))
+}
+
+fn host_name() -> Option<String> {
+  fs::read_to_string("/proc/sys/kernel/hostname").ok()
+    .map(|name| name.trim().to_string())
+    .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defaults_are_local() {
+    let registry = Registry::new();
+    assert_eq!(&Endpoint::new("localhost", 41331), registry.endpoint(Service::Logger));
+    assert_eq!(&Endpoint::new("localhost", 41330), registry.endpoint(Service::DriveCore));
+  }
+
+  #[test]
+  fn the_file_moves_services() {
+    let registry = Registry::parse(r#"
+      name = "red-car"
+
+      [services.logger]
+      host = "logbox"
+
+      [services.drive-core]
+      port = 5000
+    "#).unwrap();
+    assert_eq!("red-car", registry.name());
+    assert_eq!(&Endpoint::new("logbox", 41331), registry.endpoint(Service::Logger));
+    assert_eq!(&Endpoint::new("localhost", 5000), registry.endpoint(Service::DriveCore));
+  }
+
+  #[test]
+  fn the_example_configuration_is_valid() {
+    let registry = Registry::load(Path::new("config/mesh.toml")).unwrap();
+    assert_eq!(&Endpoint::new("localhost", 41331), registry.endpoint(Service::Logger));
+  }
+
+  #[test]
+  fn unknown_services_are_rejected() {
+    match Registry::parse("[services.camera]\nport = 5000\n") {
+      Err(MeshError::Invalid { ref field, .. }) => assert_eq!("services.camera", field),
+      other => panic!("Unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn environment_overrides_the_file() {
+    let mut registry = Registry::parse("[services.logger]\nhost = \"logbox\"\n").unwrap();
+    registry.apply_overrides(|var| match var {
+      "AICC_LOGGER_ADDR" => Some("10.0.0.7:6000".to_string()),
+      "AICC_DRIVE_CORE_ADDR" => Some("car".to_string()),
+      _ => None,
+    }).unwrap();
+    assert_eq!(&Endpoint::new("10.0.0.7", 6000), registry.endpoint(Service::Logger));
+    assert_eq!(&Endpoint::new("car", 41330), registry.endpoint(Service::DriveCore));
+
+    assert!(registry.apply_overrides(|_| Some("car:0".to_string())).is_err());
+  }
+
+  #[test]
+  fn endpoints_parse() {
+    assert_eq!(Ok(Endpoint::new("car", 41330)), Endpoint::parse("car", 41330));
+    assert_eq!(Ok(Endpoint::new("192.168.1.5", 80)), Endpoint::parse("192.168.1.5:80", 41330));
+    assert!(Endpoint::parse(":80", 41330).is_err());
+    assert!(Endpoint::parse("car:port", 41330).is_err());
+    assert_eq!("car:80", Endpoint::new("car", 80).to_string());
+  }
+}
@@ -1,10 +1,231 @@
-use std::time::Instant;
+// Time bases of the services. All times are u64 nanoseconds: the monotonic clock counts from the
+// start of the process and never jumps, the wall clock counts from the UNIX epoch and may be set
+// at any time. The wall clock time read at startup anchors the monotonic clock, so monotonic times
+// can be told in wall clock time and back.
+use std::collections::VecDeque;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+pub const NANOS_PER_MICRO: u64 = 1_000;
+pub const NANOS_PER_MILLI: u64 = 1_000_000;
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
 
 lazy_static! {
-  static ref START_TIME: Instant = Instant::now();
+  // Both clocks read at the first use of the time, which marks the start of the monotonic clock
+  static ref START: (Instant, u64) = (Instant::now(), unix_nanos());
+}
+
+/// Nanoseconds since the process started. Never goes backwards.
+pub fn monotonic_nanos() -> u64 {
+  START.0.elapsed().as_nanos() as u64
+}
+
+/// Nanoseconds since the UNIX epoch
+pub fn unix_nanos() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Microseconds since the UNIX epoch, the clock of all timestamps sent between services
+pub fn unix_micros() -> u64 {
+  unix_nanos() / NANOS_PER_MICRO
+}
+
+/// A monotonic time and the wall clock time read at the same moment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Anchor {
+  pub monotonic: u64,
+  pub unix: u64,
+}
+
+impl Anchor {
+  /// Wall clock time of the monotonic time `monotonic`
+  pub fn to_unix(&self, monotonic: u64) -> u64 {
+    (self.unix as i128 + monotonic as i128 - self.monotonic as i128).max(0) as u64
+  }
+
+  /// Monotonic time of the wall clock time `unix`. Times before the start of the process are 0.
+  pub fn to_monotonic(&self, unix: u64) -> u64 {
+    (self.monotonic as i128 + unix as i128 - self.unix as i128).max(0) as u64
+  }
+}
+
+/// The anchor recorded when the process started. Later changes of the wall clock don't move it.
+pub fn anchor() -> Anchor {
+  Anchor { monotonic: 0, unix: START.1 }
+}
+
+/// Where the time comes from. Code that should be tested against a simulated time asks a clock
+/// instead of reading the time itself.
+pub trait Clock: Send + Sync {
+  fn monotonic_nanos(&self) -> u64;
+  fn unix_nanos(&self) -> u64;
+
+  /// The monotonic time as a Duration since the start
+  fn monotonic(&self) -> Duration {
+    Duration::from_nanos(self.monotonic_nanos())
+  }
+
+  fn unix_micros(&self) -> u64 {
+    self.unix_nanos() / NANOS_PER_MICRO
+  }
+}
+
+/// The clocks of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn monotonic_nanos(&self) -> u64 {
+    monotonic_nanos()
+  }
+
+  fn unix_nanos(&self) -> u64 {
+    unix_nanos()
+  }
+}
+
+/// A clock for the tests that only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+  monotonic: AtomicU64,
+  unix: AtomicU64,
+}
+
+impl MockClock {
+  /// Starts at the monotonic time 0 and the wall clock time `unix`
+  pub fn new(unix: u64) -> MockClock {
+    MockClock { monotonic: AtomicU64::new(0), unix: AtomicU64::new(unix) }
+  }
+
+  /// Moves both clocks forward
+  pub fn advance(&self, duration: Duration) {
+    let nanos = duration.as_nanos() as u64;
+    self.monotonic.fetch_add(nanos, Ordering::SeqCst);
+    self.unix.fetch_add(nanos, Ordering::SeqCst);
+  }
+
+  /// Sets the wall clock like an NTP step does, the monotonic clock stays where it is
+  pub fn set_unix(&self, unix: u64) {
+    self.unix.store(unix, Ordering::SeqCst);
+  }
 }
 
-pub fn milliseconds() -> f32 {
-  let duration = START_TIME.elapsed();
-  duration.as_secs() as f32 + ((duration.subsec_nanos() / 1000000) as f32 / 1000f32) as f32
+impl Clock for MockClock {
+  fn monotonic_nanos(&self) -> u64 {
+    self.monotonic.load(Ordering::SeqCst)
+  }
+
+  fn unix_nanos(&self) -> u64 {
+    self.unix.load(Ordering::SeqCst)
+  }
+}
+
+// Exchanges kept to estimate the offset
+const CLOCK_SAMPLES: usize = 8;
+
+/// Estimates the offset of another service's clock to ours, like NTP does. Each exchange is a
+/// request sent at t1 (our clock), received at t2 and answered at t3 (their clock) and the answer
+/// received at t4 (our clock). Assuming the way there takes as long as the way back, their clock
+/// is ahead by ((t2 - t1) + (t3 - t4)) / 2. The exchange with the shortest round trip has the
+/// least room for asymmetry, so that one is trusted.
+pub struct ClockSync {
+  samples: VecDeque<(i64, u64)>,    // Offset and round trip of the last exchanges
+}
+
+impl ClockSync {
+  pub fn new() -> ClockSync {
+    ClockSync { samples: VecDeque::with_capacity(CLOCK_SAMPLES) }
+  }
+
+  /// Adds the timestamps of an exchange
+  pub fn add(&mut self, t1: u64, t2: u64, t3: u64, t4: u64) {
+    let offset = ((t2 as i64 - t1 as i64) + (t3 as i64 - t4 as i64)) / 2;
+    let round_trip = (t4.saturating_sub(t1)).saturating_sub(t3.saturating_sub(t2));
+    if self.samples.len() == CLOCK_SAMPLES {
+      self.samples.pop_front();
+    }
+    self.samples.push_back((offset, round_trip));
+  }
+
+  /// How far the other clock is ahead of ours in microseconds, 0 before the first exchange
+  pub fn offset(&self) -> i64 {
+    self.samples.iter().min_by_key(|&&(_, round_trip)| round_trip).map_or(0, |&(offset, _)| offset)
+  }
+
+  /// Converts one of our timestamps to the other clock
+  pub fn to_remote(&self, timestamp: u64) -> u64 {
+    (timestamp as i64 + self.offset()).max(0) as u64
+  }
+}
+
+impl Default for ClockSync {
+  fn default() -> ClockSync {
+    ClockSync::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_monotonic_clock_resolves_nanoseconds() {
+    let start = monotonic_nanos();
+    ::std::thread::sleep(Duration::from_millis(2));
+    let elapsed = monotonic_nanos() - start;
+    assert!((2 * NANOS_PER_MILLI..NANOS_PER_SECOND).contains(&elapsed));
+  }
+
+  #[test]
+  fn the_anchor_converts_both_ways() {
+    let fixed = Anchor { monotonic: 5 * NANOS_PER_SECOND, unix: 1_600_000_000 * NANOS_PER_SECOND };
+    assert_eq!(1_600_000_001 * NANOS_PER_SECOND + 7, fixed.to_unix(6 * NANOS_PER_SECOND + 7));
+    assert_eq!(6 * NANOS_PER_SECOND + 7, fixed.to_monotonic(1_600_000_001 * NANOS_PER_SECOND + 7));
+    assert_eq!(0, fixed.to_monotonic(1_500_000_000 * NANOS_PER_SECOND));
+
+    // The anchor of the process is fixed at its start
+    let now = unix_nanos();
+    let unix = anchor().to_unix(monotonic_nanos());
+    assert!((unix as i64 - now as i64).abs() < NANOS_PER_SECOND as i64);
+  }
+
+  #[test]
+  fn the_mock_clock_only_moves_when_told() {
+    let clock = MockClock::new(1_000 * NANOS_PER_SECOND);
+    assert_eq!(Duration::from_secs(0), clock.monotonic());
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(Duration::from_millis(1500), clock.monotonic());
+    assert_eq!(1_001_500_000, clock.unix_micros());
+
+    clock.set_unix(0);
+    assert_eq!(0, clock.unix_nanos());
+    assert_eq!(1500 * NANOS_PER_MILLI, clock.monotonic_nanos());
+  }
+
+  #[test]
+  fn symmetric_delays_give_the_exact_offset() {
+    let mut sync = ClockSync::new();
+    assert_eq!(0, sync.offset());
+
+    // Their clock is 5000 ahead, 300 each way, 100 to answer
+    sync.add(1000, 6300, 6400, 1700);
+    assert_eq!(5000, sync.offset());
+    assert_eq!(15000, sync.to_remote(10000));
+  }
+
+  #[test]
+  fn the_shortest_round_trip_wins() {
+    let mut sync = ClockSync::new();
+    // Slow way back: looks like 2000 behind the real offset
+    sync.add(1000, 6300, 6400, 5700);
+    // Fast exchange
+    sync.add(10000, 15100, 15100, 10200);
+    assert_eq!(5000, sync.offset());
+
+    // Old samples are forgotten
+    for i in 0..CLOCK_SAMPLES as u64 {
+      sync.add(20000 + i, 19000 + i, 19000 + i, 21000 + i);
+    }
+    assert_eq!(-1500, sync.offset());
+  }
 }